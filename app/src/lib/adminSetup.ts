@@ -29,9 +29,12 @@ import {
 import { verifierKeyFixture } from './fixtures';
 import { parseTokenAmount } from './amount';
 import { buildLutVersionedTransaction, sendLutVersionedTransaction } from './lut';
+import { MERKLE_DEPTH } from './merkle';
 
 type StatusHandler = (message: string) => void;
 
+const IDENTITY_TREE_DEPTH = 20;
+
 const getProvider = (program: Program): AnchorProvider => {
     const provider = program.provider as AnchorProvider;
     if (!provider.wallet) {
@@ -106,6 +109,7 @@ export async function initializeConfig(params: {
             .initializeConfig({
                 feeBps: 25,
                 relayerFeeBpsMax: 50,
+                relayerFeeBpsMin: 0,
                 vkRegistry,
                 mintAllowlist: [],
                 circuitIds: [0],
@@ -161,8 +165,9 @@ export async function initializeIdentityRegistry(params: {
         onStatus('Initializing identity registry...');
         const identityRegistry = deriveIdentityRegistry(program.programId);
         const ix = await program.methods
-            .initializeIdentityRegistry()
+            .initializeIdentityRegistry(IDENTITY_TREE_DEPTH)
             .accounts({
+                config: deriveConfig(program.programId),
                 identityRegistry,
                 admin,
                 systemProgram: SystemProgram.programId,
@@ -197,6 +202,8 @@ export async function initializeVerifierKey(params: {
                 publicInputsLen: verifierKeyFixture.gammaAbc.length - 1,
                 gammaAbc: verifierKeyFixture.gammaAbc.map((entry) => Buffer.from(entry)),
                 mock: false,
+                curve: 0,
+                inputEndianness: 0,
             })
             .accounts({
                 verifierKey,
@@ -290,7 +297,7 @@ export async function initializeMintState(params: {
         }
 
         const ix = await program.methods
-            .initializeMintState(0)
+            .initializeMintState(0, MERKLE_DEPTH, null)
             .accounts({
                 config,
                 vault,
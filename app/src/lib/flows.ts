@@ -72,6 +72,7 @@ import {
     getIdentityCommitment,
     getIdentityMerklePath,
     getOrCreateIdentitySecret,
+    identityAppendPath,
     loadIdentityCommitments,
     saveIdentityCommitments,
     setIdentityLeafIndex,
@@ -99,6 +100,25 @@ const computeRelayerFee = (amount: bigint, feeBps: number) => {
     }
     return fee;
 };
+const computeProtocolFee = (amount: bigint, feeBps: number) => {
+    if (feeBps <= 0) {
+        return 0n;
+    }
+    const fee = (amount * BigInt(feeBps)) / 10_000n;
+    if (fee >= amount) {
+        throw new Error('Protocol fee exceeds amount.');
+    }
+    return fee;
+};
+// Mirrors the program's split: both fees are rated on the gross amount and the
+// proof commits to their sum.
+const computeWithdrawFee = (amount: bigint, protocolFeeBps: number, relayerFeeBps: number) => {
+    const fee = computeProtocolFee(amount, protocolFeeBps) + computeRelayerFee(amount, relayerFeeBps);
+    if (fee >= amount) {
+        throw new Error('Protocol and relayer fees exceed amount.');
+    }
+    return fee;
+};
 
 const generateProofNonce = () => {
     const cryptoObj = globalThis.crypto;
@@ -373,7 +393,8 @@ async function fetchIdentityRegistry(program: Program) {
     const commitmentCount = BigInt(
         account.commitmentCount?.toString?.() ?? account.commitment_count?.toString?.() ?? 0
     );
-    return { identityRegistry, rootBytes, commitmentCount };
+    const identityAdmin = new PublicKey(account.identityAdmin ?? account.identity_admin);
+    return { identityRegistry, rootBytes, commitmentCount, identityAdmin };
 }
 
 async function ensureIdentityRegistered(
@@ -385,7 +406,8 @@ async function ensureIdentityRegistered(
 ) {
     setStep(onStep, 'sync', 'running');
     const provider = getProvider(program);
-    const { identityRegistry, rootBytes, commitmentCount } = await fetchIdentityRegistry(program);
+    const { identityRegistry, rootBytes, commitmentCount, identityAdmin } =
+        await fetchIdentityRegistry(program);
     let localCommitments = loadIdentityCommitments(program.programId);
     if (localCommitments.length !== Number(commitmentCount)) {
         setStep(onStep, 'sync', 'running');
@@ -400,41 +422,8 @@ async function ensureIdentityRegistered(
         const localRoot = await buildIdentityRoot(program.programId);
         const localRootBytes = bigIntToBytes32(localRoot);
         if (!bytesEqual(rootBytes, localRootBytes)) {
-            if (!signMessage) {
-                setStep(onStep, 'sync', 'error');
-                throw new Error('Local identity registry root does not match on-chain root.');
-            }
-            onStatus?.('Identity registry mismatch. Registering fresh identity.');
-            setStep(onStep, 'sign', 'running');
-            const commitment = await getIdentityCommitment(owner, program.programId, signMessage);
-            setStep(onStep, 'sign', 'success');
-            const { root } = await buildMerkleTree([commitment]);
-            const newRoot = bigIntToBytes32(root);
-            const ix = await program.methods
-                .registerIdentity({
-                    commitment: Buffer.from(bigIntToBytes32(commitment)),
-                    newRoot: Buffer.from(newRoot),
-                })
-                .accounts({
-                    identityRegistry,
-                    identityMember: deriveIdentityMember(program.programId, owner),
-                    payer: owner,
-                    user: owner,
-                    systemProgram: SystemProgram.programId,
-                })
-                .instruction();
-            setStep(onStep, 'submit', 'running');
-            await sendLutVersionedTransaction({
-                connection: provider.connection,
-                payer: owner,
-                instructions: [ix],
-                signTransaction: provider.wallet.signTransaction.bind(provider.wallet),
-            });
-            setStep(onStep, 'submit', 'success');
-            saveIdentityCommitments(program.programId, [commitment]);
-            setIdentityLeafIndex(owner, program.programId, 0);
-            setStep(onStep, 'sync', 'success');
-            return { identityRegistry, rootBytes: newRoot };
+            setStep(onStep, 'sync', 'error');
+            throw new Error('Local identity registry root does not match on-chain root.');
         }
     }
     setStep(onStep, 'sync', 'success');
@@ -443,8 +432,14 @@ async function ensureIdentityRegistered(
     setStep(onStep, 'sign', 'success');
     let index = localCommitments.findIndex((entry) => entry === commitment);
     if (index === -1) {
+        // Registration appends to the on-chain frontier and needs the identity admin's co-signature.
+        if (!identityAdmin.equals(owner)) {
+            throw new Error(
+                `Identity registration must be co-signed by the identity admin (${identityAdmin.toBase58()}).`
+            );
+        }
         const newCommitments = [...localCommitments, commitment];
-        const { root } = await buildMerkleTree(newCommitments);
+        const { root } = await identityAppendPath(program, commitment);
         const newRoot = bigIntToBytes32(root);
         onStatus?.('Registering identity...');
         setStep(onStep, 'submit', 'running');
@@ -458,6 +453,7 @@ async function ensureIdentityRegistered(
                 identityMember: deriveIdentityMember(program.programId, owner),
                 payer: owner,
                 user: owner,
+                identityAdmin,
                 systemProgram: SystemProgram.programId,
             })
             .instruction();
@@ -796,6 +792,7 @@ export async function runDepositFlow(params: {
             ciphertext: Buffer.from(ciphertext),
             commitment: Buffer.from(bigIntToBytes32(commitmentValue)),
             newRoot: Buffer.from(newRoot),
+            proof: null,
         })
         .accounts({
             config,
@@ -1257,7 +1254,12 @@ export async function runExternalTransferFlow(params: {
     if (!Number.isInteger(relayerFeeBps) || relayerFeeBps < 0 || relayerFeeBps > 10_000) {
         throw new Error('Invalid relayer fee bps.');
     }
-    let selectionTarget = baseUnits + computeRelayerFee(baseUnits, relayerFeeBps);
+    const configAccount = await (program.account as any).config.fetch(config);
+    // The destination is always the recipient's own token account, so the recipient is
+    // the owner the on-chain fee exemption list is matched against.
+    const feeExempt = (configAccount.feeExempt as PublicKey[]).some((key) => key.equals(recipient));
+    const protocolFeeBps = feeExempt ? 0 : Number(configAccount.feeBps);
+    let selectionTarget = baseUnits + computeWithdrawFee(baseUnits, protocolFeeBps, relayerFeeBps);
     ({ notes: inputNotes, total } = selectNotesForAmount(mint, owner, selectionTarget, MAX_INPUTS));
     onStatus(
         `Selection target=${formatAmount(selectionTarget, mintDecimals)} selectedNotes=${inputNotes.length} total=${formatAmount(total, mintDecimals)}`
@@ -1266,17 +1268,17 @@ export async function runExternalTransferFlow(params: {
         throw new Error('Insufficient shielded balance for that amount.');
     }
     if (total < selectionTarget) {
-        const maxAmount = (total * 10_000n) / (10_000n + BigInt(relayerFeeBps));
+        const maxAmount = (total * 10_000n) / (10_000n + BigInt(relayerFeeBps) + BigInt(protocolFeeBps));
         if (maxAmount <= 0n) {
             throw new Error('Insufficient shielded balance for fee.');
         }
         if (maxAmount < baseUnits) {
             onStatus(
-                `Reducing external transfer amount to ${formatTokenAmount(maxAmount, mintDecimals)} to cover relayer fee.`
+                `Reducing external transfer amount to ${formatTokenAmount(maxAmount, mintDecimals)} to cover fees.`
             );
             baseUnits = maxAmount;
         }
-        selectionTarget = baseUnits + computeRelayerFee(baseUnits, relayerFeeBps);
+        selectionTarget = baseUnits + computeWithdrawFee(baseUnits, protocolFeeBps, relayerFeeBps);
         ({ notes: inputNotes, total } = selectNotesForAmount(mint, owner, selectionTarget, MAX_INPUTS));
         onStatus(
             `Selection target=${formatAmount(selectionTarget, mintDecimals)} selectedNotes=${inputNotes.length} total=${formatAmount(total, mintDecimals)}`
@@ -1286,7 +1288,7 @@ export async function runExternalTransferFlow(params: {
         throw new Error('Insufficient shielded balance for fee.');
     }
     inputNotes.forEach(assertCiphertextFields);
-    const feeAmount = computeRelayerFee(baseUnits, relayerFeeBps);
+    const feeAmount = computeWithdrawFee(baseUnits, protocolFeeBps, relayerFeeBps);
     const changeAmount = total - baseUnits - feeAmount;
     if (changeAmount < 0n) {
         throw new Error('Insufficient shielded balance for fee.');
@@ -1414,15 +1416,24 @@ export async function runExternalTransferFlow(params: {
             throw new Error('Relayer fee ATA missing on-chain.');
         }
     }
+    const protocolFeeAta =
+        computeProtocolFee(baseUnits, protocolFeeBps) > 0
+            ? await getAssociatedTokenAddress(mint, configAccount.feeAuthority as PublicKey)
+            : null;
+    if (protocolFeeAta && !(await provider.connection.getAccountInfo(protocolFeeAta))) {
+        throw new Error('Protocol fee ATA missing on-chain.');
+    }
 
     setStep(onStep, 'submit', 'running');
     const ix = await program.methods
         .externalTransferWithProof({
             amount: new BN(baseUnits.toString()),
             relayerFeeBps,
+            relayerFeeLamports: new BN(0),
             newRoot: Buffer.from(newRootBytes),
             outputCiphertexts,
             deliverSol: wantsSol && mint.equals(WSOL_MINT),
+            proofSystem: 0,
         })
         .accounts({
             config,
@@ -1438,6 +1449,7 @@ export async function runExternalTransferFlow(params: {
             tempAuthority,
             tempWsolAta,
             relayerFeeAta,
+            protocolFeeAta,
             verifierProgram: VERIFIER_PROGRAM_ID,
             verifierKey,
             mint,
@@ -1476,6 +1488,9 @@ export async function runExternalTransferFlow(params: {
     if (relayerFeeAta) {
         lookupTableAddresses.push(relayerFeeAta);
     }
+    if (protocolFeeAta) {
+        lookupTableAddresses.push(protocolFeeAta);
+    }
     if (NULLIFIER_PADDING_CHUNKS > 0) {
         onStatus(
             `Nullifier padding: ${NULLIFIER_PADDING_CHUNKS} chunk(s) (including decoy sets for privacy).`
@@ -1,14 +1,22 @@
 import { Program } from '@coral-xyz/anchor';
 import type { AnchorProvider } from '@coral-xyz/anchor';
 import { PublicKey, SystemProgram } from '@solana/web3.js';
-import { deriveConfig, deriveNullifierSet } from './pda';
+import {
+    deriveConfig,
+    deriveNullifierSet,
+    deriveVault,
+    nullifierChunkIndex as chunkIndexForBytes,
+} from './pda';
 import { bigIntToBytes32 } from './prover';
 import { sendLutVersionedTransaction } from './lut';
 
-export const nullifierChunkIndex = (nullifier: bigint): number => {
-    const bytes = bigIntToBytes32(nullifier);
-    const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
-    return view.getUint32(0, true);
+export const nullifierChunkIndex = (nullifier: bigint, chunkCount: number): number =>
+    chunkIndexForBytes(bigIntToBytes32(nullifier), chunkCount);
+
+// Pools fix their chunk count at creation, so it is read from the vault, not the config.
+export const fetchNullifierChunks = async (program: Program, mint: PublicKey): Promise<number> => {
+    const vault = await (program.account as any).vaultPool.fetch(deriveVault(program.programId, mint));
+    return Number(vault.nullifierChunks);
 };
 
 export const ensureNullifierSet = async (
@@ -20,7 +28,7 @@ export const ensureNullifierSet = async (
     if (!provider.wallet) {
         throw new Error('Connect a wallet to initialize nullifier chunks.');
     }
-    const chunkIndex = nullifierChunkIndex(nullifier);
+    const chunkIndex = nullifierChunkIndex(nullifier, await fetchNullifierChunks(program, mint));
     const nullifierSet = deriveNullifierSet(program.programId, mint, chunkIndex);
     const info = await provider.connection.getAccountInfo(nullifierSet);
     if (info) {
@@ -51,15 +59,16 @@ export const ensureNullifierSets = async (
     nullifiers: bigint[],
     paddingChunks = 0
 ): Promise<PublicKey[]> => {
+    const chunkCount = await fetchNullifierChunks(program, mint);
     const chunkIndexes = new Set<number>();
     for (const nullifier of nullifiers) {
         if (nullifier === 0n) {
             continue;
         }
-        chunkIndexes.add(nullifierChunkIndex(nullifier));
+        chunkIndexes.add(nullifierChunkIndex(nullifier, chunkCount));
     }
     if (paddingChunks > 0) {
-        for (let index = 0; index < paddingChunks; index += 1) {
+        for (let index = 0; index < Math.min(paddingChunks, chunkCount); index += 1) {
             chunkIndexes.add(index);
         }
     }
@@ -0,0 +1,574 @@
+//! Public-input layout of the veilpay spend circuits, shared by the on-chain program and
+//! clients so both pack the same words in the same order. Every input is a 32-byte
+//! big-endian word: `root`, `identity_root`, one nullifier per input note, the output
+//! commitments and their enabled flags, `amount_out`, `fee_amount`, `circuit_id` and the
+//! pool's domain, optionally followed by `min_slot`/`max_slot`, `value_in` and the
+//! relayer fee recipient. How many nullifiers and outputs there are is the pool's
+//! [`SpendLayout`].
+//!
+//! The crate is `no_std`. With the `wasm` feature it also exports the layout and a
+//! validator to JavaScript; build it with
+//! `cargo rustc --crate-type cdylib --features wasm --target wasm32-unknown-unknown`.
+
+#![cfg_attr(not(feature = "wasm"), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Size of every public input word.
+pub const WORD_LEN: usize = 32;
+/// Largest spend layout a pool can select. Outputs stop at two because that is all the
+/// program's note ciphertext handling knows about.
+pub const MAX_INPUTS: usize = 8;
+pub const MAX_OUTPUTS: usize = 2;
+pub const SLOT_WINDOW_INPUTS_LEN: usize = 2;
+pub const VALUE_IN_INPUTS_LEN: usize = 1;
+pub const RELAYER_RECIPIENT_INPUTS_LEN: usize = 1;
+pub const MAX_PUBLIC_INPUTS_LEN: usize = SpendLayout::CAPACITY.max_len();
+// BN254 scalar field modulus r (big-endian). The verifier reduces public inputs
+// mod r, so only encodings below it are accepted as nullifiers.
+pub const SCALAR_FIELD_MODULUS: [u8; 32] = [
+    0x30, 0x64, 0x4E, 0x72, 0xE1, 0x31, 0xA0, 0x29,
+    0xB8, 0x50, 0x45, 0xB6, 0x81, 0x81, 0x58, 0x5D,
+    0x28, 0x33, 0xE8, 0x48, 0x79, 0xB9, 0x70, 0x91,
+    0x43, 0xE1, 0xF5, 0x93, 0xF0, 0x00, 0x00, 0x01,
+];
+
+/// Why a buffer does not decode. The program maps these onto its own error codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not a whole number of words between the layout's base and full length.
+    Length,
+    /// A nullifier at or above the scalar modulus.
+    NonCanonicalNullifier,
+    /// A root, commitment, domain or relayer recipient at or above the scalar modulus.
+    NonCanonicalField,
+    /// An integer word with bytes set above its width, or a flag other than 0 or 1.
+    NonCanonicalInteger,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Length => "public inputs length does not fit the spend layout",
+            Self::NonCanonicalNullifier => "nullifier is not below the scalar field modulus",
+            Self::NonCanonicalField => "field element is not below the scalar field modulus",
+            Self::NonCanonicalInteger => "integer word is not a big-endian value of its width",
+        })
+    }
+}
+
+/// How many nullifiers and output notes a spend circuit exposes. Every public input
+/// after the two roots sits at an offset derived from these, so a pool only accepts
+/// proofs from circuits of its own shape.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpendLayout {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl Default for SpendLayout {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl SpendLayout {
+    /// The 4-in/2-out circuit, used by pools that never called `set_spend_layout`.
+    pub const DEFAULT: Self = Self {
+        inputs: 4,
+        outputs: 2,
+    };
+    pub const CAPACITY: Self = Self {
+        inputs: MAX_INPUTS,
+        outputs: MAX_OUTPUTS,
+    };
+    pub const SMALLEST: Self = Self {
+        inputs: 1,
+        outputs: 1,
+    };
+
+    /// `None` unless both counts are between one and `MAX_INPUTS`/`MAX_OUTPUTS`.
+    pub fn new(inputs: usize, outputs: usize) -> Option<Self> {
+        ((1..=MAX_INPUTS).contains(&inputs) && (1..=MAX_OUTPUTS).contains(&outputs))
+            .then_some(Self { inputs, outputs })
+    }
+
+    /// Roots, nullifiers, commitments and their enabled flags, then `amount_out`,
+    /// `fee_amount`, `circuit_id` and the domain.
+    pub const fn base_len(&self) -> usize {
+        2 + self.inputs + 2 * self.outputs + 4
+    }
+
+    /// `base_len` plus the slot window, `value_in` and the relayer fee recipient.
+    pub const fn max_len(&self) -> usize {
+        self.base_len()
+            + SLOT_WINDOW_INPUTS_LEN
+            + VALUE_IN_INPUTS_LEN
+            + RELAYER_RECIPIENT_INPUTS_LEN
+    }
+
+    pub fn supports_bytes_len(&self, len: usize) -> bool {
+        len.is_multiple_of(WORD_LEN) && (self.base_len()..=self.max_len()).contains(&(len / WORD_LEN))
+    }
+}
+
+/// Decoded spend public inputs. Nullifier and output slots past the layout's counts
+/// are zero, so every layout decodes to the same shape.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicInputs {
+    pub root: [u8; 32],
+    pub identity_root: [u8; 32],
+    pub nullifiers: [[u8; 32]; MAX_INPUTS],
+    pub output_commitments: [[u8; 32]; MAX_OUTPUTS],
+    pub output_enabled: [u8; MAX_OUTPUTS],
+    pub amount_out: u64,
+    pub fee_amount: u64,
+    pub circuit_id: u32,
+    /// `domain_separator` of the pool the proof was made for.
+    pub domain: [u8; 32],
+    pub slot_window: Option<(u64, u64)>,
+    pub value_in: Option<u64>,
+    /// `pubkey_to_field` of the relayer fee account's owner. Only the full layout carries
+    /// it, after the slot window and `value_in`.
+    pub relayer_fee_recipient: Option<[u8; 32]>,
+}
+
+/// Decodes `bytes` under `layout`, accepting only canonical encodings: every field
+/// element below the scalar modulus and every integer word zero above its width.
+pub fn decode_public_inputs(bytes: &[u8], layout: SpendLayout) -> Result<PublicInputs, DecodeError> {
+    if !layout.supports_bytes_len(bytes.len()) {
+        return Err(DecodeError::Length);
+    }
+    let chunks: Vec<[u8; 32]> = bytes
+        .chunks(WORD_LEN)
+        .map(|chunk| {
+            let mut out = [0u8; 32];
+            out.copy_from_slice(chunk);
+            out
+        })
+        .collect();
+    let root = decode_field(&chunks[0])?;
+    let identity_root = decode_field(&chunks[1])?;
+    let commitments_at = 2 + layout.inputs;
+    let flags_at = commitments_at + layout.outputs;
+    let amounts_at = flags_at + layout.outputs;
+    let mut nullifiers = [[0u8; 32]; MAX_INPUTS];
+    for (i, nullifier) in nullifiers.iter_mut().take(layout.inputs).enumerate() {
+        *nullifier = chunks[2 + i];
+        if !is_canonical_scalar(nullifier) {
+            return Err(DecodeError::NonCanonicalNullifier);
+        }
+    }
+    let mut output_commitments = [[0u8; 32]; MAX_OUTPUTS];
+    for (i, commitment) in output_commitments.iter_mut().take(layout.outputs).enumerate() {
+        *commitment = decode_field(&chunks[commitments_at + i])?;
+    }
+    let mut output_enabled = [0u8; MAX_OUTPUTS];
+    for (i, flag) in output_enabled.iter_mut().take(layout.outputs).enumerate() {
+        *flag = decode_flag(&chunks[flags_at + i])?;
+    }
+    let amount_out = decode_u64(&chunks[amounts_at])?;
+    let fee_amount = decode_u64(&chunks[amounts_at + 1])?;
+    let circuit_id = decode_u32(&chunks[amounts_at + 2])?;
+    let domain = decode_field(&chunks[amounts_at + 3])?;
+    let base = layout.base_len();
+    let optional = chunks.len() - base;
+    let relayer_fee_recipient = if chunks.len() == layout.max_len() {
+        Some(decode_field(&chunks[chunks.len() - 1])?)
+    } else {
+        None
+    };
+    let slot_window = if optional >= SLOT_WINDOW_INPUTS_LEN {
+        Some((decode_u64(&chunks[base])?, decode_u64(&chunks[base + 1])?))
+    } else {
+        None
+    };
+    let value_in = if optional == VALUE_IN_INPUTS_LEN
+        || optional >= SLOT_WINDOW_INPUTS_LEN + VALUE_IN_INPUTS_LEN
+    {
+        let index = if optional == VALUE_IN_INPUTS_LEN {
+            base
+        } else {
+            base + SLOT_WINDOW_INPUTS_LEN
+        };
+        Some(decode_u64(&chunks[index])?)
+    } else {
+        None
+    };
+    Ok(PublicInputs {
+        root,
+        identity_root,
+        nullifiers,
+        output_commitments,
+        output_enabled,
+        amount_out,
+        fee_amount,
+        circuit_id,
+        domain,
+        slot_window,
+        value_in,
+        relayer_fee_recipient,
+    })
+}
+
+/// Inverse of [`decode_public_inputs`]: packs the fields into the canonical
+/// big-endian layout the circuit exposes, appending `min_slot`/`max_slot` when the
+/// proof carries a slot window, then `value_in` when the circuit declares it and
+/// finally `relayer_fee_recipient`, which only decodes back alongside both. Slots
+/// past `layout`'s counts are dropped.
+pub fn encode_public_inputs(inputs: &PublicInputs, layout: SpendLayout) -> Vec<u8> {
+    let mut fields = alloc::vec![[0u8; 32]; layout.max_len()];
+    fields[0] = inputs.root;
+    fields[1] = inputs.identity_root;
+    let outputs = 2 + layout.inputs;
+    fields[2..outputs].copy_from_slice(&inputs.nullifiers[..layout.inputs]);
+    let enabled = outputs + layout.outputs;
+    fields[outputs..enabled].copy_from_slice(&inputs.output_commitments[..layout.outputs]);
+    let amounts = enabled + layout.outputs;
+    for (i, flag) in inputs.output_enabled[..layout.outputs].iter().enumerate() {
+        fields[enabled + i] = encode_u64(*flag as u64);
+    }
+    fields[amounts] = encode_u64(inputs.amount_out);
+    fields[amounts + 1] = encode_u64(inputs.fee_amount);
+    fields[amounts + 2] = encode_u64(inputs.circuit_id as u64);
+    fields[amounts + 3] = inputs.domain;
+    let mut len = layout.base_len();
+    if let Some((min_slot, max_slot)) = inputs.slot_window {
+        fields[len] = encode_u64(min_slot);
+        fields[len + 1] = encode_u64(max_slot);
+        len += SLOT_WINDOW_INPUTS_LEN;
+    }
+    if let Some(value_in) = inputs.value_in {
+        fields[len] = encode_u64(value_in);
+        len += VALUE_IN_INPUTS_LEN;
+    }
+    if let Some(recipient) = inputs.relayer_fee_recipient {
+        fields[len] = recipient;
+        len += RELAYER_RECIPIENT_INPUTS_LEN;
+    }
+    fields[..len].concat()
+}
+
+pub fn encode_u64(value: u64) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out[24..].copy_from_slice(&value.to_be_bytes());
+    out
+}
+
+pub fn decode_u64(word: &[u8; 32]) -> Result<u64, DecodeError> {
+    if word[..24].iter().any(|b| *b != 0) {
+        return Err(DecodeError::NonCanonicalInteger);
+    }
+    Ok(u64::from_be_bytes(word[24..].try_into().unwrap()))
+}
+
+pub fn decode_u32(word: &[u8; 32]) -> Result<u32, DecodeError> {
+    if word[..28].iter().any(|b| *b != 0) {
+        return Err(DecodeError::NonCanonicalInteger);
+    }
+    Ok(u32::from_be_bytes(word[28..].try_into().unwrap()))
+}
+
+fn decode_flag(word: &[u8; 32]) -> Result<u8, DecodeError> {
+    match decode_u64(word)? {
+        value @ (0 | 1) => Ok(value as u8),
+        _ => Err(DecodeError::NonCanonicalInteger),
+    }
+}
+
+/// The verifier reduces public inputs mod r, so a root or commitment at or above the
+/// modulus would verify as a different value than the one the program records.
+fn decode_field(word: &[u8; 32]) -> Result<[u8; 32], DecodeError> {
+    if !is_canonical_scalar(word) {
+        return Err(DecodeError::NonCanonicalField);
+    }
+    Ok(*word)
+}
+
+/// `n` and `n + r` verify against the same proof but land on different nullifier bits,
+/// so the program only marks encodings below the modulus.
+pub fn is_canonical_scalar(value: &[u8; 32]) -> bool {
+    *value < SCALAR_FIELD_MODULUS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DEFAULT_BYTES_LEN: usize = SpendLayout::DEFAULT.base_len() * WORD_LEN;
+
+    /// Field elements below 2^253, which is under the scalar modulus.
+    fn arb_field() -> impl Strategy<Value = [u8; 32]> {
+        any::<[u8; 32]>().prop_map(|mut bytes| {
+            bytes[0] &= 0x1F;
+            bytes
+        })
+    }
+
+    fn arb_public_inputs() -> impl Strategy<Value = PublicInputs> {
+        (
+            arb_field(),
+            arb_field(),
+            proptest::array::uniform8(arb_field()),
+            [arb_field(), arb_field()],
+            [0u8..=1, 0u8..=1],
+            any::<u64>(),
+            any::<u64>(),
+            any::<u32>(),
+            arb_field(),
+            proptest::option::of(any::<(u64, u64)>()),
+            proptest::option::of(any::<u64>()),
+            proptest::option::of(arb_field()),
+        )
+            .prop_map(
+                |(
+                    root,
+                    identity_root,
+                    nullifiers,
+                    output_commitments,
+                    output_enabled,
+                    amount_out,
+                    fee_amount,
+                    circuit_id,
+                    domain,
+                    slot_window,
+                    value_in,
+                    relayer_fee_recipient,
+                )| PublicInputs {
+                    root,
+                    identity_root,
+                    nullifiers,
+                    output_commitments,
+                    output_enabled,
+                    amount_out,
+                    fee_amount,
+                    circuit_id,
+                    domain,
+                    slot_window,
+                    value_in,
+                    // Only the full layout carries a recipient.
+                    relayer_fee_recipient: relayer_fee_recipient
+                        .filter(|_| slot_window.is_some() && value_in.is_some()),
+                },
+            )
+    }
+
+    fn arb_layout() -> impl Strategy<Value = SpendLayout> {
+        (1..=MAX_INPUTS, 1..=MAX_OUTPUTS)
+            .prop_map(|(inputs, outputs)| SpendLayout { inputs, outputs })
+    }
+
+    /// A selectable layout and inputs that fit it: slots past its counts are zero.
+    fn arb_layout_and_inputs() -> impl Strategy<Value = (SpendLayout, PublicInputs)> {
+        (arb_layout(), arb_public_inputs()).prop_map(|(layout, mut inputs)| {
+            inputs.nullifiers[layout.inputs..].fill([0u8; 32]);
+            inputs.output_commitments[layout.outputs..].fill([0u8; 32]);
+            inputs.output_enabled[layout.outputs..].fill(0);
+            (layout, inputs)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn public_inputs_round_trip((layout, inputs) in arb_layout_and_inputs()) {
+            let bytes = encode_public_inputs(&inputs, layout);
+            prop_assert_eq!(decode_public_inputs(&bytes, layout).unwrap(), inputs);
+        }
+
+        #[test]
+        fn canonical_public_input_bytes_round_trip((layout, inputs) in arb_layout_and_inputs()) {
+            let bytes = encode_public_inputs(&inputs, layout);
+            let decoded = decode_public_inputs(&bytes, layout).unwrap();
+            prop_assert_eq!(encode_public_inputs(&decoded, layout), bytes);
+        }
+
+        // Whatever the buffer, decoding returns instead of indexing out of bounds, and
+        // anything it accepts re-encodes to the same bytes.
+        #[test]
+        fn decode_never_panics_on_arbitrary_bytes(
+            layout in arb_layout(),
+            bytes in proptest::collection::vec(any::<u8>(), 0..=MAX_PUBLIC_INPUTS_LEN * 32 + 64),
+        ) {
+            if let Ok(decoded) = decode_public_inputs(&bytes, layout) {
+                prop_assert!(layout.supports_bytes_len(bytes.len()));
+                prop_assert_eq!(encode_public_inputs(&decoded, layout), bytes);
+            }
+        }
+
+        #[test]
+        fn decode_accepts_exactly_the_layout_lengths(
+            layout in arb_layout(),
+            len in 0..=MAX_PUBLIC_INPUTS_LEN * 32 + 64,
+        ) {
+            let result = decode_public_inputs(&alloc::vec![0u8; len], layout);
+            prop_assert_eq!(result.is_ok(), layout.supports_bytes_len(len));
+            if let Err(err) = result {
+                prop_assert_eq!(err, DecodeError::Length);
+            }
+        }
+
+        // A single corrupted byte either leaves a canonical encoding, which must then
+        // round-trip, or gets the input rejected: a high byte in a u8/u32/u64 word or a
+        // field element at or above the modulus never decodes.
+        #[test]
+        fn decode_only_accepts_canonical_encodings(
+            (layout, inputs) in arb_layout_and_inputs(),
+            index in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut bytes = encode_public_inputs(&inputs, layout);
+            let at = index.index(bytes.len());
+            bytes[at] = byte;
+            if let Ok(decoded) = decode_public_inputs(&bytes, layout) {
+                prop_assert_eq!(encode_public_inputs(&decoded, layout), bytes);
+            }
+        }
+    }
+
+    fn layout_fields(count: usize) -> PublicInputs {
+        let mut nullifiers = [[0u8; 32]; MAX_INPUTS];
+        for (i, nullifier) in nullifiers.iter_mut().take(count).enumerate() {
+            *nullifier = [10 + i as u8; 32];
+        }
+        PublicInputs {
+            root: [1u8; 32],
+            identity_root: [2u8; 32],
+            nullifiers,
+            output_commitments: [[7u8; 32], [8u8; 32]],
+            output_enabled: [1, 0],
+            amount_out: 500,
+            fee_amount: 5,
+            circuit_id: 3,
+            domain: [9u8; 32],
+            slot_window: None,
+            value_in: None,
+            relayer_fee_recipient: None,
+        }
+    }
+
+    #[test]
+    fn encode_uses_circuit_field_order() {
+        let mut inputs = layout_fields(4);
+        inputs.amount_out = 0x0102_0304;
+        inputs.fee_amount = 9;
+        inputs.circuit_id = 0xabcd;
+        let bytes = encode_public_inputs(&inputs, SpendLayout::DEFAULT);
+        assert_eq!(bytes.len(), DEFAULT_BYTES_LEN);
+        let field = |index: usize| &bytes[index * 32..(index + 1) * 32];
+        assert_eq!(field(0), &[1u8; 32]);
+        assert_eq!(field(1), &[2u8; 32]);
+        assert_eq!(field(5), &[13u8; 32]);
+        assert_eq!(field(7), &[8u8; 32]);
+        assert_eq!(field(8), &encode_u64(1));
+        assert_eq!(field(9), &[0u8; 32]);
+        assert_eq!(&field(10)[28..], &[1, 2, 3, 4]);
+        assert_eq!(field(11)[31], 9);
+        assert_eq!(&field(12)[30..], &[0xab, 0xcd]);
+        assert_eq!(field(13), &[9u8; 32]);
+    }
+
+    #[test]
+    fn optional_fields_follow_the_domain_in_order() {
+        let mut inputs = layout_fields(4);
+        inputs.slot_window = Some((100, 200));
+        let bytes = encode_public_inputs(&inputs, SpendLayout::DEFAULT);
+        assert_eq!(bytes.len(), DEFAULT_BYTES_LEN + SLOT_WINDOW_INPUTS_LEN * 32);
+        assert_eq!(&bytes[14 * 32..15 * 32], &encode_u64(100));
+        assert_eq!(&bytes[15 * 32..], &encode_u64(200));
+
+        inputs.slot_window = None;
+        inputs.value_in = Some(500);
+        let bytes = encode_public_inputs(&inputs, SpendLayout::DEFAULT);
+        assert_eq!(&bytes[14 * 32..], &encode_u64(500));
+
+        inputs.slot_window = Some((100, 200));
+        inputs.relayer_fee_recipient = Some([4u8; 32]);
+        let bytes = encode_public_inputs(&inputs, SpendLayout::DEFAULT);
+        assert_eq!(bytes.len(), SpendLayout::DEFAULT.max_len() * 32);
+        assert_eq!(&bytes[16 * 32..17 * 32], &encode_u64(500));
+        assert_eq!(&bytes[17 * 32..], &[4u8; 32]);
+        assert_eq!(decode_public_inputs(&bytes, SpendLayout::DEFAULT).unwrap(), inputs);
+    }
+
+    #[test]
+    fn two_input_layout_shifts_every_later_offset() {
+        let layout = SpendLayout::new(2, 2).unwrap();
+        let inputs = layout_fields(2);
+        let bytes = encode_public_inputs(&inputs, layout);
+        assert_eq!(bytes.len(), 12 * 32);
+        let field = |index: usize| &bytes[index * 32..(index + 1) * 32];
+        assert_eq!(field(3), &[11u8; 32]);
+        assert_eq!(field(4), &[7u8; 32]);
+        assert_eq!(field(6), &encode_u64(1));
+        assert_eq!(field(8), &encode_u64(500));
+        assert_eq!(field(11), &[9u8; 32]);
+        assert_eq!(decode_public_inputs(&bytes, layout).unwrap(), inputs);
+        assert_eq!(
+            decode_public_inputs(&bytes, SpendLayout::DEFAULT),
+            Err(DecodeError::Length)
+        );
+
+        let mut windowed = inputs.clone();
+        windowed.slot_window = Some((100, 200));
+        let bytes = encode_public_inputs(&windowed, layout);
+        assert_eq!(&bytes[12 * 32..13 * 32], &encode_u64(100));
+        assert_eq!(decode_public_inputs(&bytes, layout).unwrap(), windowed);
+    }
+
+    #[test]
+    fn eight_input_layout_reads_every_nullifier() {
+        let layout = SpendLayout::new(8, 2).unwrap();
+        let mut inputs = layout_fields(8);
+        inputs.slot_window = Some((100, 200));
+        inputs.value_in = Some(505);
+        inputs.relayer_fee_recipient = Some([4u8; 32]);
+        let bytes = encode_public_inputs(&inputs, layout);
+        assert_eq!(bytes.len(), layout.max_len() * 32);
+        assert_eq!(&bytes[9 * 32..10 * 32], &[17u8; 32]);
+        assert_eq!(&bytes[10 * 32..11 * 32], &[7u8; 32]);
+        let decoded = decode_public_inputs(&bytes, layout).unwrap();
+        assert_eq!(decoded.nullifiers[7], [17u8; 32]);
+        assert_eq!(decoded, inputs);
+
+        assert_eq!(
+            decode_public_inputs(&[0u8; DEFAULT_BYTES_LEN], layout),
+            Err(DecodeError::Length)
+        );
+    }
+
+    #[test]
+    fn decode_names_the_word_that_is_not_canonical() {
+        let layout = SpendLayout::DEFAULT;
+        let bytes = encode_public_inputs(&layout_fields(4), layout);
+        let corrupt = |index: usize, word: [u8; 32]| {
+            let mut bytes = bytes.clone();
+            bytes[index * 32..(index + 1) * 32].copy_from_slice(&word);
+            decode_public_inputs(&bytes, layout).map(|_| ()).unwrap_err()
+        };
+        assert_eq!(corrupt(0, SCALAR_FIELD_MODULUS), DecodeError::NonCanonicalField);
+        assert_eq!(corrupt(2, SCALAR_FIELD_MODULUS), DecodeError::NonCanonicalNullifier);
+        assert_eq!(corrupt(8, encode_u64(2)), DecodeError::NonCanonicalInteger);
+        let mut wide = encode_u64(1);
+        wide[0] = 1;
+        assert_eq!(corrupt(10, wide), DecodeError::NonCanonicalInteger);
+        assert_eq!(corrupt(12, encode_u64(1 << 32)), DecodeError::NonCanonicalInteger);
+    }
+
+    #[test]
+    fn layouts_are_bounded() {
+        assert_eq!(SpendLayout::DEFAULT.base_len(), 14);
+        assert_eq!(SpendLayout::SMALLEST.base_len(), 9);
+        assert_eq!(MAX_PUBLIC_INPUTS_LEN, 22);
+        for (inputs, outputs) in [(0, 2), (4, 0), (MAX_INPUTS + 1, 2), (4, MAX_OUTPUTS + 1)] {
+            assert_eq!(SpendLayout::new(inputs, outputs), None);
+        }
+        assert_eq!(SpendLayout::new(4, 2), Some(SpendLayout::DEFAULT));
+    }
+}
@@ -0,0 +1,44 @@
+//! JavaScript bindings. Layouts cross the boundary as `(inputs, outputs)` pairs and
+//! public inputs as the packed big-endian buffer, so a client can check what it built
+//! against the same code the program runs.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{decode_public_inputs, encode_u64, DecodeError, SpendLayout, MAX_PUBLIC_INPUTS_LEN};
+
+fn layout(inputs: usize, outputs: usize) -> Result<SpendLayout, JsError> {
+    SpendLayout::new(inputs, outputs).ok_or_else(|| JsError::new("invalid spend layout"))
+}
+
+#[wasm_bindgen(js_name = baseLen)]
+pub fn base_len(inputs: usize, outputs: usize) -> Result<usize, JsError> {
+    Ok(layout(inputs, outputs)?.base_len())
+}
+
+#[wasm_bindgen(js_name = maxLen)]
+pub fn max_len(inputs: usize, outputs: usize) -> Result<usize, JsError> {
+    Ok(layout(inputs, outputs)?.max_len())
+}
+
+#[wasm_bindgen(js_name = maxPublicInputsLen)]
+pub fn max_public_inputs_len() -> usize {
+    MAX_PUBLIC_INPUTS_LEN
+}
+
+/// Empty when `bytes` decodes under the layout, otherwise the `DecodeError` variant.
+#[wasm_bindgen(js_name = checkPublicInputs)]
+pub fn check_public_inputs(bytes: &[u8], inputs: usize, outputs: usize) -> Result<String, JsError> {
+    let name = match decode_public_inputs(bytes, layout(inputs, outputs)?) {
+        Ok(_) => "",
+        Err(DecodeError::Length) => "Length",
+        Err(DecodeError::NonCanonicalNullifier) => "NonCanonicalNullifier",
+        Err(DecodeError::NonCanonicalField) => "NonCanonicalField",
+        Err(DecodeError::NonCanonicalInteger) => "NonCanonicalInteger",
+    };
+    Ok(name.into())
+}
+
+#[wasm_bindgen(js_name = encodeU64)]
+pub fn encode_u64_word(value: u64) -> Vec<u8> {
+    encode_u64(value).to_vec()
+}
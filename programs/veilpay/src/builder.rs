@@ -0,0 +1,438 @@
+//! Typed builders for spend instruction args. They apply the length and consistency
+//! checks the program runs on-chain, so Rust clients get `InvalidProofDataLength`,
+//! `InvalidPublicInputsLength`, `InvalidByteLength` and friends before submitting.
+
+use anchor_lang::prelude::*;
+
+use crate::{
+    parse_output_ciphertexts, parse_public_inputs, split_withdraw, to_fixed_32,
+    ExternalTransferArgs, ExternalTransferWithProofArgs, InternalTransferArgs,
+    InternalTransferWithProofArgs, ParsedPublicInputs, QueueWithdrawalArgs, SpendLayout,
+    VeilpayError, PROOF_BYTES_LEN, PROOF_SYSTEM_ALT,
+};
+
+/// Builds the args for withdrawing to an external token account: inline
+/// (`external_transfer`), from a stored proof (`external_transfer_with_proof`) or
+/// through the withdraw queue (`queue_withdrawal`).
+#[derive(Clone, Debug, Default)]
+pub struct WithdrawArgsBuilder {
+    amount: u64,
+    proof: Vec<u8>,
+    public_inputs: Vec<u8>,
+    relayer_fee_bps: u16,
+    relayer_fee_lamports: u64,
+    protocol_fee_bps: u16,
+    new_root: Vec<u8>,
+    output_ciphertexts: Vec<u8>,
+    deliver_sol: bool,
+    proof_system: u8,
+    spend_layout: SpendLayout,
+}
+
+impl WithdrawArgsBuilder {
+    pub fn new(amount: u64) -> Self {
+        Self {
+            amount,
+            ..Self::default()
+        }
+    }
+
+    pub fn proof(mut self, proof: impl Into<Vec<u8>>) -> Self {
+        self.proof = proof.into();
+        self
+    }
+
+    pub fn public_inputs(mut self, public_inputs: impl Into<Vec<u8>>) -> Self {
+        self.public_inputs = public_inputs.into();
+        self
+    }
+
+    pub fn relayer_fee_bps(mut self, relayer_fee_bps: u16) -> Self {
+        self.relayer_fee_bps = relayer_fee_bps;
+        self
+    }
+
+    /// Pays the relayer from the recipient's fee escrow instead; `relayer_fee_bps` must
+    /// then stay zero and the recipient has to sign the withdrawal.
+    pub fn relayer_fee_lamports(mut self, relayer_fee_lamports: u64) -> Self {
+        self.relayer_fee_lamports = relayer_fee_lamports;
+        self
+    }
+
+    /// Protocol fee rate the withdrawal will be charged: `Config::fee_bps`, or zero
+    /// when the payout owner is fee exempt.
+    pub fn protocol_fee_bps(mut self, protocol_fee_bps: u16) -> Self {
+        self.protocol_fee_bps = protocol_fee_bps;
+        self
+    }
+
+    pub fn new_root(mut self, new_root: impl Into<Vec<u8>>) -> Self {
+        self.new_root = new_root.into();
+        self
+    }
+
+    pub fn output_ciphertexts(mut self, output_ciphertexts: impl Into<Vec<u8>>) -> Self {
+        self.output_ciphertexts = output_ciphertexts.into();
+        self
+    }
+
+    pub fn deliver_sol(mut self, deliver_sol: bool) -> Self {
+        self.deliver_sol = deliver_sol;
+        self
+    }
+
+    /// Which of the withdrawal's verifier keys checks the proof. `build_queued` drops
+    /// it, since `queue_withdrawal` takes a single key.
+    pub fn proof_system(mut self, proof_system: u8) -> Self {
+        self.proof_system = proof_system;
+        self
+    }
+
+    /// Layout of the pool being spent from; `SpendLayout::DEFAULT` unless the pool
+    /// was switched with `set_spend_layout`.
+    pub fn spend_layout(mut self, spend_layout: SpendLayout) -> Self {
+        self.spend_layout = spend_layout;
+        self
+    }
+
+    /// Args for `external_transfer`, carrying the proof inline.
+    pub fn build(self) -> Result<ExternalTransferArgs> {
+        self.validate()?;
+        Ok(ExternalTransferArgs {
+            amount: self.amount,
+            proof: self.proof,
+            public_inputs: self.public_inputs,
+            relayer_fee_bps: self.relayer_fee_bps,
+            relayer_fee_lamports: self.relayer_fee_lamports,
+            new_root: self.new_root,
+            output_ciphertexts: self.output_ciphertexts,
+            deliver_sol: self.deliver_sol,
+            proof_system: self.proof_system,
+        })
+    }
+
+    /// Args for `external_transfer_with_proof`; the proof itself goes through
+    /// `store_proof` but is still validated here against the spend.
+    pub fn build_with_stored_proof(self) -> Result<ExternalTransferWithProofArgs> {
+        self.validate()?;
+        Ok(ExternalTransferWithProofArgs {
+            amount: self.amount,
+            relayer_fee_bps: self.relayer_fee_bps,
+            relayer_fee_lamports: self.relayer_fee_lamports,
+            new_root: self.new_root,
+            output_ciphertexts: self.output_ciphertexts,
+            deliver_sol: self.deliver_sol,
+            proof_system: self.proof_system,
+        })
+    }
+
+    /// Args for `queue_withdrawal`, which never delivers native SOL and only pays
+    /// relayers in the pool token.
+    pub fn build_queued(self) -> Result<QueueWithdrawalArgs> {
+        require!(!self.deliver_sol, VeilpayError::UnsupportedSolDelivery);
+        require!(self.relayer_fee_lamports == 0, VeilpayError::InvalidRelayerFeeMode);
+        self.validate()?;
+        Ok(QueueWithdrawalArgs {
+            amount: self.amount,
+            relayer_fee_bps: self.relayer_fee_bps,
+            new_root: self.new_root,
+            output_ciphertexts: self.output_ciphertexts,
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        require!(
+            self.proof_system <= PROOF_SYSTEM_ALT,
+            VeilpayError::InvalidProofSystem
+        );
+        require!(
+            self.relayer_fee_lamports == 0 || self.relayer_fee_bps == 0,
+            VeilpayError::InvalidRelayerFeeMode
+        );
+        let parsed = validate_spend(
+            &self.proof,
+            &self.public_inputs,
+            &self.new_root,
+            &self.output_ciphertexts,
+            self.spend_layout,
+        )?;
+        require!(parsed.amount_out == self.amount, VeilpayError::AmountMismatch);
+        require!(parsed.output_enabled[0] == 0, VeilpayError::InvalidOutputFlags);
+        let split = split_withdraw(self.amount, self.protocol_fee_bps, self.relayer_fee_bps)?;
+        require!(split.total_fee()? == parsed.fee_amount, VeilpayError::FeeMismatch);
+        Ok(())
+    }
+}
+
+/// Builds the args for a shielded-to-shielded `internal_transfer`.
+#[derive(Clone, Debug, Default)]
+pub struct InternalTransferArgsBuilder {
+    proof: Vec<u8>,
+    public_inputs: Vec<u8>,
+    new_root: Vec<u8>,
+    output_ciphertexts: Vec<u8>,
+    spend_layout: SpendLayout,
+}
+
+impl InternalTransferArgsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn proof(mut self, proof: impl Into<Vec<u8>>) -> Self {
+        self.proof = proof.into();
+        self
+    }
+
+    pub fn public_inputs(mut self, public_inputs: impl Into<Vec<u8>>) -> Self {
+        self.public_inputs = public_inputs.into();
+        self
+    }
+
+    pub fn new_root(mut self, new_root: impl Into<Vec<u8>>) -> Self {
+        self.new_root = new_root.into();
+        self
+    }
+
+    pub fn output_ciphertexts(mut self, output_ciphertexts: impl Into<Vec<u8>>) -> Self {
+        self.output_ciphertexts = output_ciphertexts.into();
+        self
+    }
+
+    /// Layout of the pool being spent from, as for `WithdrawArgsBuilder::spend_layout`.
+    pub fn spend_layout(mut self, spend_layout: SpendLayout) -> Self {
+        self.spend_layout = spend_layout;
+        self
+    }
+
+    /// Args for `internal_transfer`, carrying the proof inline.
+    pub fn build(self) -> Result<InternalTransferArgs> {
+        validate_spend(
+            &self.proof,
+            &self.public_inputs,
+            &self.new_root,
+            &self.output_ciphertexts,
+            self.spend_layout,
+        )?;
+        Ok(InternalTransferArgs {
+            proof: self.proof,
+            public_inputs: self.public_inputs,
+            new_root: self.new_root,
+            output_ciphertexts: self.output_ciphertexts,
+        })
+    }
+
+    /// Args for `internal_transfer_with_proof`, after the proof went through `store_proof`.
+    pub fn build_with_stored_proof(self) -> Result<InternalTransferWithProofArgs> {
+        validate_spend(
+            &self.proof,
+            &self.public_inputs,
+            &self.new_root,
+            &self.output_ciphertexts,
+            self.spend_layout,
+        )?;
+        Ok(InternalTransferWithProofArgs {
+            new_root: self.new_root,
+            output_ciphertexts: self.output_ciphertexts,
+        })
+    }
+}
+
+fn validate_spend(
+    proof: &[u8],
+    public_inputs: &[u8],
+    new_root: &[u8],
+    output_ciphertexts: &[u8],
+    spend_layout: SpendLayout,
+) -> Result<ParsedPublicInputs> {
+    require!(
+        proof.len() == PROOF_BYTES_LEN,
+        VeilpayError::InvalidProofDataLength
+    );
+    require!(
+        spend_layout.supports_bytes_len(public_inputs.len()),
+        VeilpayError::InvalidPublicInputsLength
+    );
+    to_fixed_32(new_root)?;
+    let parsed = parse_public_inputs(public_inputs, spend_layout)?;
+    parse_output_ciphertexts(output_ciphertexts, parsed.output_enabled)?;
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_public_inputs, MAX_INPUTS, MAX_OUTPUTS, NOTE_CIPHERTEXT_BYTES};
+
+    fn public_inputs(amount_out: u64, fee_amount: u64, output_enabled: [u8; MAX_OUTPUTS]) -> Vec<u8> {
+        public_inputs_in(SpendLayout::DEFAULT, amount_out, fee_amount, output_enabled)
+    }
+
+    fn public_inputs_in(
+        layout: SpendLayout,
+        amount_out: u64,
+        fee_amount: u64,
+        output_enabled: [u8; MAX_OUTPUTS],
+    ) -> Vec<u8> {
+        let parsed = ParsedPublicInputs {
+            root: [1u8; 32],
+            identity_root: [2u8; 32],
+            nullifiers: [[0u8; 32]; MAX_INPUTS],
+            output_commitments: [[0u8; 32]; MAX_OUTPUTS],
+            output_enabled,
+            amount_out,
+            fee_amount,
+            circuit_id: 0,
+            domain: [0u8; 32],
+            slot_window: None,
+            value_in: None,
+            relayer_fee_recipient: None,
+        };
+        encode_public_inputs(&parsed, layout)
+    }
+
+    fn withdraw(amount: u64) -> WithdrawArgsBuilder {
+        WithdrawArgsBuilder::new(amount)
+            .proof(vec![0u8; PROOF_BYTES_LEN])
+            .public_inputs(public_inputs(amount, 0, [0, 0]))
+            .new_root(vec![3u8; 32])
+    }
+
+    #[test]
+    fn withdraw_builder_accepts_consistent_args() {
+        let args = withdraw(1_000).build().unwrap();
+        assert_eq!(args.amount, 1_000);
+        assert_eq!(args.proof.len(), PROOF_BYTES_LEN);
+
+        let relayed = withdraw(10_000)
+            .public_inputs(public_inputs(10_000, 50, [0, 1]))
+            .relayer_fee_bps(50)
+            .output_ciphertexts(vec![0u8; NOTE_CIPHERTEXT_BYTES]);
+        assert!(relayed.clone().build_with_stored_proof().is_ok());
+        assert!(relayed.build_queued().is_ok());
+    }
+
+    #[test]
+    fn withdraw_builder_rejects_bad_lengths() {
+        assert_eq!(
+            withdraw(1_000).proof(vec![0u8; 255]).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidProofDataLength)
+        );
+        let mut short_inputs = public_inputs(1_000, 0, [0, 0]);
+        short_inputs.pop();
+        assert_eq!(
+            withdraw(1_000).public_inputs(short_inputs).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidPublicInputsLength)
+        );
+        assert_eq!(
+            withdraw(1_000).new_root(vec![3u8; 31]).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidByteLength)
+        );
+        assert_eq!(
+            withdraw(1_000).output_ciphertexts(vec![0u8; 5]).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidByteLength)
+        );
+    }
+
+    #[test]
+    fn withdraw_builder_rejects_args_that_disagree_with_the_proof() {
+        assert_eq!(
+            withdraw(1_000).public_inputs(public_inputs(999, 0, [0, 0])).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::AmountMismatch)
+        );
+        assert_eq!(
+            withdraw(10_000).relayer_fee_bps(50).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::FeeMismatch)
+        );
+        assert_eq!(
+            withdraw(10_000)
+                .public_inputs(public_inputs(10_000, 50, [0, 0]))
+                .relayer_fee_bps(50)
+                .protocol_fee_bps(25)
+                .build()
+                .map(|_| ())
+                .unwrap_err(),
+            error!(VeilpayError::FeeMismatch)
+        );
+        assert!(withdraw(10_000)
+            .public_inputs(public_inputs(10_000, 75, [0, 0]))
+            .relayer_fee_bps(50)
+            .protocol_fee_bps(25)
+            .build()
+            .is_ok());
+        assert_eq!(
+            withdraw(1_000).public_inputs(public_inputs(1_000, 0, [1, 0])).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidOutputFlags)
+        );
+        assert_eq!(
+            withdraw(1_000).deliver_sol(true).build_queued().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::UnsupportedSolDelivery)
+        );
+        assert_eq!(withdraw(1_000).proof_system(1).build().unwrap().proof_system, 1);
+        assert_eq!(
+            withdraw(1_000).proof_system(2).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidProofSystem)
+        );
+    }
+
+    #[test]
+    fn withdraw_builder_pays_relayers_in_one_currency() {
+        let sol_fee = withdraw(1_000).relayer_fee_lamports(5_000);
+        let args = sol_fee.clone().build().unwrap();
+        assert_eq!((args.relayer_fee_bps, args.relayer_fee_lamports), (0, 5_000));
+        assert_eq!(sol_fee.clone().build_with_stored_proof().unwrap().relayer_fee_lamports, 5_000);
+        assert_eq!(
+            sol_fee.build_queued().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidRelayerFeeMode)
+        );
+        assert_eq!(
+            withdraw(10_000)
+                .public_inputs(public_inputs(10_000, 50, [0, 0]))
+                .relayer_fee_bps(50)
+                .relayer_fee_lamports(5_000)
+                .build()
+                .map(|_| ())
+                .unwrap_err(),
+            error!(VeilpayError::InvalidRelayerFeeMode)
+        );
+    }
+
+    #[test]
+    fn internal_builder_validates_lengths() {
+        let builder = InternalTransferArgsBuilder::new()
+            .proof(vec![0u8; PROOF_BYTES_LEN])
+            .public_inputs(public_inputs(0, 0, [1, 1]))
+            .new_root(vec![3u8; 32])
+            .output_ciphertexts(vec![0u8; 2 * NOTE_CIPHERTEXT_BYTES]);
+        assert!(builder.clone().build().is_ok());
+        assert!(builder.clone().build_with_stored_proof().is_ok());
+        assert_eq!(
+            builder.new_root(Vec::new()).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidByteLength)
+        );
+    }
+
+    #[test]
+    fn builders_check_inputs_against_the_pool_layout() {
+        let narrow = SpendLayout::new(2, 1).unwrap();
+        let inputs = public_inputs_in(narrow, 1_000, 0, [0, 0]);
+        assert_eq!(
+            withdraw(1_000).public_inputs(inputs.clone()).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidPublicInputsLength)
+        );
+        assert!(withdraw(1_000).public_inputs(inputs).spend_layout(narrow).build().is_ok());
+
+        let eight_in = SpendLayout::new(8, 2).unwrap();
+
+        let builder = InternalTransferArgsBuilder::new()
+            .proof(vec![0u8; PROOF_BYTES_LEN])
+            .public_inputs(public_inputs(0, 0, [1, 0]))
+            .new_root(vec![3u8; 32])
+            .output_ciphertexts(vec![0u8; NOTE_CIPHERTEXT_BYTES]);
+        assert!(builder.clone().build().is_ok());
+        assert_eq!(
+            builder.spend_layout(eight_in).build().map(|_| ()).unwrap_err(),
+            error!(VeilpayError::InvalidPublicInputsLength)
+        );
+    }
+}
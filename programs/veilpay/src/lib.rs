@@ -1,24 +1,65 @@
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::{self, AssociatedToken};
-use anchor_spl::token::{self, CloseAccount, Mint, Token, TokenAccount, Transfer};
+use anchor_spl::token_interface::{
+    self, CloseAccount, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+use solana_keccak_hasher as keccak;
+use solana_poseidon::{hashv, Endianness, Parameters};
+use veilpay_public_inputs::{
+    decode_public_inputs, decode_u32, decode_u64, is_canonical_scalar, DecodeError, MAX_INPUTS,
+    MAX_OUTPUTS, MAX_PUBLIC_INPUTS_LEN, SCALAR_FIELD_MODULUS,
+};
 use verifier::cpi::accounts::VerifyGroth16 as VerifyGroth16Cpi;
 
+pub use veilpay_public_inputs::{
+    encode_public_inputs, PublicInputs as ParsedPublicInputs, SpendLayout,
+};
+
 declare_id!("6f4VN8NcbHQarNoimUSkxYpJTiP6BcZ3u2rBsSv4j5jk");
 
+pub mod builder;
+
 const MAX_ALLOWLIST: usize = 32;
 const MAX_CIRCUITS: usize = 8;
-const MAX_INPUTS: usize = 4;
-const MAX_OUTPUTS: usize = 2;
-const PUBLIC_INPUTS_LEN: usize = 13;
+const MAX_FEE_EXEMPT: usize = 8;
+const MAX_RELAYERS: usize = 16;
+const CONFIG_VIEW_ALLOWLIST_PAGE: usize = 16;
+const DEPOSIT_PUBLIC_INPUTS_LEN: usize = 4;
 const MAX_ROOT_HISTORY: usize = 32;
+// How many slots after being overwritten a root stays usable, so a transaction built
+// against it just before the ring moved on still lands.
+const ROOT_GRACE_SLOTS: u64 = 1;
+const MAX_TREE_DEPTH: usize = 32;
+// The identity circuits prove membership in a depth-20 tree (empty root `ZERO_ROOT`);
+// `IdentityRegistry::depth` only caps how many leaves of it are used.
+const IDENTITY_TREE_DEPTH: usize = 20;
 const MAX_VK_ENTRIES: usize = 16;
+const VK_STATUS_ACTIVE: u8 = 1;
+const VK_STATUS_INACTIVE: u8 = 0;
+const PROOF_SYSTEM_PRIMARY: u8 = 0;
+const PROOF_SYSTEM_ALT: u8 = 1;
+const IDENTITY_POLICY_ALLOWLIST: u8 = 0;
+const IDENTITY_POLICY_DENYLIST: u8 = 1;
 const NULLIFIER_BITS: usize = 8192;
 const NULLIFIER_BYTES: usize = NULLIFIER_BITS / 8;
+// Spreads a pool's nullifiers over the whole u32 chunk space, as the original
+// per-nullifier chunk index did, so honest spends practically never share a bit.
+const DEFAULT_NULLIFIER_CHUNKS: u32 = u32::MAX;
 const NOTE_CIPHERTEXT_BYTES: usize = 128;
 const NOTE_OUTPUTS: usize = 2;
 const NOTE_OUTPUT_BYTES: usize = NOTE_CIPHERTEXT_BYTES * NOTE_OUTPUTS;
+// The shared layout's output cap is what the ciphertext handling supports.
+const _: () = assert!(MAX_OUTPUTS == NOTE_OUTPUTS);
 const PROOF_BYTES_LEN: usize = 256;
-const PUBLIC_INPUTS_BYTES_LEN: usize = PUBLIC_INPUTS_LEN * 32;
+const MAX_PUBLIC_INPUTS_BYTES_LEN: usize = MAX_PUBLIC_INPUTS_LEN * 32;
+const COMPACT_EVENTS_VERSION: u8 = 1;
+const EVENT_NOTE_OUTPUT: u32 = 1 << 0;
+const EVENT_TRANSFER: u32 = 1 << 1;
+const EVENT_DEPOSIT: u32 = 1 << 2;
+const EVENT_WITHDRAW: u32 = 1 << 3;
+const EVENT_FLAGS_ALL: u32 = EVENT_NOTE_OUTPUT | EVENT_TRANSFER | EVENT_DEPOSIT | EVENT_WITHDRAW;
+/// Layout version `initialize_config` writes and `migrate_config` upgrades to.
+const CONFIG_VERSION: u32 = 2;
 const ZERO_ROOT: [u8; 32] = [
     0x21, 0x34, 0xE7, 0x6A, 0xC5, 0xD2, 0x1A, 0xAB,
     0x18, 0x6C, 0x2B, 0xE1, 0xDD, 0x8F, 0x84, 0xEE,
@@ -42,20 +83,79 @@ pub mod veilpay {
             args.circuit_ids.len() <= MAX_CIRCUITS,
             VeilpayError::CircuitListTooLarge
         );
+        require!(
+            args.relayer_fee_bps_min <= args.relayer_fee_bps_max,
+            VeilpayError::InvalidRelayerFeeBounds
+        );
 
         let config = &mut ctx.accounts.config;
         config.admin = ctx.accounts.admin.key();
+        config.pending_admin = Pubkey::default();
+        config.fee_authority = ctx.accounts.admin.key();
+        config.pending_fee_authority = Pubkey::default();
         config.fee_bps = args.fee_bps;
         config.relayer_fee_bps_max = args.relayer_fee_bps_max;
+        config.relayer_fee_bps_min = args.relayer_fee_bps_min;
+        config.relayer_fee_abs_min = 0;
+        config.fee_exempt = Vec::new();
+        config.relayer_allowlist_enabled = false;
+        config.relayer_allowlist = Vec::new();
         config.vk_registry = args.vk_registry;
+        config.verifier_program = verifier::ID;
         config.mint_allowlist = args.mint_allowlist;
         config.circuit_ids = args.circuit_ids;
         config.paused = false;
-        config.version = 1;
+        config.compact_events = false;
+        config.require_deposit_proof = false;
+        config.event_flags = EVENT_FLAGS_ALL;
+        config.withdraw_queue_threshold = 0;
+        config.withdraw_queue_delay_slots = 0;
+        config.root_max_age_slots = 0;
+        config.max_single_withdraw_bps = 0;
+        config.nullifier_chunks = DEFAULT_NULLIFIER_CHUNKS;
+        config.version = CONFIG_VERSION;
         config.bump = ctx.bumps.config;
         Ok(())
     }
 
+    /// Brings a config written by an older program up to `CONFIG_VERSION`. A v1 account
+    /// cannot load as `Account<Config>`, so the bytes are decoded by hand, the account is
+    /// grown to the current size and the upgraded config written back. Running it on a
+    /// current config is a no-op.
+    pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+        let info = ctx.accounts.config.to_account_info();
+        let mut config = decode_config(&info.try_borrow_data()?)?;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        let from_version = migrate_config_fields(&mut config)?;
+        if from_version == config.version {
+            return Ok(());
+        }
+
+        let space = 8 + Config::INIT_SPACE;
+        if info.data_len() < space {
+            let lamports = Rent::get()?
+                .minimum_balance(space)
+                .saturating_sub(info.lamports());
+            if lamports > 0 {
+                let cpi_ctx = CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.admin.to_account_info(),
+                        to: info.clone(),
+                    },
+                );
+                anchor_lang::system_program::transfer(cpi_ctx, lamports)?;
+            }
+            info.resize(space)?;
+        }
+        config.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+        emit!(ConfigMigratedEvent {
+            from_version,
+            to_version: config.version,
+        });
+        Ok(())
+    }
+
     pub fn register_mint(ctx: Context<RegisterMint>, mint: Pubkey) -> Result<()> {
         let config = &mut ctx.accounts.config;
         require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
@@ -69,25 +169,121 @@ pub mod veilpay {
         Ok(())
     }
 
+    /// Drops `mint` from the allowlist. Deposits, new mint state and internal transfers
+    /// are refused from then on, while external withdrawals (and the nullifier chunks
+    /// they need) keep working so already shielded funds can still leave the pool.
+    /// Returns `MintNotAllowed` when the mint is not listed.
+    pub fn remove_mint(ctx: Context<RegisterMint>, mint: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        let index = config
+            .mint_allowlist
+            .iter()
+            .position(|listed| *listed == mint)
+            .ok_or(VeilpayError::MintNotAllowed)?;
+        // `remove` keeps the order `get_config` pages through.
+        config.mint_allowlist.remove(index);
+        Ok(())
+    }
+
     pub fn initialize_vk_registry(ctx: Context<InitializeVkRegistry>) -> Result<()> {
         let registry = &mut ctx.accounts.vk_registry;
         registry.entries = Vec::new();
         registry.bump = ctx.bumps.vk_registry;
+        registry.capacity = MAX_VK_ENTRIES as u16;
+        Ok(())
+    }
+
+    pub fn register_vk_entry(ctx: Context<RegisterVkEntry>, args: RegisterVkEntryArgs) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        let public_inputs_len = ctx.accounts.verifier_key.public_inputs_len as usize;
+        require!(
+            public_inputs_len_supported(public_inputs_len)
+                || public_inputs_len == DEPOSIT_PUBLIC_INPUTS_LEN,
+            VeilpayError::VkInputCountMismatch
+        );
+        let entry = VkEntry {
+            circuit_id: args.circuit_id,
+            vk_account: ctx.accounts.verifier_key.key(),
+            vk_hash: args.vk_hash,
+            status: VK_STATUS_ACTIVE,
+        };
+        upsert_vk_entry(&mut ctx.accounts.vk_registry, entry)
+    }
+
+    /// Reallocates the registry to hold `new_max` entries; the admin pays for growth and
+    /// gets the rent back when it shrinks. Shrinking below the registered entries fails.
+    pub fn resize_vk_registry(ctx: Context<ResizeVkRegistry>, new_max: u16) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        let registry = &mut ctx.accounts.vk_registry;
+        require!(
+            new_max > 0 && new_max as usize >= registry.entries.len(),
+            VeilpayError::VkRegistryTooSmall
+        );
+        registry.capacity = new_max;
         Ok(())
     }
 
-    pub fn initialize_identity_registry(ctx: Context<InitializeIdentityRegistry>) -> Result<()> {
+    /// Deactivating a circuit makes every spend bound to it fail with `VkInactive`
+    /// until it is set active again; the entry and its key stay registered.
+    pub fn set_vk_status(ctx: Context<SetVkStatus>, circuit_id: u32, status: u8) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        set_vk_entry_status(&mut ctx.accounts.vk_registry, circuit_id, status)
+    }
+
+    pub fn initialize_identity_registry(
+        ctx: Context<InitializeIdentityRegistry>,
+        depth: u8,
+    ) -> Result<()> {
+        require!(
+            depth > 0 && depth as usize <= IDENTITY_TREE_DEPTH,
+            VeilpayError::InvalidTreeDepth
+        );
+        let (zero_hashes, empty_root) = empty_subtrees(IDENTITY_TREE_DEPTH as u8)?;
         let registry = &mut ctx.accounts.identity_registry;
-        registry.merkle_root = ZERO_ROOT;
+        registry.merkle_root = empty_root;
+        registry.denylist_root = ZERO_ROOT;
         registry.commitment_count = 0;
+        registry.depth = depth;
+        registry.identity_admin = ctx.accounts.config.admin;
+        registry.identity_required = true;
+        registry.filled_subtrees = zero_hashes.clone();
+        registry.zero_hashes = zero_hashes;
         registry.bump = ctx.bumps.identity_registry;
         Ok(())
     }
 
+    /// Appends `commitment` to the identity tree. Only the registry's `identity_admin`
+    /// may add members, and `new_root` must equal the root the program computes from
+    /// the stored frontier, so a registration can only ever add one leaf to the set.
     pub fn register_identity(ctx: Context<RegisterIdentity>, args: RegisterIdentityArgs) -> Result<()> {
-        let _commitment = to_fixed_32(&args.commitment)?;
+        let commitment = to_fixed_32(&args.commitment)?;
         let new_root = to_fixed_32(&args.new_root)?;
-        let registry = &mut ctx.accounts.identity_registry;
+        let registry: &mut IdentityRegistry = &mut ctx.accounts.identity_registry;
+        require!(
+            registry.identity_admin == ctx.accounts.identity_admin.key(),
+            VeilpayError::Unauthorized
+        );
+        require!(
+            registry.commitment_count < max_identities(registry.depth),
+            VeilpayError::IdentityRegistryFull
+        );
+        let root = frontier_append(
+            &mut registry.filled_subtrees,
+            &registry.zero_hashes,
+            registry.commitment_count,
+            commitment,
+        )?;
+        require!(root == new_root, VeilpayError::NewRootMismatch);
         registry.commitment_count = registry.commitment_count.saturating_add(1);
         registry.merkle_root = new_root;
         let member = &mut ctx.accounts.identity_member;
@@ -99,7 +295,62 @@ pub mod veilpay {
         Ok(())
     }
 
-    pub fn initialize_mint_state(ctx: Context<InitializeMintState>, chunk_index: u32) -> Result<()> {
+    pub fn set_identity_tree_depth(ctx: Context<UpdateIdentityRegistry>, depth: u8) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        require!(
+            depth > 0 && depth as usize <= IDENTITY_TREE_DEPTH,
+            VeilpayError::InvalidTreeDepth
+        );
+        let registry = &mut ctx.accounts.identity_registry;
+        require!(
+            registry.commitment_count <= max_identities(depth),
+            VeilpayError::IdentityRegistryFull
+        );
+        registry.depth = depth;
+        Ok(())
+    }
+
+    /// Whether spends are checked against the registry at all. Off makes every pool
+    /// permissionless; on also refuses the empty membership tree, so a registry nobody
+    /// has been added to yet reads as misconfigured rather than open.
+    pub fn set_identity_required(
+        ctx: Context<UpdateIdentityRegistry>,
+        required: bool,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        ctx.accounts.identity_registry.identity_required = required;
+        Ok(())
+    }
+
+    pub fn set_identity_denylist_root(
+        ctx: Context<UpdateIdentityRegistry>,
+        denylist_root: [u8; 32],
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        ctx.accounts.identity_registry.denylist_root = denylist_root;
+        Ok(())
+    }
+
+    /// `tree_depth` sizes the pool's on-chain commitment tree and must be 1 to
+    /// `MAX_TREE_DEPTH`. `vault_ata` must be the vault's associated token account; if
+    /// it does not exist yet, the first deposit creates it.
+    /// `circuit_id` pins the pool to a single spend circuit; `None` accepts any circuit
+    /// the allowlists do.
+    pub fn initialize_mint_state(
+        ctx: Context<InitializeMintState>,
+        chunk_index: u32,
+        tree_depth: u8,
+        circuit_id: Option<u32>,
+    ) -> Result<()> {
         require!(
             ctx.accounts.config.admin == ctx.accounts.admin.key(),
             VeilpayError::Unauthorized
@@ -108,9 +359,15 @@ pub mod veilpay {
             ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
             VeilpayError::MintNotAllowed
         );
+        let nullifier_chunks = ctx.accounts.config.nullifier_chunks;
+        require!(
+            chunk_index < nullifier_chunks,
+            VeilpayError::ChunkIndexOutOfRange
+        );
         let vault_key = ctx.accounts.vault.key();
         let vault_ata_key = ctx.accounts.vault_ata.key();
         let mint_key = ctx.accounts.mint.key();
+        check_pool_vault_ata(&ctx.accounts.vault_ata, vault_key, &ctx.accounts.mint)?;
 
         let vault = &mut ctx.accounts.vault;
         vault.vault_pda = vault_key;
@@ -119,6 +376,14 @@ pub mod veilpay {
         vault.total_deposited = 0;
         vault.total_withdrawn = 0;
         vault.nonce = 0;
+        vault.max_chunks = u32::MAX;
+        vault.nullifier_chunks = nullifier_chunks;
+        vault.verifier_program_override = None;
+        vault.identity_policy = IDENTITY_POLICY_ALLOWLIST;
+        vault.deposit_cap = 0;
+        vault.per_withdraw_cap = 0;
+        vault.min_deposit = 0;
+        vault.paused = false;
         vault.bump = ctx.bumps.vault;
 
         let shielded = &mut ctx.accounts.shielded_state;
@@ -126,14 +391,24 @@ pub mod veilpay {
         shielded.merkle_root = ZERO_ROOT;
         shielded.root_history = Vec::new();
         shielded.root_history_index = 0;
+        shielded.root_slots = Vec::new();
+        shielded.root_history_len = MAX_ROOT_HISTORY as u32;
+        shielded.evicted_root = None;
+        shielded.evicted_root_slot = 0;
+        shielded.evicted_at_slot = 0;
         shielded.commitment_count = 0;
-        shielded.circuit_id = 0;
+        shielded.circuit_id = circuit_id;
+        shielded.circuit_ids = Vec::new();
+        shielded.max_inputs = SpendLayout::DEFAULT.inputs as u8;
+        shielded.max_outputs = SpendLayout::DEFAULT.outputs as u8;
         shielded.version = 1;
         shielded.bump = ctx.bumps.shielded_state;
+        init_tree(shielded, tree_depth)?;
 
         let nullifier = &mut ctx.accounts.nullifier_set;
         nullifier.mint = mint_key;
         nullifier.chunk_index = chunk_index;
+        nullifier.chunk_count = nullifier_chunks;
         nullifier.bitset = [0u8; NULLIFIER_BYTES];
         nullifier.count = 0;
         nullifier.bump = ctx.bumps.nullifier_set;
@@ -141,297 +416,327 @@ pub mod veilpay {
         Ok(())
     }
 
+    pub fn initialize_tree(ctx: Context<InitializeTree>, depth: u8) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        let shielded = &mut ctx.accounts.shielded_state;
+        require!(shielded.commitment_count == 0, VeilpayError::TreeNotEmpty);
+        init_tree(shielded, depth)
+    }
+
     pub fn initialize_nullifier_chunk(
         ctx: Context<InitializeNullifierChunk>,
         chunk_index: u32,
     ) -> Result<()> {
+        let vault = &ctx.accounts.vault;
         require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
+            chunk_index < vault.max_chunks && chunk_index < vault.nullifier_chunks,
+            VeilpayError::ChunkIndexOutOfRange
         );
         let nullifier = &mut ctx.accounts.nullifier_set;
         nullifier.mint = ctx.accounts.mint.key();
         nullifier.chunk_index = chunk_index;
+        nullifier.chunk_count = vault.nullifier_chunks;
         nullifier.bitset = [0u8; NULLIFIER_BYTES];
         nullifier.count = 0;
         nullifier.bump = ctx.bumps.nullifier_set;
         Ok(())
     }
 
-    pub fn configure_fees(ctx: Context<ConfigureFees>, fee_bps: u16, relayer_fee_bps_max: u16) -> Result<()> {
-        let config = &mut ctx.accounts.config;
-        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
-        config.fee_bps = fee_bps;
-        config.relayer_fee_bps_max = relayer_fee_bps_max;
+    /// Permissionless, idempotent form of `initialize_nullifier_chunk` so relayers can
+    /// create the chunk a spend needs right before submitting it. The caller pays rent;
+    /// a chunk that already exists is left untouched. Like withdrawals it skips the
+    /// allowlist: the pool's vault only exists if the mint was listed when it was
+    /// created, and a delisted pool still needs chunks for its remaining exits.
+    pub fn ensure_nullifier_chunk(
+        ctx: Context<EnsureNullifierChunk>,
+        chunk_index: u32,
+    ) -> Result<()> {
+        let vault = &ctx.accounts.vault;
+        require!(
+            chunk_index < vault.max_chunks && chunk_index < vault.nullifier_chunks,
+            VeilpayError::ChunkIndexOutOfRange
+        );
+        let nullifier = &mut ctx.accounts.nullifier_set;
+        if nullifier.mint != Pubkey::default() {
+            return Ok(());
+        }
+        nullifier.mint = ctx.accounts.mint.key();
+        nullifier.chunk_index = chunk_index;
+        nullifier.chunk_count = vault.nullifier_chunks;
+        nullifier.bitset = [0u8; NULLIFIER_BYTES];
+        nullifier.count = 0;
+        nullifier.bump = ctx.bumps.nullifier_set;
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Deposit>, args: DepositArgs) -> Result<()> {
-        require!(!ctx.accounts.config.paused, VeilpayError::ProtocolPaused);
+    /// Emergency repair for a corrupted chunk that marks unspent nullifiers as used.
+    /// Clearing a bit that belongs to a genuinely spent note re-enables a double spend,
+    /// so this only runs while the protocol is paused and with `confirm` set, and every
+    /// call is logged and emits `NullifierClearedEvent`.
+    pub fn admin_clear_nullifier_bit(
+        ctx: Context<AdminClearNullifierBit>,
+        nullifier: [u8; 32],
+        confirm: bool,
+    ) -> Result<()> {
         require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
+        require!(ctx.accounts.config.paused, VeilpayError::ProtocolNotPaused);
+        require!(confirm, VeilpayError::ConfirmationRequired);
+        let set = &mut ctx.accounts.nullifier_set;
+        let (chunk_index, bit_index) = nullifier_position(&nullifier, set.chunk_count);
         require!(
-            ctx.accounts.identity_member.owner == ctx.accounts.user.key(),
-            VeilpayError::Unauthorized
+            chunk_index == set.chunk_index,
+            VeilpayError::NullifierChunkMismatch
         );
+        let byte_index = (bit_index / 8) as usize;
+        let bit_mask = 1u8 << (bit_index % 8);
         require!(
-            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
-            VeilpayError::InvalidVaultAuthority
+            (set.bitset[byte_index] & bit_mask) != 0,
+            VeilpayError::NullifierNotSet
         );
-        let new_root = to_fixed_32(&args.new_root)?;
-        let commitment = to_fixed_32(&args.commitment)?;
-        let ciphertext = to_fixed_128(&args.ciphertext)?;
-
-        let cpi_accounts = anchor_spl::token::Transfer {
-            from: ctx.accounts.user_ata.to_account_info(),
-            to: ctx.accounts.vault_ata.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        anchor_spl::token::transfer(cpi_ctx, args.amount)?;
-
-        let vault = &mut ctx.accounts.vault;
-        vault.total_deposited = vault
-            .total_deposited
-            .checked_add(args.amount)
-            .ok_or(VeilpayError::MathOverflow)?;
-        vault.nonce = vault.nonce.saturating_add(1);
-
-        let shielded = &mut ctx.accounts.shielded_state;
-        let leaf_index = shielded.commitment_count;
-        emit!(NoteOutputEvent {
-            mint: ctx.accounts.mint.key(),
-            leaf_index,
-            commitment,
-            ciphertext,
-            kind: NoteOutputKind::Deposit as u8,
+        set.bitset[byte_index] &= !bit_mask;
+        set.count = set.count.saturating_sub(1);
+        msg!(
+            "admin {} cleared nullifier bit {} in chunk {} for mint {}",
+            ctx.accounts.admin.key(),
+            bit_index,
+            chunk_index,
+            set.mint
+        );
+        emit!(NullifierClearedEvent {
+            mint: set.mint,
+            chunk_index,
+            bit_index,
+            nullifier,
+            admin: ctx.accounts.admin.key(),
         });
-        shielded.commitment_count = shielded.commitment_count.saturating_add(1);
-        append_root(shielded, new_root);
         Ok(())
     }
 
-    pub fn internal_transfer<'info>(
-        ctx: Context<'_, '_, 'info, 'info, InternalTransfer<'info>>,
-        args: InternalTransferArgs,
+    pub fn set_max_chunks(ctx: Context<UpdateVault>, max_chunks: u32) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        ctx.accounts.vault.max_chunks = max_chunks;
+        Ok(())
+    }
+
+    pub fn set_verifier_program_override(
+        ctx: Context<UpdateVault>,
+        verifier_program: Option<Pubkey>,
     ) -> Result<()> {
-        require!(!ctx.accounts.config.paused, VeilpayError::ProtocolPaused);
         require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
-        verify_groth16(
-            &ctx.accounts.verifier_program,
-            &ctx.accounts.verifier_key,
-            args.proof.clone(),
-            args.public_inputs.clone(),
-        )?;
-        let parsed = Box::new(parse_public_inputs(&args.public_inputs)?);
-        let output_ciphertexts =
-            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
-        require!(parsed.amount_out == 0, VeilpayError::InvalidOutputFlags);
-        require!(parsed.fee_amount == 0, VeilpayError::InvalidOutputFlags);
+        ctx.accounts.vault.verifier_program_override = verifier_program;
+        Ok(())
+    }
+
+    /// Zero disables either cap.
+    pub fn configure_vault_limits(
+        ctx: Context<UpdateVault>,
+        deposit_cap: u64,
+        per_withdraw_cap: u64,
+    ) -> Result<()> {
         require!(
-            parsed.output_enabled[0] == 1,
-            VeilpayError::InvalidOutputFlags
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
+        let vault = &mut ctx.accounts.vault;
+        vault.deposit_cap = deposit_cap;
+        vault.per_withdraw_cap = per_withdraw_cap;
+        Ok(())
+    }
+
+    /// Smallest amount a deposit may credit to the pool; zero disables the check. Tiny,
+    /// odd amounts are easy to match to their withdrawal, so a floor keeps notes closer
+    /// to a common denomination.
+    pub fn set_min_deposit(ctx: Context<UpdateVault>, min_deposit: u64) -> Result<()> {
         require!(
-            ctx.accounts.config.circuit_ids.contains(&parsed.circuit_id),
-            VeilpayError::CircuitNotAllowed
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
+        ctx.accounts.vault.min_deposit = min_deposit;
+        Ok(())
+    }
+
+    pub fn set_identity_policy(ctx: Context<UpdateVault>, identity_policy: u8) -> Result<()> {
         require!(
-            parsed.identity_root == ctx.accounts.identity_registry.merkle_root,
-            VeilpayError::IdentityRootMismatch
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
         require!(
-            root_known(&ctx.accounts.shielded_state, parsed.root),
-            VeilpayError::UnknownRoot
+            identity_policy == IDENTITY_POLICY_ALLOWLIST
+                || identity_policy == IDENTITY_POLICY_DENYLIST,
+            VeilpayError::InvalidIdentityPolicy
         );
-        mark_nullifiers(
-            &mut ctx.accounts.nullifier_set,
-            ctx.remaining_accounts,
-            &parsed.nullifiers,
-        )?;
-        let shielded = &mut ctx.accounts.shielded_state;
-        let new_root = to_fixed_32(&args.new_root)?;
-        let mut next_index = shielded.commitment_count;
-        for idx in 0..NOTE_OUTPUTS {
-            if parsed.output_enabled[idx] == 1 {
-                emit!(NoteOutputEvent {
-                    mint: ctx.accounts.mint.key(),
-                    leaf_index: next_index,
-                    commitment: parsed.output_commitments[idx],
-                    ciphertext: output_ciphertexts[idx],
-                    kind: NoteOutputKind::Internal as u8,
-                });
-                next_index = next_index.saturating_add(1);
-            }
-        }
-        let output_count = (parsed.output_enabled[0] + parsed.output_enabled[1]) as u64;
-        require!(output_count > 0, VeilpayError::InvalidOutputFlags);
-        shielded.commitment_count = shielded.commitment_count.saturating_add(output_count);
-        append_root(shielded, new_root);
+        ctx.accounts.vault.identity_policy = identity_policy;
         Ok(())
     }
 
-    pub fn internal_transfer_with_proof<'info>(
-        ctx: Context<'_, '_, 'info, 'info, InternalTransferWithProof<'info>>,
-        args: InternalTransferWithProofArgs,
+    pub fn configure_fees(
+        ctx: Context<ConfigureFees>,
+        fee_bps: u16,
+        relayer_fee_bps_max: u16,
+        relayer_fee_bps_min: u16,
     ) -> Result<()> {
-        require!(!ctx.accounts.config.paused, VeilpayError::ProtocolPaused);
+        let config = &mut ctx.accounts.config;
         require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
+            config.fee_authority == ctx.accounts.fee_authority.key(),
+            VeilpayError::Unauthorized
         );
         require!(
-            ctx.accounts.proof_account.mint == ctx.accounts.mint.key(),
-            VeilpayError::InvalidProofAccountMint
+            relayer_fee_bps_min <= relayer_fee_bps_max,
+            VeilpayError::InvalidRelayerFeeBounds
         );
-        verify_groth16(
-            &ctx.accounts.verifier_program,
-            &ctx.accounts.verifier_key,
-            ctx.accounts.proof_account.proof.clone(),
-            ctx.accounts.proof_account.public_inputs.clone(),
-        )?;
-        let parsed = Box::new(parse_public_inputs(
-            &ctx.accounts.proof_account.public_inputs,
-        )?);
-        let output_ciphertexts =
-            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
-        require!(parsed.amount_out == 0, VeilpayError::InvalidOutputFlags);
-        require!(parsed.fee_amount == 0, VeilpayError::InvalidOutputFlags);
+        config.fee_bps = fee_bps;
+        config.relayer_fee_bps_max = relayer_fee_bps_max;
+        config.relayer_fee_bps_min = relayer_fee_bps_min;
+        Ok(())
+    }
+
+    pub fn set_relayer_fee_min(ctx: Context<ConfigureFees>, relayer_fee_abs_min: u64) -> Result<()> {
+        let config = &mut ctx.accounts.config;
         require!(
-            parsed.output_enabled[0] == 1,
-            VeilpayError::InvalidOutputFlags
+            config.fee_authority == ctx.accounts.fee_authority.key(),
+            VeilpayError::Unauthorized
         );
+        config.relayer_fee_abs_min = relayer_fee_abs_min;
+        Ok(())
+    }
+
+    pub fn propose_fee_authority(ctx: Context<ConfigureFees>, new_fee_authority: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
         require!(
-            ctx.accounts.config.circuit_ids.contains(&parsed.circuit_id),
-            VeilpayError::CircuitNotAllowed
+            config.fee_authority == ctx.accounts.fee_authority.key(),
+            VeilpayError::Unauthorized
         );
+        config.pending_fee_authority = new_fee_authority;
+        Ok(())
+    }
+
+    pub fn accept_fee_authority(ctx: Context<AcceptFeeAuthority>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
         require!(
-            parsed.identity_root == ctx.accounts.identity_registry.merkle_root,
-            VeilpayError::IdentityRootMismatch
+            config.pending_fee_authority != Pubkey::default()
+                && config.pending_fee_authority == ctx.accounts.new_fee_authority.key(),
+            VeilpayError::Unauthorized
         );
+        config.fee_authority = config.pending_fee_authority;
+        config.pending_fee_authority = Pubkey::default();
+        Ok(())
+    }
+
+    pub fn propose_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
         require!(
-            root_known(&ctx.accounts.shielded_state, parsed.root),
-            VeilpayError::UnknownRoot
+            config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
-        mark_nullifiers(
-            &mut ctx.accounts.nullifier_set,
-            ctx.remaining_accounts,
-            &parsed.nullifiers,
-        )?;
-        let shielded = &mut ctx.accounts.shielded_state;
-        let new_root = to_fixed_32(&args.new_root)?;
-        let mut next_index = shielded.commitment_count;
-        for idx in 0..NOTE_OUTPUTS {
-            if parsed.output_enabled[idx] == 1 {
-                emit!(NoteOutputEvent {
-                    mint: ctx.accounts.mint.key(),
-                    leaf_index: next_index,
-                    commitment: parsed.output_commitments[idx],
-                    ciphertext: output_ciphertexts[idx],
-                    kind: NoteOutputKind::Internal as u8,
-                });
-                next_index = next_index.saturating_add(1);
-            }
-        }
-        let output_count = (parsed.output_enabled[0] + parsed.output_enabled[1]) as u64;
-        require!(output_count > 0, VeilpayError::InvalidOutputFlags);
-        shielded.commitment_count = shielded.commitment_count.saturating_add(output_count);
-        append_root(shielded, new_root);
+        config.pending_admin = new_admin;
         Ok(())
     }
 
-    pub fn store_proof(ctx: Context<StoreProof>, args: StoreProofArgs) -> Result<()> {
-        require!(
-            args.proof.len() == PROOF_BYTES_LEN,
-            VeilpayError::InvalidProofDataLength
-        );
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
         require!(
-            args.public_inputs.len() == PUBLIC_INPUTS_BYTES_LEN,
-            VeilpayError::InvalidPublicInputsLength
+            config.pending_admin != Pubkey::default()
+                && config.pending_admin == ctx.accounts.new_admin.key(),
+            VeilpayError::Unauthorized
         );
-        let proof_account = &mut ctx.accounts.proof_account;
-        proof_account.owner = ctx.accounts.payer.key();
-        proof_account.recipient = args.recipient;
-        proof_account.destination_ata = args.destination_ata;
-        proof_account.mint = args.mint;
-        proof_account.nonce = args.nonce;
-        proof_account.proof = args.proof;
-        proof_account.public_inputs = args.public_inputs;
-        proof_account.bump = ctx.bumps.proof_account;
+        let old_admin = config.admin;
+        config.admin = config.pending_admin;
+        config.pending_admin = Pubkey::default();
+        emit!(AdminChangedEvent {
+            old_admin,
+            new_admin: config.admin,
+        });
         Ok(())
     }
 
-    pub fn external_transfer<'info>(
-        ctx: Context<'_, '_, 'info, 'info, ExternalTransfer<'info>>,
-        args: ExternalTransferArgs,
-    ) -> Result<()> {
-        require!(!ctx.accounts.config.paused, VeilpayError::ProtocolPaused);
+    /// Sweeps `amount` of protocol fees out of a vault-owned fee account (any vault
+    /// token account except the pool's `vault_ata`, which backs user notes).
+    pub fn withdraw_protocol_fees(ctx: Context<WithdrawProtocolFees>, amount: u64) -> Result<()> {
         require!(
-            args.relayer_fee_bps <= ctx.accounts.config.relayer_fee_bps_max,
-            VeilpayError::RelayerFeeTooHigh
-        );
-        require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
-        );
-        require!(
-            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
-            VeilpayError::InvalidVaultAuthority
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
-        verify_groth16(
-            &ctx.accounts.verifier_program,
-            &ctx.accounts.verifier_key,
-            args.proof.clone(),
-            args.public_inputs.clone(),
+        check_protocol_fee_account(
+            &ctx.accounts.config,
+            &ctx.accounts.vault,
+            &ctx.accounts.fee_ata,
+            ctx.accounts.mint.key(),
         )?;
-        let parsed = Box::new(parse_public_inputs(&args.public_inputs)?);
-        let output_ciphertexts =
-            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
-        require!(parsed.amount_out == args.amount, VeilpayError::AmountMismatch);
-        require!(parsed.output_enabled[0] == 0, VeilpayError::InvalidOutputFlags);
         require!(
-            ctx.accounts.config.circuit_ids.contains(&parsed.circuit_id),
-            VeilpayError::CircuitNotAllowed
+            ctx.accounts.fee_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidProtocolFeeAccount
         );
         require!(
-            parsed.identity_root == ctx.accounts.identity_registry.merkle_root,
-            VeilpayError::IdentityRootMismatch
+            ctx.accounts.destination_ata.mint == ctx.accounts.mint.key(),
+            VeilpayError::InvalidRecipientTokenAccount
         );
         require!(
-            root_known(&ctx.accounts.shielded_state, parsed.root),
-            VeilpayError::UnknownRoot
+            amount <= ctx.accounts.fee_ata.amount,
+            VeilpayError::InsufficientProtocolFees
         );
-        let (net_amount, fee_amount) = split_relayer_fee(args.amount, args.relayer_fee_bps)?;
-        require!(fee_amount == parsed.fee_amount, VeilpayError::FeeMismatch);
-        mark_nullifiers(
-            &mut ctx.accounts.nullifier_set,
-            ctx.remaining_accounts,
-            &parsed.nullifiers,
-        )?;
 
         let bump_seed = [ctx.accounts.vault.bump];
         let mint_key = ctx.accounts.mint.key();
         let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
         let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.fee_ata.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.destination_ata.to_account_info(),
+            authority: ctx.accounts.vault.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts,
+            signer_seeds,
+        );
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.mint.decimals)?;
+        emit!(ProtocolFeesWithdrawnEvent {
+            mint: mint_key,
+            amount,
+            destination: ctx.accounts.destination_ata.key(),
+            admin: ctx.accounts.admin.key(),
+        });
+        Ok(())
+    }
 
-        if fee_amount > 0 {
-            let relayer_fee_ata = ctx
-                .accounts
-                .relayer_fee_ata
-                .as_ref()
-                .ok_or(VeilpayError::MissingRelayerFeeAccount)?;
-            require!(
-                relayer_fee_ata.mint == ctx.accounts.mint.key(),
-                VeilpayError::InvalidRelayerFeeAccount
-            );
-            let cpi_accounts = anchor_spl::token::Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: relayer_fee_ata.to_account_info(),
+    /// Retires a drained pool: closes its vault, shielded state and one nullifier chunk,
+    /// plus the empty vault token account, returning the rent to the admin. Vault-owned
+    /// protocol fee accounts are not tracked, so sweep them first.
+    pub fn close_mint_state(ctx: Context<CloseMintState>) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        require!(
+            ctx.accounts.vault.total_deposited == ctx.accounts.vault.total_withdrawn,
+            VeilpayError::PoolNotDrained
+        );
+
+        let vault_ata = &ctx.accounts.vault_ata;
+        if !vault_ata.data_is_empty() {
+            let balance = TokenAccount::try_deserialize(&mut &vault_ata.data.borrow()[..])?.amount;
+            require!(balance == 0, VeilpayError::VaultNotEmpty);
+
+            let bump_seed = [ctx.accounts.vault.bump];
+            let mint_key = ctx.accounts.mint.key();
+            let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
+            let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+            let cpi_accounts = CloseAccount {
+                account: vault_ata.to_account_info(),
+                destination: ctx.accounts.admin.to_account_info(),
                 authority: ctx.accounts.vault.to_account_info(),
             };
             let cpi_ctx = CpiContext::new_with_signer(
@@ -439,1049 +744,4256 @@ pub mod veilpay {
                 cpi_accounts,
                 signer_seeds,
             );
-            anchor_spl::token::transfer(cpi_ctx, fee_amount)?;
+            token_interface::close_account(cpi_ctx)?;
         }
 
-        if args.deliver_sol {
-            require!(
-                ctx.accounts.mint.key() == anchor_spl::token::spl_token::native_mint::id(),
-                VeilpayError::UnsupportedSolDelivery
-            );
-            let expected_ata = associated_token::get_associated_token_address(
-                &ctx.accounts.temp_authority.key(),
-                &ctx.accounts.mint.key(),
-            );
-            require!(
-                ctx.accounts.temp_wsol_ata.key() == expected_ata,
-                VeilpayError::InvalidTempAccount
-            );
-            if ctx.accounts.temp_wsol_ata.data_is_empty() {
-                let cpi_accounts = associated_token::Create {
-                    payer: ctx.accounts.payer.to_account_info(),
-                    associated_token: ctx.accounts.temp_wsol_ata.to_account_info(),
-                    authority: ctx.accounts.temp_authority.to_account_info(),
-                    mint: ctx.accounts.mint.to_account_info(),
-                    system_program: ctx.accounts.system_program.to_account_info(),
-                    token_program: ctx.accounts.token_program.to_account_info(),
-                };
-                let cpi_ctx = CpiContext::new(
-                    ctx.accounts.associated_token_program.to_account_info(),
-                    cpi_accounts,
-                );
-                associated_token::create(cpi_ctx)?;
-            } else {
-                require!(
-                    ctx.accounts.temp_wsol_ata.owner == &ctx.accounts.token_program.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-                let temp_ata: TokenAccount =
-                    TokenAccount::try_deserialize(&mut &ctx.accounts.temp_wsol_ata.data.borrow()[..])?;
-                require!(
-                    temp_ata.mint == ctx.accounts.mint.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-                require!(
-                    temp_ata.owner == ctx.accounts.temp_authority.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-            }
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: ctx.accounts.temp_wsol_ata.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                signer_seeds,
-            );
-            token::transfer(cpi_ctx, net_amount)?;
-            let temp_bump = ctx.bumps.temp_authority;
-            let recipient_key = ctx.accounts.recipient.key();
-            let vault_nonce_bytes = ctx.accounts.vault.nonce.to_le_bytes();
-            let temp_seeds: &[&[u8]] = &[
-                b"temp_wsol",
-                recipient_key.as_ref(),
-                vault_nonce_bytes.as_ref(),
-                &[temp_bump],
-            ];
-            let temp_signer: &[&[&[u8]]] = &[temp_seeds];
-            let cpi_accounts = CloseAccount {
-                account: ctx.accounts.temp_wsol_ata.to_account_info(),
-                destination: ctx.accounts.recipient.to_account_info(),
-                authority: ctx.accounts.temp_authority.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                temp_signer,
-            );
-            token::close_account(cpi_ctx)?;
-        } else {
+        emit!(MintStateClosedEvent {
+            mint: ctx.accounts.mint.key(),
+            chunk_index: ctx.accounts.nullifier_set.chunk_index,
+            admin: ctx.accounts.admin.key(),
+        });
+        Ok(())
+    }
+
+    pub fn set_circuit_ids(ctx: Context<UpdateConfig>, circuit_ids: Vec<u32>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        require!(
+            circuit_ids.len() <= MAX_CIRCUITS,
+            VeilpayError::CircuitListTooLarge
+        );
+        config.circuit_ids = circuit_ids;
+        Ok(())
+    }
+
+    pub fn add_fee_exempt(ctx: Context<UpdateConfig>, owner: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        if !config.fee_exempt.contains(&owner) {
             require!(
-                ctx.accounts.destination_ata.owner == &ctx.accounts.token_program.key(),
-                VeilpayError::InvalidRecipientTokenAccount
+                config.fee_exempt.len() < MAX_FEE_EXEMPT,
+                VeilpayError::FeeExemptListTooLarge
             );
-            let destination_ata: TokenAccount =
-                TokenAccount::try_deserialize(&mut &ctx.accounts.destination_ata.data.borrow()[..])?;
+            config.fee_exempt.push(owner);
+        }
+        Ok(())
+    }
+
+    pub fn remove_fee_exempt(ctx: Context<UpdateConfig>, owner: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.fee_exempt.retain(|exempt| *exempt != owner);
+        Ok(())
+    }
+
+    pub fn add_relayer(ctx: Context<UpdateConfig>, relayer: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        if !config.relayer_allowlist.contains(&relayer) {
             require!(
-                destination_ata.mint == ctx.accounts.mint.key(),
-                VeilpayError::InvalidRecipientTokenAccount
-            );
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: ctx.accounts.destination_ata.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                signer_seeds,
+                config.relayer_allowlist.len() < MAX_RELAYERS,
+                VeilpayError::RelayerListTooLarge
             );
-            token::transfer(cpi_ctx, net_amount)?;
+            config.relayer_allowlist.push(relayer);
         }
+        Ok(())
+    }
 
-        let vault = &mut ctx.accounts.vault;
-        vault.total_withdrawn = vault
-            .total_withdrawn
-            .checked_add(args.amount)
-            .ok_or(VeilpayError::MathOverflow)?;
-        vault.nonce = vault.nonce.saturating_add(1);
+    pub fn remove_relayer(ctx: Context<UpdateConfig>, relayer: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.relayer_allowlist.retain(|listed| *listed != relayer);
+        Ok(())
+    }
 
-        if parsed.output_enabled[1] == 1 {
-            let new_root = to_fixed_32(&args.new_root)?;
-            let shielded = &mut ctx.accounts.shielded_state;
-            let leaf_index = shielded.commitment_count;
-            emit!(NoteOutputEvent {
-                mint: ctx.accounts.mint.key(),
-                leaf_index,
-                commitment: parsed.output_commitments[1],
-                ciphertext: output_ciphertexts[1],
-                kind: NoteOutputKind::External as u8,
-            });
-            shielded.commitment_count = shielded.commitment_count.saturating_add(1);
-            append_root(shielded, new_root);
-        }
+    /// While enabled, only `payer` signers on the relayer allowlist may submit
+    /// withdrawals. An empty list then blocks them all.
+    pub fn set_relayer_allowlist_enabled(ctx: Context<UpdateConfig>, enabled: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.relayer_allowlist_enabled = enabled;
         Ok(())
     }
 
-    pub fn external_transfer_with_proof<'info>(
-        ctx: Context<'_, '_, 'info, 'info, ExternalTransferWithProof<'info>>,
-        args: ExternalTransferWithProofArgs,
-    ) -> Result<()> {
-        require!(!ctx.accounts.config.paused, VeilpayError::ProtocolPaused);
-        require!(
-            args.relayer_fee_bps <= ctx.accounts.config.relayer_fee_bps_max,
-            VeilpayError::RelayerFeeTooHigh
-        );
-        require!(
-            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
-            VeilpayError::MintNotAllowed
-        );
+    pub fn set_pool_circuits(ctx: Context<UpdateShieldedState>, circuit_ids: Vec<u32>) -> Result<()> {
         require!(
-            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
-            VeilpayError::InvalidVaultAuthority
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
         require!(
-            ctx.accounts.proof_account.mint == ctx.accounts.mint.key(),
-            VeilpayError::InvalidProofAccountMint
+            circuit_ids.len() <= MAX_CIRCUITS,
+            VeilpayError::CircuitListTooLarge
         );
+        ctx.accounts.shielded_state.circuit_ids = circuit_ids;
+        Ok(())
+    }
+
+    /// Sets how many roots the pool keeps, up to `MAX_ROOT_HISTORY`. The history is
+    /// rewritten oldest first and, when shrinking, only the newest `len` roots survive.
+    pub fn set_root_history_len(ctx: Context<UpdateShieldedState>, len: u32) -> Result<()> {
         require!(
-            ctx.accounts.proof_account.recipient == ctx.accounts.recipient.key(),
-            VeilpayError::InvalidProofAccountRecipient
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
         require!(
-            ctx.accounts.proof_account.destination_ata == ctx.accounts.destination_ata.key(),
-            VeilpayError::InvalidProofAccountDestination
+            len > 0 && len as usize <= MAX_ROOT_HISTORY,
+            VeilpayError::InvalidRootHistoryLen
         );
-        verify_groth16(
-            &ctx.accounts.verifier_program,
-            &ctx.accounts.verifier_key,
-            ctx.accounts.proof_account.proof.clone(),
-            ctx.accounts.proof_account.public_inputs.clone(),
-        )?;
-        let parsed = Box::new(parse_public_inputs(
-            &ctx.accounts.proof_account.public_inputs,
-        )?);
-        let output_ciphertexts =
-            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
-        require!(parsed.amount_out == args.amount, VeilpayError::AmountMismatch);
-        require!(parsed.output_enabled[0] == 0, VeilpayError::InvalidOutputFlags);
+        let shielded = &mut ctx.accounts.shielded_state;
+        let entries: Vec<([u8; 32], u64)> = root_history_in_order(shielded)
+            .into_iter()
+            .filter_map(|(root, recorded)| recorded.map(|recorded| (root, recorded)))
+            .collect();
+        let kept = &entries[entries.len().saturating_sub(len as usize)..];
+        shielded.root_history = kept.iter().map(|(root, _)| *root).collect();
+        shielded.root_slots = kept.iter().map(|(_, recorded)| *recorded).collect();
+        shielded.root_history_index = 0;
+        shielded.root_history_len = len;
+        Ok(())
+    }
+
+    /// Switches the pool to circuits with `max_inputs` nullifiers and `max_outputs`
+    /// output notes. Proofs are parsed with the layout in force when they are spent,
+    /// so stored proofs for the old shape stop parsing.
+    pub fn set_spend_layout(
+        ctx: Context<UpdateShieldedState>,
+        max_inputs: u8,
+        max_outputs: u8,
+    ) -> Result<()> {
         require!(
-            ctx.accounts.config.circuit_ids.contains(&parsed.circuit_id),
-            VeilpayError::CircuitNotAllowed
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
         );
+        SpendLayout::new(max_inputs as usize, max_outputs as usize)
+            .ok_or(VeilpayError::InvalidSpendLayout)?;
+        let shielded = &mut ctx.accounts.shielded_state;
+        shielded.max_inputs = max_inputs;
+        shielded.max_outputs = max_outputs;
+        Ok(())
+    }
+
+    pub fn set_compact_events(ctx: Context<UpdateConfig>, enabled: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.compact_events = enabled;
+        Ok(())
+    }
+
+    pub fn set_require_deposit_proof(ctx: Context<UpdateConfig>, required: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.require_deposit_proof = required;
+        Ok(())
+    }
+
+    pub fn set_event_flags(ctx: Context<UpdateConfig>, event_flags: u32) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        require!(
+            event_flags & !EVENT_FLAGS_ALL == 0,
+            VeilpayError::InvalidEventFlags
+        );
+        config.event_flags = event_flags;
+        Ok(())
+    }
+
+    /// Emergency switch for every deposit and withdraw path. Re-sending the current
+    /// value succeeds without emitting anything.
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        if config.paused == paused {
+            return Ok(());
+        }
+        config.paused = paused;
+        emit!(PausedEvent {
+            paused,
+            admin: ctx.accounts.admin.key(),
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Pauses deposits and withdrawals for one mint while the rest of the protocol keeps
+    /// running. The global pause still applies on top. Re-sending the current value
+    /// succeeds without emitting anything.
+    pub fn set_vault_paused(ctx: Context<UpdateVault>, paused: bool) -> Result<()> {
+        require!(
+            ctx.accounts.config.admin == ctx.accounts.admin.key(),
+            VeilpayError::Unauthorized
+        );
+        let vault = &mut ctx.accounts.vault;
+        if vault.paused == paused {
+            return Ok(());
+        }
+        vault.paused = paused;
+        emit!(VaultPausedEvent {
+            mint: vault.mint,
+            paused,
+            admin: ctx.accounts.admin.key(),
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    pub fn set_root_max_age(ctx: Context<UpdateConfig>, max_age_slots: u64) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.root_max_age_slots = max_age_slots;
+        Ok(())
+    }
+
+    pub fn set_max_single_withdraw(ctx: Context<UpdateConfig>, max_single_withdraw_bps: u16) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        require!(
+            max_single_withdraw_bps <= 10_000,
+            VeilpayError::InvalidWithdrawCap
+        );
+        config.max_single_withdraw_bps = max_single_withdraw_bps;
+        Ok(())
+    }
+
+    /// Number of nullifier chunks pools created from now on hash nullifiers into.
+    /// Existing pools keep the count they were created with, since changing it would
+    /// move already-spent nullifiers to other chunks.
+    pub fn set_nullifier_chunks(ctx: Context<UpdateConfig>, nullifier_chunks: u32) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        require!(nullifier_chunks > 0, VeilpayError::InvalidNullifierChunks);
+        config.nullifier_chunks = nullifier_chunks;
+        Ok(())
+    }
+
+    pub fn configure_withdraw_queue(
+        ctx: Context<UpdateConfig>,
+        threshold: u64,
+        delay_slots: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(config.admin == ctx.accounts.admin.key(), VeilpayError::Unauthorized);
+        config.withdraw_queue_threshold = threshold;
+        config.withdraw_queue_delay_slots = delay_slots;
+        Ok(())
+    }
+
+    /// Returns the config through return data so clients can simulate this instead of
+    /// decoding the raw account. The mint allowlist is paged to keep the payload under
+    /// the return-data limit; callers advance `allowlist_offset` until it reaches
+    /// `mint_allowlist_len`.
+    pub fn get_config(ctx: Context<GetConfig>, allowlist_offset: u32) -> Result<ConfigView> {
+        let config = &ctx.accounts.config;
+        let start = (allowlist_offset as usize).min(config.mint_allowlist.len());
+        let end = (start + CONFIG_VIEW_ALLOWLIST_PAGE).min(config.mint_allowlist.len());
+        Ok(ConfigView {
+            admin: config.admin,
+            pending_admin: config.pending_admin,
+            fee_authority: config.fee_authority,
+            pending_fee_authority: config.pending_fee_authority,
+            fee_bps: config.fee_bps,
+            relayer_fee_bps_max: config.relayer_fee_bps_max,
+            relayer_fee_bps_min: config.relayer_fee_bps_min,
+            relayer_fee_abs_min: config.relayer_fee_abs_min,
+            vk_registry: config.vk_registry,
+            verifier_program: config.verifier_program,
+            mint_allowlist_len: config.mint_allowlist.len() as u32,
+            mint_allowlist_offset: start as u32,
+            mint_allowlist: config.mint_allowlist[start..end].to_vec(),
+            circuit_ids: config.circuit_ids.clone(),
+            paused: config.paused,
+            compact_events: config.compact_events,
+            require_deposit_proof: config.require_deposit_proof,
+            event_flags: config.event_flags,
+            withdraw_queue_threshold: config.withdraw_queue_threshold,
+            withdraw_queue_delay_slots: config.withdraw_queue_delay_slots,
+            root_max_age_slots: config.root_max_age_slots,
+            max_single_withdraw_bps: config.max_single_withdraw_bps,
+            nullifier_chunks: config.nullifier_chunks,
+            version: config.version,
+        })
+    }
+
+    /// Whether `nullifier` is already marked, as return data, so clients can simulate
+    /// this before building a spend. `nullifier_set` must be the chunk the nullifier
+    /// maps to (`NullifierChunkMismatch` otherwise).
+    pub fn is_nullifier_spent(ctx: Context<CheckNullifier>, nullifier: [u8; 32]) -> Result<bool> {
+        let (byte_index, bit_mask) = nullifier_bit(&ctx.accounts.nullifier_set, &nullifier)?;
+        Ok(ctx.accounts.nullifier_set.bitset[byte_index] & bit_mask != 0)
+    }
+
+    /// Where `nullifier` lives in this pool's nullifier set, as return data, so relayers
+    /// pick the chunk accounts for `remaining_accounts` with the program's own derivation
+    /// instead of a copy of it.
+    pub fn nullifier_chunk_for(
+        ctx: Context<NullifierChunkFor>,
+        nullifier: [u8; 32],
+    ) -> Result<NullifierChunkView> {
+        let vault = &ctx.accounts.vault;
+        let (chunk_index, bit_index) = nullifier_position(&nullifier, vault.nullifier_chunks);
+        Ok(NullifierChunkView {
+            chunk_index,
+            bit_index,
+            nullifier_set: nullifier_set_address(&vault.mint, chunk_index),
+        })
+    }
+
+    /// Roots a spend against this pool is accepted with right now, as return data:
+    /// `merkle_root` first, then the rest of the history oldest to newest, skipping roots
+    /// past `root_max_age_slots`. Raw 32-byte words without a length prefix, since a
+    /// full history already fills the 1024-byte return-data limit.
+    pub fn get_valid_roots(ctx: Context<GetValidRoots>) -> Result<()> {
+        let mut roots = valid_roots(
+            &ctx.accounts.shielded_state,
+            Clock::get()?.slot,
+            ctx.accounts.config.root_max_age_slots,
+        );
+        // A full history plus a root still in its grace window is one word over the
+        // limit; the grace root is the oldest, so it is the one left out.
+        if roots.len() > MAX_ROOT_HISTORY {
+            roots.remove(1);
+        }
+        anchor_lang::solana_program::program::set_return_data(&roots.concat());
+        Ok(())
+    }
+
+    /// Read-only monitoring endpoint: one `HealthEvent` summarising pause state, vault
+    /// solvency, active circuits and identity registry usage for a mint.
+    pub fn emit_health(ctx: Context<EmitHealth>) -> Result<()> {
+        let vault = &ctx.accounts.vault;
+        let liabilities = vault.total_deposited.saturating_sub(vault.total_withdrawn);
+        let vault_balance = ctx.accounts.vault_ata.amount;
+        let active_circuits = ctx
+            .accounts
+            .vk_registry
+            .entries
+            .iter()
+            .filter(|entry| {
+                entry.status == VK_STATUS_ACTIVE
+                    && ctx.accounts.config.circuit_ids.contains(&entry.circuit_id)
+            })
+            .count() as u32;
+        let identity_registry = &ctx.accounts.identity_registry;
+        emit!(HealthEvent {
+            mint: ctx.accounts.mint.key(),
+            paused: ctx.accounts.config.paused || vault.paused,
+            vault_balance,
+            liabilities,
+            solvent: vault_balance >= liabilities,
+            active_circuits,
+            identity_count: identity_registry.commitment_count,
+            identity_capacity: max_identities(identity_registry.depth),
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Read-only pre-flight for a withdraw: runs `validate_withdraw_semantics` against
+    /// the supplied accounts without verifying the proof or moving funds, and emits a
+    /// `WithdrawPrecheckEvent` listing every invariant that failed (not just the first).
+    /// Public inputs come from `proof_account` when one is given.
+    pub fn precheck_withdraw(ctx: Context<PrecheckWithdraw>, args: PrecheckWithdrawArgs) -> Result<()> {
+        let proof_account = ctx.accounts.proof_account.as_ref();
+        let public_inputs = proof_account
+            .map(|proof| &proof.public_inputs)
+            .unwrap_or(&args.public_inputs);
+        let parsed = parse_public_inputs(
+            public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?;
+        let semantics = validate_withdraw_semantics(
+            &parsed,
+            &WithdrawTarget {
+                config: &ctx.accounts.config,
+                vault: &ctx.accounts.vault,
+                shielded: &ctx.accounts.shielded_state,
+                identity_registry: &ctx.accounts.identity_registry,
+                vk_registry: &ctx.accounts.vk_registry,
+                verifier_key: ctx.accounts.verifier_key.key(),
+                proof_binding: proof_account.map(|proof| ProofBinding {
+                    bound_recipient: proof.recipient,
+                    bound_destination: proof.destination_ata,
+                    recipient: ctx.accounts.recipient.key(),
+                    destination_ata: ctx.accounts.destination_ata.key(),
+                }),
+                fee_owner: payout_owner(
+                    false,
+                    ctx.accounts.recipient.key(),
+                    &ctx.accounts.destination_ata,
+                ),
+            },
+            args.amount,
+            args.relayer_fee_bps,
+            Clock::get()?.slot,
+        );
+        let (passed, failures) = semantics.report();
+        emit!(WithdrawPrecheckEvent {
+            mint: ctx.accounts.mint.key(),
+            passed,
+            failures,
+        });
+        Ok(())
+    }
+
+    pub fn deposit(ctx: Context<Deposit>, args: DepositArgs) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        require!(
+            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
+            VeilpayError::MintNotAllowed
+        );
+        require!(
+            ctx.accounts.identity_member.owner == ctx.accounts.user.key(),
+            VeilpayError::Unauthorized
+        );
+        require!(
+            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidVaultAuthority
+        );
+        let new_root = to_fixed_32(&args.new_root)?;
+        let commitment = to_fixed_32(&args.commitment)?;
+        let ciphertext = to_fixed_128(&args.ciphertext)?;
+
+        // Token-2022 transfer-fee mints deliver less than `args.amount`, so the pool is
+        // credited (and a deposit proof is checked) against what the vault received.
+        let balance_before = ctx.accounts.vault_ata.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_ata.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.vault_ata.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, args.amount, ctx.accounts.mint.decimals)?;
+        ctx.accounts.vault_ata.reload()?;
+        let received = ctx
+            .accounts
+            .vault_ata
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(VeilpayError::MathOverflow)?;
+        check_min_deposit(&ctx.accounts.vault, received)?;
+        match &args.proof {
+            Some(proof) => verify_deposit_proof(ctx.accounts, proof, commitment, received)?,
+            None => require!(
+                !ctx.accounts.config.require_deposit_proof,
+                VeilpayError::DepositProofRequired
+            ),
+        }
+
+        let vault = &mut ctx.accounts.vault;
+        vault.total_deposited = check_deposit_cap(vault, received)?;
+        vault.nonce = vault.nonce.saturating_add(1);
+
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        let shielded = &mut ctx.accounts.shielded_state;
+        let leaf_index = shielded.commitment_count;
+        events.note_output(leaf_index, commitment, ciphertext, NoteOutputKind::Deposit);
+        append_commitments(shielded, &[commitment], new_root)?;
+        append_root(
+            shielded,
+            new_root,
+            Clock::get()?.slot,
+            ctx.accounts.config.root_max_age_slots,
+        );
+        events.deposit(commitment, new_root, received, shielded.commitment_count);
+        events.flush();
+        Ok(())
+    }
+
+    pub fn internal_transfer<'info>(
+        ctx: Context<'_, '_, 'info, 'info, InternalTransfer<'info>>,
+        args: InternalTransferArgs,
+    ) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        require!(
+            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
+            VeilpayError::MintNotAllowed
+        );
+        verify_groth16(
+            verifier_program_id(&ctx.accounts.config, &ctx.accounts.vault),
+            &ctx.accounts.verifier_program,
+            &ctx.accounts.verifier_key,
+            args.proof.clone(),
+            args.public_inputs.clone(),
+        )?;
+        let parsed = Box::new(parse_public_inputs(
+            &args.public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?);
+        let output_ciphertexts =
+            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
+        require!(parsed.amount_out == 0, VeilpayError::InvalidOutputFlags);
+        require!(parsed.fee_amount == 0, VeilpayError::InvalidOutputFlags);
+        require!(
+            parsed.output_enabled[0] == 1,
+            VeilpayError::InvalidOutputFlags
+        );
+        check_domain(&parsed, &ctx.accounts.mint.key())?;
+        check_circuit_allowed(
+            &ctx.accounts.config,
+            &ctx.accounts.shielded_state,
+            parsed.circuit_id,
+        )?;
+        check_pool_circuit(&ctx.accounts.shielded_state, parsed.circuit_id)?;
+        check_vk_binding(
+            &ctx.accounts.vk_registry,
+            parsed.circuit_id,
+            ctx.accounts.verifier_key.key(),
+        )?;
+        check_identity_root(
+            &ctx.accounts.vault,
+            &ctx.accounts.identity_registry,
+            parsed.identity_root,
+        )?;
+        require!(
+            root_known(
+                &ctx.accounts.shielded_state,
+                parsed.root,
+                Clock::get()?.slot,
+                ctx.accounts.config.root_max_age_slots,
+            ),
+            VeilpayError::UnknownRoot
+        );
+        check_slot_window(&parsed, Clock::get()?.slot)?;
+        check_value_conservation(&parsed)?;
+        mark_nullifiers(
+            &mut ctx.accounts.nullifier_set,
+            ctx.remaining_accounts,
+            &ctx.accounts.vault,
+            &parsed.nullifiers,
+        )?;
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        let shielded = &mut ctx.accounts.shielded_state;
+        let new_root = to_fixed_32(&args.new_root)?;
+        let mut next_index = shielded.commitment_count;
+        for idx in 0..NOTE_OUTPUTS {
+            if parsed.output_enabled[idx] == 1 {
+                events.note_output(
+                    next_index,
+                    parsed.output_commitments[idx],
+                    output_ciphertexts[idx],
+                    NoteOutputKind::Internal,
+                );
+                next_index = next_index.saturating_add(1);
+            }
+        }
+        let output_count = (parsed.output_enabled[0] + parsed.output_enabled[1]) as u64;
+        require!(output_count > 0, VeilpayError::InvalidOutputFlags);
+        let leaves: Vec<[u8; 32]> = (0..NOTE_OUTPUTS)
+            .filter(|idx| parsed.output_enabled[*idx] == 1)
+            .map(|idx| parsed.output_commitments[idx])
+            .collect();
+        append_commitments(shielded, &leaves, new_root)?;
+        append_root(
+            shielded,
+            new_root,
+            Clock::get()?.slot,
+            ctx.accounts.config.root_max_age_slots,
+        );
+        events.transfer(TransferKind::Internal, 0, 0, parsed.circuit_id);
+        events.shielded_transfer(new_root, output_count as u8, true);
+        events.flush();
+        Ok(())
+    }
+
+    pub fn internal_transfer_with_proof<'info>(
+        ctx: Context<'_, '_, 'info, 'info, InternalTransferWithProof<'info>>,
+        args: InternalTransferWithProofArgs,
+    ) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        require!(
+            ctx.accounts.config.mint_allowlist.contains(&ctx.accounts.mint.key()),
+            VeilpayError::MintNotAllowed
+        );
+        require!(
+            ctx.accounts.proof_account.mint == ctx.accounts.mint.key(),
+            VeilpayError::InvalidProofAccountMint
+        );
+        verify_groth16(
+            verifier_program_id(&ctx.accounts.config, &ctx.accounts.vault),
+            &ctx.accounts.verifier_program,
+            &ctx.accounts.verifier_key,
+            ctx.accounts.proof_account.proof.clone(),
+            ctx.accounts.proof_account.public_inputs.clone(),
+        )?;
+        let parsed = Box::new(parse_public_inputs(
+            &ctx.accounts.proof_account.public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?);
+        let output_ciphertexts =
+            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
+        require!(parsed.amount_out == 0, VeilpayError::InvalidOutputFlags);
+        require!(parsed.fee_amount == 0, VeilpayError::InvalidOutputFlags);
+        require!(
+            parsed.output_enabled[0] == 1,
+            VeilpayError::InvalidOutputFlags
+        );
+        check_domain(&parsed, &ctx.accounts.mint.key())?;
+        check_circuit_allowed(
+            &ctx.accounts.config,
+            &ctx.accounts.shielded_state,
+            parsed.circuit_id,
+        )?;
+        check_pool_circuit(&ctx.accounts.shielded_state, parsed.circuit_id)?;
+        check_vk_binding(
+            &ctx.accounts.vk_registry,
+            parsed.circuit_id,
+            ctx.accounts.verifier_key.key(),
+        )?;
+        check_identity_root(
+            &ctx.accounts.vault,
+            &ctx.accounts.identity_registry,
+            parsed.identity_root,
+        )?;
+        require!(
+            root_known(
+                &ctx.accounts.shielded_state,
+                parsed.root,
+                Clock::get()?.slot,
+                ctx.accounts.config.root_max_age_slots,
+            ),
+            VeilpayError::UnknownRoot
+        );
+        check_slot_window(&parsed, Clock::get()?.slot)?;
+        check_value_conservation(&parsed)?;
+        mark_nullifiers(
+            &mut ctx.accounts.nullifier_set,
+            ctx.remaining_accounts,
+            &ctx.accounts.vault,
+            &parsed.nullifiers,
+        )?;
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        let shielded = &mut ctx.accounts.shielded_state;
+        let new_root = to_fixed_32(&args.new_root)?;
+        let mut next_index = shielded.commitment_count;
+        for idx in 0..NOTE_OUTPUTS {
+            if parsed.output_enabled[idx] == 1 {
+                events.note_output(
+                    next_index,
+                    parsed.output_commitments[idx],
+                    output_ciphertexts[idx],
+                    NoteOutputKind::Internal,
+                );
+                next_index = next_index.saturating_add(1);
+            }
+        }
+        let output_count = (parsed.output_enabled[0] + parsed.output_enabled[1]) as u64;
+        require!(output_count > 0, VeilpayError::InvalidOutputFlags);
+        let leaves: Vec<[u8; 32]> = (0..NOTE_OUTPUTS)
+            .filter(|idx| parsed.output_enabled[*idx] == 1)
+            .map(|idx| parsed.output_commitments[idx])
+            .collect();
+        append_commitments(shielded, &leaves, new_root)?;
+        append_root(
+            shielded,
+            new_root,
+            Clock::get()?.slot,
+            ctx.accounts.config.root_max_age_slots,
+        );
+        events.transfer(TransferKind::Internal, 0, 0, parsed.circuit_id);
+        events.shielded_transfer(new_root, output_count as u8, true);
+        events.flush();
+        Ok(())
+    }
+
+    pub fn store_proof(ctx: Context<StoreProof>, args: StoreProofArgs) -> Result<()> {
+        require!(
+            args.proof.len() == PROOF_BYTES_LEN,
+            VeilpayError::InvalidProofDataLength
+        );
+        require!(
+            public_inputs_bytes_len_supported(args.public_inputs.len()),
+            VeilpayError::InvalidPublicInputsLength
+        );
+        let proof_account = &mut ctx.accounts.proof_account;
+        proof_account.owner = ctx.accounts.payer.key();
+        proof_account.recipient = args.recipient;
+        proof_account.destination_ata = args.destination_ata;
+        proof_account.mint = args.mint;
+        proof_account.nonce = args.nonce;
+        proof_account.proof = args.proof;
+        proof_account.public_inputs = args.public_inputs;
+        proof_account.bump = ctx.bumps.proof_account;
+        Ok(())
+    }
+
+    pub fn external_transfer<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExternalTransfer<'info>>,
+        args: ExternalTransferArgs,
+    ) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        check_relayer_allowed(&ctx.accounts.config, &ctx.accounts.payer.key())?;
+        check_relayer_fee_mode(
+            &ctx.accounts.config,
+            args.relayer_fee_bps,
+            args.relayer_fee_lamports,
+        )?;
+        require!(
+            !withdrawal_must_queue(&ctx.accounts.config, args.amount),
+            VeilpayError::WithdrawalMustBeQueued
+        );
+        require!(
+            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidVaultAuthority
+        );
+        check_single_withdraw(
+            &ctx.accounts.config,
+            ctx.accounts.vault_ata.amount,
+            args.amount,
+        )?;
+        check_withdraw_cap(&ctx.accounts.vault, args.amount)?;
+        let verifier_key = select_verifier_key(
+            args.proof_system,
+            &ctx.accounts.verifier_key,
+            ctx.accounts.verifier_key_alt.as_ref(),
+        )?;
+        verify_groth16(
+            verifier_program_id(&ctx.accounts.config, &ctx.accounts.vault),
+            &ctx.accounts.verifier_program,
+            verifier_key,
+            args.proof.clone(),
+            args.public_inputs.clone(),
+        )?;
+        let parsed = Box::new(parse_public_inputs(
+            &args.public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?);
+        let output_ciphertexts =
+            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
+        let fee_owner = payout_owner(
+            args.deliver_sol,
+            ctx.accounts.recipient.key(),
+            &ctx.accounts.destination_ata,
+        );
+        validate_withdraw_semantics(
+            &parsed,
+            &WithdrawTarget {
+                config: &ctx.accounts.config,
+                vault: &ctx.accounts.vault,
+                shielded: &ctx.accounts.shielded_state,
+                identity_registry: &ctx.accounts.identity_registry,
+                vk_registry: &ctx.accounts.vk_registry,
+                verifier_key: verifier_key.key(),
+                proof_binding: None,
+                fee_owner,
+            },
+            args.amount,
+            args.relayer_fee_bps,
+            Clock::get()?.slot,
+        )
+        .into_result()?;
+        let split = split_withdraw(
+            args.amount,
+            protocol_fee_bps(&ctx.accounts.config, fee_owner),
+            args.relayer_fee_bps,
+        )?;
+        check_vault_balance(ctx.accounts.vault_ata.amount, &split)?;
+        mark_nullifiers(
+            &mut ctx.accounts.nullifier_set,
+            ctx.remaining_accounts,
+            &ctx.accounts.vault,
+            &parsed.nullifiers,
+        )?;
+
+        // Checks-effects-interactions: every pool update lands, and is written back to the
+        // accounts, before the first token CPI.
+        let withdraw_nonce = ctx.accounts.vault.nonce;
+        let vault = &mut ctx.accounts.vault;
+        vault.total_withdrawn = vault
+            .total_withdrawn
+            .checked_add(args.amount)
+            .ok_or(VeilpayError::MathOverflow)?;
+        vault.nonce = vault.nonce.saturating_add(1);
+
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        events.transfer(TransferKind::External, args.amount, split.total_fee()?, parsed.circuit_id);
+        events.withdraw(
+            parsed.nullifiers,
+            args.amount,
+            split.total_fee()?,
+            ctx.accounts.recipient.key(),
+        );
+        if parsed.output_enabled[1] == 1 {
+            let new_root = to_fixed_32(&args.new_root)?;
+            let shielded = &mut ctx.accounts.shielded_state;
+            let leaf_index = shielded.commitment_count;
+            events.note_output(
+                leaf_index,
+                parsed.output_commitments[1],
+                output_ciphertexts[1],
+                NoteOutputKind::External,
+            );
+            append_commitments(shielded, &[parsed.output_commitments[1]], new_root)?;
+            append_root(
+                shielded,
+                new_root,
+                Clock::get()?.slot,
+                ctx.accounts.config.root_max_age_slots,
+            );
+        }
+        events.shielded_transfer(
+            ctx.accounts.shielded_state.merkle_root,
+            parsed.output_enabled[1],
+            false,
+        );
+        persist_spend_state(
+            &ctx.accounts.vault,
+            &ctx.accounts.shielded_state,
+            &ctx.accounts.nullifier_set,
+        )?;
+
+        let bump_seed = [ctx.accounts.vault.bump];
+        let mint_key = ctx.accounts.mint.key();
+        let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
+        let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+
+        if split.protocol_fee > 0 {
+            let protocol_fee_ata = ctx
+                .accounts
+                .protocol_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingProtocolFeeAccount)?;
+            check_protocol_fee_account(
+                &ctx.accounts.config,
+                &ctx.accounts.vault,
+                protocol_fee_ata,
+                ctx.accounts.mint.key(),
+            )?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: protocol_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.protocol_fee, ctx.accounts.mint.decimals)?;
+        }
+
+        if split.relayer_fee > 0 {
+            let relayer_fee_ata = ctx
+                .accounts
+                .relayer_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingRelayerFeeAccount)?;
+            require!(
+                relayer_fee_ata.mint == ctx.accounts.mint.key(),
+                VeilpayError::InvalidRelayerFeeAccount
+            );
+            check_relayer_fee_recipient(&parsed, &relayer_fee_ata.owner)?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: relayer_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.relayer_fee, ctx.accounts.mint.decimals)?;
+        }
+        pay_relayer_fee_lamports(
+            ctx.accounts.fee_escrow.as_ref(),
+            &ctx.accounts.recipient,
+            &ctx.accounts.payer,
+            &parsed,
+            args.relayer_fee_lamports,
+        )?;
+
+        if args.deliver_sol {
+            require!(
+                ctx.accounts.mint.key() == anchor_spl::token::spl_token::native_mint::id(),
+                VeilpayError::UnsupportedSolDelivery
+            );
+            let expected_ata = associated_token::get_associated_token_address(
+                &ctx.accounts.temp_authority.key(),
+                &ctx.accounts.mint.key(),
+            );
+            require!(
+                ctx.accounts.temp_wsol_ata.key() == expected_ata,
+                VeilpayError::InvalidTempAccount
+            );
+            if ctx.accounts.temp_wsol_ata.data_is_empty() {
+                let cpi_accounts = associated_token::Create {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    associated_token: ctx.accounts.temp_wsol_ata.to_account_info(),
+                    authority: ctx.accounts.temp_authority.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new(
+                    ctx.accounts.associated_token_program.to_account_info(),
+                    cpi_accounts,
+                );
+                associated_token::create(cpi_ctx)?;
+            } else {
+                require!(
+                    ctx.accounts.temp_wsol_ata.owner == &ctx.accounts.token_program.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+                let temp_ata: TokenAccount =
+                    TokenAccount::try_deserialize(&mut &ctx.accounts.temp_wsol_ata.data.borrow()[..])?;
+                require!(
+                    temp_ata.mint == ctx.accounts.mint.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+                require!(
+                    temp_ata.owner == ctx.accounts.temp_authority.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+            }
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.temp_wsol_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.net_amount, ctx.accounts.mint.decimals)?;
+            let temp_bump = ctx.bumps.temp_authority;
+            let recipient_key = ctx.accounts.recipient.key();
+            let vault_nonce_bytes = withdraw_nonce.to_le_bytes();
+            let temp_seeds: &[&[u8]] = &[
+                b"temp_wsol",
+                recipient_key.as_ref(),
+                vault_nonce_bytes.as_ref(),
+                &[temp_bump],
+            ];
+            let temp_signer: &[&[&[u8]]] = &[temp_seeds];
+            let cpi_accounts = CloseAccount {
+                account: ctx.accounts.temp_wsol_ata.to_account_info(),
+                destination: ctx.accounts.recipient.to_account_info(),
+                authority: ctx.accounts.temp_authority.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                temp_signer,
+            );
+            token_interface::close_account(cpi_ctx)?;
+        } else {
+            require!(
+                ctx.accounts.destination_ata.owner == &ctx.accounts.token_program.key(),
+                VeilpayError::InvalidRecipientTokenAccount
+            );
+            let destination_ata: TokenAccount =
+                TokenAccount::try_deserialize(&mut &ctx.accounts.destination_ata.data.borrow()[..])?;
+            require!(
+                destination_ata.mint == ctx.accounts.mint.key(),
+                VeilpayError::InvalidRecipientTokenAccount
+            );
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.destination_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.net_amount, ctx.accounts.mint.decimals)?;
+        }
+        events.flush();
+        Ok(())
+    }
+
+    pub fn external_transfer_with_proof<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExternalTransferWithProof<'info>>,
+        args: ExternalTransferWithProofArgs,
+    ) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        check_relayer_allowed(&ctx.accounts.config, &ctx.accounts.payer.key())?;
+        check_relayer_fee_mode(
+            &ctx.accounts.config,
+            args.relayer_fee_bps,
+            args.relayer_fee_lamports,
+        )?;
+        require!(
+            !withdrawal_must_queue(&ctx.accounts.config, args.amount),
+            VeilpayError::WithdrawalMustBeQueued
+        );
+        require!(
+            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidVaultAuthority
+        );
+        check_single_withdraw(
+            &ctx.accounts.config,
+            ctx.accounts.vault_ata.amount,
+            args.amount,
+        )?;
+        check_withdraw_cap(&ctx.accounts.vault, args.amount)?;
+        require!(
+            ctx.accounts.proof_account.mint == ctx.accounts.mint.key(),
+            VeilpayError::InvalidProofAccountMint
+        );
+        let verifier_key = select_verifier_key(
+            args.proof_system,
+            &ctx.accounts.verifier_key,
+            ctx.accounts.verifier_key_alt.as_ref(),
+        )?;
+        verify_groth16(
+            verifier_program_id(&ctx.accounts.config, &ctx.accounts.vault),
+            &ctx.accounts.verifier_program,
+            verifier_key,
+            ctx.accounts.proof_account.proof.clone(),
+            ctx.accounts.proof_account.public_inputs.clone(),
+        )?;
+        let parsed = Box::new(parse_public_inputs(
+            &ctx.accounts.proof_account.public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?);
+        let output_ciphertexts =
+            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
+        let fee_owner = payout_owner(
+            args.deliver_sol,
+            ctx.accounts.recipient.key(),
+            &ctx.accounts.destination_ata,
+        );
+        validate_withdraw_semantics(
+            &parsed,
+            &WithdrawTarget {
+                config: &ctx.accounts.config,
+                vault: &ctx.accounts.vault,
+                shielded: &ctx.accounts.shielded_state,
+                identity_registry: &ctx.accounts.identity_registry,
+                vk_registry: &ctx.accounts.vk_registry,
+                verifier_key: verifier_key.key(),
+                proof_binding: Some(ProofBinding {
+                    bound_recipient: ctx.accounts.proof_account.recipient,
+                    bound_destination: ctx.accounts.proof_account.destination_ata,
+                    recipient: ctx.accounts.recipient.key(),
+                    destination_ata: ctx.accounts.destination_ata.key(),
+                }),
+                fee_owner,
+            },
+            args.amount,
+            args.relayer_fee_bps,
+            Clock::get()?.slot,
+        )
+        .into_result()?;
+        let split = split_withdraw(
+            args.amount,
+            protocol_fee_bps(&ctx.accounts.config, fee_owner),
+            args.relayer_fee_bps,
+        )?;
+        check_vault_balance(ctx.accounts.vault_ata.amount, &split)?;
+        mark_nullifiers(
+            &mut ctx.accounts.nullifier_set,
+            ctx.remaining_accounts,
+            &ctx.accounts.vault,
+            &parsed.nullifiers,
+        )?;
+
+        // Checks-effects-interactions: every pool update lands, and is written back to the
+        // accounts, before the first token CPI.
+        let withdraw_nonce = ctx.accounts.vault.nonce;
+        let vault = &mut ctx.accounts.vault;
+        vault.total_withdrawn = vault
+            .total_withdrawn
+            .checked_add(args.amount)
+            .ok_or(VeilpayError::MathOverflow)?;
+        vault.nonce = vault.nonce.saturating_add(1);
+
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        events.transfer(TransferKind::External, args.amount, split.total_fee()?, parsed.circuit_id);
+        events.withdraw(
+            parsed.nullifiers,
+            args.amount,
+            split.total_fee()?,
+            ctx.accounts.recipient.key(),
+        );
+        if parsed.output_enabled[1] == 1 {
+            let new_root = to_fixed_32(&args.new_root)?;
+            let shielded = &mut ctx.accounts.shielded_state;
+            let leaf_index = shielded.commitment_count;
+            events.note_output(
+                leaf_index,
+                parsed.output_commitments[1],
+                output_ciphertexts[1],
+                NoteOutputKind::External,
+            );
+            append_commitments(shielded, &[parsed.output_commitments[1]], new_root)?;
+            append_root(
+                shielded,
+                new_root,
+                Clock::get()?.slot,
+                ctx.accounts.config.root_max_age_slots,
+            );
+        }
+        events.shielded_transfer(
+            ctx.accounts.shielded_state.merkle_root,
+            parsed.output_enabled[1],
+            false,
+        );
+        persist_spend_state(
+            &ctx.accounts.vault,
+            &ctx.accounts.shielded_state,
+            &ctx.accounts.nullifier_set,
+        )?;
+
+        let bump_seed = [ctx.accounts.vault.bump];
+        let mint_key = ctx.accounts.mint.key();
+        let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
+        let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+
+        if split.protocol_fee > 0 {
+            let protocol_fee_ata = ctx
+                .accounts
+                .protocol_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingProtocolFeeAccount)?;
+            check_protocol_fee_account(
+                &ctx.accounts.config,
+                &ctx.accounts.vault,
+                protocol_fee_ata,
+                ctx.accounts.mint.key(),
+            )?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: protocol_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.protocol_fee, ctx.accounts.mint.decimals)?;
+        }
+
+        if split.relayer_fee > 0 {
+            let relayer_fee_ata = ctx
+                .accounts
+                .relayer_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingRelayerFeeAccount)?;
+            require!(
+                relayer_fee_ata.mint == ctx.accounts.mint.key(),
+                VeilpayError::InvalidRelayerFeeAccount
+            );
+            check_relayer_fee_recipient(&parsed, &relayer_fee_ata.owner)?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: relayer_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.relayer_fee, ctx.accounts.mint.decimals)?;
+        }
+        pay_relayer_fee_lamports(
+            ctx.accounts.fee_escrow.as_ref(),
+            &ctx.accounts.recipient,
+            &ctx.accounts.payer,
+            &parsed,
+            args.relayer_fee_lamports,
+        )?;
+
+        if args.deliver_sol {
+            require!(
+                ctx.accounts.mint.key() == anchor_spl::token::spl_token::native_mint::id(),
+                VeilpayError::UnsupportedSolDelivery
+            );
+            let expected_ata = associated_token::get_associated_token_address(
+                &ctx.accounts.temp_authority.key(),
+                &ctx.accounts.mint.key(),
+            );
+            require!(
+                ctx.accounts.temp_wsol_ata.key() == expected_ata,
+                VeilpayError::InvalidTempAccount
+            );
+            if ctx.accounts.temp_wsol_ata.data_is_empty() {
+                let cpi_accounts = associated_token::Create {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    associated_token: ctx.accounts.temp_wsol_ata.to_account_info(),
+                    authority: ctx.accounts.temp_authority.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new(
+                    ctx.accounts.associated_token_program.to_account_info(),
+                    cpi_accounts,
+                );
+                associated_token::create(cpi_ctx)?;
+            } else {
+                require!(
+                    ctx.accounts.temp_wsol_ata.owner == &ctx.accounts.token_program.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+                let temp_ata: TokenAccount =
+                    TokenAccount::try_deserialize(&mut &ctx.accounts.temp_wsol_ata.data.borrow()[..])?;
+                require!(
+                    temp_ata.mint == ctx.accounts.mint.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+                require!(
+                    temp_ata.owner == ctx.accounts.temp_authority.key(),
+                    VeilpayError::InvalidTempAccount
+                );
+            }
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.temp_wsol_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.net_amount, ctx.accounts.mint.decimals)?;
+            let temp_bump = ctx.bumps.temp_authority;
+            let recipient_key = ctx.accounts.recipient.key();
+            let vault_nonce_bytes = withdraw_nonce.to_le_bytes();
+            let temp_seeds: &[&[u8]] = &[
+                b"temp_wsol",
+                recipient_key.as_ref(),
+                vault_nonce_bytes.as_ref(),
+                &[temp_bump],
+            ];
+            let temp_signer: &[&[&[u8]]] = &[temp_seeds];
+            let cpi_accounts = CloseAccount {
+                account: ctx.accounts.temp_wsol_ata.to_account_info(),
+                destination: ctx.accounts.recipient.to_account_info(),
+                authority: ctx.accounts.temp_authority.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                temp_signer,
+            );
+            token_interface::close_account(cpi_ctx)?;
+        } else {
+            require!(
+                ctx.accounts.destination_ata.owner == &ctx.accounts.token_program.key(),
+                VeilpayError::InvalidRecipientTokenAccount
+            );
+            let destination_ata: TokenAccount =
+                TokenAccount::try_deserialize(&mut &ctx.accounts.destination_ata.data.borrow()[..])?;
+            require!(
+                destination_ata.mint == ctx.accounts.mint.key(),
+                VeilpayError::InvalidRecipientTokenAccount
+            );
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.destination_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.net_amount, ctx.accounts.mint.decimals)?;
+        }
+        events.flush();
+        Ok(())
+    }
+
+    pub fn queue_withdrawal<'info>(
+        ctx: Context<'_, '_, 'info, 'info, QueueWithdrawal<'info>>,
+        args: QueueWithdrawalArgs,
+    ) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        check_relayer_allowed(&ctx.accounts.config, &ctx.accounts.payer.key())?;
+        require!(
+            ctx.accounts.config.withdraw_queue_threshold > 0,
+            VeilpayError::WithdrawQueueDisabled
+        );
+        check_relayer_fee_bps(&ctx.accounts.config, args.relayer_fee_bps)?;
+        require!(
+            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidVaultAuthority
+        );
+        check_single_withdraw(
+            &ctx.accounts.config,
+            ctx.accounts.vault_ata.amount,
+            args.amount,
+        )?;
+        check_withdraw_cap(&ctx.accounts.vault, args.amount)?;
+        require!(
+            ctx.accounts.proof_account.mint == ctx.accounts.mint.key(),
+            VeilpayError::InvalidProofAccountMint
+        );
+        verify_groth16(
+            verifier_program_id(&ctx.accounts.config, &ctx.accounts.vault),
+            &ctx.accounts.verifier_program,
+            &ctx.accounts.verifier_key,
+            ctx.accounts.proof_account.proof.clone(),
+            ctx.accounts.proof_account.public_inputs.clone(),
+        )?;
+        let parsed = Box::new(parse_public_inputs(
+            &ctx.accounts.proof_account.public_inputs,
+            spend_layout(&ctx.accounts.shielded_state),
+        )?);
+        let output_ciphertexts =
+            Box::new(parse_output_ciphertexts(&args.output_ciphertexts, parsed.output_enabled)?);
+        validate_withdraw_semantics(
+            &parsed,
+            &WithdrawTarget {
+                config: &ctx.accounts.config,
+                vault: &ctx.accounts.vault,
+                shielded: &ctx.accounts.shielded_state,
+                identity_registry: &ctx.accounts.identity_registry,
+                vk_registry: &ctx.accounts.vk_registry,
+                verifier_key: ctx.accounts.verifier_key.key(),
+                proof_binding: None,
+                // The destination is only loaded at claim time, so queued withdrawals
+                // always pay the protocol fee.
+                fee_owner: None,
+            },
+            args.amount,
+            args.relayer_fee_bps,
+            Clock::get()?.slot,
+        )
+        .into_result()?;
+        let split = split_withdraw(
+            args.amount,
+            protocol_fee_bps(&ctx.accounts.config, None),
+            args.relayer_fee_bps,
+        )?;
+        check_vault_balance(ctx.accounts.vault_ata.amount, &split)?;
+        mark_nullifiers(
+            &mut ctx.accounts.nullifier_set,
+            ctx.remaining_accounts,
+            &ctx.accounts.vault,
+            &parsed.nullifiers,
+        )?;
+
+        // Checks-effects-interactions: every pool update lands, and is written back to the
+        // accounts, before the first token CPI.
+        let clock = Clock::get()?;
+        let pending = &mut ctx.accounts.pending_withdrawal;
+        pending.mint = ctx.accounts.mint.key();
+        pending.recipient = ctx.accounts.proof_account.recipient;
+        pending.destination_ata = ctx.accounts.proof_account.destination_ata;
+        pending.rent_payer = ctx.accounts.payer.key();
+        pending.amount = split.net_amount;
+        pending.ready_slot = clock
+            .slot
+            .checked_add(ctx.accounts.config.withdraw_queue_delay_slots)
+            .ok_or(VeilpayError::MathOverflow)?;
+        pending.nonce = ctx.accounts.vault.nonce;
+        pending.bump = ctx.bumps.pending_withdrawal;
+
+        let vault = &mut ctx.accounts.vault;
+        vault.total_withdrawn = vault
+            .total_withdrawn
+            .checked_add(args.amount)
+            .ok_or(VeilpayError::MathOverflow)?;
+        vault.nonce = vault.nonce.saturating_add(1);
+
+        let mut events = EventLog::new(&ctx.accounts.config, ctx.accounts.mint.key());
+        events.transfer(TransferKind::Queued, args.amount, split.total_fee()?, parsed.circuit_id);
+        events.withdraw(
+            parsed.nullifiers,
+            args.amount,
+            split.total_fee()?,
+            ctx.accounts.proof_account.recipient,
+        );
+        if parsed.output_enabled[1] == 1 {
+            let new_root = to_fixed_32(&args.new_root)?;
+            let shielded = &mut ctx.accounts.shielded_state;
+            let leaf_index = shielded.commitment_count;
+            events.note_output(
+                leaf_index,
+                parsed.output_commitments[1],
+                output_ciphertexts[1],
+                NoteOutputKind::External,
+            );
+            append_commitments(shielded, &[parsed.output_commitments[1]], new_root)?;
+            append_root(
+                shielded,
+                new_root,
+                Clock::get()?.slot,
+                ctx.accounts.config.root_max_age_slots,
+            );
+        }
+        events.shielded_transfer(
+            ctx.accounts.shielded_state.merkle_root,
+            parsed.output_enabled[1],
+            false,
+        );
+        persist_spend_state(
+            &ctx.accounts.vault,
+            &ctx.accounts.shielded_state,
+            &ctx.accounts.nullifier_set,
+        )?;
+        ctx.accounts.pending_withdrawal.exit(&crate::ID)?;
+
+        let bump_seed = [ctx.accounts.vault.bump];
+        let mint_key = ctx.accounts.mint.key();
+        let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
+        let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+
+        if split.protocol_fee > 0 {
+            let protocol_fee_ata = ctx
+                .accounts
+                .protocol_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingProtocolFeeAccount)?;
+            check_protocol_fee_account(
+                &ctx.accounts.config,
+                &ctx.accounts.vault,
+                protocol_fee_ata,
+                ctx.accounts.mint.key(),
+            )?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: protocol_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.protocol_fee, ctx.accounts.mint.decimals)?;
+        }
+
+        if split.relayer_fee > 0 {
+            let relayer_fee_ata = ctx
+                .accounts
+                .relayer_fee_ata
+                .as_ref()
+                .ok_or(VeilpayError::MissingRelayerFeeAccount)?;
+            require!(
+                relayer_fee_ata.mint == ctx.accounts.mint.key(),
+                VeilpayError::InvalidRelayerFeeAccount
+            );
+            check_relayer_fee_recipient(&parsed, &relayer_fee_ata.owner)?;
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.vault_ata.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: relayer_fee_ata.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer_seeds,
+            );
+            token_interface::transfer_checked(cpi_ctx, split.relayer_fee, ctx.accounts.mint.decimals)?;
+        }
+        events.flush();
+        Ok(())
+    }
+
+    pub fn claim_withdrawal(ctx: Context<ClaimWithdrawal>) -> Result<()> {
+        check_not_paused(&ctx.accounts.config, &ctx.accounts.vault)?;
+        let pending = &ctx.accounts.pending_withdrawal;
+        require!(
+            Clock::get()?.slot >= pending.ready_slot,
+            VeilpayError::WithdrawalNotReady
+        );
+        require!(
+            pending.rent_payer == ctx.accounts.rent_payer.key(),
+            VeilpayError::Unauthorized
+        );
+        require!(
+            pending.destination_ata == ctx.accounts.destination_ata.key(),
+            VeilpayError::InvalidRecipientTokenAccount
+        );
+        require!(
+            ctx.accounts.destination_ata.mint == ctx.accounts.mint.key(),
+            VeilpayError::InvalidRecipientTokenAccount
+        );
+        require!(
+            ctx.accounts.vault_ata.owner == ctx.accounts.vault.key(),
+            VeilpayError::InvalidVaultAuthority
+        );
+        require!(
+            ctx.accounts.vault_ata.amount >= pending.amount,
+            VeilpayError::InsufficientVaultBalance
+        );
+
+        let bump_seed = [ctx.accounts.vault.bump];
+        let mint_key = ctx.accounts.mint.key();
+        let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
+        let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.vault_ata.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.destination_ata.to_account_info(),
+            authority: ctx.accounts.vault.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts,
+            signer_seeds,
+        );
+        token_interface::transfer_checked(cpi_ctx, pending.amount, ctx.accounts.mint.decimals)?;
+        Ok(())
+    }
+
+    /// Tops up `owner`'s fee escrow, creating it on first use. Anyone may fund it; only
+    /// the owner can approve paying a relayer from it or take the balance back.
+    pub fn fund_fee_escrow(
+        ctx: Context<FundFeeEscrow>,
+        owner: Pubkey,
+        lamports: u64,
+    ) -> Result<()> {
+        let escrow = &mut ctx.accounts.fee_escrow;
+        escrow.owner = owner;
+        escrow.bump = ctx.bumps.fee_escrow;
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            anchor_lang::system_program::Transfer {
+                from: ctx.accounts.funder.to_account_info(),
+                to: ctx.accounts.fee_escrow.to_account_info(),
+            },
+        );
+        anchor_lang::system_program::transfer(cpi_ctx, lamports)
+    }
+
+    pub fn withdraw_fee_escrow(ctx: Context<WithdrawFeeEscrow>, lamports: u64) -> Result<()> {
+        require!(
+            lamports <= fee_escrow_available(&ctx.accounts.fee_escrow)?,
+            VeilpayError::InsufficientFeeEscrow
+        );
+        ctx.accounts.fee_escrow.sub_lamports(lamports)?;
+        ctx.accounts.owner.add_lamports(lamports)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::INIT_SPACE,
+        seeds = [b"config", crate::ID.as_ref()],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    /// CHECK: may still hold an older `Config` layout; `migrate_config` decodes it.
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump, owner = crate::ID)]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterMint<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVkRegistry<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + VkRegistry::INIT_SPACE,
+        seeds = [b"vk_registry"],
+        bump
+    )]
+    pub vk_registry: Account<'info, VkRegistry>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterVkEntry<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Account<'info, VkRegistry>,
+    pub verifier_key: Account<'info, verifier::VerifierKey>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(new_max: u16)]
+pub struct ResizeVkRegistry<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"vk_registry"],
+        bump = vk_registry.bump,
+        realloc = vk_registry_space(new_max as usize),
+        realloc::payer = admin,
+        realloc::zero = false
+    )]
+    pub vk_registry: Account<'info, VkRegistry>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetVkStatus<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Account<'info, VkRegistry>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeIdentityRegistry<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + IdentityRegistry::INIT_SPACE,
+        seeds = [b"identity_registry"],
+        bump
+    )]
+    pub identity_registry: Account<'info, IdentityRegistry>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateIdentityRegistry<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Account<'info, IdentityRegistry>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterIdentity<'info> {
+    #[account(mut, seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Account<'info, IdentityRegistry>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + IdentityMember::INIT_SPACE,
+        seeds = [b"identity_member", user.key().as_ref()],
+        bump
+    )]
+    pub identity_member: Account<'info, IdentityMember>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub user: Signer<'info>,
+    pub identity_admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chunk_index: u32)]
+pub struct InitializeMintState<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + VaultPool::INIT_SPACE,
+        seeds = [b"vault", mint.key().as_ref()],
+        bump
+    )]
+    pub vault: Box<Account<'info, VaultPool>>,
+    /// CHECK: The vault's associated token account, which may not exist yet; checked
+    /// by `check_pool_vault_ata`.
+    pub vault_ata: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + ShieldedState::INIT_SPACE,
+        seeds = [b"shielded", mint.key().as_ref()],
+        bump
+    )]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + NullifierSet::INIT_SPACE,
+        seeds = [b"nullifier_set", mint.key().as_ref(), chunk_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTree<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+#[instruction(chunk_index: u32)]
+pub struct InitializeNullifierChunk<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + NullifierSet::INIT_SPACE,
+        seeds = [b"nullifier_set", mint.key().as_ref(), chunk_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(chunk_index: u32)]
+pub struct EnsureNullifierChunk<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + NullifierSet::INIT_SPACE,
+        seeds = [b"nullifier_set", mint.key().as_ref(), chunk_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateShieldedState<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct AdminClearNullifierBit<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateVault<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureFees<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub fee_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptFeeAuthority<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub new_fee_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub new_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawProtocolFees<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(mut)]
+    pub fee_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMintState<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, close = admin, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Box<Account<'info, VaultPool>>,
+    /// CHECK: The pool's recorded token account, which may never have been created;
+    /// checked for a zero balance before it is closed.
+    #[account(mut, address = vault.vault_ata)]
+    pub vault_ata: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"shielded", mint.key().as_ref()],
+        bump = shielded_state.bump
+    )]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(
+        mut,
+        close = admin,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetConfig<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct CheckNullifier<'info> {
+    #[account(
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct NullifierChunkFor<'info> {
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct GetValidRoots<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct EmitHealth<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(address = vault.vault_ata)]
+    pub vault_ata: InterfaceAccount<'info, TokenAccount>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    /// Created on the first deposit into a pool whose vault account does not exist yet,
+    /// paid by the depositor.
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(seeds = [b"identity_member", user.key().as_ref()], bump = identity_member.bump)]
+    pub identity_member: Account<'info, IdentityMember>,
+    #[account(mut)]
+    pub user_ata: InterfaceAccount<'info, TokenAccount>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    /// CHECK: Only required with a deposit proof; key checked against the configured verifier.
+    #[account(executable)]
+    pub verifier_program: Option<UncheckedAccount<'info>>,
+    /// CHECK: Only required with a deposit proof; owner and registry binding checked at runtime.
+    pub verifier_key: Option<UncheckedAccount<'info>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InternalTransfer<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump,
+        constraint = nullifier_set.mint == mint.key() @ VeilpayError::NullifierChunkMismatch
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    /// CHECK: Must match the mint's verifier override or the configured verifier program.
+    #[account(executable)]
+    pub verifier_program: UncheckedAccount<'info>,
+    /// CHECK: Must be owned by the selected verifier program, which validates it.
+    pub verifier_key: UncheckedAccount<'info>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct InternalTransferWithProof<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump,
+        constraint = nullifier_set.mint == mint.key() @ VeilpayError::NullifierChunkMismatch
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(
+        mut,
+        close = payer,
+        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
+        bump = proof_account.bump
+    )]
+    pub proof_account: Account<'info, ProofAccount>,
+    /// CHECK: Must match the mint's verifier override or the configured verifier program.
+    #[account(executable)]
+    pub verifier_program: UncheckedAccount<'info>,
+    /// CHECK: Must be owned by the selected verifier program, which validates it.
+    pub verifier_key: UncheckedAccount<'info>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+#[instruction(args: StoreProofArgs)]
+pub struct StoreProof<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + ProofAccount::INIT_SPACE,
+        seeds = [b"proof", mint.key().as_ref(), args.nonce.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proof_account: Account<'info, ProofAccount>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PrecheckWithdraw<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    /// CHECK: Only compared against the VK registry entry for the proof's circuit.
+    pub verifier_key: UncheckedAccount<'info>,
+    /// CHECK: Only compared against the stored proof's recipient.
+    pub recipient: UncheckedAccount<'info>,
+    /// CHECK: Only compared against the stored proof's destination.
+    pub destination_ata: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
+        bump = proof_account.bump
+    )]
+    pub proof_account: Option<Account<'info, ProofAccount>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct ExternalTransfer<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump,
+        constraint = nullifier_set.mint == mint.key() @ VeilpayError::NullifierChunkMismatch
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    /// CHECK: Validated in instruction when needed.
+    #[account(mut)]
+    pub destination_ata: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub recipient: SystemAccount<'info>,
+    /// CHECK: PDA signer for temporary WSOL ownership.
+    #[account(
+        seeds = [
+            b"temp_wsol",
+            recipient.key().as_ref(),
+            vault.nonce.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub temp_authority: UncheckedAccount<'info>,
+    /// CHECK: Created/validated at runtime when SOL delivery is requested.
+    #[account(mut)]
+    pub temp_wsol_ata: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub relayer_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// Must be a `fee_authority` or vault-owned fee account for the mint when a protocol
+    /// fee is due.
+    #[account(mut)]
+    pub protocol_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// CHECK: Must match the mint's verifier override or the configured verifier program.
+    #[account(executable)]
+    pub verifier_program: UncheckedAccount<'info>,
+    /// CHECK: Must be owned by the selected verifier program, which validates it.
+    pub verifier_key: UncheckedAccount<'info>,
+    /// CHECK: Used instead of `verifier_key` when the args select `PROOF_SYSTEM_ALT`;
+    /// checked the same way.
+    pub verifier_key_alt: Option<UncheckedAccount<'info>>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    /// The recipient's escrow, required when `relayer_fee_lamports` is non-zero.
+    #[account(
+        mut,
+        seeds = [b"fee_escrow", recipient.key().as_ref()],
+        bump = fee_escrow.bump
+    )]
+    pub fee_escrow: Option<Account<'info, FeeEscrow>>,
+}
+
+#[derive(Accounts)]
+pub struct ExternalTransferWithProof<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump,
+        constraint = nullifier_set.mint == mint.key() @ VeilpayError::NullifierChunkMismatch
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(
+        mut,
+        close = payer,
+        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
+        bump = proof_account.bump
+    )]
+    pub proof_account: Account<'info, ProofAccount>,
+    /// CHECK: Validated in instruction when needed.
+    #[account(mut)]
+    pub destination_ata: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub recipient: SystemAccount<'info>,
+    /// CHECK: PDA signer for temporary WSOL ownership.
+    #[account(
+        seeds = [
+            b"temp_wsol",
+            recipient.key().as_ref(),
+            vault.nonce.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub temp_authority: UncheckedAccount<'info>,
+    /// CHECK: Created/validated at runtime when SOL delivery is requested.
+    #[account(mut)]
+    pub temp_wsol_ata: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub relayer_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// Must be a `fee_authority` or vault-owned fee account for the mint when a protocol
+    /// fee is due.
+    #[account(mut)]
+    pub protocol_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// CHECK: Must match the mint's verifier override or the configured verifier program.
+    #[account(executable)]
+    pub verifier_program: UncheckedAccount<'info>,
+    /// CHECK: Must be owned by the selected verifier program, which validates it.
+    pub verifier_key: UncheckedAccount<'info>,
+    /// CHECK: Used instead of `verifier_key` when the args select `PROOF_SYSTEM_ALT`;
+    /// checked the same way.
+    pub verifier_key_alt: Option<UncheckedAccount<'info>>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    /// The recipient's escrow, required when `relayer_fee_lamports` is non-zero.
+    #[account(
+        mut,
+        seeds = [b"fee_escrow", recipient.key().as_ref()],
+        bump = fee_escrow.bump
+    )]
+    pub fee_escrow: Option<Account<'info, FeeEscrow>>,
+}
+
+#[derive(Accounts)]
+pub struct QueueWithdrawal<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
+    pub shielded_state: Box<Account<'info, ShieldedState>>,
+    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
+    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
+    #[account(
+        mut,
+        seeds = [
+            b"nullifier_set",
+            mint.key().as_ref(),
+            nullifier_set.chunk_index.to_le_bytes().as_ref()
+        ],
+        bump = nullifier_set.bump,
+        constraint = nullifier_set.mint == mint.key() @ VeilpayError::NullifierChunkMismatch
+    )]
+    pub nullifier_set: Box<Account<'info, NullifierSet>>,
+    #[account(
+        mut,
+        close = payer,
+        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
+        bump = proof_account.bump
+    )]
+    pub proof_account: Account<'info, ProofAccount>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PendingWithdrawal::INIT_SPACE,
+        seeds = [b"withdrawal", mint.key().as_ref(), vault.nonce.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pending_withdrawal: Account<'info, PendingWithdrawal>,
+    #[account(mut)]
+    pub relayer_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// Must be a `fee_authority` or vault-owned fee account for the mint when a protocol
+    /// fee is due.
+    #[account(mut)]
+    pub protocol_fee_ata: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+    /// CHECK: Must match the mint's verifier override or the configured verifier program.
+    #[account(executable)]
+    pub verifier_program: UncheckedAccount<'info>,
+    /// CHECK: Must be owned by the selected verifier program, which validates it.
+    pub verifier_key: UncheckedAccount<'info>,
+    #[account(seeds = [b"vk_registry"], bump = vk_registry.bump)]
+    pub vk_registry: Box<Account<'info, VkRegistry>>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimWithdrawal<'info> {
+    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
+    pub vault: Account<'info, VaultPool>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = vault,
+        associated_token::token_program = token_program
+    )]
+    pub vault_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [
+            b"withdrawal",
+            mint.key().as_ref(),
+            pending_withdrawal.nonce.to_le_bytes().as_ref()
+        ],
+        bump = pending_withdrawal.bump
+    )]
+    pub pending_withdrawal: Account<'info, PendingWithdrawal>,
+    #[account(mut)]
+    pub destination_ata: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub rent_payer: SystemAccount<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct FundFeeEscrow<'info> {
+    #[account(
+        init_if_needed,
+        payer = funder,
+        space = 8 + FeeEscrow::INIT_SPACE,
+        seeds = [b"fee_escrow", owner.as_ref()],
+        bump
+    )]
+    pub fee_escrow: Account<'info, FeeEscrow>,
+    #[account(mut)]
+    pub funder: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFeeEscrow<'info> {
+    #[account(
+        mut,
+        seeds = [b"fee_escrow", owner.key().as_ref()],
+        bump = fee_escrow.bump,
+        has_one = owner
+    )]
+    pub fee_escrow: Account<'info, FeeEscrow>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InitializeConfigArgs {
+    pub fee_bps: u16,
+    pub relayer_fee_bps_max: u16,
+    pub relayer_fee_bps_min: u16,
+    pub vk_registry: Pubkey,
+    pub mint_allowlist: Vec<Pubkey>,
+    pub circuit_ids: Vec<u32>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct DepositArgs {
+    /// Debited from `user_ata`. Transfer-fee mints deliver less; the note and any
+    /// deposit proof must carry the amount the vault actually receives.
+    pub amount: u64,
+    /// The note encrypted for its owner, exactly `NOTE_CIPHERTEXT_BYTES` long. It is
+    /// not stored; the deposit's `NoteOutputEvent` carries it for view-key scanning.
+    pub ciphertext: Vec<u8>,
+    pub commitment: Vec<u8>,
+    pub new_root: Vec<u8>,
+    pub proof: Option<DepositProof>,
+}
+
+/// Deposit circuit proof. Public inputs are `[commitment, amount, identity_root,
+/// circuit_id]`, 32-byte big-endian fields like the spend layout.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct DepositProof {
+    pub proof: Vec<u8>,
+    pub public_inputs: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InternalTransferArgs {
+    pub proof: Vec<u8>,
+    pub public_inputs: Vec<u8>,
+    pub new_root: Vec<u8>,
+    pub output_ciphertexts: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InternalTransferWithProofArgs {
+    pub new_root: Vec<u8>,
+    pub output_ciphertexts: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct StoreProofArgs {
+    pub nonce: u64,
+    pub recipient: Pubkey,
+    pub destination_ata: Pubkey,
+    pub mint: Pubkey,
+    pub proof: Vec<u8>,
+    pub public_inputs: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ExternalTransferArgs {
+    pub amount: u64,
+    pub proof: Vec<u8>,
+    pub public_inputs: Vec<u8>,
+    pub relayer_fee_bps: u16,
+    /// Relayer fee in lamports, paid to `payer` from the recipient's fee escrow instead
+    /// of taking `relayer_fee_bps` of the tokens. The recipient must sign to approve it.
+    pub relayer_fee_lamports: u64,
+    pub new_root: Vec<u8>,
+    pub output_ciphertexts: Vec<u8>,
+    pub deliver_sol: bool,
+    /// `PROOF_SYSTEM_PRIMARY` verifies against `verifier_key`, `PROOF_SYSTEM_ALT`
+    /// against `verifier_key_alt`.
+    pub proof_system: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ExternalTransferWithProofArgs {
+    pub amount: u64,
+    pub relayer_fee_bps: u16,
+    /// Relayer fee in lamports, paid to `payer` from the recipient's fee escrow instead
+    /// of taking `relayer_fee_bps` of the tokens. The recipient must sign to approve it.
+    pub relayer_fee_lamports: u64,
+    pub new_root: Vec<u8>,
+    pub output_ciphertexts: Vec<u8>,
+    pub deliver_sol: bool,
+    /// `PROOF_SYSTEM_PRIMARY` verifies against `verifier_key`, `PROOF_SYSTEM_ALT`
+    /// against `verifier_key_alt`.
+    pub proof_system: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct QueueWithdrawalArgs {
+    pub amount: u64,
+    pub relayer_fee_bps: u16,
+    pub new_root: Vec<u8>,
+    pub output_ciphertexts: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PrecheckWithdrawArgs {
+    pub amount: u64,
+    pub relayer_fee_bps: u16,
+    pub public_inputs: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RegisterVkEntryArgs {
+    pub circuit_id: u32,
+    pub vk_hash: [u8; 32],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RegisterIdentityArgs {
+    pub commitment: Vec<u8>,
+    pub new_root: Vec<u8>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ConfigView {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+    pub fee_authority: Pubkey,
+    pub pending_fee_authority: Pubkey,
+    pub fee_bps: u16,
+    pub relayer_fee_bps_max: u16,
+    pub relayer_fee_bps_min: u16,
+    pub relayer_fee_abs_min: u64,
+    pub vk_registry: Pubkey,
+    pub verifier_program: Pubkey,
+    pub mint_allowlist_len: u32,
+    pub mint_allowlist_offset: u32,
+    pub mint_allowlist: Vec<Pubkey>,
+    pub circuit_ids: Vec<u32>,
+    pub paused: bool,
+    pub compact_events: bool,
+    pub require_deposit_proof: bool,
+    pub event_flags: u32,
+    pub withdraw_queue_threshold: u64,
+    pub withdraw_queue_delay_slots: u64,
+    pub root_max_age_slots: u64,
+    pub max_single_withdraw_bps: u16,
+    pub nullifier_chunks: u32,
+    pub version: u32,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NullifierChunkView {
+    pub chunk_index: u32,
+    /// Bit within the chunk's bitset, `0..NULLIFIER_BITS`.
+    pub bit_index: u16,
+    /// Address of the `NullifierSet` PDA for `chunk_index`.
+    pub nullifier_set: Pubkey,
+}
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+    pub fee_authority: Pubkey,
+    pub pending_fee_authority: Pubkey,
+    pub fee_bps: u16,
+    pub relayer_fee_bps_max: u16,
+    pub relayer_fee_bps_min: u16,
+    pub relayer_fee_abs_min: u64,
+    #[max_len(MAX_FEE_EXEMPT)]
+    pub fee_exempt: Vec<Pubkey>,
+    pub relayer_allowlist_enabled: bool,
+    #[max_len(MAX_RELAYERS)]
+    pub relayer_allowlist: Vec<Pubkey>,
+    pub vk_registry: Pubkey,
+    pub verifier_program: Pubkey,
+    #[max_len(MAX_ALLOWLIST)]
+    pub mint_allowlist: Vec<Pubkey>,
+    #[max_len(MAX_CIRCUITS)]
+    pub circuit_ids: Vec<u32>,
+    pub paused: bool,
+    pub compact_events: bool,
+    pub require_deposit_proof: bool,
+    pub event_flags: u32,
+    pub withdraw_queue_threshold: u64,
+    pub withdraw_queue_delay_slots: u64,
+    pub root_max_age_slots: u64,
+    pub max_single_withdraw_bps: u16,
+    pub nullifier_chunks: u32,
+    pub version: u32,
+    pub bump: u8,
+}
+
+/// `Config` as the first program version laid it out. Only `decode_config` reads it.
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+struct ConfigV1 {
+    admin: Pubkey,
+    fee_bps: u16,
+    relayer_fee_bps_max: u16,
+    vk_registry: Pubkey,
+    #[max_len(MAX_ALLOWLIST)]
+    mint_allowlist: Vec<Pubkey>,
+    #[max_len(MAX_CIRCUITS)]
+    circuit_ids: Vec<u32>,
+    paused: bool,
+    version: u32,
+    bump: u8,
+}
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct VaultPool {
+    pub vault_pda: Pubkey,
+    pub vault_ata: Pubkey,
+    pub mint: Pubkey,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub nonce: u64,
+    pub max_chunks: u32,
+    pub nullifier_chunks: u32,
+    pub verifier_program_override: Option<Pubkey>,
+    pub identity_policy: u8,
+    pub deposit_cap: u64,
+    pub per_withdraw_cap: u64,
+    pub min_deposit: u64,
+    pub paused: bool,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ShieldedState {
+    pub mint: Pubkey,
+    pub merkle_root: [u8; 32],
+    #[max_len(MAX_ROOT_HISTORY)]
+    pub root_history: Vec<[u8; 32]>,
+    pub root_history_index: u32,
+    #[max_len(MAX_ROOT_HISTORY)]
+    pub root_slots: Vec<u64>,
+    /// Roots kept before the oldest is overwritten; at most `MAX_ROOT_HISTORY`.
+    pub root_history_len: u32,
+    /// The root most recently overwritten, accepted for `ROOT_GRACE_SLOTS` more slots.
+    pub evicted_root: Option<[u8; 32]>,
+    pub evicted_root_slot: u64,
+    pub evicted_at_slot: u64,
+    pub commitment_count: u64,
+    /// Spend circuit the pool is pinned to, if any.
+    pub circuit_id: Option<u32>,
+    #[max_len(MAX_CIRCUITS)]
+    pub circuit_ids: Vec<u32>,
+    pub tree_depth: u8,
+    #[max_len(MAX_TREE_DEPTH)]
+    pub filled_subtrees: Vec<[u8; 32]>,
+    #[max_len(MAX_TREE_DEPTH)]
+    pub zero_hashes: Vec<[u8; 32]>,
+    /// Spend layout proofs for this pool are parsed with; zero means `SpendLayout::DEFAULT`.
+    pub max_inputs: u8,
+    pub max_outputs: u8,
+    pub version: u32,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct IdentityRegistry {
+    pub merkle_root: [u8; 32],
+    pub denylist_root: [u8; 32],
+    pub commitment_count: u64,
+    pub depth: u8,
+    pub identity_admin: Pubkey,
+    /// When false no spend's `identity_root` is checked.
+    pub identity_required: bool,
+    #[max_len(IDENTITY_TREE_DEPTH)]
+    pub filled_subtrees: Vec<[u8; 32]>,
+    #[max_len(IDENTITY_TREE_DEPTH)]
+    pub zero_hashes: Vec<[u8; 32]>,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct IdentityMember {
+    pub owner: Pubkey,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ProofAccount {
+    pub owner: Pubkey,
+    pub recipient: Pubkey,
+    pub destination_ata: Pubkey,
+    pub mint: Pubkey,
+    pub nonce: u64,
+    #[max_len(PROOF_BYTES_LEN)]
+    pub proof: Vec<u8>,
+    #[max_len(MAX_PUBLIC_INPUTS_BYTES_LEN)]
+    pub public_inputs: Vec<u8>,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct PendingWithdrawal {
+    pub mint: Pubkey,
+    pub recipient: Pubkey,
+    pub destination_ata: Pubkey,
+    pub rent_payer: Pubkey,
+    pub amount: u64,
+    pub ready_slot: u64,
+    pub nonce: u64,
+    pub bump: u8,
+}
+
+/// Lamports a recipient set aside to pay relayers in SOL. Everything above the
+/// rent-exempt minimum can be spent.
+#[account]
+#[derive(InitSpace)]
+pub struct FeeEscrow {
+    pub owner: Pubkey,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct NullifierSet {
+    pub mint: Pubkey,
+    pub chunk_index: u32,
+    pub chunk_count: u32,
+    pub bitset: [u8; NULLIFIER_BYTES],
+    pub count: u32,
+    pub bump: u8,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct VkRegistry {
+    #[max_len(MAX_VK_ENTRIES)]
+    pub entries: Vec<VkEntry>,
+    pub bump: u8,
+    /// Entries the account has room for; zero on registries created before
+    /// `resize_vk_registry`, which were sized for `MAX_VK_ENTRIES`.
+    pub capacity: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct VkEntry {
+    pub circuit_id: u32,
+    pub vk_account: Pubkey,
+    pub vk_hash: [u8; 32],
+    pub status: u8,
+}
+
+fn root_history_capacity(state: &ShieldedState) -> usize {
+    (state.root_history_len as usize).clamp(1, MAX_ROOT_HISTORY)
+}
+
+/// Records `new_root` in the history. With `max_age_slots == 0` the history is a
+/// FIFO ring; otherwise a full history evicts the entry recorded at the oldest slot.
+/// The overwritten root is kept aside for the grace window `root_known` allows.
+fn append_root(state: &mut ShieldedState, new_root: [u8; 32], slot: u64, max_age_slots: u64) {
+    let capacity = root_history_capacity(state);
+    if state.root_history.len() < capacity {
+        state.root_history.push(new_root);
+        state.root_slots.push(slot);
+    } else {
+        let idx = if max_age_slots > 0 {
+            state
+                .root_slots
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, recorded)| **recorded)
+                .map(|(idx, _)| idx)
+                .unwrap_or(0)
+        } else {
+            let idx = (state.root_history_index as usize) % capacity;
+            state.root_history_index = state.root_history_index.wrapping_add(1);
+            idx
+        };
+        state.evicted_root = Some(state.root_history[idx]);
+        state.evicted_at_slot = slot;
+        state.root_history[idx] = new_root;
+        if let Some(recorded) = state.root_slots.get_mut(idx) {
+            state.evicted_root_slot = *recorded;
+            *recorded = slot;
+        }
+    }
+    state.merkle_root = new_root;
+}
+
+/// The history oldest first. A FIFO ring that has wrapped starts at `root_history_index`;
+/// age-based eviction overwrites whichever slot is oldest, so entries are then ordered
+/// by the slot they were recorded at.
+fn root_history_in_order(state: &ShieldedState) -> Vec<([u8; 32], Option<u64>)> {
+    let len = state.root_history.len();
+    let start = if len == root_history_capacity(state) {
+        state.root_history_index as usize % len
+    } else {
+        0
+    };
+    let mut entries: Vec<([u8; 32], Option<u64>)> = (0..len)
+        .map(|offset| {
+            let idx = (start + offset) % len;
+            (state.root_history[idx], state.root_slots.get(idx).copied())
+        })
+        .collect();
+    entries.sort_by_key(|(_, recorded)| *recorded);
+    entries
+}
+
+/// `merkle_root` followed by the other roots `root_known` accepts at `slot`, oldest first.
+/// A root still in its grace window counts as the oldest.
+fn valid_roots(state: &ShieldedState, slot: u64, max_age_slots: u64) -> Vec<[u8; 32]> {
+    let mut roots = vec![state.merkle_root];
+    roots.extend(
+        root_history_in_order(state)
+            .into_iter()
+            .filter(|(root, recorded)| {
+                *root != state.merkle_root
+                    && recorded
+                        .is_some_and(|recorded| root_within_age(recorded, slot, max_age_slots))
+            })
+            .map(|(root, _)| root),
+    );
+    if let Some(evicted) = evicted_root_in_grace(state, slot, max_age_slots) {
+        if !roots.contains(&evicted) {
+            roots.insert(1, evicted);
+        }
+    }
+    roots
+}
+
+/// Appends `leaves` to the incremental tree from leaf index `commitment_count` on,
+/// requires the result to equal the root the caller claimed and advances
+/// `commitment_count`. A batch that would not fit in `2^tree_depth` leaves fails with
+/// `TreeFull` before any leaf is written.
+fn append_commitments(state: &mut ShieldedState, leaves: &[[u8; 32]], claimed_root: [u8; 32]) -> Result<()> {
+    let count = state
+        .commitment_count
+        .checked_add(leaves.len() as u64)
+        .ok_or(VeilpayError::MathOverflow)?;
+    require!(
+        count <= 1u64 << state.tree_depth,
+        VeilpayError::TreeFull
+    );
+    let mut root = state.merkle_root;
+    for (offset, leaf) in leaves.iter().enumerate() {
+        let index = state
+            .commitment_count
+            .checked_add(offset as u64)
+            .ok_or(VeilpayError::MathOverflow)?;
+        root = append_leaf(state, index, *leaf)?;
+    }
+    require!(root == claimed_root, VeilpayError::NewRootMismatch);
+    state.commitment_count = count;
+    Ok(())
+}
+
+fn append_leaf(state: &mut ShieldedState, index: u64, leaf: [u8; 32]) -> Result<[u8; 32]> {
+    frontier_append(&mut state.filled_subtrees, &state.zero_hashes, index, leaf)
+}
+
+/// Hashes `leaf` up from position `index`, parking it in `filled_subtrees` at every
+/// level where it is a left child, and returns the new root. The tree depth is the
+/// frontier length.
+fn frontier_append(
+    filled_subtrees: &mut [[u8; 32]],
+    zero_hashes: &[[u8; 32]],
+    index: u64,
+    leaf: [u8; 32],
+) -> Result<[u8; 32]> {
+    let depth = filled_subtrees.len();
+    require!(index >> depth == 0, VeilpayError::TreeFull);
+    let mut position = index;
+    let mut node = leaf;
+    for level in 0..depth {
+        node = if position & 1 == 0 {
+            filled_subtrees[level] = node;
+            poseidon_pair(&node, &zero_hashes[level])?
+        } else {
+            poseidon_pair(&filled_subtrees[level], &node)?
+        };
+        position >>= 1;
+    }
+    Ok(node)
+}
+
+/// Entry `i` is the root of an all-zero subtree of height `i`; also returns the empty
+/// root of the whole tree, which for depth 20 equals `ZERO_ROOT`.
+fn empty_subtrees(depth: u8) -> Result<(Vec<[u8; 32]>, [u8; 32])> {
+    let mut zero = [0u8; 32];
+    let mut zero_hashes = Vec::with_capacity(depth as usize);
+    for _ in 0..depth {
+        zero_hashes.push(zero);
+        zero = poseidon_pair(&zero, &zero)?;
+    }
+    Ok((zero_hashes, zero))
+}
+
+/// Resets the incremental tree to an empty tree of `depth` levels.
+fn init_tree(state: &mut ShieldedState, depth: u8) -> Result<()> {
+    require!(
+        depth > 0 && depth as usize <= MAX_TREE_DEPTH,
+        VeilpayError::InvalidTreeDepth
+    );
+    let (zero_hashes, empty_root) = empty_subtrees(depth)?;
+    state.tree_depth = depth;
+    state.filled_subtrees = zero_hashes.clone();
+    state.zero_hashes = zero_hashes;
+    state.merkle_root = empty_root;
+    state.root_history = Vec::new();
+    state.root_history_index = 0;
+    state.root_slots = Vec::new();
+    state.evicted_root = None;
+    Ok(())
+}
+
+fn poseidon_pair(left: &[u8; 32], right: &[u8; 32]) -> Result<[u8; 32]> {
+    let hash = hashv(Parameters::Bn254X5, Endianness::BigEndian, &[left, right])
+        .map_err(|_| error!(VeilpayError::PoseidonFailed))?;
+    Ok(hash.to_bytes())
+}
+
+fn to_fixed_32(bytes: &[u8]) -> Result<[u8; 32]> {
+    require!(bytes.len() == 32, VeilpayError::InvalidByteLength);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(bytes);
+    Ok(out)
+}
+
+fn to_fixed_128(bytes: &[u8]) -> Result<[u8; 128]> {
+    require!(bytes.len() == 128, VeilpayError::InvalidByteLength);
+    let mut out = [0u8; 128];
+    out.copy_from_slice(bytes);
+    Ok(out)
+}
+
+fn parse_output_ciphertexts(
+    bytes: &[u8],
+    output_enabled: [u8; NOTE_OUTPUTS],
+) -> Result<[[u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS]> {
+    if bytes.is_empty() {
+        return Ok([[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS]);
+    }
+    if bytes.len() == NOTE_CIPHERTEXT_BYTES {
+        let mut out = [[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS];
+        if output_enabled[0] == 1 && output_enabled[1] == 0 {
+            out[0].copy_from_slice(bytes);
+            return Ok(out);
+        }
+        if output_enabled[0] == 0 && output_enabled[1] == 1 {
+            out[1].copy_from_slice(bytes);
+            return Ok(out);
+        }
+        return Err(error!(VeilpayError::InvalidByteLength));
+    }
+    require!(
+        bytes.len() == NOTE_OUTPUT_BYTES,
+        VeilpayError::InvalidByteLength
+    );
+    let mut out = [[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS];
+    out[0].copy_from_slice(&bytes[..NOTE_CIPHERTEXT_BYTES]);
+    out[1].copy_from_slice(&bytes[NOTE_CIPHERTEXT_BYTES..NOTE_OUTPUT_BYTES]);
+    Ok(out)
+}
+
+#[event]
+pub struct NoteOutputEvent {
+    pub mint: Pubkey,
+    pub leaf_index: u64,
+    pub commitment: [u8; 32],
+    pub ciphertext: [u8; NOTE_CIPHERTEXT_BYTES],
+    pub kind: u8,
+}
+
+#[repr(u8)]
+pub enum NoteOutputKind {
+    Deposit = 0,
+    Internal = 1,
+    External = 2,
+}
+
+#[event]
+pub struct TransferEvent {
+    pub mint: Pubkey,
+    pub kind: u8,
+    pub amount: u64,
+    pub fee_amount: u64,
+    pub circuit_id: u32,
+}
+
+#[repr(u8)]
+pub enum TransferKind {
+    Internal = 0,
+    External = 1,
+    Queued = 2,
+}
+
+/// Every deposit, with the tree state right after its commitment was appended.
+#[event]
+pub struct DepositEvent {
+    pub mint: Pubkey,
+    pub commitment: [u8; 32],
+    pub new_root: [u8; 32],
+    pub amount: u64,
+    pub commitment_count: u64,
+}
+
+/// Every external or queued withdrawal. `nullifiers` always has `MAX_INPUTS` entries;
+/// unused slots are zero so consumers can filter them out.
+#[event]
+pub struct WithdrawEvent {
+    pub mint: Pubkey,
+    pub nullifiers: [[u8; 32]; MAX_INPUTS],
+    pub amount: u64,
+    pub fee_amount: u64,
+    pub recipient: Pubkey,
+}
+
+/// Tree state after any spend: the current root and how many output notes it
+/// appended (zero for a withdrawal without change).
+#[event]
+pub struct ShieldedTransferEvent {
+    pub mint: Pubkey,
+    pub new_root: [u8; 32],
+    pub output_count: u8,
+    pub internal: bool,
+}
+
+#[event]
+pub struct HealthEvent {
+    pub mint: Pubkey,
+    pub paused: bool,
+    pub vault_balance: u64,
+    pub liabilities: u64,
+    pub solvent: bool,
+    pub active_circuits: u32,
+    pub identity_count: u64,
+    pub identity_capacity: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PausedEvent {
+    pub paused: bool,
+    pub admin: Pubkey,
+    pub slot: u64,
+}
+
+#[event]
+pub struct VaultPausedEvent {
+    pub mint: Pubkey,
+    pub paused: bool,
+    pub admin: Pubkey,
+    pub slot: u64,
+}
+
+#[event]
+pub struct ConfigMigratedEvent {
+    pub from_version: u32,
+    pub to_version: u32,
+}
+
+#[event]
+pub struct AdminChangedEvent {
+    pub old_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[event]
+pub struct ProtocolFeesWithdrawnEvent {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub destination: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct MintStateClosedEvent {
+    pub mint: Pubkey,
+    pub chunk_index: u32,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct MissingChunkEvent {
+    pub mint: Pubkey,
+    pub chunk_index: u32,
+}
+
+/// One per real nullifier a spend marks; zero-padded slots are skipped.
+#[event]
+pub struct NullifierSpentEvent {
+    pub mint: Pubkey,
+    pub chunk_index: u32,
+    pub nullifier: [u8; 32],
+}
+
+#[event]
+pub struct NullifierClearedEvent {
+    pub mint: Pubkey,
+    pub chunk_index: u32,
+    pub bit_index: u16,
+    pub nullifier: [u8; 32],
+    pub admin: Pubkey,
+}
+
+/// `passed` has bit `check as u8` set for every invariant that held.
+#[event]
+pub struct WithdrawPrecheckEvent {
+    pub mint: Pubkey,
+    pub passed: u32,
+    pub failures: Vec<WithdrawCheckFailure>,
+}
+
+/// Withdraw invariants in the order the withdraw instructions enforce them.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawCheck {
+    Amount,
+    OutputFlags,
+    Circuit,
+    VkBinding,
+    IdentityRoot,
+    Root,
+    SlotWindow,
+    ValueConservation,
+    Fee,
+    RelayerFeeFloor,
+    Recipient,
+    Domain,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawCheckFailure {
+    pub check: WithdrawCheck,
+    pub error_code: u32,
+}
+
+/// Single per-instruction event emitted instead of the typed events when
+/// `Config.compact_events` is set. The mint is hoisted out of each entry; indexers
+/// decode `events` with the `CompactEvent` schema and re-attach `mint`.
+#[event]
+pub struct CompactEvents {
+    pub version: u8,
+    pub mint: Pubkey,
+    pub events: Vec<CompactEvent>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum CompactEvent {
+    NoteOutput {
+        leaf_index: u64,
+        commitment: [u8; 32],
+        ciphertext: [u8; NOTE_CIPHERTEXT_BYTES],
+        kind: u8,
+    },
+    Transfer {
+        kind: u8,
+        amount: u64,
+        fee_amount: u64,
+        circuit_id: u32,
+    },
+    Deposit {
+        commitment: [u8; 32],
+        new_root: [u8; 32],
+        amount: u64,
+        commitment_count: u64,
+    },
+    Withdraw {
+        nullifiers: [[u8; 32]; MAX_INPUTS],
+        amount: u64,
+        fee_amount: u64,
+        recipient: Pubkey,
+    },
+    ShieldedTransfer {
+        new_root: [u8; 32],
+        output_count: u8,
+        internal: bool,
+    },
+}
+
+struct EventLog {
+    compact: bool,
+    flags: u32,
+    mint: Pubkey,
+    events: Vec<CompactEvent>,
+}
+
+impl EventLog {
+    fn new(config: &Config, mint: Pubkey) -> Self {
+        Self {
+            compact: config.compact_events,
+            flags: config.event_flags,
+            mint,
+            events: Vec::new(),
+        }
+    }
+
+    fn note_output(
+        &mut self,
+        leaf_index: u64,
+        commitment: [u8; 32],
+        ciphertext: [u8; NOTE_CIPHERTEXT_BYTES],
+        kind: NoteOutputKind,
+    ) {
+        if self.flags & EVENT_NOTE_OUTPUT == 0 {
+            return;
+        }
+        if self.compact {
+            self.events.push(CompactEvent::NoteOutput {
+                leaf_index,
+                commitment,
+                ciphertext,
+                kind: kind as u8,
+            });
+        } else {
+            emit!(NoteOutputEvent {
+                mint: self.mint,
+                leaf_index,
+                commitment,
+                ciphertext,
+                kind: kind as u8,
+            });
+        }
+    }
+
+    fn transfer(&mut self, kind: TransferKind, amount: u64, fee_amount: u64, circuit_id: u32) {
+        if self.flags & EVENT_TRANSFER == 0 {
+            return;
+        }
+        if self.compact {
+            self.events.push(CompactEvent::Transfer {
+                kind: kind as u8,
+                amount,
+                fee_amount,
+                circuit_id,
+            });
+        } else {
+            emit!(TransferEvent {
+                mint: self.mint,
+                kind: kind as u8,
+                amount,
+                fee_amount,
+                circuit_id,
+            });
+        }
+    }
+
+    fn deposit(&mut self, commitment: [u8; 32], new_root: [u8; 32], amount: u64, commitment_count: u64) {
+        if self.flags & EVENT_DEPOSIT == 0 {
+            return;
+        }
+        if self.compact {
+            self.events.push(CompactEvent::Deposit {
+                commitment,
+                new_root,
+                amount,
+                commitment_count,
+            });
+        } else {
+            emit!(DepositEvent {
+                mint: self.mint,
+                commitment,
+                new_root,
+                amount,
+                commitment_count,
+            });
+        }
+    }
+
+    fn withdraw(
+        &mut self,
+        nullifiers: [[u8; 32]; MAX_INPUTS],
+        amount: u64,
+        fee_amount: u64,
+        recipient: Pubkey,
+    ) {
+        if self.flags & EVENT_WITHDRAW == 0 {
+            return;
+        }
+        if self.compact {
+            self.events.push(CompactEvent::Withdraw {
+                nullifiers,
+                amount,
+                fee_amount,
+                recipient,
+            });
+        } else {
+            emit!(WithdrawEvent {
+                mint: self.mint,
+                nullifiers,
+                amount,
+                fee_amount,
+                recipient,
+            });
+        }
+    }
+
+    fn shielded_transfer(&mut self, new_root: [u8; 32], output_count: u8, internal: bool) {
+        if self.flags & EVENT_TRANSFER == 0 {
+            return;
+        }
+        if self.compact {
+            self.events.push(CompactEvent::ShieldedTransfer {
+                new_root,
+                output_count,
+                internal,
+            });
+        } else {
+            emit!(ShieldedTransferEvent {
+                mint: self.mint,
+                new_root,
+                output_count,
+                internal,
+            });
+        }
+    }
+
+    fn flush(self) {
+        if self.compact && !self.events.is_empty() {
+            emit!(CompactEvents {
+                version: COMPACT_EVENTS_VERSION,
+                mint: self.mint,
+                events: self.events,
+            });
+        }
+    }
+}
+
+/// The layout stored on the pool. Pools created before it was configurable hold zeros
+/// and keep the default.
+fn spend_layout(shielded: &ShieldedState) -> SpendLayout {
+    if shielded.max_inputs == 0 {
+        return SpendLayout::DEFAULT;
+    }
+    SpendLayout {
+        inputs: shielded.max_inputs as usize,
+        outputs: shielded.max_outputs as usize,
+    }
+}
+
+/// Decodes `bytes` under `layout` with the layout shared with clients. Nullifier and
+/// output slots past the layout's counts stay zero, so callers always see
+/// `MAX_INPUTS`/`MAX_OUTPUTS` entries.
+pub fn parse_public_inputs(bytes: &[u8], layout: SpendLayout) -> Result<ParsedPublicInputs> {
+    decode_public_inputs(bytes, layout).map_err(public_input_error)
+}
+
+fn public_input_error(err: DecodeError) -> Error {
+    match err {
+        DecodeError::Length | DecodeError::NonCanonicalInteger => {
+            error!(VeilpayError::InvalidPublicInputs)
+        }
+        DecodeError::NonCanonicalNullifier => error!(VeilpayError::NonCanonicalNullifier),
+        DecodeError::NonCanonicalField => error!(VeilpayError::NonCanonicalPublicInput),
+    }
+}
+
+/// Whether some selectable layout could produce `len` inputs. A pool still checks
+/// the exact length against its own layout when it parses them.
+fn public_inputs_len_supported(len: usize) -> bool {
+    (SpendLayout::SMALLEST.base_len()..=MAX_PUBLIC_INPUTS_LEN).contains(&len)
+}
+
+fn public_inputs_bytes_len_supported(len: usize) -> bool {
+    len.is_multiple_of(32) && public_inputs_len_supported(len / 32)
+}
+
+fn check_slot_window(parsed: &ParsedPublicInputs, slot: u64) -> Result<()> {
+    if let Some((min_slot, max_slot)) = parsed.slot_window {
+        require!(slot >= min_slot, VeilpayError::ProofNotYetValid);
+        require!(slot <= max_slot, VeilpayError::ProofExpired);
+    }
+    Ok(())
+}
+
+/// Redundant with the circuit's own balance constraint: a declared `value_in` caps what
+/// the spend may pay out, so a circuit regression that mints value is still rejected.
+fn check_value_conservation(parsed: &ParsedPublicInputs) -> Result<()> {
+    if let Some(value_in) = parsed.value_in {
+        let value_out = parsed
+            .amount_out
+            .checked_add(parsed.fee_amount)
+            .ok_or(VeilpayError::MathOverflow)?;
+        require!(value_out <= value_in, VeilpayError::ValueConservationViolation);
+    }
+    Ok(())
+}
+
+fn parse_u64(bytes: &[u8; 32]) -> Result<u64> {
+    decode_u64(bytes).map_err(public_input_error)
+}
+
+fn parse_u32(bytes: &[u8; 32]) -> Result<u32> {
+    decode_u32(bytes).map_err(public_input_error)
+}
+
+/// Marks a spend's nullifiers in two passes. The first resolves each one to its chunk
+/// of `vault`'s set, `primary` or the chunk PDA for the vault's mint found among
+/// `remaining` by address (so decoy chunks and other mints' sets with the same index
+/// are never touched), and checks it is unspent and not repeated within the spend.
+/// Only then is anything marked, so a missing chunk or a spent nullifier fails with
+/// every bitset unchanged. Each extra chunk is loaded once and written back after marking.
+fn mark_nullifiers<'info>(
+    primary: &mut Account<'info, NullifierSet>,
+    remaining: &'info [AccountInfo<'info>],
+    vault: &VaultPool,
+    nullifiers: &[[u8; 32]; MAX_INPUTS],
+) -> Result<()> {
+    require_keys_eq!(primary.mint, vault.mint, VeilpayError::NullifierChunkMismatch);
+    let mut loaded: Vec<Account<'info, NullifierSet>> = Vec::new();
+    // Chunk of each nullifier to mark: `None` for `primary`, else an index into `loaded`.
+    let mut pending: Vec<(Option<usize>, [u8; 32])> = Vec::with_capacity(MAX_INPUTS);
+    for nullifier in nullifiers {
+        if is_zero_32(nullifier) {
+            continue;
+        }
         require!(
-            parsed.identity_root == ctx.accounts.identity_registry.merkle_root,
-            VeilpayError::IdentityRootMismatch
+            is_canonical_scalar(nullifier),
+            VeilpayError::NonCanonicalNullifier
         );
         require!(
-            root_known(&ctx.accounts.shielded_state, parsed.root),
-            VeilpayError::UnknownRoot
+            pending.iter().all(|(_, seen)| seen != nullifier),
+            VeilpayError::NullifierAlreadyUsed
         );
-        let (net_amount, fee_amount) = split_relayer_fee(args.amount, args.relayer_fee_bps)?;
-        require!(fee_amount == parsed.fee_amount, VeilpayError::FeeMismatch);
-        mark_nullifiers(
-            &mut ctx.accounts.nullifier_set,
-            ctx.remaining_accounts,
-            &parsed.nullifiers,
-        )?;
-
-        let bump_seed = [ctx.accounts.vault.bump];
-        let mint_key = ctx.accounts.mint.key();
-        let vault_seeds: &[&[u8]] = &[b"vault", mint_key.as_ref(), &bump_seed];
-        let signer_seeds: &[&[&[u8]]] = &[vault_seeds];
-
-        if fee_amount > 0 {
-            let relayer_fee_ata = ctx
-                .accounts
-                .relayer_fee_ata
-                .as_ref()
-                .ok_or(VeilpayError::MissingRelayerFeeAccount)?;
-            require!(
-                relayer_fee_ata.mint == ctx.accounts.mint.key(),
-                VeilpayError::InvalidRelayerFeeAccount
-            );
-            let cpi_accounts = anchor_spl::token::Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: relayer_fee_ata.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                signer_seeds,
-            );
-            anchor_spl::token::transfer(cpi_ctx, fee_amount)?;
-        }
-
-        if args.deliver_sol {
-            require!(
-                ctx.accounts.mint.key() == anchor_spl::token::spl_token::native_mint::id(),
-                VeilpayError::UnsupportedSolDelivery
-            );
-            let expected_ata = associated_token::get_associated_token_address(
-                &ctx.accounts.temp_authority.key(),
-                &ctx.accounts.mint.key(),
-            );
-            require!(
-                ctx.accounts.temp_wsol_ata.key() == expected_ata,
-                VeilpayError::InvalidTempAccount
-            );
-            if ctx.accounts.temp_wsol_ata.data_is_empty() {
-                let cpi_accounts = associated_token::Create {
-                    payer: ctx.accounts.payer.to_account_info(),
-                    associated_token: ctx.accounts.temp_wsol_ata.to_account_info(),
-                    authority: ctx.accounts.temp_authority.to_account_info(),
-                    mint: ctx.accounts.mint.to_account_info(),
-                    system_program: ctx.accounts.system_program.to_account_info(),
-                    token_program: ctx.accounts.token_program.to_account_info(),
-                };
-                let cpi_ctx = CpiContext::new(
-                    ctx.accounts.associated_token_program.to_account_info(),
-                    cpi_accounts,
-                );
-                associated_token::create(cpi_ctx)?;
-            } else {
-                require!(
-                    ctx.accounts.temp_wsol_ata.owner == &ctx.accounts.token_program.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-                let temp_ata: TokenAccount =
-                    TokenAccount::try_deserialize(&mut &ctx.accounts.temp_wsol_ata.data.borrow()[..])?;
-                require!(
-                    temp_ata.mint == ctx.accounts.mint.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-                require!(
-                    temp_ata.owner == ctx.accounts.temp_authority.key(),
-                    VeilpayError::InvalidTempAccount
-                );
-            }
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: ctx.accounts.temp_wsol_ata.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                signer_seeds,
-            );
-            token::transfer(cpi_ctx, net_amount)?;
-            let temp_bump = ctx.bumps.temp_authority;
-            let recipient_key = ctx.accounts.recipient.key();
-            let vault_nonce_bytes = ctx.accounts.vault.nonce.to_le_bytes();
-            let temp_seeds: &[&[u8]] = &[
-                b"temp_wsol",
-                recipient_key.as_ref(),
-                vault_nonce_bytes.as_ref(),
-                &[temp_bump],
-            ];
-            let temp_signer: &[&[&[u8]]] = &[temp_seeds];
-            let cpi_accounts = CloseAccount {
-                account: ctx.accounts.temp_wsol_ata.to_account_info(),
-                destination: ctx.accounts.recipient.to_account_info(),
-                authority: ctx.accounts.temp_authority.to_account_info(),
-            };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                temp_signer,
-            );
-            token::close_account(cpi_ctx)?;
+        let (chunk_index, _) = nullifier_position(nullifier, vault.nullifier_chunks);
+        let target = if primary.chunk_index == chunk_index {
+            None
+        } else if let Some(idx) = loaded.iter().position(|set| set.chunk_index == chunk_index) {
+            Some(idx)
         } else {
-            require!(
-                ctx.accounts.destination_ata.owner == &ctx.accounts.token_program.key(),
-                VeilpayError::InvalidRecipientTokenAccount
-            );
-            let destination_ata: TokenAccount =
-                TokenAccount::try_deserialize(&mut &ctx.accounts.destination_ata.data.borrow()[..])?;
-            require!(
-                destination_ata.mint == ctx.accounts.mint.key(),
-                VeilpayError::InvalidRecipientTokenAccount
-            );
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.vault_ata.to_account_info(),
-                to: ctx.accounts.destination_ata.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
+            let address = nullifier_set_address(&vault.mint, chunk_index);
+            let Some(info) = remaining
+                .iter()
+                .find(|info| info.is_writable && *info.key == address)
+            else {
+                // The transaction fails anyway; the event tells the client which
+                // `ensure_nullifier_chunk` call (or extra account) it was missing.
+                msg!("missing nullifier chunk {}", chunk_index);
+                emit!(MissingChunkEvent {
+                    mint: vault.mint,
+                    chunk_index,
+                });
+                return err!(VeilpayError::MissingNullifierAccount);
             };
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts,
-                signer_seeds,
+            let set = Account::<NullifierSet>::try_from(info)?;
+            require!(
+                set.mint == vault.mint && set.chunk_index == chunk_index,
+                VeilpayError::NullifierChunkMismatch
             );
-            token::transfer(cpi_ctx, net_amount)?;
+            loaded.push(set);
+            Some(loaded.len() - 1)
+        };
+        let set: &NullifierSet = match target {
+            None => primary,
+            Some(idx) => &loaded[idx],
+        };
+        unspent_nullifier_bit(set, nullifier)?;
+        pending.push((target, *nullifier));
+    }
+    for (target, nullifier) in pending {
+        match target {
+            None => mark_nullifier(primary, nullifier)?,
+            Some(idx) => mark_nullifier(&mut loaded[idx], nullifier)?,
         }
+    }
+    for set in &loaded {
+        set.exit(&crate::ID)?;
+    }
+    Ok(())
+}
 
-        let vault = &mut ctx.accounts.vault;
-        vault.total_withdrawn = vault
-            .total_withdrawn
-            .checked_add(args.amount)
-            .ok_or(VeilpayError::MathOverflow)?;
-        vault.nonce = vault.nonce.saturating_add(1);
+fn nullifier_set_address(mint: &Pubkey, chunk_index: u32) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"nullifier_set", mint.as_ref(), chunk_index.to_le_bytes().as_ref()],
+        &crate::ID,
+    )
+    .0
+}
 
-        if parsed.output_enabled[1] == 1 {
-            let new_root = to_fixed_32(&args.new_root)?;
-            let shielded = &mut ctx.accounts.shielded_state;
-            let leaf_index = shielded.commitment_count;
-            emit!(NoteOutputEvent {
-                mint: ctx.accounts.mint.key(),
-                leaf_index,
-                commitment: parsed.output_commitments[1],
-                ciphertext: output_ciphertexts[1],
-                kind: NoteOutputKind::External as u8,
-            });
-            shielded.commitment_count = shielded.commitment_count.saturating_add(1);
-            append_root(shielded, new_root);
-        }
-        Ok(())
-    }
+fn is_zero_32(value: &[u8; 32]) -> bool {
+    value.iter().all(|b| *b == 0)
 }
 
-#[derive(Accounts)]
-pub struct InitializeConfig<'info> {
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + Config::INIT_SPACE,
-        seeds = [b"config", crate::ID.as_ref()],
-        bump
-    )]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
+/// A key's big-endian value reduced mod r. Keys are uniform 256-bit values, so most sit
+/// above the modulus and cannot be exposed as a public input verbatim.
+pub fn pubkey_to_field(key: &Pubkey) -> [u8; 32] {
+    reduce_to_field(key.to_bytes())
 }
 
-#[derive(Accounts)]
-pub struct RegisterMint<'info> {
-    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    pub admin: Signer<'info>,
+/// Field element every proof against `mint`'s pool must expose as `domain`, so a proof
+/// made for another deployment or pool cannot be replayed here.
+pub fn domain_separator(mint: &Pubkey) -> [u8; 32] {
+    let digest = keccak::hashv(&[b"veilpay-domain", crate::ID.as_ref(), mint.as_ref()]);
+    reduce_to_field(digest.to_bytes())
 }
 
-#[derive(Accounts)]
-pub struct InitializeVkRegistry<'info> {
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + VkRegistry::INIT_SPACE,
-        seeds = [b"vk_registry"],
-        bump
-    )]
-    pub vk_registry: Account<'info, VkRegistry>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
+fn reduce_to_field(mut value: [u8; 32]) -> [u8; 32] {
+    while value >= SCALAR_FIELD_MODULUS {
+        let mut borrow = 0u16;
+        for i in (0..32).rev() {
+            let sub = SCALAR_FIELD_MODULUS[i] as u16 + borrow;
+            borrow = u16::from((value[i] as u16) < sub);
+            value[i] = (value[i] as u16 + (borrow << 8) - sub) as u8;
+        }
+    }
+    value
 }
 
-#[derive(Accounts)]
-pub struct InitializeIdentityRegistry<'info> {
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + IdentityRegistry::INIT_SPACE,
-        seeds = [b"identity_registry"],
-        bump
-    )]
-    pub identity_registry: Account<'info, IdentityRegistry>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
+fn root_within_age(recorded: u64, slot: u64, max_age_slots: u64) -> bool {
+    max_age_slots == 0 || slot.saturating_sub(recorded) <= max_age_slots
 }
 
-#[derive(Accounts)]
-pub struct RegisterIdentity<'info> {
-    #[account(mut, seeds = [b"identity_registry"], bump = identity_registry.bump)]
-    pub identity_registry: Account<'info, IdentityRegistry>,
-    #[account(
-        init_if_needed,
-        payer = payer,
-        space = 8 + IdentityMember::INIT_SPACE,
-        seeds = [b"identity_member", user.key().as_ref()],
-        bump
-    )]
-    pub identity_member: Account<'info, IdentityMember>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub user: Signer<'info>,
-    pub system_program: Program<'info, System>,
+/// The root `append_root` last overwrote, while `slot` is no more than
+/// `ROOT_GRACE_SLOTS` past the overwrite and the root has not aged out.
+fn evicted_root_in_grace(state: &ShieldedState, slot: u64, max_age_slots: u64) -> Option<[u8; 32]> {
+    state.evicted_root.filter(|_| {
+        slot.saturating_sub(state.evicted_at_slot) <= ROOT_GRACE_SLOTS
+            && root_within_age(state.evicted_root_slot, slot, max_age_slots)
+    })
 }
 
-#[derive(Accounts)]
-#[instruction(chunk_index: u32)]
-pub struct InitializeMintState<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + VaultPool::INIT_SPACE,
-        seeds = [b"vault", mint.key().as_ref()],
-        bump
-    )]
-    pub vault: Box<Account<'info, VaultPool>>,
-    #[account(mut)]
-    pub vault_ata: Account<'info, TokenAccount>,
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + ShieldedState::INIT_SPACE,
-        seeds = [b"shielded", mint.key().as_ref()],
-        bump
-    )]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + NullifierSet::INIT_SPACE,
-        seeds = [b"nullifier_set", mint.key().as_ref(), chunk_index.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub mint: Account<'info, Mint>,
-    pub system_program: Program<'info, System>,
+/// The current root is always accepted; historical roots older than
+/// `max_age_slots` are ignored when age-based expiry is enabled. The root the
+/// history last overwrote stays accepted through `ROOT_GRACE_SLOTS` more slots.
+fn root_known(state: &ShieldedState, root: [u8; 32], slot: u64, max_age_slots: u64) -> bool {
+    if state.merkle_root == root {
+        return true;
+    }
+    state
+        .root_history
+        .iter()
+        .zip(state.root_slots.iter())
+        .any(|(r, recorded)| *r == root && root_within_age(*recorded, slot, max_age_slots))
+        || evicted_root_in_grace(state, slot, max_age_slots) == Some(root)
+}
+
+/// The global pause is checked first, so it is the error reported when both are set.
+fn check_not_paused(config: &Config, vault: &VaultPool) -> Result<()> {
+    require!(!config.paused, VeilpayError::ProtocolPaused);
+    require!(!vault.paused, VeilpayError::VaultPaused);
+    Ok(())
+}
+
+/// Claims are not checked: the relayer that queued the withdrawal already was.
+fn check_relayer_allowed(config: &Config, relayer: &Pubkey) -> Result<()> {
+    require!(
+        !config.relayer_allowlist_enabled || config.relayer_allowlist.contains(relayer),
+        VeilpayError::RelayerNotAllowed
+    );
+    Ok(())
 }
 
-#[derive(Accounts)]
-#[instruction(chunk_index: u32)]
-pub struct InitializeNullifierChunk<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(
-        init,
-        payer = payer,
-        space = 8 + NullifierSet::INIT_SPACE,
-        seeds = [b"nullifier_set", mint.key().as_ref(), chunk_index.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub mint: Account<'info, Mint>,
-    pub system_program: Program<'info, System>,
+/// The global allowlist always applies; a non-empty pool list narrows it further.
+fn check_circuit_allowed(config: &Config, shielded: &ShieldedState, circuit_id: u32) -> Result<()> {
+    require!(
+        config.circuit_ids.contains(&circuit_id),
+        VeilpayError::CircuitNotAllowed
+    );
+    require!(
+        shielded.circuit_ids.is_empty() || shielded.circuit_ids.contains(&circuit_id),
+        VeilpayError::PoolCircuitNotAllowed
+    );
+    Ok(())
 }
-#[derive(Accounts)]
-pub struct ConfigureFees<'info> {
-    #[account(mut, seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    pub admin: Signer<'info>,
+
+/// Spends against a pinned pool must use its circuit. Deposit proofs run their own
+/// circuit and are not held to the pin.
+fn check_pool_circuit(shielded: &ShieldedState, circuit_id: u32) -> Result<()> {
+    if let Some(pinned) = shielded.circuit_id {
+        require!(pinned == circuit_id, VeilpayError::PoolCircuitMismatch);
+    }
+    Ok(())
 }
 
-#[derive(Accounts)]
-pub struct Deposit<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
-    pub vault: Account<'info, VaultPool>,
-    #[account(mut)]
-    pub vault_ata: Account<'info, TokenAccount>,
-    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    pub user: Signer<'info>,
-    #[account(seeds = [b"identity_member", user.key().as_ref()], bump = identity_member.bump)]
-    pub identity_member: Account<'info, IdentityMember>,
-    #[account(mut)]
-    pub user_ata: Account<'info, TokenAccount>,
-    pub mint: Account<'info, Mint>,
-    pub token_program: Program<'info, Token>,
+/// Applies each layout step between the stored version and `CONFIG_VERSION` in order
+/// and returns the version the config started at. Fields appended after a step are
+/// read from the zeroed tail of the account, so a step only has to replace zeros
+/// (or values the current program would refuse) with working defaults.
+fn migrate_config_fields(config: &mut Config) -> Result<u32> {
+    let from_version = config.version;
+    require!(
+        (1..=CONFIG_VERSION).contains(&from_version),
+        VeilpayError::UnsupportedConfigVersion
+    );
+    if config.version == 1 {
+        // v1 had none of these fields; `decode_config` leaves them at their defaults.
+        config.fee_authority = config.admin;
+        config.verifier_program = verifier::ID;
+        config.event_flags = EVENT_FLAGS_ALL;
+        config.nullifier_chunks = DEFAULT_NULLIFIER_CHUNKS;
+        config.version = 2;
+    }
+    Ok(from_version)
 }
 
-#[derive(Accounts)]
-pub struct InternalTransfer<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
-    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
-    #[account(mut)]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    pub verifier_program: Program<'info, verifier::program::Verifier>,
-    pub verifier_key: Account<'info, verifier::VerifierKey>,
-    pub mint: Account<'info, Mint>,
+/// Reads a config in whichever layout wrote it. A v1 account is exactly the size of
+/// `ConfigV1`, so its length tells the layouts apart; its newer fields come back at
+/// their defaults for the v1 step of `migrate_config_fields` to fill in.
+fn decode_config(data: &[u8]) -> Result<Config> {
+    if data.len() != 8 + ConfigV1::INIT_SPACE {
+        return Config::try_deserialize(&mut &data[..]);
+    }
+    require!(
+        data[..8] == *Config::DISCRIMINATOR,
+        ErrorCode::AccountDiscriminatorMismatch
+    );
+    let v1 = ConfigV1::deserialize(&mut &data[8..])
+        .map_err(|_| error!(ErrorCode::AccountDidNotDeserialize))?;
+    Ok(Config {
+        admin: v1.admin,
+        fee_bps: v1.fee_bps,
+        relayer_fee_bps_max: v1.relayer_fee_bps_max,
+        vk_registry: v1.vk_registry,
+        mint_allowlist: v1.mint_allowlist,
+        circuit_ids: v1.circuit_ids,
+        paused: v1.paused,
+        version: v1.version,
+        bump: v1.bump,
+        ..Config::default()
+    })
 }
 
-#[derive(Accounts)]
-pub struct InternalTransferWithProof<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
-    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
-    #[account(mut)]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    #[account(
-        mut,
-        close = payer,
-        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
-        bump = proof_account.bump
-    )]
-    pub proof_account: Account<'info, ProofAccount>,
-    pub verifier_program: Program<'info, verifier::program::Verifier>,
-    pub verifier_key: Account<'info, verifier::VerifierKey>,
-    pub mint: Account<'info, Mint>,
+/// Serializes the spend's pool accounts ahead of Anchor's exit. Token CPIs run after
+/// this, so anything they call into reads the nullifiers as marked, the withdrawal as
+/// counted and the change note as appended, never the pre-spend state.
+fn persist_spend_state(
+    vault: &Account<VaultPool>,
+    shielded: &Account<ShieldedState>,
+    nullifier_set: &Account<NullifierSet>,
+) -> Result<()> {
+    vault.exit(&crate::ID)?;
+    shielded.exit(&crate::ID)?;
+    nullifier_set.exit(&crate::ID)
 }
 
-#[derive(Accounts)]
-#[instruction(args: StoreProofArgs)]
-pub struct StoreProof<'info> {
-    #[account(
-        init,
-        payer = payer,
-        space = 8 + ProofAccount::INIT_SPACE,
-        seeds = [b"proof", mint.key().as_ref(), args.nonce.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub proof_account: Account<'info, ProofAccount>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub mint: Account<'info, Mint>,
-    pub system_program: Program<'info, System>,
+/// Account size for a registry holding `capacity` entries.
+fn vk_registry_space(capacity: usize) -> usize {
+    8 + VkRegistry::INIT_SPACE - MAX_VK_ENTRIES * VkEntry::INIT_SPACE
+        + capacity * VkEntry::INIT_SPACE
 }
 
-#[derive(Accounts)]
-pub struct ExternalTransfer<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
-    pub vault: Account<'info, VaultPool>,
-    #[account(mut)]
-    pub vault_ata: Box<Account<'info, TokenAccount>>,
-    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
-    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
-    #[account(mut)]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    /// CHECK: Validated in instruction when needed.
-    #[account(mut)]
-    pub destination_ata: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub recipient: SystemAccount<'info>,
-    /// CHECK: PDA signer for temporary WSOL ownership.
-    #[account(
-        seeds = [
-            b"temp_wsol",
-            recipient.key().as_ref(),
-            vault.nonce.to_le_bytes().as_ref()
-        ],
-        bump
-    )]
-    pub temp_authority: UncheckedAccount<'info>,
-    /// CHECK: Created/validated at runtime when SOL delivery is requested.
-    #[account(mut)]
-    pub temp_wsol_ata: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub relayer_fee_ata: Option<Box<Account<'info, TokenAccount>>>,
-    pub verifier_program: Program<'info, verifier::program::Verifier>,
-    pub verifier_key: Account<'info, verifier::VerifierKey>,
-    pub mint: Account<'info, Mint>,
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+fn vk_registry_capacity(registry: &VkRegistry) -> usize {
+    match registry.capacity {
+        0 => MAX_VK_ENTRIES,
+        capacity => capacity as usize,
+    }
 }
 
-#[derive(Accounts)]
-pub struct ExternalTransferWithProof<'info> {
-    #[account(seeds = [b"config", crate::ID.as_ref()], bump = config.bump)]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"vault", mint.key().as_ref()], bump = vault.bump)]
-    pub vault: Account<'info, VaultPool>,
-    #[account(mut)]
-    pub vault_ata: Box<Account<'info, TokenAccount>>,
-    #[account(mut, seeds = [b"shielded", mint.key().as_ref()], bump = shielded_state.bump)]
-    pub shielded_state: Box<Account<'info, ShieldedState>>,
-    #[account(seeds = [b"identity_registry"], bump = identity_registry.bump)]
-    pub identity_registry: Box<Account<'info, IdentityRegistry>>,
-    #[account(mut)]
-    pub nullifier_set: Box<Account<'info, NullifierSet>>,
-    #[account(
-        mut,
-        close = payer,
-        seeds = [b"proof", mint.key().as_ref(), proof_account.nonce.to_le_bytes().as_ref()],
-        bump = proof_account.bump
-    )]
-    pub proof_account: Account<'info, ProofAccount>,
-    /// CHECK: Validated in instruction when needed.
-    #[account(mut)]
-    pub destination_ata: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub recipient: SystemAccount<'info>,
-    /// CHECK: PDA signer for temporary WSOL ownership.
-    #[account(
-        seeds = [
-            b"temp_wsol",
-            recipient.key().as_ref(),
-            vault.nonce.to_le_bytes().as_ref()
-        ],
-        bump
-    )]
-    pub temp_authority: UncheckedAccount<'info>,
-    /// CHECK: Created/validated at runtime when SOL delivery is requested.
-    #[account(mut)]
-    pub temp_wsol_ata: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub relayer_fee_ata: Option<Box<Account<'info, TokenAccount>>>,
-    pub verifier_program: Program<'info, verifier::program::Verifier>,
-    pub verifier_key: Account<'info, verifier::VerifierKey>,
-    pub mint: Account<'info, Mint>,
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+/// Replaces the entry for the same circuit, or appends while the registry has room.
+fn upsert_vk_entry(registry: &mut VkRegistry, entry: VkEntry) -> Result<()> {
+    if let Some(existing) = registry
+        .entries
+        .iter_mut()
+        .find(|existing| existing.circuit_id == entry.circuit_id)
+    {
+        *existing = entry;
+    } else {
+        require!(
+            registry.entries.len() < vk_registry_capacity(registry),
+            VeilpayError::VkRegistryFull
+        );
+        registry.entries.push(entry);
+    }
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct InitializeConfigArgs {
-    pub fee_bps: u16,
-    pub relayer_fee_bps_max: u16,
-    pub vk_registry: Pubkey,
-    pub mint_allowlist: Vec<Pubkey>,
-    pub circuit_ids: Vec<u32>,
+fn set_vk_entry_status(registry: &mut VkRegistry, circuit_id: u32, status: u8) -> Result<()> {
+    require!(
+        status == VK_STATUS_ACTIVE || status == VK_STATUS_INACTIVE,
+        VeilpayError::InvalidVkStatus
+    );
+    let entry = registry
+        .entries
+        .iter_mut()
+        .find(|entry| entry.circuit_id == circuit_id)
+        .ok_or(VeilpayError::CircuitNotRegistered)?;
+    entry.status = status;
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct DepositArgs {
-    pub amount: u64,
-    pub ciphertext: Vec<u8>,
-    pub commitment: Vec<u8>,
-    pub new_root: Vec<u8>,
+/// The supplied verifier key must be the registry's active key for the proof's circuit,
+/// so a client cannot pick its own (e.g. mock) key. A circuit with no entry fails with
+/// `CircuitNotRegistered`, one whose entry points at another key with `VkCircuitMismatch`.
+fn check_vk_binding(registry: &VkRegistry, circuit_id: u32, verifier_key: Pubkey) -> Result<()> {
+    let entry = registry
+        .entries
+        .iter()
+        .find(|entry| entry.circuit_id == circuit_id)
+        .ok_or(VeilpayError::CircuitNotRegistered)?;
+    require_keys_eq!(entry.vk_account, verifier_key, VeilpayError::VkCircuitMismatch);
+    require!(entry.status == VK_STATUS_ACTIVE, VeilpayError::VkInactive);
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct InternalTransferArgs {
-    pub proof: Vec<u8>,
-    pub public_inputs: Vec<u8>,
-    pub new_root: Vec<u8>,
-    pub output_ciphertexts: Vec<u8>,
+/// Allowlist pools prove membership in the registry tree; denylist pools prove the
+/// spender is absent from the denylist tree, so the proof must commit to that root instead.
+fn check_identity_root(
+    vault: &VaultPool,
+    registry: &IdentityRegistry,
+    identity_root: [u8; 32],
+) -> Result<()> {
+    if !registry.identity_required {
+        return Ok(());
+    }
+    let expected = match vault.identity_policy {
+        // An empty denylist is a real state: nobody is denied.
+        IDENTITY_POLICY_DENYLIST => registry.denylist_root,
+        _ => {
+            // Nobody is a member of the empty tree; a proof against it could only open
+            // the zero leaf.
+            require!(
+                registry.merkle_root != ZERO_ROOT,
+                VeilpayError::IdentityRegistryEmpty
+            );
+            registry.merkle_root
+        }
+    };
+    require!(identity_root == expected, VeilpayError::IdentityRootMismatch);
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct InternalTransferWithProofArgs {
-    pub new_root: Vec<u8>,
-    pub output_ciphertexts: Vec<u8>,
+fn verify_deposit_proof(
+    accounts: &Deposit,
+    proof: &DepositProof,
+    commitment: [u8; 32],
+    amount: u64,
+) -> Result<()> {
+    require!(
+        proof.public_inputs.len() == DEPOSIT_PUBLIC_INPUTS_LEN * 32,
+        VeilpayError::InvalidPublicInputsLength
+    );
+    let verifier_program = accounts
+        .verifier_program
+        .as_ref()
+        .ok_or(VeilpayError::MissingVerifierAccounts)?;
+    let verifier_key = accounts
+        .verifier_key
+        .as_ref()
+        .ok_or(VeilpayError::MissingVerifierAccounts)?;
+    verify_groth16(
+        verifier_program_id(&accounts.config, &accounts.vault),
+        verifier_program,
+        verifier_key,
+        proof.proof.clone(),
+        proof.public_inputs.clone(),
+    )?;
+    let field = |index: usize| -> [u8; 32] {
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&proof.public_inputs[index * 32..(index + 1) * 32]);
+        out
+    };
+    require!(field(0) == commitment, VeilpayError::DepositCommitmentMismatch);
+    require!(parse_u64(&field(1))? == amount, VeilpayError::AmountMismatch);
+    let circuit_id = parse_u32(&field(3))?;
+    check_circuit_allowed(&accounts.config, &accounts.shielded_state, circuit_id)?;
+    check_vk_binding(&accounts.vk_registry, circuit_id, verifier_key.key())?;
+    check_identity_root(&accounts.vault, &accounts.identity_registry, field(2))
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct StoreProofArgs {
-    pub nonce: u64,
-    pub recipient: Pubkey,
-    pub destination_ata: Pubkey,
-    pub mint: Pubkey,
-    pub proof: Vec<u8>,
-    pub public_inputs: Vec<u8>,
+/// A pool's vault account is always the vault's associated token account, so every
+/// path that moves pool funds agrees on a single address. It may not exist yet, in
+/// which case `deposit` creates it on demand; otherwise it must be a token account.
+fn check_pool_vault_ata(
+    vault_ata: &UncheckedAccount,
+    vault: Pubkey,
+    mint: &InterfaceAccount<Mint>,
+) -> Result<()> {
+    let expected_ata = associated_token::get_associated_token_address_with_program_id(
+        &vault,
+        &mint.key(),
+        mint.to_account_info().owner,
+    );
+    require_keys_eq!(vault_ata.key(), expected_ata, VeilpayError::InvalidVaultAuthority);
+    if vault_ata.data_is_empty() {
+        return Ok(());
+    }
+    require_keys_eq!(
+        *vault_ata.owner,
+        *mint.to_account_info().owner,
+        ErrorCode::AccountOwnedByWrongProgram
+    );
+    TokenAccount::try_deserialize(&mut &vault_ata.try_borrow_data()?[..])?;
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct ExternalTransferArgs {
-    pub amount: u64,
-    pub proof: Vec<u8>,
-    pub public_inputs: Vec<u8>,
-    pub relayer_fee_bps: u16,
-    pub new_root: Vec<u8>,
-    pub output_ciphertexts: Vec<u8>,
-    pub deliver_sol: bool,
+/// Lets a deployment accept proofs from two proof systems while migrating between
+/// them; whichever key is picked must still be the one bound to the circuit.
+fn select_verifier_key<T>(proof_system: u8, primary: T, alt: Option<T>) -> Result<T> {
+    match proof_system {
+        PROOF_SYSTEM_PRIMARY => Ok(primary),
+        PROOF_SYSTEM_ALT => alt.ok_or_else(|| error!(VeilpayError::MissingVerifierAccounts)),
+        _ => err!(VeilpayError::InvalidProofSystem),
+    }
+}
+
+fn verifier_program_id(config: &Config, vault: &VaultPool) -> Pubkey {
+    vault.verifier_program_override.unwrap_or(config.verifier_program)
+}
+
+fn verify_groth16<'info>(
+    expected_program: Pubkey,
+    verifier_program: &UncheckedAccount<'info>,
+    verifier_key: &UncheckedAccount<'info>,
+    proof: Vec<u8>,
+    public_inputs: Vec<u8>,
+) -> Result<()> {
+    // The verifier would reject it too, but only after the CPI has been paid for.
+    require!(
+        proof.len() == PROOF_BYTES_LEN,
+        VeilpayError::InvalidProofDataLength
+    );
+    require_keys_eq!(
+        verifier_program.key(),
+        expected_program,
+        VeilpayError::InvalidVerifierProgram
+    );
+    require_keys_eq!(
+        *verifier_key.owner,
+        expected_program,
+        VeilpayError::InvalidVerifierProgram
+    );
+    let cpi_accounts = VerifyGroth16Cpi {
+        verifier_key: verifier_key.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(verifier_program.to_account_info(), cpi_accounts);
+    verifier::cpi::verify_groth16(cpi_ctx, proof, public_inputs)
+        .map_err(|_| error!(VeilpayError::InvalidProof))?;
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct ExternalTransferWithProofArgs {
-    pub amount: u64,
-    pub relayer_fee_bps: u16,
-    pub new_root: Vec<u8>,
-    pub output_ciphertexts: Vec<u8>,
-    pub deliver_sol: bool,
+fn mark_nullifier(set: &mut NullifierSet, nullifier: [u8; 32]) -> Result<()> {
+    let (byte_index, bit_mask) = unspent_nullifier_bit(set, &nullifier)?;
+    set.bitset[byte_index] |= bit_mask;
+    set.count = set.count.saturating_add(1);
+    // The bitset only keeps one bit per nullifier, so indexers mirroring the spent set
+    // need the value itself. Emitted regardless of `event_flags`/`compact_events`.
+    emit!(NullifierSpentEvent {
+        mint: set.mint,
+        chunk_index: set.chunk_index,
+        nullifier,
+    });
+    Ok(())
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct RegisterIdentityArgs {
-    pub commitment: Vec<u8>,
-    pub new_root: Vec<u8>,
+/// `nullifier_bit`, failing with `NullifierAlreadyUsed` if the bit is already set.
+fn unspent_nullifier_bit(set: &NullifierSet, nullifier: &[u8; 32]) -> Result<(usize, u8)> {
+    let (byte_index, bit_mask) = nullifier_bit(set, nullifier)?;
+    require!(
+        (set.bitset[byte_index] & bit_mask) == 0,
+        VeilpayError::NullifierAlreadyUsed
+    );
+    Ok((byte_index, bit_mask))
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Config {
-    pub admin: Pubkey,
-    pub fee_bps: u16,
-    pub relayer_fee_bps_max: u16,
-    pub vk_registry: Pubkey,
-    #[max_len(MAX_ALLOWLIST)]
-    pub mint_allowlist: Vec<Pubkey>,
-    #[max_len(MAX_CIRCUITS)]
-    pub circuit_ids: Vec<u32>,
-    pub paused: bool,
-    pub version: u32,
-    pub bump: u8,
+/// Byte offset and mask of `nullifier` in `set`'s bitset, once the nullifier is known
+/// to map to this chunk.
+fn nullifier_bit(set: &NullifierSet, nullifier: &[u8; 32]) -> Result<(usize, u8)> {
+    let (chunk_index, bit_index) = nullifier_position(nullifier, set.chunk_count);
+    require!(
+        chunk_index == set.chunk_index,
+        VeilpayError::NullifierChunkMismatch
+    );
+    Ok(((bit_index / 8) as usize, 1u8 << (bit_index % 8)))
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct VaultPool {
-    pub vault_pda: Pubkey,
-    pub vault_ata: Pubkey,
-    pub mint: Pubkey,
-    pub total_deposited: u64,
-    pub total_withdrawn: u64,
-    pub nonce: u64,
-    pub bump: u8,
+/// Chunk and bit both come from `keccak(nullifier)` rather than the nullifier's own
+/// bytes, so whoever picks the nullifier encoding cannot steer spends onto a chosen bit.
+fn nullifier_position(nullifier: &[u8; 32], chunk_count: u32) -> (u32, u16) {
+    let digest = keccak::hashv(&[nullifier]).to_bytes();
+    let chunk_index =
+        u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]) % chunk_count.max(1);
+    let bit_index = u16::from_le_bytes([digest[4], digest[5]]) % (NULLIFIER_BITS as u16);
+    (chunk_index, bit_index)
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct ShieldedState {
-    pub mint: Pubkey,
-    pub merkle_root: [u8; 32],
-    #[max_len(MAX_ROOT_HISTORY)]
-    pub root_history: Vec<[u8; 32]>,
-    pub root_history_index: u32,
-    pub commitment_count: u64,
-    pub circuit_id: u32,
-    pub version: u32,
-    pub bump: u8,
+fn max_identities(depth: u8) -> u64 {
+    1u64 << depth
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct IdentityRegistry {
-    pub merkle_root: [u8; 32],
-    pub commitment_count: u64,
-    pub bump: u8,
+fn withdrawal_must_queue(config: &Config, amount: u64) -> bool {
+    config.withdraw_queue_threshold > 0 && amount >= config.withdraw_queue_threshold
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct IdentityMember {
-    pub owner: Pubkey,
-    pub bump: u8,
+/// Everything `validate_withdraw_semantics` compares the proof against, borrowed from
+/// whichever withdraw context runs it.
+pub struct WithdrawTarget<'a, 'info> {
+    pub config: &'a Config,
+    pub vault: &'a VaultPool,
+    pub shielded: &'a ShieldedState,
+    pub identity_registry: &'a Account<'info, IdentityRegistry>,
+    pub vk_registry: &'a VkRegistry,
+    pub verifier_key: Pubkey,
+    pub proof_binding: Option<ProofBinding>,
+    /// Owner matched against `Config::fee_exempt`; `None` when the destination owner
+    /// is not known, in which case the protocol fee always applies.
+    pub fee_owner: Option<Pubkey>,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct ProofAccount {
-    pub owner: Pubkey,
+/// Recipient and destination a stored proof was bound to, next to the accounts the
+/// spend actually supplied.
+pub struct ProofBinding {
+    pub bound_recipient: Pubkey,
+    pub bound_destination: Pubkey,
     pub recipient: Pubkey,
     pub destination_ata: Pubkey,
-    pub mint: Pubkey,
-    pub nonce: u64,
-    #[max_len(PROOF_BYTES_LEN)]
-    pub proof: Vec<u8>,
-    #[max_len(PUBLIC_INPUTS_BYTES_LEN)]
-    pub public_inputs: Vec<u8>,
-    pub bump: u8,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct NullifierSet {
-    pub mint: Pubkey,
-    pub chunk_index: u32,
-    pub bitset: [u8; NULLIFIER_BYTES],
-    pub count: u32,
-    pub bump: u8,
+/// Outcome of every withdraw invariant, kept in enforcement order.
+pub struct WithdrawSemantics {
+    pub results: Vec<(WithdrawCheck, Result<()>)>,
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct VkRegistry {
-    #[max_len(MAX_VK_ENTRIES)]
-    pub entries: Vec<VkEntry>,
-    pub bump: u8,
+impl WithdrawSemantics {
+    /// First failure in enforcement order, so withdraws keep returning the error the
+    /// inline checks used to.
+    pub fn into_result(self) -> Result<()> {
+        for (_, result) in self.results {
+            result?;
+        }
+        Ok(())
+    }
+
+    fn report(&self) -> (u32, Vec<WithdrawCheckFailure>) {
+        let mut passed = 0u32;
+        let mut failures = Vec::new();
+        for (check, result) in &self.results {
+            match result {
+                Ok(()) => passed |= 1 << (*check as u8),
+                Err(err) => failures.push(WithdrawCheckFailure {
+                    check: *check,
+                    error_code: error_code_number(err),
+                }),
+            }
+        }
+        (passed, failures)
+    }
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
-pub struct VkEntry {
-    pub circuit_id: u32,
-    pub vk_account: Pubkey,
-    pub vk_hash: [u8; 32],
-    pub status: u8,
+/// Evaluates every semantic withdraw invariant (no early return) so the pre-flight can
+/// report all failures; the withdraw instructions turn it back into the first error.
+pub fn validate_withdraw_semantics(
+    parsed: &ParsedPublicInputs,
+    target: &WithdrawTarget,
+    amount: u64,
+    relayer_fee_bps: u16,
+    slot: u64,
+) -> WithdrawSemantics {
+    let protocol_fee_bps = protocol_fee_bps(target.config, target.fee_owner);
+    let fee = split_withdraw(amount, protocol_fee_bps, relayer_fee_bps).and_then(|split| {
+        // The proof commits to everything leaving the note besides the net payout, which
+        // is zero whenever both fees round down to nothing.
+        require!(split.total_fee()? == parsed.fee_amount, VeilpayError::FeeMismatch);
+        Ok(split)
+    });
+    let relayer_fee_floor = match &fee {
+        Ok(split) => check_relayer_fee_floor(target.config, split.relayer_fee),
+        // Nothing to compare until the fee itself checks out.
+        Err(_) => Ok(()),
+    };
+    let recipient = match &target.proof_binding {
+        Some(binding) => check_proof_binding(binding),
+        None => Ok(()),
+    };
+    let results = vec![
+        (
+            WithdrawCheck::Amount,
+            ensure(parsed.amount_out == amount, VeilpayError::AmountMismatch),
+        ),
+        (
+            WithdrawCheck::OutputFlags,
+            ensure(parsed.output_enabled[0] == 0, VeilpayError::InvalidOutputFlags),
+        ),
+        (
+            WithdrawCheck::Circuit,
+            check_circuit_allowed(target.config, target.shielded, parsed.circuit_id)
+                .and_then(|_| check_pool_circuit(target.shielded, parsed.circuit_id)),
+        ),
+        (
+            WithdrawCheck::VkBinding,
+            check_vk_binding(target.vk_registry, parsed.circuit_id, target.verifier_key),
+        ),
+        (
+            WithdrawCheck::IdentityRoot,
+            check_identity_root(target.vault, target.identity_registry, parsed.identity_root),
+        ),
+        (
+            WithdrawCheck::Root,
+            ensure(
+                root_known(
+                    target.shielded,
+                    parsed.root,
+                    slot,
+                    target.config.root_max_age_slots,
+                ),
+                VeilpayError::UnknownRoot,
+            ),
+        ),
+        (WithdrawCheck::SlotWindow, check_slot_window(parsed, slot)),
+        (WithdrawCheck::ValueConservation, check_value_conservation(parsed)),
+        (WithdrawCheck::Fee, fee.map(|_| ())),
+        (WithdrawCheck::RelayerFeeFloor, relayer_fee_floor),
+        (WithdrawCheck::Recipient, recipient),
+        (WithdrawCheck::Domain, check_domain(parsed, &target.vault.mint)),
+    ];
+    WithdrawSemantics { results }
 }
 
-fn append_root(state: &mut ShieldedState, new_root: [u8; 32]) {
-    if state.root_history.len() < MAX_ROOT_HISTORY {
-        state.root_history.push(new_root);
+fn ensure(condition: bool, error: VeilpayError) -> Result<()> {
+    if condition {
+        Ok(())
     } else {
-        let idx = (state.root_history_index as usize) % MAX_ROOT_HISTORY;
-        state.root_history[idx] = new_root;
-        state.root_history_index = state.root_history_index.wrapping_add(1);
+        Err(error.into())
     }
-    state.merkle_root = new_root;
 }
 
-fn to_fixed_32(bytes: &[u8]) -> Result<[u8; 32]> {
-    require!(bytes.len() == 32, VeilpayError::InvalidByteLength);
-    let mut out = [0u8; 32];
-    out.copy_from_slice(bytes);
-    Ok(out)
+fn check_proof_binding(binding: &ProofBinding) -> Result<()> {
+    require!(
+        binding.bound_recipient == binding.recipient,
+        VeilpayError::InvalidProofAccountRecipient
+    );
+    require!(
+        binding.bound_destination == binding.destination_ata,
+        VeilpayError::InvalidProofAccountDestination
+    );
+    Ok(())
 }
 
-fn to_fixed_128(bytes: &[u8]) -> Result<[u8; 128]> {
-    require!(bytes.len() == 128, VeilpayError::InvalidByteLength);
-    let mut out = [0u8; 128];
-    out.copy_from_slice(bytes);
-    Ok(out)
+fn error_code_number(err: &Error) -> u32 {
+    match err {
+        Error::AnchorError(err) => err.error_code_number,
+        Error::ProgramError(err) => u64::from(err.program_error.clone()) as u32,
+    }
 }
 
-fn parse_output_ciphertexts(
-    bytes: &[u8],
-    output_enabled: [u8; NOTE_OUTPUTS],
-) -> Result<[[u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS]> {
-    if bytes.is_empty() {
-        return Ok([[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS]);
-    }
-    if bytes.len() == NOTE_CIPHERTEXT_BYTES {
-        let mut out = [[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS];
-        if output_enabled[0] == 1 && output_enabled[1] == 0 {
-            out[0].copy_from_slice(bytes);
-            return Ok(out);
-        }
-        if output_enabled[0] == 0 && output_enabled[1] == 1 {
-            out[1].copy_from_slice(bytes);
-            return Ok(out);
-        }
-        return Err(error!(VeilpayError::InvalidByteLength));
+/// Caps what one withdrawal (relayer fee included) may take out of the vault so a
+/// single exploited transaction cannot empty it. Zero bps disables the cap.
+fn check_single_withdraw(config: &Config, vault_balance: u64, amount: u64) -> Result<()> {
+    if config.max_single_withdraw_bps == 0 {
+        return Ok(());
     }
+    let cap = (vault_balance as u128) * (config.max_single_withdraw_bps as u128) / 10_000u128;
     require!(
-        bytes.len() == NOTE_OUTPUT_BYTES,
-        VeilpayError::InvalidByteLength
+        (amount as u128) <= cap,
+        VeilpayError::SingleWithdrawTooLarge
     );
-    let mut out = [[0u8; NOTE_CIPHERTEXT_BYTES]; NOTE_OUTPUTS];
-    out[0].copy_from_slice(&bytes[..NOTE_CIPHERTEXT_BYTES]);
-    out[1].copy_from_slice(&bytes[NOTE_CIPHERTEXT_BYTES..NOTE_OUTPUT_BYTES]);
-    Ok(out)
+    Ok(())
 }
 
-#[event]
-pub struct NoteOutputEvent {
-    pub mint: Pubkey,
-    pub leaf_index: u64,
-    pub commitment: [u8; 32],
-    pub ciphertext: [u8; NOTE_CIPHERTEXT_BYTES],
-    pub kind: u8,
+/// Rejects a withdrawal the vault cannot pay in full before anything is marked or any
+/// fee leaves it, instead of letting the token program fail on whichever transfer runs
+/// dry.
+fn check_vault_balance(vault_balance: u64, split: &WithdrawSplit) -> Result<()> {
+    let needed = split
+        .net_amount
+        .checked_add(split.total_fee()?)
+        .ok_or(VeilpayError::MathOverflow)?;
+    require!(vault_balance >= needed, VeilpayError::InsufficientVaultBalance);
+    Ok(())
 }
 
-#[repr(u8)]
-pub enum NoteOutputKind {
-    Deposit = 0,
-    Internal = 1,
-    External = 2,
+/// Returns the pool's `total_deposited` after crediting `amount`, failing when that
+/// would pass `deposit_cap` (zero means unlimited).
+fn check_deposit_cap(vault: &VaultPool, amount: u64) -> Result<u64> {
+    let total = vault
+        .total_deposited
+        .checked_add(amount)
+        .ok_or(VeilpayError::MathOverflow)?;
+    require!(
+        vault.deposit_cap == 0 || total <= vault.deposit_cap,
+        VeilpayError::DepositCapExceeded
+    );
+    Ok(total)
 }
 
-#[derive(Clone)]
-struct ParsedPublicInputs {
-    root: [u8; 32],
-    identity_root: [u8; 32],
-    nullifiers: [[u8; 32]; MAX_INPUTS],
-    output_commitments: [[u8; 32]; MAX_OUTPUTS],
-    output_enabled: [u8; MAX_OUTPUTS],
-    amount_out: u64,
-    fee_amount: u64,
-    circuit_id: u32,
+/// Checked against what the vault received, like the deposit cap. Zero disables it.
+fn check_min_deposit(vault: &VaultPool, amount: u64) -> Result<()> {
+    require!(amount >= vault.min_deposit, VeilpayError::DepositTooSmall);
+    Ok(())
 }
 
-fn parse_public_inputs(bytes: &[u8]) -> Result<ParsedPublicInputs> {
+/// Per-pool absolute limit on one withdrawal's `amount`, next to the config-wide
+/// share-of-vault limit above. Zero disables it.
+fn check_withdraw_cap(vault: &VaultPool, amount: u64) -> Result<()> {
     require!(
-        bytes.len() == PUBLIC_INPUTS_LEN * 32,
-        VeilpayError::InvalidPublicInputs
+        vault.per_withdraw_cap == 0 || amount <= vault.per_withdraw_cap,
+        VeilpayError::WithdrawCapExceeded
     );
-    let chunks: Vec<[u8; 32]> = bytes
-        .chunks(32)
-        .map(|chunk| {
-            let mut out = [0u8; 32];
-            out.copy_from_slice(chunk);
-            out
-        })
-        .collect();
-    let root = chunks[0];
-    let identity_root = chunks[1];
-    let mut nullifiers = [[0u8; 32]; MAX_INPUTS];
-    for i in 0..MAX_INPUTS {
-        nullifiers[i] = chunks[2 + i];
-    }
-    let mut output_commitments = [[0u8; 32]; MAX_OUTPUTS];
-    for i in 0..MAX_OUTPUTS {
-        output_commitments[i] = chunks[2 + MAX_INPUTS + i];
-    }
-    let mut output_enabled = [0u8; MAX_OUTPUTS];
-    for i in 0..MAX_OUTPUTS {
-        output_enabled[i] = parse_u8(&chunks[2 + MAX_INPUTS + MAX_OUTPUTS + i])?;
-    }
-    let amount_out = parse_u64(&chunks[2 + MAX_INPUTS + MAX_OUTPUTS + MAX_OUTPUTS])?;
-    let fee_amount = parse_u64(&chunks[2 + MAX_INPUTS + MAX_OUTPUTS + MAX_OUTPUTS + 1])?;
-    let circuit_id = parse_u32(&chunks[2 + MAX_INPUTS + MAX_OUTPUTS + MAX_OUTPUTS + 2])?;
-    Ok(ParsedPublicInputs {
-        root,
-        identity_root,
-        nullifiers,
-        output_commitments,
-        output_enabled,
-        amount_out,
-        fee_amount,
-        circuit_id,
-    })
+    Ok(())
 }
 
-fn parse_u64(bytes: &[u8; 32]) -> Result<u64> {
-    if bytes[..24].iter().any(|b| *b != 0) {
-        return Err(error!(VeilpayError::InvalidPublicInputs));
-    }
-    Ok(u64::from_be_bytes(bytes[24..].try_into().unwrap()))
+/// Relayer fee rate bounds from `configure_fees`. Unlike the absolute floor below, a
+/// non-zero `relayer_fee_bps_min` applies to every token-fee withdrawal, self-relayed
+/// or not.
+fn check_relayer_fee_bps(config: &Config, relayer_fee_bps: u16) -> Result<()> {
+    require!(
+        relayer_fee_bps <= config.relayer_fee_bps_max,
+        VeilpayError::RelayerFeeTooHigh
+    );
+    require!(
+        relayer_fee_bps >= config.relayer_fee_bps_min,
+        VeilpayError::RelayerFeeTooLow
+    );
+    Ok(())
 }
 
-fn parse_u32(bytes: &[u8; 32]) -> Result<u32> {
-    if bytes[..28].iter().any(|b| *b != 0) {
-        return Err(error!(VeilpayError::InvalidPublicInputs));
+/// A withdrawal pays its relayer in the pool token or in lamports, never both. The
+/// token rate bounds only apply to the former; a lamport fee is whatever the recipient
+/// signs for.
+fn check_relayer_fee_mode(
+    config: &Config,
+    relayer_fee_bps: u16,
+    relayer_fee_lamports: u64,
+) -> Result<()> {
+    if relayer_fee_lamports == 0 {
+        return check_relayer_fee_bps(config, relayer_fee_bps);
     }
-    Ok(u32::from_be_bytes(bytes[28..].try_into().unwrap()))
-}
-
-fn parse_u8(bytes: &[u8; 32]) -> Result<u8> {
-    let value = parse_u64(bytes)?;
-    require!(value <= 1, VeilpayError::InvalidPublicInputs);
-    Ok(value as u8)
+    require!(relayer_fee_bps == 0, VeilpayError::InvalidRelayerFeeMode);
+    Ok(())
 }
 
-fn mark_nullifiers<'info>(
-    primary: &mut Account<'info, NullifierSet>,
-    remaining: &'info [AccountInfo<'info>],
-    nullifiers: &[[u8; 32]; MAX_INPUTS],
+/// Moves a lamport relayer fee out of the recipient's escrow. The recipient signing
+/// the withdrawal is what approves the amount, since anyone can submit a spend to them.
+fn pay_relayer_fee_lamports<'info>(
+    escrow: Option<&Account<'info, FeeEscrow>>,
+    recipient: &SystemAccount<'info>,
+    relayer: &Signer<'info>,
+    parsed: &ParsedPublicInputs,
+    lamports: u64,
 ) -> Result<()> {
-    for nullifier in nullifiers {
-        if is_zero_32(nullifier) {
-            continue;
-        }
-        let (chunk_index, _) = nullifier_position(nullifier);
-        if primary.chunk_index == chunk_index {
-            mark_nullifier(primary, *nullifier)?;
-            continue;
-        }
-        let mut matched: Option<Account<NullifierSet>> = None;
-        for info in remaining {
-            if !info.is_writable {
-                continue;
-            }
-            let set = Account::<NullifierSet>::try_from(info)?;
-            if set.chunk_index == chunk_index {
-                matched = Some(set);
-                break;
-            }
-        }
-        let mut set = matched.ok_or(VeilpayError::MissingNullifierAccount)?;
-        mark_nullifier(&mut set, *nullifier)?;
+    if lamports == 0 {
+        return Ok(());
     }
+    let escrow = escrow.ok_or(VeilpayError::MissingFeeEscrow)?;
+    require!(
+        recipient.to_account_info().is_signer,
+        VeilpayError::FeeEscrowNotApproved
+    );
+    check_relayer_fee_recipient(parsed, &relayer.key())?;
+    require!(
+        lamports <= fee_escrow_available(escrow)?,
+        VeilpayError::InsufficientFeeEscrow
+    );
+    escrow.sub_lamports(lamports)?;
+    relayer.add_lamports(lamports)?;
     Ok(())
 }
 
-fn is_zero_32(value: &[u8; 32]) -> bool {
-    value.iter().all(|b| *b == 0)
+/// Escrow balance above the rent-exempt minimum, which stays behind so the account
+/// survives for the next withdrawal.
+fn fee_escrow_available(escrow: &Account<FeeEscrow>) -> Result<u64> {
+    let info = escrow.to_account_info();
+    let reserve = Rent::get()?.minimum_balance(info.data_len());
+    Ok(info.lamports().saturating_sub(reserve))
 }
 
-fn root_known(state: &ShieldedState, root: [u8; 32]) -> bool {
-    if state.merkle_root == root {
-        return true;
+/// Proofs that commit to a relayer fee recipient pin the fee to accounts it owns, so a
+/// rebuilt or front-run instruction cannot redirect the relayer's fee.
+fn check_relayer_fee_recipient(parsed: &ParsedPublicInputs, owner: &Pubkey) -> Result<()> {
+    if let Some(recipient) = parsed.relayer_fee_recipient {
+        require!(
+            recipient == pubkey_to_field(owner),
+            VeilpayError::RelayerRecipientMismatch
+        );
     }
-    state.root_history.iter().any(|r| *r == root)
+    Ok(())
 }
 
-fn verify_groth16<'info>(
-    verifier_program: &Program<'info, verifier::program::Verifier>,
-    verifier_key: &Account<'info, verifier::VerifierKey>,
-    proof: Vec<u8>,
-    public_inputs: Vec<u8>,
-) -> Result<()> {
-    let cpi_accounts = VerifyGroth16Cpi {
-        verifier_key: verifier_key.to_account_info(),
-    };
-    let cpi_ctx = CpiContext::new(verifier_program.to_account_info(), cpi_accounts);
-    verifier::cpi::verify_groth16(cpi_ctx, proof, public_inputs)
-        .map_err(|_| error!(VeilpayError::InvalidProof))?;
+fn check_domain(parsed: &ParsedPublicInputs, mint: &Pubkey) -> Result<()> {
+    require!(
+        parsed.domain == domain_separator(mint),
+        VeilpayError::DomainMismatch
+    );
     Ok(())
 }
 
-fn mark_nullifier(set: &mut NullifierSet, nullifier: [u8; 32]) -> Result<()> {
-    let (chunk_index, bit_index) = nullifier_position(&nullifier);
+/// A relayed spend (non-zero fee) must cover the relayer's fixed cost; self-relayed
+/// spends with no fee are unaffected.
+fn check_relayer_fee_floor(config: &Config, fee_amount: u64) -> Result<()> {
     require!(
-        chunk_index == set.chunk_index,
-        VeilpayError::NullifierChunkMismatch
+        fee_amount == 0 || fee_amount >= config.relayer_fee_abs_min,
+        VeilpayError::RelayerFeeTooLow
     );
-    let byte_index = (bit_index / 8) as usize;
-    let bit_mask = 1u8 << (bit_index % 8);
+    Ok(())
+}
+
+/// Fees are rated on the gross amount in basis points and rounded down, so at a
+/// nonzero rate an amount below `10_000 / fee_bps` owes nothing. A fee that rounds to
+/// zero is handled exactly like a zero rate: the proof must commit to no fee, nothing
+/// is transferred and no fee account is needed.
+fn rate_fee(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(VeilpayError::MathOverflow)?
+        / 10_000u128;
+    u64::try_from(fee).map_err(|_| error!(VeilpayError::MathOverflow))
+}
+
+fn split_relayer_fee(amount: u64, fee_bps: u16) -> Result<(u64, u64)> {
+    let fee_u64 = rate_fee(amount, fee_bps)?;
+    if fee_u64 == 0 {
+        return Ok((amount, 0));
+    }
+    require!(fee_u64 < amount, VeilpayError::RelayerFeeExceedsAmount);
+    let net = amount.checked_sub(fee_u64).ok_or(VeilpayError::MathOverflow)?;
+    Ok((net, fee_u64))
+}
+
+fn split_protocol_fee(amount: u64, fee_bps: u16) -> Result<(u64, u64)> {
+    let fee_u64 = rate_fee(amount, fee_bps)?;
+    if fee_u64 == 0 {
+        return Ok((amount, 0));
+    }
+    require!(fee_u64 < amount, VeilpayError::ProtocolFeeExceedsAmount);
+    let net = amount.checked_sub(fee_u64).ok_or(VeilpayError::MathOverflow)?;
+    Ok((net, fee_u64))
+}
+
+/// Protocol fees go either straight to a `fee_authority` token account or to a
+/// vault-owned fee account that `withdraw_protocol_fees` sweeps later. The pool's own
+/// `vault_ata` is never a fee account: fees left there would be indistinguishable
+/// from note-backing deposits.
+fn check_protocol_fee_account(
+    config: &Config,
+    vault: &VaultPool,
+    fee_ata: &InterfaceAccount<TokenAccount>,
+    mint: Pubkey,
+) -> Result<()> {
     require!(
-        (set.bitset[byte_index] & bit_mask) == 0,
-        VeilpayError::NullifierAlreadyUsed
+        fee_ata.mint == mint
+            && fee_ata.key() != vault.vault_ata
+            && (fee_ata.owner == config.fee_authority || fee_ata.owner == vault.vault_pda),
+        VeilpayError::InvalidProtocolFeeAccount
     );
-    set.bitset[byte_index] |= bit_mask;
-    set.count = set.count.saturating_add(1);
     Ok(())
 }
 
-fn nullifier_position(nullifier: &[u8; 32]) -> (u32, u16) {
-    let chunk_index = u32::from_le_bytes([nullifier[0], nullifier[1], nullifier[2], nullifier[3]]);
-    let bit_index = u16::from_le_bytes([nullifier[4], nullifier[5]]) % (NULLIFIER_BITS as u16);
-    (chunk_index, bit_index)
+/// Protocol fee rate for a withdrawal paying out to `owner`: `Config::fee_bps` unless
+/// the owner is on the exemption list.
+fn protocol_fee_bps(config: &Config, owner: Option<Pubkey>) -> u16 {
+    match owner {
+        Some(owner) if config.fee_exempt.contains(&owner) => 0,
+        _ => config.fee_bps,
+    }
 }
 
-fn split_relayer_fee(amount: u64, fee_bps: u16) -> Result<(u64, u64)> {
-    if fee_bps == 0 {
-        return Ok((amount, 0));
+/// Owner of the account a withdrawal pays out to: the recipient itself for SOL
+/// delivery, otherwise the destination token account's owner. `None` when the
+/// destination is not (yet) a token account.
+fn payout_owner(deliver_sol: bool, recipient: Pubkey, destination_ata: &AccountInfo) -> Option<Pubkey> {
+    if deliver_sol {
+        return Some(recipient);
+    }
+    if destination_ata.owner != &anchor_spl::token::ID
+        && destination_ata.owner != &anchor_spl::token_2022::ID
+    {
+        return None;
+    }
+    TokenAccount::try_deserialize(&mut &destination_ata.data.borrow()[..])
+        .ok()
+        .map(|account| account.owner)
+}
+
+/// How a withdrawal's amount leaves the vault, in transfer order: protocol fee,
+/// relayer fee, then the recipient's net. Both fees are rated on the gross amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WithdrawSplit {
+    pub protocol_fee: u64,
+    pub relayer_fee: u64,
+    pub net_amount: u64,
+}
+
+impl WithdrawSplit {
+    /// What the proof's `fee_amount` must commit to.
+    pub fn total_fee(&self) -> Result<u64> {
+        self.protocol_fee
+            .checked_add(self.relayer_fee)
+            .ok_or(error!(VeilpayError::MathOverflow))
     }
-    let fee = (amount as u128)
-        .checked_mul(fee_bps as u128)
-        .ok_or(VeilpayError::MathOverflow)?
-        / 10_000u128;
-    let fee_u64 = u64::try_from(fee).map_err(|_| VeilpayError::MathOverflow)?;
-    require!(fee_u64 < amount, VeilpayError::RelayerFeeExceedsAmount);
-    let net = amount.checked_sub(fee_u64).ok_or(VeilpayError::MathOverflow)?;
-    Ok((net, fee_u64))
+}
+
+fn split_withdraw(amount: u64, protocol_fee_bps: u16, relayer_fee_bps: u16) -> Result<WithdrawSplit> {
+    let (after_protocol, protocol_fee) = split_protocol_fee(amount, protocol_fee_bps)?;
+    let (_, relayer_fee) = split_relayer_fee(amount, relayer_fee_bps)?;
+    let net_amount = after_protocol
+        .checked_sub(relayer_fee)
+        .filter(|net| *net > 0)
+        .ok_or(VeilpayError::FeesExceedAmount)?;
+    Ok(WithdrawSplit {
+        protocol_fee,
+        relayer_fee,
+        net_amount,
+    })
 }
 
 #[error_code]
@@ -1552,4 +5064,1178 @@ pub enum VeilpayError {
     InvalidRecipientTokenAccount,
     #[msg("Invalid temporary WSOL account")]
     InvalidTempAccount,
+    #[msg("Invalid tree depth")]
+    InvalidTreeDepth,
+    #[msg("Tree already has commitments")]
+    TreeNotEmpty,
+    #[msg("Poseidon hash failed")]
+    PoseidonFailed,
+    #[msg("Withdrawal exceeds the queue threshold and must be queued")]
+    WithdrawalMustBeQueued,
+    #[msg("Withdrawal queue is disabled")]
+    WithdrawQueueDisabled,
+    #[msg("Queued withdrawal is not ready")]
+    WithdrawalNotReady,
+    #[msg("Verifier key public input count does not match the circuit")]
+    VkInputCountMismatch,
+    #[msg("VK registry is full")]
+    VkRegistryFull,
+    #[msg("Nullifier chunk index out of range")]
+    ChunkIndexOutOfRange,
+    #[msg("Identity registry is full")]
+    IdentityRegistryFull,
+    #[msg("Verifier program does not match the mint's verifier")]
+    InvalidVerifierProgram,
+    #[msg("Circuit has no verifier key registered")]
+    CircuitNotRegistered,
+    #[msg("Verifier key is not the one registered for the circuit")]
+    VkCircuitMismatch,
+    #[msg("Proof slot window has not started")]
+    ProofNotYetValid,
+    #[msg("Proof slot window has passed")]
+    ProofExpired,
+    #[msg("Invalid identity policy")]
+    InvalidIdentityPolicy,
+    #[msg("Proof pays out more than its declared input value")]
+    ValueConservationViolation,
+    #[msg("Fee exemption list exceeds max length")]
+    FeeExemptListTooLarge,
+    #[msg("Unknown event flag")]
+    InvalidEventFlags,
+    #[msg("Protocol must be paused")]
+    ProtocolNotPaused,
+    #[msg("Confirmation flag required")]
+    ConfirmationRequired,
+    #[msg("Nullifier bit is not set")]
+    NullifierNotSet,
+    #[msg("Deposit proof required")]
+    DepositProofRequired,
+    #[msg("Deposit proof commitment mismatch")]
+    DepositCommitmentMismatch,
+    #[msg("Verifier accounts required for proof")]
+    MissingVerifierAccounts,
+    #[msg("Relayer fee below minimum")]
+    RelayerFeeTooLow,
+    #[msg("Circuit not allowed for this pool")]
+    PoolCircuitNotAllowed,
+    #[msg("Nullifier is not a canonical field element")]
+    NonCanonicalNullifier,
+    #[msg("Withdrawal exceeds the single-transaction cap")]
+    SingleWithdrawTooLarge,
+    #[msg("Withdraw cap must be at most 10000 bps")]
+    InvalidWithdrawCap,
+    #[msg("Missing protocol fee account")]
+    MissingProtocolFeeAccount,
+    #[msg("Invalid protocol fee account")]
+    InvalidProtocolFeeAccount,
+    #[msg("Protocol fee exceeds amount")]
+    ProtocolFeeExceedsAmount,
+    #[msg("Protocol and relayer fees exceed amount")]
+    FeesExceedAmount,
+    #[msg("Verifier key entry is not active")]
+    VkInactive,
+    #[msg("New root does not match the on-chain tree append")]
+    NewRootMismatch,
+    #[msg("Shielded tree is full")]
+    TreeFull,
+    #[msg("Nullifier chunk count must be positive")]
+    InvalidNullifierChunks,
+    #[msg("Amount exceeds the fee account balance")]
+    InsufficientProtocolFees,
+    #[msg("Relayer fee minimum exceeds the maximum")]
+    InvalidRelayerFeeBounds,
+    #[msg("Verifier key status must be active (1) or inactive (0)")]
+    InvalidVkStatus,
+    #[msg("Deposit would exceed the pool's deposit cap")]
+    DepositCapExceeded,
+    #[msg("Withdrawal amount exceeds the pool's per-withdrawal cap")]
+    WithdrawCapExceeded,
+    #[msg("Verifier key registry capacity must cover its entries")]
+    VkRegistryTooSmall,
+    #[msg("Public input is not below the scalar field modulus")]
+    NonCanonicalPublicInput,
+    #[msg("Unknown proof system selector")]
+    InvalidProofSystem,
+    #[msg("Relayer fee account is not owned by the recipient the proof commits to")]
+    RelayerRecipientMismatch,
+    #[msg("Pool still has deposits that were not withdrawn")]
+    PoolNotDrained,
+    #[msg("Vault token account still holds funds")]
+    VaultNotEmpty,
+    #[msg("Proof was made for another deployment or pool")]
+    DomainMismatch,
+    #[msg("Root history length must be between 1 and MAX_ROOT_HISTORY")]
+    InvalidRootHistoryLen,
+    #[msg("Proof circuit does not match the circuit this pool is pinned to")]
+    PoolCircuitMismatch,
+    #[msg("Relayer allowlist exceeds max length")]
+    RelayerListTooLarge,
+    #[msg("Relayer is not on the allowlist")]
+    RelayerNotAllowed,
+    #[msg("Vault is paused")]
+    VaultPaused,
+    #[msg("Deposit is below the pool minimum")]
+    DepositTooSmall,
+    #[msg("Invalid spend layout")]
+    InvalidSpendLayout,
+    #[msg("Relayer fee must be paid in either the pool token or lamports")]
+    InvalidRelayerFeeMode,
+    #[msg("Fee escrow account required")]
+    MissingFeeEscrow,
+    #[msg("Recipient must sign to pay the relayer from its fee escrow")]
+    FeeEscrowNotApproved,
+    #[msg("Fee escrow balance too low")]
+    InsufficientFeeEscrow,
+    #[msg("Identity registry has no members")]
+    IdentityRegistryEmpty,
+    #[msg("Config version is not one this program can migrate")]
+    UnsupportedConfigVersion,
+    #[msg("Vault balance does not cover the withdrawal")]
+    InsufficientVaultBalance,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use veilpay_public_inputs::encode_u64;
+
+    const PUBLIC_INPUTS_BYTES_LEN: usize = SpendLayout::DEFAULT.base_len() * 32;
+
+    fn arb_layout() -> impl Strategy<Value = SpendLayout> {
+        (1..=MAX_INPUTS, 1..=MAX_OUTPUTS)
+            .prop_map(|(inputs, outputs)| SpendLayout { inputs, outputs })
+    }
+
+    proptest! {
+        #[test]
+        fn parse_accepts_exactly_the_layout_lengths(
+            layout in arb_layout(),
+            len in 0..=MAX_PUBLIC_INPUTS_BYTES_LEN + 64,
+        ) {
+            let result = parse_public_inputs(&vec![0u8; len], layout);
+            prop_assert_eq!(result.is_ok(), layout.supports_bytes_len(len));
+            if result.is_err() {
+                prop_assert_eq!(result.unwrap_err(), error!(VeilpayError::InvalidPublicInputs));
+            }
+        }
+    }
+
+    #[test]
+    fn slot_window_bounds_are_inclusive() {
+        let mut parsed =
+            parse_public_inputs(&[0u8; PUBLIC_INPUTS_BYTES_LEN], SpendLayout::DEFAULT).unwrap();
+        assert!(check_slot_window(&parsed, 0).is_ok());
+
+        parsed.slot_window = Some((100, 200));
+        assert_eq!(
+            check_slot_window(&parsed, 99).unwrap_err(),
+            error!(VeilpayError::ProofNotYetValid)
+        );
+        assert!(check_slot_window(&parsed, 100).is_ok());
+        assert!(check_slot_window(&parsed, 200).is_ok());
+        assert_eq!(
+            check_slot_window(&parsed, 201).unwrap_err(),
+            error!(VeilpayError::ProofExpired)
+        );
+    }
+
+    #[test]
+    fn relayer_fee_recipient_closes_the_full_layout() {
+        let relayer = Pubkey::new_from_array([0xff; 32]);
+        let mut parsed =
+            parse_public_inputs(&[0u8; PUBLIC_INPUTS_BYTES_LEN], SpendLayout::DEFAULT).unwrap();
+        parsed.slot_window = Some((100, 200));
+        parsed.value_in = Some(500);
+        parsed.relayer_fee_recipient = Some(pubkey_to_field(&relayer));
+        let bytes = encode_public_inputs(&parsed, SpendLayout::DEFAULT);
+        assert_eq!(bytes.len(), SpendLayout::DEFAULT.max_len() * 32);
+        assert_eq!(&bytes[16 * 32..17 * 32], &encode_u64(500));
+        assert_eq!(parse_public_inputs(&bytes, SpendLayout::DEFAULT).unwrap(), parsed);
+
+        let mut raw = bytes.clone();
+        raw[17 * 32..].copy_from_slice(&relayer.to_bytes());
+        assert_eq!(
+            parse_public_inputs(&raw, SpendLayout::DEFAULT).unwrap_err(),
+            error!(VeilpayError::NonCanonicalPublicInput)
+        );
+    }
+
+    #[test]
+    fn pubkey_to_field_reduces_below_the_modulus() {
+        let small = Pubkey::new_from_array([0x01; 32]);
+        assert_eq!(pubkey_to_field(&small), small.to_bytes());
+        let reduced = pubkey_to_field(&Pubkey::new_from_array(SCALAR_FIELD_MODULUS));
+        assert_eq!(reduced, [0u8; 32]);
+        let field = pubkey_to_field(&Pubkey::new_from_array([0xff; 32]));
+        assert!(field < SCALAR_FIELD_MODULUS);
+        assert!(is_canonical_scalar(&field));
+    }
+
+    #[test]
+    fn domain_separator_binds_the_pool_mint() {
+        let mint = Pubkey::new_unique();
+        let mut parsed =
+            parse_public_inputs(&[0u8; PUBLIC_INPUTS_BYTES_LEN], SpendLayout::DEFAULT).unwrap();
+        assert_eq!(
+            check_domain(&parsed, &mint).unwrap_err(),
+            error!(VeilpayError::DomainMismatch)
+        );
+
+        parsed.domain = domain_separator(&mint);
+        assert!(is_canonical_scalar(&parsed.domain));
+        assert!(check_domain(&parsed, &mint).is_ok());
+        assert_eq!(
+            check_domain(&parsed, &Pubkey::new_unique()).unwrap_err(),
+            error!(VeilpayError::DomainMismatch)
+        );
+    }
+
+    #[test]
+    fn relayer_fee_must_go_to_the_committed_recipient() {
+        let relayer = Pubkey::new_unique();
+        let mut parsed =
+            parse_public_inputs(&[0u8; PUBLIC_INPUTS_BYTES_LEN], SpendLayout::DEFAULT).unwrap();
+        assert!(check_relayer_fee_recipient(&parsed, &Pubkey::new_unique()).is_ok());
+
+        parsed.relayer_fee_recipient = Some(pubkey_to_field(&relayer));
+        assert!(check_relayer_fee_recipient(&parsed, &relayer).is_ok());
+        assert_eq!(
+            check_relayer_fee_recipient(&parsed, &Pubkey::new_unique()).unwrap_err(),
+            error!(VeilpayError::RelayerRecipientMismatch)
+        );
+    }
+
+    #[test]
+    fn value_conservation_caps_amount_plus_fee() {
+        let mut parsed =
+            parse_public_inputs(&[0u8; PUBLIC_INPUTS_BYTES_LEN], SpendLayout::DEFAULT).unwrap();
+        parsed.amount_out = 900;
+        parsed.fee_amount = 100;
+        assert!(check_value_conservation(&parsed).is_ok());
+
+        parsed.value_in = Some(1_000);
+        assert!(check_value_conservation(&parsed).is_ok());
+        parsed.value_in = Some(999);
+        assert_eq!(
+            check_value_conservation(&parsed).unwrap_err(),
+            error!(VeilpayError::ValueConservationViolation)
+        );
+    }
+
+    #[test]
+    fn nullifiers_must_be_below_the_scalar_modulus() {
+        let mut below = SCALAR_FIELD_MODULUS;
+        below[31] -= 1;
+        assert!(is_canonical_scalar(&below));
+        let mut small = [0u8; 32];
+        small[4] = 0xA0;
+        assert!(is_canonical_scalar(&small));
+        assert!(!is_canonical_scalar(&SCALAR_FIELD_MODULUS));
+        assert!(!is_canonical_scalar(&[0xFF; 32]));
+    }
+
+    #[test]
+    fn public_inputs_reject_field_elements_at_or_above_the_modulus() {
+        let valid = ParsedPublicInputs {
+            root: [1u8; 32],
+            identity_root: [2u8; 32],
+            nullifiers: [[3u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]],
+            output_commitments: [[4u8; 32], [0u8; 32]],
+            output_enabled: [1, 0],
+            amount_out: 0,
+            fee_amount: 0,
+            circuit_id: 0,
+            domain: [5u8; 32],
+            slot_window: None,
+            value_in: None,
+            relayer_fee_recipient: None,
+        };
+        let layout = SpendLayout::DEFAULT;
+        assert!(parse_public_inputs(&encode_public_inputs(&valid, layout), layout).is_ok());
+        let parse_err = |parsed: ParsedPublicInputs| {
+            parse_public_inputs(&encode_public_inputs(&parsed, layout), layout)
+                .map(|_| ())
+                .unwrap_err()
+        };
+        let mut above = SCALAR_FIELD_MODULUS;
+        above[31] += 1;
+        for value in [SCALAR_FIELD_MODULUS, above] {
+            let non_canonical = error!(VeilpayError::NonCanonicalPublicInput);
+            let mut parsed = valid.clone();
+            parsed.root = value;
+            assert_eq!(parse_err(parsed), non_canonical);
+            let mut parsed = valid.clone();
+            parsed.identity_root = value;
+            assert_eq!(parse_err(parsed), non_canonical);
+            let mut parsed = valid.clone();
+            parsed.output_commitments[1] = value;
+            assert_eq!(parse_err(parsed), non_canonical);
+            let mut parsed = valid.clone();
+            parsed.domain = value;
+            assert_eq!(parse_err(parsed), non_canonical);
+            let mut parsed = valid.clone();
+            parsed.nullifiers[1] = value;
+            assert_eq!(parse_err(parsed), error!(VeilpayError::NonCanonicalNullifier));
+        }
+    }
+
+    #[test]
+    fn withdraw_split_takes_protocol_fee_then_relayer_fee() {
+        assert_eq!(
+            split_withdraw(10_000, 25, 50).unwrap(),
+            WithdrawSplit {
+                protocol_fee: 25,
+                relayer_fee: 50,
+                net_amount: 9_925,
+            }
+        );
+        assert_eq!(split_withdraw(10_000, 25, 50).unwrap().total_fee().unwrap(), 75);
+        assert_eq!(split_withdraw(10_000, 0, 0).unwrap().net_amount, 10_000);
+        assert_eq!(split_withdraw(u64::MAX, 0, 0).unwrap().net_amount, u64::MAX);
+        assert_eq!(
+            split_withdraw(10_000, 10_000, 0).unwrap_err(),
+            error!(VeilpayError::ProtocolFeeExceedsAmount)
+        );
+        assert_eq!(
+            split_withdraw(10_000, 5_000, 5_000).unwrap_err(),
+            error!(VeilpayError::FeesExceedAmount)
+        );
+    }
+
+    #[test]
+    fn fees_round_down_to_zero_below_the_boundary() {
+        // At 50 bps the relayer fee first reaches 1 at an amount of 200.
+        assert_eq!(split_relayer_fee(199, 50).unwrap(), (199, 0));
+        assert_eq!(split_relayer_fee(200, 50).unwrap(), (199, 1));
+        assert_eq!(split_relayer_fee(399, 50).unwrap(), (398, 1));
+        assert_eq!(split_protocol_fee(399, 25).unwrap(), (399, 0));
+        assert_eq!(split_protocol_fee(400, 25).unwrap(), (399, 1));
+
+        let dust = split_withdraw(199, 25, 50).unwrap();
+        assert_eq!(dust.total_fee().unwrap(), 0);
+        assert_eq!(dust.net_amount, 199);
+        assert_eq!(split_withdraw(400, 25, 50).unwrap().total_fee().unwrap(), 3);
+    }
+
+    #[test]
+    fn relayer_fee_bps_bounds_are_inclusive() {
+        let config = Config {
+            relayer_fee_bps_min: 10,
+            relayer_fee_bps_max: 50,
+            ..Config::default()
+        };
+        assert!(check_relayer_fee_bps(&config, 10).is_ok());
+        assert!(check_relayer_fee_bps(&config, 50).is_ok());
+        assert_eq!(
+            check_relayer_fee_bps(&config, 9).unwrap_err(),
+            error!(VeilpayError::RelayerFeeTooLow)
+        );
+        assert_eq!(
+            check_relayer_fee_bps(&config, 51).unwrap_err(),
+            error!(VeilpayError::RelayerFeeTooHigh)
+        );
+        assert!(check_relayer_fee_bps(&Config::default(), 0).is_ok());
+    }
+
+    #[test]
+    fn migrating_a_v1_config_keeps_its_settings_and_fills_defaults() {
+        let admin = Pubkey::new_unique();
+        let vk_registry = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        // Built field by field in the original layout: admin, fee_bps,
+        // relayer_fee_bps_max, vk_registry, mint_allowlist, circuit_ids, paused,
+        // version, bump.
+        let mut data = Config::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(admin.as_ref());
+        data.extend_from_slice(&25u16.to_le_bytes());
+        data.extend_from_slice(&50u16.to_le_bytes());
+        data.extend_from_slice(vk_registry.as_ref());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(mint.as_ref());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.push(254);
+        // A v1 account keeps its full allocation, and a shrunk list leaves stale bytes.
+        data.resize(8 + 32 + 2 + 2 + 32 + (4 + 32 * 32) + (4 + 8 * 4) + 1 + 4 + 1, 0xaa);
+
+        let mut config = decode_config(&data).unwrap();
+        assert_eq!(migrate_config_fields(&mut config).unwrap(), 1);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.admin, admin);
+        assert_eq!(config.fee_bps, 25);
+        assert_eq!(config.relayer_fee_bps_max, 50);
+        assert_eq!(config.vk_registry, vk_registry);
+        assert_eq!(config.mint_allowlist, vec![mint]);
+        assert_eq!(config.circuit_ids, vec![0, 7]);
+        assert!(config.paused);
+        assert_eq!(config.bump, 254);
+        assert_eq!(config.fee_authority, admin);
+        assert_eq!(config.pending_admin, Pubkey::default());
+        assert_eq!(config.relayer_fee_bps_min, 0);
+        assert_eq!(config.verifier_program, verifier::ID);
+        assert_eq!(config.event_flags, EVENT_FLAGS_ALL);
+        assert_eq!(config.nullifier_chunks, DEFAULT_NULLIFIER_CHUNKS);
+
+        let mut migrated = vec![0u8; 8 + Config::INIT_SPACE];
+        config.try_serialize(&mut &mut migrated[..]).unwrap();
+        let mut reread = decode_config(&migrated).unwrap();
+        assert_eq!(migrate_config_fields(&mut reread).unwrap(), CONFIG_VERSION);
+        assert_eq!(reread.fee_authority, admin);
+        assert_eq!(reread.mint_allowlist, vec![mint]);
+
+        data[0] ^= 1;
+        assert_eq!(
+            decode_config(&data).err(),
+            Some(error!(ErrorCode::AccountDiscriminatorMismatch))
+        );
+        for version in [0, CONFIG_VERSION + 1] {
+            reread.version = version;
+            assert_eq!(
+                migrate_config_fields(&mut reread).unwrap_err(),
+                error!(VeilpayError::UnsupportedConfigVersion)
+            );
+        }
+    }
+
+    #[test]
+    fn vault_balance_must_cover_the_payout_and_every_fee() {
+        let split = split_withdraw(10_000, 25, 50).unwrap();
+        assert!(check_vault_balance(10_000, &split).is_ok());
+        assert_eq!(
+            check_vault_balance(9_999, &split).unwrap_err(),
+            error!(VeilpayError::InsufficientVaultBalance)
+        );
+        let overflowing = WithdrawSplit {
+            protocol_fee: 0,
+            relayer_fee: 1,
+            net_amount: u64::MAX,
+        };
+        assert_eq!(
+            check_vault_balance(u64::MAX, &overflowing).unwrap_err(),
+            error!(VeilpayError::MathOverflow)
+        );
+    }
+
+    fn identity_registry(merkle_root: [u8; 32], identity_required: bool) -> IdentityRegistry {
+        IdentityRegistry {
+            merkle_root,
+            denylist_root: ZERO_ROOT,
+            commitment_count: 0,
+            depth: IDENTITY_TREE_DEPTH as u8,
+            identity_admin: Pubkey::default(),
+            identity_required,
+            filled_subtrees: Vec::new(),
+            zero_hashes: Vec::new(),
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn identity_root_check_follows_identity_required() {
+        let allowlist = VaultPool::default();
+        let populated = identity_registry([7u8; 32], true);
+        assert!(check_identity_root(&allowlist, &populated, [7u8; 32]).is_ok());
+        assert_eq!(
+            check_identity_root(&allowlist, &populated, [8u8; 32]).unwrap_err(),
+            error!(VeilpayError::IdentityRootMismatch)
+        );
+
+        let empty = identity_registry(ZERO_ROOT, true);
+        assert_eq!(
+            check_identity_root(&allowlist, &empty, ZERO_ROOT).unwrap_err(),
+            error!(VeilpayError::IdentityRegistryEmpty)
+        );
+        // The empty denylist still admits everyone.
+        let denylist = VaultPool {
+            identity_policy: IDENTITY_POLICY_DENYLIST,
+            ..VaultPool::default()
+        };
+        assert!(check_identity_root(&denylist, &empty, ZERO_ROOT).is_ok());
+
+        for registry in [identity_registry(ZERO_ROOT, false), identity_registry([7u8; 32], false)] {
+            assert!(check_identity_root(&allowlist, &registry, [8u8; 32]).is_ok());
+            assert!(check_identity_root(&denylist, &registry, [8u8; 32]).is_ok());
+        }
+    }
+
+    #[test]
+    fn lamport_relayer_fee_replaces_the_token_rate() {
+        let config = Config {
+            relayer_fee_bps_min: 10,
+            relayer_fee_bps_max: 50,
+            ..Config::default()
+        };
+        assert!(check_relayer_fee_mode(&config, 0, 5_000).is_ok());
+        assert_eq!(
+            check_relayer_fee_mode(&config, 10, 5_000).unwrap_err(),
+            error!(VeilpayError::InvalidRelayerFeeMode)
+        );
+        assert_eq!(
+            check_relayer_fee_mode(&config, 0, 0).unwrap_err(),
+            error!(VeilpayError::RelayerFeeTooLow)
+        );
+        assert!(check_relayer_fee_mode(&config, 10, 0).is_ok());
+    }
+
+    #[test]
+    fn vault_caps_allow_exactly_the_cap() {
+        let vault = VaultPool {
+            total_deposited: 600,
+            deposit_cap: 1_000,
+            per_withdraw_cap: 250,
+            ..VaultPool::default()
+        };
+        assert_eq!(check_deposit_cap(&vault, 400).unwrap(), 1_000);
+        assert_eq!(
+            check_deposit_cap(&vault, 401).unwrap_err(),
+            error!(VeilpayError::DepositCapExceeded)
+        );
+        assert!(check_withdraw_cap(&vault, 250).is_ok());
+        assert_eq!(
+            check_withdraw_cap(&vault, 251).unwrap_err(),
+            error!(VeilpayError::WithdrawCapExceeded)
+        );
+
+        let unlimited = VaultPool {
+            total_deposited: u64::MAX - 1,
+            ..VaultPool::default()
+        };
+        assert_eq!(check_deposit_cap(&unlimited, 1).unwrap(), u64::MAX);
+        assert_eq!(
+            check_deposit_cap(&unlimited, 2).unwrap_err(),
+            error!(VeilpayError::MathOverflow)
+        );
+        assert!(check_withdraw_cap(&unlimited, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn min_deposit_allows_exactly_the_minimum() {
+        let vault = VaultPool {
+            min_deposit: 1_000,
+            ..VaultPool::default()
+        };
+        assert!(check_min_deposit(&vault, 1_000).is_ok());
+        assert_eq!(
+            check_min_deposit(&vault, 999).unwrap_err(),
+            error!(VeilpayError::DepositTooSmall)
+        );
+        assert!(check_min_deposit(&VaultPool::default(), 0).is_ok());
+    }
+
+    #[test]
+    fn fee_exempt_owners_skip_only_the_protocol_fee() {
+        let exempt = Pubkey::new_unique();
+        let config = Config {
+            fee_bps: 25,
+            fee_exempt: vec![exempt],
+            ..Config::default()
+        };
+        assert_eq!(protocol_fee_bps(&config, Some(exempt)), 0);
+        assert_eq!(protocol_fee_bps(&config, Some(Pubkey::new_unique())), 25);
+        assert_eq!(protocol_fee_bps(&config, None), 25);
+    }
+
+    #[test]
+    fn vk_binding_requires_the_active_registered_key() {
+        let key = Pubkey::new_unique();
+        let mut registry = VkRegistry {
+            entries: vec![VkEntry {
+                circuit_id: 3,
+                vk_account: key,
+                vk_hash: [0u8; 32],
+                status: VK_STATUS_ACTIVE,
+            }],
+            bump: 0,
+            capacity: 0,
+        };
+        assert!(check_vk_binding(&registry, 3, key).is_ok());
+        assert_eq!(
+            check_vk_binding(&registry, 4, key).unwrap_err(),
+            error!(VeilpayError::CircuitNotRegistered)
+        );
+        assert_eq!(
+            check_vk_binding(&registry, 3, Pubkey::new_unique()).unwrap_err(),
+            error!(VeilpayError::VkCircuitMismatch)
+        );
+        registry.entries[0].status = VK_STATUS_INACTIVE;
+        assert_eq!(
+            check_vk_binding(&registry, 3, key).unwrap_err(),
+            error!(VeilpayError::VkInactive)
+        );
+    }
+
+    #[test]
+    fn vk_registry_caps_entries_and_toggles_status() {
+        let entry = |circuit_id: u32| VkEntry {
+            circuit_id,
+            vk_account: Pubkey::new_unique(),
+            vk_hash: [0u8; 32],
+            status: VK_STATUS_ACTIVE,
+        };
+        let mut registry = VkRegistry {
+            entries: Vec::new(),
+            bump: 0,
+            capacity: 0,
+        };
+        for circuit_id in 0..MAX_VK_ENTRIES as u32 {
+            upsert_vk_entry(&mut registry, entry(circuit_id)).unwrap();
+        }
+        assert_eq!(
+            upsert_vk_entry(&mut registry, entry(MAX_VK_ENTRIES as u32)).unwrap_err(),
+            error!(VeilpayError::VkRegistryFull)
+        );
+        let replacement = entry(0);
+        let key = replacement.vk_account;
+        upsert_vk_entry(&mut registry, replacement).unwrap();
+        assert_eq!(registry.entries.len(), MAX_VK_ENTRIES);
+
+        set_vk_entry_status(&mut registry, 0, VK_STATUS_INACTIVE).unwrap();
+        assert_eq!(
+            check_vk_binding(&registry, 0, key).unwrap_err(),
+            error!(VeilpayError::VkInactive)
+        );
+        set_vk_entry_status(&mut registry, 0, VK_STATUS_ACTIVE).unwrap();
+        assert!(check_vk_binding(&registry, 0, key).is_ok());
+        assert_eq!(
+            set_vk_entry_status(&mut registry, 0, 2).unwrap_err(),
+            error!(VeilpayError::InvalidVkStatus)
+        );
+        assert_eq!(
+            set_vk_entry_status(&mut registry, MAX_VK_ENTRIES as u32, VK_STATUS_INACTIVE)
+                .unwrap_err(),
+            error!(VeilpayError::CircuitNotRegistered)
+        );
+    }
+
+    #[test]
+    fn vk_registry_capacity_grows_past_the_default() {
+        assert_eq!(vk_registry_space(MAX_VK_ENTRIES), 8 + VkRegistry::INIT_SPACE);
+        assert_eq!(
+            vk_registry_space(MAX_VK_ENTRIES + 1) - vk_registry_space(MAX_VK_ENTRIES),
+            VkEntry::INIT_SPACE
+        );
+        let entry = |circuit_id: u32| VkEntry {
+            circuit_id,
+            vk_account: Pubkey::new_unique(),
+            vk_hash: [0u8; 32],
+            status: VK_STATUS_ACTIVE,
+        };
+        let mut registry = VkRegistry {
+            entries: (0..MAX_VK_ENTRIES as u32).map(entry).collect(),
+            bump: 0,
+            capacity: 0,
+        };
+        assert_eq!(vk_registry_capacity(&registry), MAX_VK_ENTRIES);
+        let extra = MAX_VK_ENTRIES as u32;
+        assert_eq!(
+            upsert_vk_entry(&mut registry, entry(extra)).unwrap_err(),
+            error!(VeilpayError::VkRegistryFull)
+        );
+        registry.capacity = MAX_VK_ENTRIES as u16 + 1;
+        upsert_vk_entry(&mut registry, entry(extra)).unwrap();
+        assert_eq!(registry.entries.len(), MAX_VK_ENTRIES + 1);
+    }
+
+    fn empty_tree(depth: u8) -> ShieldedState {
+        let mut state = ShieldedState {
+            mint: Pubkey::default(),
+            merkle_root: [0u8; 32],
+            root_history: Vec::new(),
+            root_history_index: 0,
+            root_slots: Vec::new(),
+            root_history_len: MAX_ROOT_HISTORY as u32,
+            evicted_root: None,
+            evicted_root_slot: 0,
+            evicted_at_slot: 0,
+            commitment_count: 0,
+            circuit_id: None,
+            circuit_ids: Vec::new(),
+            tree_depth: 0,
+            filled_subtrees: Vec::new(),
+            zero_hashes: Vec::new(),
+            max_inputs: 0,
+            max_outputs: 0,
+            version: 0,
+            bump: 0,
+        };
+        init_tree(&mut state, depth).unwrap();
+        state
+    }
+
+    fn leaf(value: u8) -> [u8; 32] {
+        let mut leaf = [0u8; 32];
+        leaf[31] = value;
+        leaf
+    }
+
+    #[test]
+    fn first_append_hashes_the_leaf_against_the_empty_subtrees() {
+        let mut state = empty_tree(20);
+        assert_eq!(state.merkle_root, ZERO_ROOT);
+
+        let mut expected = leaf(7);
+        for level in 0..20 {
+            expected = poseidon_pair(&expected, &state.zero_hashes[level]).unwrap();
+        }
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(7)], [0u8; 32]).unwrap_err(),
+            error!(VeilpayError::NewRootMismatch)
+        );
+        let mut state = empty_tree(20);
+        append_commitments(&mut state, &[leaf(7)], expected).unwrap();
+        assert_eq!(state.filled_subtrees[0], leaf(7));
+    }
+
+    #[test]
+    fn appends_match_a_full_recomputation() {
+        let mut state = empty_tree(2);
+        let leaves = [leaf(1), leaf(2), leaf(3), leaf(4)];
+        let left = poseidon_pair(&leaves[0], &leaves[1]).unwrap();
+        let right = poseidon_pair(&leaves[2], &leaves[3]).unwrap();
+        let full = poseidon_pair(&left, &right).unwrap();
+
+        let partial = poseidon_pair(&left, &poseidon_pair(&leaves[2], &[0u8; 32]).unwrap()).unwrap();
+        append_commitments(&mut state, &leaves[..3], partial).unwrap();
+        assert_eq!(state.commitment_count, 3);
+        append_commitments(&mut state, &leaves[3..], full).unwrap();
+        assert_eq!(state.commitment_count, 4);
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(5)], full).unwrap_err(),
+            error!(VeilpayError::TreeFull)
+        );
+    }
+
+    #[test]
+    fn pools_cannot_be_created_without_a_tree() {
+        let mut state = empty_tree(4);
+        assert_eq!(
+            init_tree(&mut state, 0).unwrap_err(),
+            error!(VeilpayError::InvalidTreeDepth)
+        );
+        assert_eq!(state.tree_depth, 4);
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(1)], [9u8; 32]).unwrap_err(),
+            error!(VeilpayError::NewRootMismatch)
+        );
+    }
+
+    #[test]
+    fn nullifier_chunks_come_from_the_hash_not_the_leading_bytes() {
+        let first = [0u8; 32];
+        let second = (1u8..=u8::MAX)
+            .map(|last| {
+                let mut candidate = first;
+                candidate[31] = last;
+                candidate
+            })
+            .find(|candidate| nullifier_position(candidate, 1024).0 != nullifier_position(&first, 1024).0)
+            .unwrap();
+        assert_eq!(first[..4], second[..4]);
+        assert_ne!(
+            nullifier_position(&first, 1024).0,
+            nullifier_position(&second, 1024).0
+        );
+
+        let mut steered = [0u8; 32];
+        steered[..4].copy_from_slice(&1227u32.to_le_bytes());
+        let digest = keccak::hashv(&[&steered]).to_bytes();
+        let (chunk_index, _) = nullifier_position(&steered, 1024);
+        assert_eq!(
+            chunk_index,
+            u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1024
+        );
+        assert_eq!(
+            nullifier_position(&steered, 1),
+            (0, u16::from_le_bytes([digest[4], digest[5]]) % NULLIFIER_BITS as u16)
+        );
+    }
+
+    #[test]
+    fn distinct_nullifiers_do_not_collide_at_the_default_chunk_count() {
+        // Same leading bytes, which used to pick the bit directly.
+        let mut first = [0u8; 32];
+        first[4] = 0x34;
+        first[5] = 0xa2;
+        let mut second = first;
+        second[31] = 1;
+        assert_ne!(
+            nullifier_position(&first, DEFAULT_NULLIFIER_CHUNKS),
+            nullifier_position(&second, DEFAULT_NULLIFIER_CHUNKS)
+        );
+
+        let positions: std::collections::HashSet<_> = (0u16..1024)
+            .map(|tweak| {
+                let mut nullifier = first;
+                nullifier[30..].copy_from_slice(&tweak.to_be_bytes());
+                nullifier_position(&nullifier, DEFAULT_NULLIFIER_CHUNKS)
+            })
+            .collect();
+        assert_eq!(positions.len(), 1024);
+    }
+
+    #[test]
+    fn known_nullifier_maps_to_a_fixed_chunk_and_bit() {
+        let mut nullifier = [0u8; 32];
+        nullifier[4] = 0x34;
+        nullifier[5] = 0xa2;
+        nullifier[31] = 0x2a;
+        assert_eq!(nullifier_position(&nullifier, 16), (9, 4681));
+        assert_eq!(nullifier_position(&nullifier, 1), (0, 4681));
+    }
+
+    #[test]
+    fn mark_nullifier_rejects_sets_for_another_chunk() {
+        let nullifier = [7u8; 32];
+        let (chunk_index, bit_index) = nullifier_position(&nullifier, 16);
+        let mut set = NullifierSet {
+            mint: Pubkey::default(),
+            chunk_index: (chunk_index + 1) % 16,
+            chunk_count: 16,
+            bitset: [0u8; NULLIFIER_BYTES],
+            count: 0,
+            bump: 0,
+        };
+        assert_eq!(
+            mark_nullifier(&mut set, nullifier).unwrap_err(),
+            error!(VeilpayError::NullifierChunkMismatch)
+        );
+        set.chunk_index = chunk_index;
+        mark_nullifier(&mut set, nullifier).unwrap();
+        assert_ne!(set.bitset[(bit_index / 8) as usize] & (1 << (bit_index % 8)), 0);
+        assert_eq!(
+            mark_nullifier(&mut set, nullifier).unwrap_err(),
+            error!(VeilpayError::NullifierAlreadyUsed)
+        );
+    }
+
+    fn nullifier_in_chunk(chunk_index: u32, chunk_count: u32) -> [u8; 32] {
+        (1u8..=u8::MAX)
+            .map(|tweak| {
+                let mut nullifier = [0u8; 32];
+                nullifier[31] = tweak;
+                nullifier
+            })
+            .find(|nullifier| nullifier_position(nullifier, chunk_count).0 == chunk_index)
+            .unwrap()
+    }
+
+    fn nullifier_set_data(mint: Pubkey, chunk_index: u32, spent: &[[u8; 32]]) -> Vec<u8> {
+        let mut set = NullifierSet {
+            mint,
+            chunk_index,
+            chunk_count: 2,
+            bitset: [0u8; NULLIFIER_BYTES],
+            count: 0,
+            bump: 0,
+        };
+        for nullifier in spent {
+            mark_nullifier(&mut set, *nullifier).unwrap();
+        }
+        let mut data = Vec::new();
+        set.try_serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn mark_nullifiers_checks_every_chunk_before_marking_any() {
+        let mint = Pubkey::new_unique();
+        let first = nullifier_in_chunk(0, 2);
+        let last = nullifier_in_chunk(1, 2);
+        let mut nullifiers = [[0u8; 32]; MAX_INPUTS];
+        nullifiers[0] = first;
+        nullifiers[1] = last;
+
+        let primary_key = nullifier_set_address(&mint, 0);
+        let other_key = nullifier_set_address(&mint, 1);
+        let (mut primary_lamports, mut other_lamports) = (1u64, 1u64);
+        let mut primary_data = nullifier_set_data(mint, 0, &[]);
+        let mut other_data = nullifier_set_data(mint, 1, &[last]);
+        let primary_info = AccountInfo::new(
+            &primary_key,
+            false,
+            true,
+            &mut primary_lamports,
+            &mut primary_data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let remaining = [AccountInfo::new(
+            &other_key,
+            false,
+            true,
+            &mut other_lamports,
+            &mut other_data,
+            &crate::ID,
+            false,
+            0,
+        )];
+        let mut primary = Account::<NullifierSet>::try_from(&primary_info).unwrap();
+        let untouched = |set: &NullifierSet| set.count == 0 && set.bitset.iter().all(|b| *b == 0);
+        let vault = VaultPool {
+            mint,
+            nullifier_chunks: 2,
+            ..VaultPool::default()
+        };
+
+        // Another mint's set at the same index cannot stand in as the primary.
+        let decoy_mint = Pubkey::new_unique();
+        let decoy_key = nullifier_set_address(&decoy_mint, 0);
+        let mut decoy_lamports = 1u64;
+        let mut decoy_data = nullifier_set_data(decoy_mint, 0, &[]);
+        let decoy_info = AccountInfo::new(
+            &decoy_key,
+            false,
+            true,
+            &mut decoy_lamports,
+            &mut decoy_data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let mut decoy = Account::<NullifierSet>::try_from(&decoy_info).unwrap();
+        let mut fresh = [[0u8; 32]; MAX_INPUTS];
+        fresh[0] = first;
+        assert_eq!(
+            mark_nullifiers(&mut decoy, &remaining, &vault, &fresh).unwrap_err(),
+            error!(VeilpayError::NullifierChunkMismatch)
+        );
+        assert!(untouched(&decoy));
+
+        assert_eq!(
+            mark_nullifiers(&mut primary, &[], &vault, &nullifiers).unwrap_err(),
+            error!(VeilpayError::MissingNullifierAccount)
+        );
+        assert!(untouched(&primary));
+
+        assert_eq!(
+            mark_nullifiers(&mut primary, &remaining, &vault, &nullifiers).unwrap_err(),
+            error!(VeilpayError::NullifierAlreadyUsed)
+        );
+        assert!(untouched(&primary));
+
+        let mut repeated = [[0u8; 32]; MAX_INPUTS];
+        repeated[0] = first;
+        repeated[1] = first;
+        assert_eq!(
+            mark_nullifiers(&mut primary, &remaining, &vault, &repeated).unwrap_err(),
+            error!(VeilpayError::NullifierAlreadyUsed)
+        );
+        assert!(untouched(&primary));
+
+        mark_nullifiers(&mut primary, &remaining, &vault, &fresh).unwrap();
+        assert_eq!(primary.count, 1);
+        assert_eq!(Account::<NullifierSet>::try_from(&remaining[0]).unwrap().count, 1);
+    }
+
+    #[test]
+    fn identity_frontier_starts_at_the_circuit_empty_root() {
+        let (zero_hashes, empty_root) = empty_subtrees(IDENTITY_TREE_DEPTH as u8).unwrap();
+        assert_eq!(empty_root, ZERO_ROOT);
+        let mut filled_subtrees = zero_hashes.clone();
+        let first = frontier_append(&mut filled_subtrees, &zero_hashes, 0, leaf(1)).unwrap();
+        let second = frontier_append(&mut filled_subtrees, &zero_hashes, 1, leaf(2)).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            frontier_append(&mut filled_subtrees, &zero_hashes, 1 << IDENTITY_TREE_DEPTH, leaf(3))
+                .unwrap_err(),
+            error!(VeilpayError::TreeFull)
+        );
+    }
+
+    #[test]
+    fn proof_system_selects_the_verifier_key() {
+        let (primary, alt) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(select_verifier_key(PROOF_SYSTEM_PRIMARY, primary, Some(alt)).unwrap(), primary);
+        assert_eq!(select_verifier_key(PROOF_SYSTEM_PRIMARY, primary, None).unwrap(), primary);
+        assert_eq!(select_verifier_key(PROOF_SYSTEM_ALT, primary, Some(alt)).unwrap(), alt);
+        assert_eq!(
+            select_verifier_key(PROOF_SYSTEM_ALT, primary, None).unwrap_err(),
+            error!(VeilpayError::MissingVerifierAccounts)
+        );
+        assert_eq!(
+            select_verifier_key(2, primary, Some(alt)).unwrap_err(),
+            error!(VeilpayError::InvalidProofSystem)
+        );
+    }
+
+    #[test]
+    fn valid_roots_list_the_current_root_then_the_history_in_order() {
+        let mut state = empty_tree(1);
+        let empty_root = state.merkle_root;
+        assert_eq!(valid_roots(&state, 0, 0), vec![empty_root]);
+
+        for value in 1..=10u8 {
+            append_root(&mut state, leaf(value), value as u64, 0);
+        }
+        let expected: Vec<_> = [10u8].into_iter().chain(1..10).map(leaf).collect();
+        assert_eq!(valid_roots(&state, 10, 0), expected);
+
+        // Past the wrap the ring's oldest entry sits at `root_history_index`.
+        for value in 11..=40u8 {
+            append_root(&mut state, leaf(value), value as u64, 0);
+        }
+        assert_ne!(state.root_history_index, 0);
+        let roots = valid_roots(&state, 40 + ROOT_GRACE_SLOTS + 1, 0);
+        assert_eq!(roots.len(), MAX_ROOT_HISTORY);
+        let expected: Vec<_> = [40u8].into_iter().chain(9..40).map(leaf).collect();
+        assert_eq!(roots, expected);
+
+        // Only roots `root_known` would still accept are listed.
+        let roots = valid_roots(&state, 40, 5);
+        let expected: Vec<_> = [40u8].into_iter().chain(35..40).map(leaf).collect();
+        assert_eq!(roots, expected);
+        assert!(roots.iter().all(|root| root_known(&state, *root, 40, 5)));
+        assert!(!root_known(&state, leaf(34), 40, 5));
+    }
+
+    #[test]
+    fn overwritten_root_is_accepted_for_one_more_slot() {
+        let mut state = empty_tree(1);
+        state.root_history_len = 4;
+        for value in 1..=4u8 {
+            append_root(&mut state, leaf(value), value as u64 * 10, 0);
+        }
+        assert_eq!(state.evicted_root, None);
+
+        // The fifth root overwrites the first at slot 50.
+        append_root(&mut state, leaf(5), 50, 0);
+        assert_eq!(state.root_history.len(), 4);
+        assert!(root_known(&state, leaf(1), 50, 0));
+        assert!(root_known(&state, leaf(1), 50 + ROOT_GRACE_SLOTS, 0));
+        assert!(!root_known(&state, leaf(1), 50 + ROOT_GRACE_SLOTS + 1, 0));
+        assert_eq!(
+            valid_roots(&state, 50, 0),
+            [5u8, 1, 2, 3, 4].map(leaf).to_vec()
+        );
+        assert_eq!(
+            valid_roots(&state, 52, 0),
+            [5u8, 2, 3, 4].map(leaf).to_vec()
+        );
+
+        // The grace window does not outlive the age limit.
+        assert!(!root_known(&state, leaf(1), 50, 39));
+        assert!(root_known(&state, leaf(1), 50, 40));
+
+        // Only the most recently overwritten root gets the grace window.
+        append_root(&mut state, leaf(6), 50, 0);
+        assert!(!root_known(&state, leaf(1), 50, 0));
+        assert!(root_known(&state, leaf(2), 50, 0));
+    }
+
+    #[test]
+    fn pinned_pool_accepts_only_its_circuit() {
+        let mut state = empty_tree(1);
+        assert!(check_pool_circuit(&state, 0).is_ok());
+        assert!(check_pool_circuit(&state, 7).is_ok());
+
+        state.circuit_id = Some(0);
+        assert!(check_pool_circuit(&state, 0).is_ok());
+        assert_eq!(
+            check_pool_circuit(&state, 7).unwrap_err(),
+            error!(VeilpayError::PoolCircuitMismatch)
+        );
+    }
+
+    #[test]
+    fn relayer_allowlist_applies_only_when_enabled() {
+        let relayer = Pubkey::new_unique();
+        let mut config = Config::default();
+        assert!(check_relayer_allowed(&config, &relayer).is_ok());
+
+        config.relayer_allowlist_enabled = true;
+        assert_eq!(
+            check_relayer_allowed(&config, &relayer).unwrap_err(),
+            error!(VeilpayError::RelayerNotAllowed)
+        );
+        config.relayer_allowlist.push(relayer);
+        assert!(check_relayer_allowed(&config, &relayer).is_ok());
+        assert!(check_relayer_allowed(&config, &Pubkey::new_unique()).is_err());
+    }
+
+    #[test]
+    fn depth_four_tree_rejects_the_seventeenth_commitment() {
+        let mut state = empty_tree(4);
+        for index in 0..16u64 {
+            let value = leaf(index as u8 + 1);
+            let mut shadow = state.filled_subtrees.clone();
+            let root = frontier_append(&mut shadow, &state.zero_hashes, index, value).unwrap();
+            append_commitments(&mut state, &[value], root).unwrap();
+        }
+        assert_eq!(state.commitment_count, 16);
+
+        let frontier = state.filled_subtrees.clone();
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(17)], [0u8; 32]).unwrap_err(),
+            error!(VeilpayError::TreeFull)
+        );
+        assert_eq!(state.commitment_count, 16);
+        assert_eq!(state.filled_subtrees, frontier);
+
+        // A batch straddling the boundary is refused as a whole.
+        let mut state = empty_tree(1);
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(1), leaf(2), leaf(3)], [0u8; 32]).unwrap_err(),
+            error!(VeilpayError::TreeFull)
+        );
+        assert_eq!(state.commitment_count, 0);
+
+        let mut state = empty_tree(4);
+        state.tree_depth = 0;
+        state.commitment_count = u64::MAX;
+        assert_eq!(
+            append_commitments(&mut state, &[leaf(1)], [0u8; 32]).unwrap_err(),
+            error!(VeilpayError::MathOverflow)
+        );
+    }
+
+    #[test]
+    fn global_pause_overrides_the_vault_flag() {
+        let config = Config::default();
+        let vault = VaultPool::default();
+        assert!(check_not_paused(&config, &vault).is_ok());
+        let paused_vault = VaultPool {
+            paused: true,
+            ..VaultPool::default()
+        };
+        assert_eq!(
+            check_not_paused(&config, &paused_vault).unwrap_err(),
+            error!(VeilpayError::VaultPaused)
+        );
+        let paused_config = Config {
+            paused: true,
+            ..Config::default()
+        };
+        for vault in [&vault, &paused_vault] {
+            assert_eq!(
+                check_not_paused(&paused_config, vault).unwrap_err(),
+                error!(VeilpayError::ProtocolPaused)
+            );
+        }
+    }
+
+    #[test]
+    fn spend_layout_is_bounded_and_defaults_for_older_pools() {
+        assert_eq!(SpendLayout::DEFAULT.base_len(), 14);
+        assert_eq!(SpendLayout::new(1, 1).unwrap().base_len(), 9);
+        for (inputs, outputs) in [(0, 2), (4, 0), (MAX_INPUTS + 1, 2), (4, MAX_OUTPUTS + 1)] {
+            assert_eq!(SpendLayout::new(inputs, outputs), None);
+        }
+
+        let mut state = empty_tree(1);
+        assert_eq!(spend_layout(&state), SpendLayout::DEFAULT);
+        state.max_inputs = 2;
+        state.max_outputs = 1;
+        assert_eq!(
+            spend_layout(&state),
+            SpendLayout {
+                inputs: 2,
+                outputs: 1
+            }
+        );
+    }
 }
@@ -0,0 +1,49 @@
+//! Test-only program that calls `verifier::try_verify_groth16` over CPI, the way an
+//! integrating program would, and hands the verdict back as its own return data.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::{get_return_data, set_return_data};
+use verifier::cpi::accounts::VerifyGroth16 as VerifyGroth16Cpi;
+
+declare_id!("5doXwgQC8axbkbzDbgE46Wa9mKG1Nm6f4vj28Bo17F9w");
+
+#[program]
+pub mod verifier_probe {
+    use super::*;
+
+    /// Asks the verifier about each key in turn, as a caller choosing between keys
+    /// would, and returns one verdict byte per key.
+    pub fn probe<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Probe<'info>>,
+        proof: Vec<u8>,
+        public_inputs: Vec<u8>,
+    ) -> Result<()> {
+        let mut verdicts = Vec::with_capacity(ctx.remaining_accounts.len());
+        for verifier_key in ctx.remaining_accounts {
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.verifier_program.to_account_info(),
+                VerifyGroth16Cpi {
+                    verifier_key: verifier_key.clone(),
+                },
+            );
+            verifier::cpi::try_verify_groth16(cpi_ctx, proof.clone(), public_inputs.clone())?;
+            let (program_id, data) = get_return_data().ok_or(ProbeError::MissingVerdict)?;
+            require_keys_eq!(program_id, verifier::ID, ProbeError::MissingVerdict);
+            require!(data.len() == 1, ProbeError::MissingVerdict);
+            verdicts.push(data[0]);
+        }
+        set_return_data(&verdicts);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Probe<'info> {
+    pub verifier_program: Program<'info, verifier::program::Verifier>,
+}
+
+#[error_code]
+pub enum ProbeError {
+    #[msg("Verifier returned no verdict")]
+    MissingVerdict,
+}
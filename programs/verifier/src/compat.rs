@@ -0,0 +1,233 @@
+//! Off-chain helpers for Groth16 data produced by other toolchains. snarkjs JSON lists
+//! each G2 coordinate as `[c0, c1]` while the syscalls want `c1` first, and some
+//! exporters emit little-endian words, so integrators can find the encoding a proof
+//! verifies under and normalize it before submission.
+
+use solana_bn254::prelude::{
+    alt_bn128_g1_addition_be, alt_bn128_g1_addition_le, alt_bn128_g1_multiplication_be,
+    alt_bn128_g1_multiplication_le, alt_bn128_pairing_be, alt_bn128_pairing_le,
+    ALT_BN128_G1_MULTIPLICATION_INPUT_SIZE, ALT_BN128_PAIRING_ELEMENT_SIZE,
+};
+
+use crate::{negate_g1, pairing_is_one};
+
+/// Byte order of each 32-byte word, matching the `_be`/`_le` syscall families.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Be,
+    Le,
+}
+
+/// Order of the two halves of each G2 coordinate: as supplied (snarkjs lists `c0`
+/// first) or swapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum G2Order {
+    Snarkjs,
+    Swapped,
+}
+
+/// Verifier key points as big-endian words, G2 halves in the order they were supplied.
+#[derive(Clone, Debug)]
+pub struct CompatKey {
+    pub alpha_g1: [u8; 64],
+    pub beta_g2: [u8; 128],
+    pub gamma_g2: [u8; 128],
+    pub delta_g2: [u8; 128],
+    pub gamma_abc: Vec<[u8; 64]>,
+}
+
+/// Proof points in the same convention as [`CompatKey`].
+#[derive(Clone, Copy, Debug)]
+pub struct CompatProof {
+    pub a: [u8; 64],
+    pub b: [u8; 128],
+    pub c: [u8; 64],
+}
+
+/// Tries every endianness and G2 order for the key and the proof, returning the first
+/// `(endian, vk_order, proof_order)` under which the pairing check passes.
+pub fn detect_encoding(
+    vk: &CompatKey,
+    proof: &CompatProof,
+    public_inputs: &[[u8; 32]],
+) -> Option<(Endian, G2Order, G2Order)> {
+    if vk.gamma_abc.len() != public_inputs.len() + 1 {
+        return None;
+    }
+    for endian in [Endian::Be, Endian::Le] {
+        for vk_order in [G2Order::Snarkjs, G2Order::Swapped] {
+            for proof_order in [G2Order::Snarkjs, G2Order::Swapped] {
+                if verifies(vk, proof, public_inputs, endian, vk_order, proof_order) == Some(true) {
+                    return Some((endian, vk_order, proof_order));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Re-encodes a big-endian word for `endian`.
+pub fn encode_scalar(word: &[u8; 32], endian: Endian) -> [u8; 32] {
+    let mut out = *word;
+    if endian == Endian::Le {
+        out.reverse();
+    }
+    out
+}
+
+pub fn encode_g1(point: &[u8; 64], endian: Endian) -> [u8; 64] {
+    let mut out = *point;
+    if endian == Endian::Le {
+        out[..32].reverse();
+        out[32..].reverse();
+    }
+    out
+}
+
+pub fn encode_g2(point: &[u8; 128], order: G2Order, endian: Endian) -> [u8; 128] {
+    let mut out = *point;
+    if order == G2Order::Swapped {
+        for coordinate in out.chunks_exact_mut(64) {
+            let (c0, c1) = coordinate.split_at_mut(32);
+            c0.swap_with_slice(c1);
+        }
+    }
+    if endian == Endian::Le {
+        out.chunks_exact_mut(32).for_each(<[u8]>::reverse);
+    }
+    out
+}
+
+fn verifies(
+    vk: &CompatKey,
+    proof: &CompatProof,
+    public_inputs: &[[u8; 32]],
+    endian: Endian,
+    vk_order: G2Order,
+    proof_order: G2Order,
+) -> Option<bool> {
+    let mut vk_x = encode_g1(&vk.gamma_abc[0], endian);
+    for (point, input) in vk.gamma_abc[1..].iter().zip(public_inputs) {
+        let term = g1_mul(
+            &encode_g1(point, endian),
+            &encode_scalar(input, endian),
+            endian,
+        )?;
+        vk_x = g1_add(&vk_x, &term, endian)?;
+    }
+    // Word reversal is its own inverse, so `encode_g1` also takes `vk_x` back to big-endian.
+    let neg_vk_x = encode_g1(&negate_g1(&encode_g1(&vk_x, endian)), endian);
+
+    let mut input = Vec::with_capacity(ALT_BN128_PAIRING_ELEMENT_SIZE * 4);
+    input.extend_from_slice(&encode_g1(&proof.a, endian));
+    input.extend_from_slice(&encode_g2(&proof.b, proof_order, endian));
+    input.extend_from_slice(&encode_g1(&negate_g1(&vk.alpha_g1), endian));
+    input.extend_from_slice(&encode_g2(&vk.beta_g2, vk_order, endian));
+    input.extend_from_slice(&neg_vk_x);
+    input.extend_from_slice(&encode_g2(&vk.gamma_g2, vk_order, endian));
+    input.extend_from_slice(&encode_g1(&negate_g1(&proof.c), endian));
+    input.extend_from_slice(&encode_g2(&vk.delta_g2, vk_order, endian));
+    let output = match endian {
+        Endian::Be => alt_bn128_pairing_be(&input),
+        Endian::Le => alt_bn128_pairing_le(&input),
+    }
+    .ok()?;
+    Some(pairing_is_one(&output))
+}
+
+fn g1_add(a: &[u8; 64], b: &[u8; 64], endian: Endian) -> Option<[u8; 64]> {
+    let mut input = [0u8; 128];
+    input[..64].copy_from_slice(a);
+    input[64..].copy_from_slice(b);
+    let out = match endian {
+        Endian::Be => alt_bn128_g1_addition_be(&input),
+        Endian::Le => alt_bn128_g1_addition_le(&input),
+    }
+    .ok()?;
+    out.try_into().ok()
+}
+
+fn g1_mul(point: &[u8; 64], scalar: &[u8; 32], endian: Endian) -> Option<[u8; 64]> {
+    let mut input = [0u8; ALT_BN128_G1_MULTIPLICATION_INPUT_SIZE];
+    input[..64].copy_from_slice(point);
+    input[64..96].copy_from_slice(scalar);
+    let out = match endian {
+        Endian::Be => alt_bn128_g1_multiplication_be(&input),
+        Endian::Le => alt_bn128_g1_multiplication_le(&input),
+    }
+    .ok()?;
+    out.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn hex_array<const N: usize>(value: &Value) -> [u8; N] {
+        hex::decode(value.as_str().unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    /// `tests/fixtures/groth16.json` is already in the verifier layout: big-endian with
+    /// `c1` first. Swapping every G2 back gives what snarkjs would have written.
+    fn snarkjs_fixture() -> (CompatKey, CompatProof, Vec<[u8; 32]>) {
+        let fixture: Value =
+            serde_json::from_str(include_str!("../../../tests/fixtures/groth16.json")).unwrap();
+        let to_snarkjs = |value: &Value| encode_g2(&hex_array(value), G2Order::Swapped, Endian::Be);
+        let proof: [u8; 256] = hex_array(&fixture["proof"]);
+        let key = CompatKey {
+            alpha_g1: hex_array(&fixture["alpha_g1"]),
+            beta_g2: to_snarkjs(&fixture["beta_g2"]),
+            gamma_g2: to_snarkjs(&fixture["gamma_g2"]),
+            delta_g2: to_snarkjs(&fixture["delta_g2"]),
+            gamma_abc: fixture["gamma_abc"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(hex_array)
+                .collect(),
+        };
+        let b: [u8; 128] = proof[64..192].try_into().unwrap();
+        let proof = CompatProof {
+            a: proof[..64].try_into().unwrap(),
+            b: encode_g2(&b, G2Order::Swapped, Endian::Be),
+            c: proof[192..].try_into().unwrap(),
+        };
+        let inputs = fixture["public_inputs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(hex_array)
+            .collect();
+        (key, proof, inputs)
+    }
+
+    #[test]
+    fn detects_the_snarkjs_layout_of_the_fixture() {
+        let (key, proof, inputs) = snarkjs_fixture();
+        assert_eq!(
+            detect_encoding(&key, &proof, &inputs),
+            Some((Endian::Be, G2Order::Swapped, G2Order::Swapped))
+        );
+
+        let mut native = key.clone();
+        native.beta_g2 = encode_g2(&key.beta_g2, G2Order::Swapped, Endian::Be);
+        native.gamma_g2 = encode_g2(&key.gamma_g2, G2Order::Swapped, Endian::Be);
+        native.delta_g2 = encode_g2(&key.delta_g2, G2Order::Swapped, Endian::Be);
+        assert_eq!(
+            detect_encoding(&native, &proof, &inputs),
+            Some((Endian::Be, G2Order::Snarkjs, G2Order::Swapped))
+        );
+    }
+
+    #[test]
+    fn returns_none_when_nothing_verifies() {
+        let (key, mut proof, inputs) = snarkjs_fixture();
+        assert_eq!(detect_encoding(&key, &proof, &[]), None);
+        proof.a = negate_g1(&proof.a);
+        assert_eq!(detect_encoding(&key, &proof, &inputs), None);
+    }
+}
@@ -1,4 +1,6 @@
 use anchor_lang::prelude::*;
+use solana_keccak_hasher as keccak;
+use solana_sha256_hasher::hashv;
 use solana_bn254::prelude::{
     alt_bn128_g1_addition_be, alt_bn128_g1_multiplication_be, alt_bn128_pairing_be,
     ALT_BN128_G1_POINT_SIZE, ALT_BN128_G1_MULTIPLICATION_INPUT_SIZE,
@@ -7,7 +9,35 @@ use solana_bn254::prelude::{
 
 declare_id!("2ST31vJyrHVjR3jzMdQLHyVJDapphp2bU55VQjb6L5m6");
 
-const MAX_PUBLIC_INPUTS: usize = 16;
+#[cfg(all(any(test, feature = "std"), not(target_os = "solana")))]
+pub mod compat;
+
+const MAX_PUBLIC_INPUTS: usize = 18;
+const PROOF_LEN: usize = 256;
+const MAX_GAMMA_ABC_PER_WRITE: usize = 8;
+
+/// Smallest batch `verify_groth16_batch` folds into one random-linear-combination
+/// pairing. A single check pairs 4 elements (36_364 + 3 * 12_121 = 72_727 CU per
+/// proof); a batch of n pairs n + 3 elements and pays 3 extra G1 multiplications
+/// (3_840 CU each) for every proof after the first, so n = 2 costs ~100k CU against
+/// ~145k for two single checks. Below the threshold the RLC work is pure overhead.
+pub const BATCH_THRESHOLD: usize = 2;
+
+/// Curve a key's points live on. Only BN254 has pairing syscalls today; BLS12-381
+/// keys are accepted by the layout but rejected until the runtime exposes them.
+pub const CURVE_BN254: u8 = 0;
+pub const CURVE_BLS12_381: u8 = 1;
+
+/// Proof system a key belongs to. Every key this program creates today is Groth16; the
+/// `verify_groth16*` instructions refuse any other tag, so a key for a future system
+/// can never be fed to the Groth16 pairing check.
+pub const PROOF_SYSTEM_GROTH16: u8 = 0;
+pub const PROOF_SYSTEM_PLONK: u8 = 1;
+
+/// Byte order of each 32-byte public input word. Proof and key points are always
+/// big-endian; only the scalars some snarkjs-based integrations emit little-endian.
+pub const INPUT_ENDIAN_BE: u8 = 0;
+pub const INPUT_ENDIAN_LE: u8 = 1;
 
 #[program]
 pub mod verifier {
@@ -31,14 +61,27 @@ pub mod verifier {
             );
         }
 
+        check_curve_supported(args.curve)?;
+        check_input_endianness(args.input_endianness)?;
+
         let key = &mut ctx.accounts.verifier_key;
         key.alpha_g1 = args.alpha_g1;
+        key.neg_alpha_g1 = negate_g1(&args.alpha_g1);
         key.beta_g2 = args.beta_g2;
         key.gamma_g2 = args.gamma_g2;
         key.delta_g2 = args.delta_g2;
         key.public_inputs_len = args.public_inputs_len;
+        key.gamma_abc_written = args.gamma_abc.len() as u32;
+        key.expected_gamma_abc_hash = gamma_abc_hash(&args.gamma_abc);
+        key.finalized = true;
         key.gamma_abc = args.gamma_abc;
         key.mock = args.mock;
+        key.curve = args.curve;
+        key.proof_system = PROOF_SYSTEM_GROTH16;
+        key.input_endianness = args.input_endianness;
+        key.admin = ctx.accounts.admin.key();
+        key.vk_version = 0;
+        key.frozen = false;
         key.bump = ctx.bumps.verifier_key;
         Ok(())
     }
@@ -64,14 +107,32 @@ pub mod verifier {
             );
         }
 
+        check_curve_supported(args.curve)?;
+        check_input_endianness(args.input_endianness)?;
+        // A zero hash marks keys from before finalization existed, which skip the check.
+        require!(
+            args.expected_gamma_abc_hash != [0u8; 32],
+            VerifierError::InvalidVerifierKey
+        );
+
         let key = &mut ctx.accounts.verifier_key;
         key.alpha_g1 = args.alpha_g1;
+        key.neg_alpha_g1 = negate_g1(&args.alpha_g1);
         key.beta_g2 = args.beta_g2;
         key.gamma_g2 = args.gamma_g2;
         key.delta_g2 = args.delta_g2;
         key.public_inputs_len = args.public_inputs_len;
         key.gamma_abc = vec![[0u8; 64]; args.gamma_abc_len as usize];
+        key.gamma_abc_written = 0;
+        key.expected_gamma_abc_hash = args.expected_gamma_abc_hash;
+        key.finalized = false;
         key.mock = args.mock;
+        key.curve = args.curve;
+        key.proof_system = PROOF_SYSTEM_GROTH16;
+        key.input_endianness = args.input_endianness;
+        key.admin = ctx.accounts.admin.key();
+        key.vk_version = 0;
+        key.frozen = false;
         key.bump = ctx.bumps.verifier_key;
         Ok(())
     }
@@ -82,50 +143,203 @@ pub mod verifier {
     ) -> Result<()> {
         require!(!args.gamma_abc.is_empty(), VerifierError::InvalidInputCount);
         let key = &mut ctx.accounts.verifier_key;
+        require!(!key.frozen, VerifierError::VerifierKeyFrozen);
+        require!(!key.finalized, VerifierError::VerifierKeyFinalized);
         let start = args.start_index as usize;
         let end = start + args.gamma_abc.len();
         require!(end <= key.gamma_abc.len(), VerifierError::InvalidInputCount);
         for (offset, entry) in args.gamma_abc.iter().enumerate() {
             key.gamma_abc[start + offset] = *entry;
         }
+        track_gamma_abc_written(key, start, end);
         Ok(())
     }
 
-    pub fn verify_groth16(
+    /// Writes up to `MAX_GAMMA_ABC_PER_WRITE` entries starting at `start_index` and
+    /// returns how many were written; callers resume at `start_index + written`.
+    pub fn write_gamma_abc_batch(
+        ctx: Context<SetVerifierKeyGammaAbc>,
+        args: SetVerifierKeyGammaAbcArgs,
+    ) -> Result<u32> {
+        require!(!args.gamma_abc.is_empty(), VerifierError::InvalidInputCount);
+        let key = &mut ctx.accounts.verifier_key;
+        require!(!key.frozen, VerifierError::VerifierKeyFrozen);
+        require!(!key.finalized, VerifierError::VerifierKeyFinalized);
+        let start = args.start_index as usize;
+        require!(
+            start + args.gamma_abc.len() <= key.gamma_abc.len(),
+            VerifierError::InvalidInputCount
+        );
+        let count = args.gamma_abc.len().min(MAX_GAMMA_ABC_PER_WRITE);
+        key.gamma_abc[start..start + count].copy_from_slice(&args.gamma_abc[..count]);
+        track_gamma_abc_written(key, start, start + count);
+        Ok(count as u32)
+    }
+
+    /// Swaps the whole `gamma_abc` in one transaction so verification never sees a
+    /// partially rewritten key. Only fits keys small enough for a single transaction;
+    /// larger keys still go through the header + batched writes.
+    pub fn replace_gamma_abc(
+        ctx: Context<UpdateVerifierKey>,
+        gamma_abc: Vec<[u8; 64]>,
+    ) -> Result<()> {
+        let key = &mut ctx.accounts.verifier_key;
+        require!(!key.frozen, VerifierError::VerifierKeyFrozen);
+        check_gamma_abc_replacement(key, &gamma_abc)?;
+        key.gamma_abc_written = gamma_abc.len() as u32;
+        key.expected_gamma_abc_hash = gamma_abc_hash(&gamma_abc);
+        key.finalized = true;
+        key.gamma_abc = gamma_abc;
+        key.vk_version = key.vk_version.saturating_add(1);
+        Ok(())
+    }
+
+    /// Replaces every curve point of a key whose trusted setup turned out to be flawed,
+    /// so pools bound to this address move to the new setup without being re-created.
+    /// Only the admin captured at initialization may rotate, and a frozen key never
+    /// changes. `public_inputs_len` may change, but `gamma_abc` must then be resized to
+    /// match in the same call.
+    pub fn update_verifier_key(
+        ctx: Context<UpdateVerifierKey>,
+        args: UpdateVerifierKeyArgs,
+    ) -> Result<()> {
+        let key = &mut ctx.accounts.verifier_key;
+        require!(!key.frozen, VerifierError::VerifierKeyFrozen);
+        require!(
+            args.gamma_abc.len() <= MAX_PUBLIC_INPUTS + 1,
+            VerifierError::TooManyInputs
+        );
+        require!(
+            args.gamma_abc.len() == args.public_inputs_len as usize + 1,
+            VerifierError::InvalidInputCount
+        );
+        if !key.mock {
+            for point in std::iter::once(&args.alpha_g1).chain(&args.gamma_abc) {
+                check_g1_on_curve(point)?;
+            }
+        }
+        key.alpha_g1 = args.alpha_g1;
+        key.neg_alpha_g1 = negate_g1(&args.alpha_g1);
+        key.beta_g2 = args.beta_g2;
+        key.gamma_g2 = args.gamma_g2;
+        key.delta_g2 = args.delta_g2;
+        key.public_inputs_len = args.public_inputs_len;
+        key.gamma_abc_written = args.gamma_abc.len() as u32;
+        key.expected_gamma_abc_hash = gamma_abc_hash(&args.gamma_abc);
+        key.finalized = true;
+        key.gamma_abc = args.gamma_abc;
+        key.vk_version = key.vk_version.saturating_add(1);
+        Ok(())
+    }
+
+    /// Closes a chunked upload: the assembled `gamma_abc` must hash to the
+    /// `expected_gamma_abc_hash` given to the header, so a dropped or reordered chunk is
+    /// caught here rather than as failing proofs. Non-mock keys from the header path do
+    /// not verify until this succeeds, and their chunk writes are refused afterwards.
+    pub fn finalize_verifier_key(ctx: Context<UpdateVerifierKey>) -> Result<()> {
+        let key = &mut ctx.accounts.verifier_key;
+        require!(
+            key.gamma_abc_written as usize == key.gamma_abc.len(),
+            VerifierError::InvalidVerifierKey
+        );
+        require!(
+            gamma_abc_hash(&key.gamma_abc) == key.expected_gamma_abc_hash,
+            VerifierError::GammaAbcHashMismatch
+        );
+        key.finalized = true;
+        Ok(())
+    }
+
+    /// Permanently blocks further `gamma_abc` writes to the key.
+    pub fn freeze_verifier_key(ctx: Context<UpdateVerifierKey>) -> Result<()> {
+        ctx.accounts.verifier_key.frozen = true;
+        Ok(())
+    }
+
+    /// Debug entrypoint: returns `vk_x = gamma_abc[0] + sum(input_i * gamma_abc[i + 1])`
+    /// for the stored key so clients can diff it against their own MSM when chasing
+    /// public-input packing bugs.
+    pub fn compute_vk_x_only(
         ctx: Context<VerifyGroth16>,
-        proof: Vec<u8>,
         public_inputs: Vec<u8>,
-    ) -> Result<()> {
+    ) -> Result<[u8; 64]> {
         let key = &ctx.accounts.verifier_key;
         require!(
             public_inputs.len() == key.public_inputs_len as usize * 32,
             VerifierError::InvalidInputCount
         );
+        require!(
+            key.gamma_abc.len() == key.public_inputs_len as usize + 1 && gamma_abc_complete(key),
+            VerifierError::InvalidVerifierKey
+        );
+        compute_vk_x(key, &public_inputs)
+    }
+
+    pub fn verify_groth16(
+        ctx: Context<VerifyGroth16>,
+        proof: Vec<u8>,
+        public_inputs: Vec<u8>,
+    ) -> Result<()> {
+        let key = &ctx.accounts.verifier_key;
+        check_groth16_key(key)?;
+        check_lengths(key, &proof, &public_inputs)?;
         if key.mock {
             return Ok(());
         }
+        check_curve_supported(key.curve)?;
+        verify_single(key, &proof, &public_inputs)
+    }
 
-        let (a, b, c) = parse_proof(&proof)?;
-        let vk_x = compute_vk_x(&key.gamma_abc, &public_inputs)?;
-
-        let neg_alpha = negate_g1(&key.alpha_g1);
-        let neg_vk_x = negate_g1(&vk_x);
-        let neg_c = negate_g1(&c);
-
-        let mut pairing_input = Vec::with_capacity(ALT_BN128_PAIRING_ELEMENT_SIZE * 4);
-        pairing_input.extend_from_slice(&a);
-        pairing_input.extend_from_slice(&b);
-        pairing_input.extend_from_slice(&neg_alpha);
-        pairing_input.extend_from_slice(&key.beta_g2);
-        pairing_input.extend_from_slice(&neg_vk_x);
-        pairing_input.extend_from_slice(&key.gamma_g2);
-        pairing_input.extend_from_slice(&neg_c);
-        pairing_input.extend_from_slice(&key.delta_g2);
-
-        let result = alt_bn128_pairing_be(&pairing_input).map_err(|_| VerifierError::PairingFailed)?;
-        require!(pairing_is_one(&result), VerifierError::InvalidProof);
+    /// Like `verify_groth16`, but a well-formed proof that fails the pairing check is
+    /// reported rather than reverting: return data is one byte, 1 when the proof
+    /// verifies and 0 when it does not. Wrong lengths, points off the curve and
+    /// unsupported keys still fail, so a CPI caller can try several keys in turn.
+    pub fn try_verify_groth16(
+        ctx: Context<VerifyGroth16>,
+        proof: Vec<u8>,
+        public_inputs: Vec<u8>,
+    ) -> Result<()> {
+        let key = &ctx.accounts.verifier_key;
+        check_groth16_key(key)?;
+        check_lengths(key, &proof, &public_inputs)?;
+        let valid = if key.mock {
+            true
+        } else {
+            check_curve_supported(key.curve)?;
+            pairing_holds(verify_single(key, &proof, &public_inputs))?
+        };
+        anchor_lang::solana_program::program::set_return_data(&[u8::from(valid)]);
         Ok(())
     }
+
+    /// Verifies several proofs against one key, checking each on its own below
+    /// `BATCH_THRESHOLD` and folding them into one pairing at or above it.
+    pub fn verify_groth16_batch(
+        ctx: Context<VerifyGroth16>,
+        proofs: Vec<Vec<u8>>,
+        public_inputs: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let key = &ctx.accounts.verifier_key;
+        check_groth16_key(key)?;
+        require!(
+            !proofs.is_empty() && proofs.len() == public_inputs.len(),
+            VerifierError::BatchLengthMismatch
+        );
+        for (proof, inputs) in proofs.iter().zip(&public_inputs) {
+            check_lengths(key, proof, inputs)?;
+        }
+        if key.mock {
+            return Ok(());
+        }
+        check_curve_supported(key.curve)?;
+        if proofs.len() < BATCH_THRESHOLD {
+            for (proof, inputs) in proofs.iter().zip(&public_inputs) {
+                verify_single(key, proof, inputs)?;
+            }
+            return Ok(());
+        }
+        verify_batched(key, &proofs, &public_inputs)
+    }
 }
 
 #[derive(Accounts)]
@@ -166,12 +380,20 @@ pub struct SetVerifierKeyGammaAbc<'info> {
     #[account(
         mut,
         seeds = [b"verifier_key", args.key_id.to_le_bytes().as_ref()],
-        bump = verifier_key.bump
+        bump = verifier_key.bump,
+        has_one = admin @ VerifierError::Unauthorized
     )]
     pub verifier_key: Account<'info, VerifierKey>,
     pub admin: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateVerifierKey<'info> {
+    #[account(mut, has_one = admin @ VerifierError::Unauthorized)]
+    pub verifier_key: Account<'info, VerifierKey>,
+    pub admin: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct VerifyGroth16<'info> {
     pub verifier_key: Account<'info, VerifierKey>,
@@ -189,6 +411,32 @@ pub struct VerifierKey {
     pub gamma_abc: Vec<[u8; 64]>,
     pub mock: bool,
     pub bump: u8,
+    // Everything below was appended after the original layout, so keys created before a
+    // field read it as zero from the account's unused tail.
+    /// Contiguous prefix of `gamma_abc` written so far. Zero on older keys, which were
+    /// always written in full; see `gamma_abc_complete`.
+    pub gamma_abc_written: u32,
+    /// `CURVE_BN254` on older keys.
+    pub curve: u8,
+    pub vk_version: u32,
+    pub frozen: bool,
+    /// `-alpha_g1`, cached at initialization for the single-proof pairing. Older keys
+    /// fall back to negating on the fly.
+    pub neg_alpha_g1: [u8; 64],
+    /// `INPUT_ENDIAN_BE` or `INPUT_ENDIAN_LE`, appended for the same reason: older keys
+    /// read zero, i.e. big-endian.
+    pub input_endianness: u8,
+    /// Signer of the initialization, the only one allowed to change the key afterwards.
+    /// Older keys read the default key, which cannot sign, so they can no longer change.
+    pub admin: Pubkey,
+    /// keccak of the concatenated `gamma_abc` points. Supplied to the header for chunked
+    /// uploads and computed from the points everywhere else; zero on older keys.
+    pub expected_gamma_abc_hash: [u8; 32],
+    /// Set once `gamma_abc` is known to match `expected_gamma_abc_hash`.
+    pub finalized: bool,
+    /// `PROOF_SYSTEM_GROTH16` for every key so far; appended, so older keys read zero,
+    /// which is Groth16.
+    pub proof_system: u8,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -201,6 +449,8 @@ pub struct InitializeVerifierKeyArgs {
     pub public_inputs_len: u32,
     pub gamma_abc: Vec<[u8; 64]>,
     pub mock: bool,
+    pub curve: u8,
+    pub input_endianness: u8,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -213,6 +463,19 @@ pub struct InitializeVerifierKeyHeaderArgs {
     pub public_inputs_len: u32,
     pub gamma_abc_len: u32,
     pub mock: bool,
+    pub curve: u8,
+    pub input_endianness: u8,
+    pub expected_gamma_abc_hash: [u8; 32],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct UpdateVerifierKeyArgs {
+    pub alpha_g1: [u8; 64],
+    pub beta_g2: [u8; 128],
+    pub gamma_g2: [u8; 128],
+    pub delta_g2: [u8; 128],
+    pub public_inputs_len: u32,
+    pub gamma_abc: Vec<[u8; 64]>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -222,26 +485,251 @@ pub struct SetVerifierKeyGammaAbcArgs {
     pub gamma_abc: Vec<[u8; 64]>,
 }
 
+/// `gamma_abc_written` counts the contiguous prefix of `gamma_abc` that has been
+/// populated, so writes past the prefix do not advance it.
+fn track_gamma_abc_written(key: &mut VerifierKey, start: usize, end: usize) {
+    if start <= key.gamma_abc_written as usize && end > key.gamma_abc_written as usize {
+        key.gamma_abc_written = end as u32;
+    }
+}
+
+/// Runs before any curve work, mock keys included, so a malformed submission costs
+/// as little compute as possible.
+fn check_lengths(key: &VerifierKey, proof: &[u8], public_inputs: &[u8]) -> Result<()> {
+    require!(proof.len() == PROOF_LEN, VerifierError::InvalidProof);
+    require!(
+        public_inputs.len() == key.public_inputs_len as usize * 32,
+        VerifierError::InvalidInputCount
+    );
+    Ok(())
+}
+
+/// BN254 G1 has cofactor 1, so an `A` or `C` on the curve is already in the prime-order
+/// subgroup and one addition syscall each settles it. `B` is left to `pairing`.
 fn parse_proof(proof: &[u8]) -> Result<([u8; 64], [u8; 128], [u8; 64])> {
-    require!(proof.len() == 256, VerifierError::InvalidProof);
+    require!(proof.len() == PROOF_LEN, VerifierError::InvalidProof);
     let a = to_fixed_64(&proof[0..64])?;
     let b = to_fixed_128(&proof[64..192])?;
     let c = to_fixed_64(&proof[192..256])?;
+    check_g1_on_curve(&a)?;
+    check_g1_on_curve(&c)?;
     Ok((a, b, c))
 }
 
-fn compute_vk_x(gamma_abc: &[[u8; 64]], public_inputs: &[u8]) -> Result<[u8; 64]> {
+/// BLS12-381 needs 48-byte G1 / 96-byte G2 coordinates and its own pairing syscall,
+/// neither of which this runtime provides, so such keys fail up front instead of
+/// being fed to the alt_bn128 path.
+fn check_curve_supported(curve: u8) -> Result<()> {
+    match curve {
+        CURVE_BN254 => Ok(()),
+        CURVE_BLS12_381 => err!(VerifierError::UnsupportedCurve),
+        _ => err!(VerifierError::InvalidCurve),
+    }
+}
+
+/// Runs before the length checks, which assume Groth16's 256-byte proof.
+fn check_groth16_key(key: &VerifierKey) -> Result<()> {
+    require!(
+        key.proof_system == PROOF_SYSTEM_GROTH16,
+        VerifierError::UnsupportedProofSystem
+    );
+    Ok(())
+}
+
+fn gamma_abc_hash(gamma_abc: &[[u8; 64]]) -> [u8; 32] {
+    let points: Vec<&[u8]> = gamma_abc.iter().map(|point| point.as_slice()).collect();
+    keccak::hashv(&points).to_bytes()
+}
+
+/// Keys with a zero expected hash predate the write counter and were written in one
+/// call, so their `gamma_abc_written` of zero does not mean missing points.
+fn gamma_abc_complete(key: &VerifierKey) -> bool {
+    key.expected_gamma_abc_hash == [0u8; 32]
+        || key.gamma_abc_written as usize == key.gamma_abc.len()
+}
+
+/// Holds a replacement `gamma_abc` to the rules `initialize_verifier_key` applied to
+/// the original: mock keys only need a non-empty array, real keys one point per
+/// public input plus one, each on the curve.
+fn check_gamma_abc_replacement(key: &VerifierKey, gamma_abc: &[[u8; 64]]) -> Result<()> {
+    require!(
+        gamma_abc.len() <= MAX_PUBLIC_INPUTS + 1,
+        VerifierError::TooManyInputs
+    );
+    if key.mock {
+        require!(!gamma_abc.is_empty(), VerifierError::InvalidInputCount);
+        return Ok(());
+    }
+    require!(
+        gamma_abc.len() == key.public_inputs_len as usize + 1,
+        VerifierError::InvalidInputCount
+    );
+    for point in gamma_abc {
+        check_g1_on_curve(point)?;
+    }
+    Ok(())
+}
+
+/// Keys with a zero expected hash predate finalization and stay usable as they were.
+fn check_finalized(key: &VerifierKey) -> Result<()> {
+    require!(
+        key.finalized || key.expected_gamma_abc_hash == [0u8; 32],
+        VerifierError::VerifierKeyNotFinalized
+    );
+    Ok(())
+}
+
+fn check_input_endianness(endianness: u8) -> Result<()> {
+    match endianness {
+        INPUT_ENDIAN_BE | INPUT_ENDIAN_LE => Ok(()),
+        _ => err!(VerifierError::InvalidInputEndianness),
+    }
+}
+
+fn verify_single(key: &VerifierKey, proof: &[u8], public_inputs: &[u8]) -> Result<()> {
+    check_finalized(key)?;
+    let (a, b, c) = parse_proof(proof)?;
+    let vk_x = compute_vk_x(key, public_inputs)?;
+
+    let neg_alpha = neg_alpha_g1(key);
+    let neg_vk_x = negate_g1(&vk_x);
+    let neg_c = negate_g1(&c);
+
+    let mut pairing_input = Vec::with_capacity(ALT_BN128_PAIRING_ELEMENT_SIZE * 4);
+    pairing_input.extend_from_slice(&a);
+    pairing_input.extend_from_slice(&b);
+    pairing_input.extend_from_slice(&neg_alpha);
+    pairing_input.extend_from_slice(&key.beta_g2);
+    pairing_input.extend_from_slice(&neg_vk_x);
+    pairing_input.extend_from_slice(&key.gamma_g2);
+    pairing_input.extend_from_slice(&neg_c);
+    pairing_input.extend_from_slice(&key.delta_g2);
+
+    let result = pairing(&pairing_input, &[b])?;
+    check_pairing_result(&result)
+}
+
+/// Checks `prod e(r_i * A_i, B_i) = e(sum r_i * alpha, beta) * e(sum r_i * vk_x_i, gamma) *
+/// e(sum r_i * C_i, delta)` with `r_0 = 1` and the remaining `r_i` derived from a hash
+/// of the whole batch, so a prover cannot pick proofs whose errors cancel.
+fn verify_batched(key: &VerifierKey, proofs: &[Vec<u8>], public_inputs: &[Vec<u8>]) -> Result<()> {
+    check_finalized(key)?;
+    let transcript: Vec<&[u8]> = proofs
+        .iter()
+        .zip(public_inputs)
+        .flat_map(|(proof, inputs)| [proof.as_slice(), inputs.as_slice()])
+        .collect();
+    let seed = hashv(&transcript).to_bytes();
+
+    let mut pairing_input = Vec::with_capacity(ALT_BN128_PAIRING_ELEMENT_SIZE * (proofs.len() + 3));
+    let mut scalar_sum = [0u8; 32];
+    let mut vk_x_acc: Option<[u8; 64]> = None;
+    let mut c_acc: Option<[u8; 64]> = None;
+    let mut proof_b = Vec::with_capacity(proofs.len());
+    for (i, (proof, inputs)) in proofs.iter().zip(public_inputs).enumerate() {
+        let (a, b, c) = parse_proof(proof)?;
+        let vk_x = compute_vk_x(key, inputs)?;
+        let r = batch_scalar(&seed, i);
+        let (a, vk_x, c) = if i == 0 {
+            (a, vk_x, c)
+        } else {
+            (g1_mul(&a, &r)?, g1_mul(&vk_x, &r)?, g1_mul(&c, &r)?)
+        };
+        scalar_sum = add_be(&scalar_sum, &r);
+        vk_x_acc = Some(match vk_x_acc {
+            Some(acc) => g1_add(&acc, &vk_x)?,
+            None => vk_x,
+        });
+        c_acc = Some(match c_acc {
+            Some(acc) => g1_add(&acc, &c)?,
+            None => c,
+        });
+        pairing_input.extend_from_slice(&a);
+        pairing_input.extend_from_slice(&b);
+        proof_b.push(b);
+    }
+    let (Some(vk_x_acc), Some(c_acc)) = (vk_x_acc, c_acc) else {
+        return err!(VerifierError::BatchLengthMismatch);
+    };
+    let alpha_acc = g1_mul(&key.alpha_g1, &scalar_sum)?;
+
+    pairing_input.extend_from_slice(&negate_g1(&alpha_acc));
+    pairing_input.extend_from_slice(&key.beta_g2);
+    pairing_input.extend_from_slice(&negate_g1(&vk_x_acc));
+    pairing_input.extend_from_slice(&key.gamma_g2);
+    pairing_input.extend_from_slice(&negate_g1(&c_acc));
+    pairing_input.extend_from_slice(&key.delta_g2);
+
+    let result = pairing(&pairing_input, &proof_b)?;
+    check_pairing_result(&result)
+}
+
+/// 128-bit batch scalar; the first proof uses 1 so it skips its multiplications.
+/// Sums of these stay far below the scalar field modulus, so no reduction is needed.
+fn batch_scalar(seed: &[u8; 32], index: usize) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    if index == 0 {
+        out[31] = 1;
+        return out;
+    }
+    let digest = hashv(&[seed.as_slice(), &(index as u32).to_le_bytes()]).to_bytes();
+    out[16..].copy_from_slice(&digest[..16]);
+    out
+}
+
+fn add_be(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let mut carry = 0u16;
+    for i in (0..32).rev() {
+        let sum = a[i] as u16 + b[i] as u16 + carry;
+        out[i] = sum as u8;
+        carry = sum >> 8;
+    }
+    out
+}
+
+/// `gamma_abc[0] + sum(input_i * gamma_abc[i + 1])`. The runtime has no G1 MSM
+/// syscall, so the savings come from the inputs themselves: zero inputs (disabled
+/// outputs, unused nullifier slots) contribute the identity and are skipped outright,
+/// and inputs equal to one (enable flags) add their point without a multiplication.
+/// Little-endian inputs are reversed here, so everything past this point is big-endian.
+fn compute_vk_x(key: &VerifierKey, public_inputs: &[u8]) -> Result<[u8; 64]> {
+    let gamma_abc = &key.gamma_abc;
     require!(!gamma_abc.is_empty(), VerifierError::InvalidVerifierKey);
     let mut acc = gamma_abc[0];
     let input_chunks = public_inputs.chunks(32).enumerate();
     for (i, chunk) in input_chunks {
-        let scalar = to_fixed_32(chunk)?;
-        let term = g1_mul(&gamma_abc[i + 1], &scalar)?;
-        acc = g1_add(&acc, &term)?;
+        let mut scalar = to_fixed_32(chunk)?;
+        if key.input_endianness == INPUT_ENDIAN_LE {
+            scalar.reverse();
+        }
+        let point = &gamma_abc[i + 1];
+        match scalar_kind(&scalar) {
+            ScalarKind::Zero => continue,
+            ScalarKind::One => acc = g1_add(&acc, point)?,
+            ScalarKind::Other => acc = g1_add(&acc, &g1_mul(point, &scalar)?)?,
+        }
     }
     Ok(acc)
 }
 
+enum ScalarKind {
+    Zero,
+    One,
+    Other,
+}
+
+fn scalar_kind(scalar: &[u8; 32]) -> ScalarKind {
+    if scalar[..31].iter().any(|b| *b != 0) {
+        return ScalarKind::Other;
+    }
+    match scalar[31] {
+        0 => ScalarKind::Zero,
+        1 => ScalarKind::One,
+        _ => ScalarKind::Other,
+    }
+}
+
 fn g1_add(a: &[u8; 64], b: &[u8; 64]) -> Result<[u8; 64]> {
     let mut input = [0u8; 128];
     input[..64].copy_from_slice(a);
@@ -260,19 +748,88 @@ fn g1_mul(point: &[u8; 64], scalar: &[u8; 32]) -> Result<[u8; 64]> {
     Ok(to_fixed_64(&out)?)
 }
 
+/// The addition syscall rejects points off the curve, so adding the identity
+/// doubles as a cheap validity check.
+fn check_g1_on_curve(point: &[u8; 64]) -> Result<()> {
+    let mut input = [0u8; 128];
+    input[..64].copy_from_slice(point);
+    alt_bn128_g1_addition_be(&input).map_err(|_| VerifierError::InvalidCurvePoint)?;
+    Ok(())
+}
+
+/// The pairing syscall deserializes every G2 input with full validation and fails on a
+/// point off the twist or outside the order-r subgroup, so an invalid `B` never reaches
+/// the Miller loop. There is no G2 arithmetic syscall to check `B` more cheaply up
+/// front, so the valid path pays nothing extra; only after a failure is each proof's `B`
+/// checked on its own, to report `PointNotInSubgroup` instead of `PairingFailed`.
+fn pairing(input: &[u8], proof_b: &[[u8; 128]]) -> Result<Vec<u8>> {
+    match alt_bn128_pairing_be(input) {
+        Ok(result) => Ok(result),
+        Err(_) => {
+            for b in proof_b {
+                check_g2_subgroup(b)?;
+            }
+            err!(VerifierError::PairingFailed)
+        }
+    }
+}
+
+/// A lone `e(0, point)` costs one pairing element and succeeds exactly when the syscall
+/// accepts `point` as a member of the G2 subgroup, infinity included.
+fn check_g2_subgroup(point: &[u8; 128]) -> Result<()> {
+    let mut input = [0u8; ALT_BN128_PAIRING_ELEMENT_SIZE];
+    input[64..].copy_from_slice(point);
+    alt_bn128_pairing_be(&input).map_err(|_| VerifierError::PointNotInSubgroup)?;
+    Ok(())
+}
+
+fn neg_alpha_g1(key: &VerifierKey) -> [u8; 64] {
+    if key.neg_alpha_g1 == [0u8; 64] {
+        negate_g1(&key.alpha_g1)
+    } else {
+        key.neg_alpha_g1
+    }
+}
+
+/// alt_bn128 encodes the point at infinity as 64 zero bytes, and that is the only
+/// input returned unchanged. `(x, 0)` is never on the curve; its negation gets
+/// `y = p`, which the syscalls reject instead of reading it as infinity.
 fn negate_g1(point: &[u8; 64]) -> [u8; 64] {
     let mut out = *point;
-    let mut y = [0u8; 32];
-    y.copy_from_slice(&point[32..64]);
-    if y.iter().all(|b| *b == 0) {
+    if point.iter().all(|b| *b == 0) {
         return out;
     }
+    let mut y = [0u8; 32];
+    y.copy_from_slice(&point[32..64]);
     let p = field_modulus();
     let neg_y = sub_mod_be(&p, &y);
     out[32..64].copy_from_slice(&neg_y);
     out
 }
 
+/// A well-formed proof whose pairing product is not one fails with `PairingNotOne`
+/// rather than `InvalidProof`, and logs the trailing output byte, so a wrong proof or
+/// key can be told apart from malformed input.
+fn check_pairing_result(output: &[u8]) -> Result<()> {
+    if pairing_is_one(output) {
+        return Ok(());
+    }
+    msg!(
+        "pairing result is not one, last byte {}",
+        output.last().copied().unwrap_or_default()
+    );
+    err!(VerifierError::PairingNotOne)
+}
+
+/// `Ok(false)` for a product that is not one; every other failure stands.
+fn pairing_holds(result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(err) if err == error!(VerifierError::PairingNotOne) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
 fn pairing_is_one(output: &[u8]) -> bool {
     if output.len() != ALT_BN128_PAIRING_OUTPUT_SIZE {
         return false;
@@ -343,4 +900,186 @@ pub enum VerifierError {
     AdditionFailed,
     #[msg("G1 multiplication failed")]
     MultiplicationFailed,
+    #[msg("Proof and public input batches must be non-empty and the same length")]
+    BatchLengthMismatch,
+    #[msg("Point is not on the curve")]
+    InvalidCurvePoint,
+    #[msg("Verifier key is frozen")]
+    VerifierKeyFrozen,
+    #[msg("Curve not supported by this runtime")]
+    UnsupportedCurve,
+    #[msg("Unknown curve")]
+    InvalidCurve,
+    #[msg("Pairing product is not one")]
+    PairingNotOne,
+    #[msg("Unknown public input endianness")]
+    InvalidInputEndianness,
+    #[msg("Signer is not the verifier key admin")]
+    Unauthorized,
+    #[msg("gamma_abc does not match the expected hash")]
+    GammaAbcHashMismatch,
+    #[msg("Verifier key has not been finalized")]
+    VerifierKeyNotFinalized,
+    #[msg("Verifier key is finalized")]
+    VerifierKeyFinalized,
+    #[msg("Proof point is not in the prime-order subgroup")]
+    PointNotInSubgroup,
+    #[msg("Verifier key is for a proof system this instruction does not verify")]
+    UnsupportedProofSystem,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negate_g1_only_passes_the_all_zero_point_through() {
+        assert_eq!(negate_g1(&[0u8; 64]), [0u8; 64]);
+
+        let mut generator = [0u8; 64];
+        generator[31] = 1;
+        generator[63] = 2;
+        assert_eq!(negate_g1(&negate_g1(&generator)), generator);
+
+        let mut zero_y = [0u8; 64];
+        zero_y[31] = 1;
+        let negated = negate_g1(&zero_y);
+        assert_ne!(negated, zero_y);
+        assert_eq!(negated[32..], field_modulus());
+        assert!(check_g1_on_curve(&negated).is_err());
+    }
+
+    /// On the twist with `x = 1` but outside the order-r subgroup (the G2 cofactor is
+    /// large), encoded `c1` first like every G2 point the syscalls take.
+    fn off_subgroup_g2() -> [u8; 128] {
+        let mut point = [0u8; 128];
+        point[63] = 1;
+        point[64..].copy_from_slice(
+            &hex::decode(
+                "0d1271953ed9ea0836846e70a1934187998c7f790cb4d7511b7f8da82de048a4\
+                 2869111d5381f072f8e2728fdb825a51aadd70e52c9830e9ab4b871c0531f1bb",
+            )
+            .unwrap(),
+        );
+        point
+    }
+
+    /// A key with no public inputs, every G2 point at infinity and a zero expected hash,
+    /// so it verifies without being finalized.
+    fn groth16_key(gamma_abc_0: [u8; 64]) -> VerifierKey {
+        VerifierKey {
+            alpha_g1: [0u8; 64],
+            beta_g2: [0u8; 128],
+            gamma_g2: [0u8; 128],
+            delta_g2: [0u8; 128],
+            public_inputs_len: 0,
+            gamma_abc: vec![gamma_abc_0],
+            mock: false,
+            bump: 0,
+            gamma_abc_written: 1,
+            curve: CURVE_BN254,
+            vk_version: 0,
+            frozen: false,
+            neg_alpha_g1: [0u8; 64],
+            input_endianness: INPUT_ENDIAN_BE,
+            admin: Pubkey::default(),
+            expected_gamma_abc_hash: [0u8; 32],
+            finalized: false,
+            proof_system: PROOF_SYSTEM_GROTH16,
+        }
+    }
+
+    #[test]
+    fn off_subgroup_b_fails_with_point_not_in_subgroup() {
+        let mut generator = [0u8; 64];
+        generator[31] = 1;
+        generator[63] = 2;
+        let key = groth16_key(generator);
+        let b = off_subgroup_g2();
+        assert!(check_g2_subgroup(&[0u8; 128]).is_ok());
+        assert_eq!(
+            check_g2_subgroup(&b).unwrap_err(),
+            error!(VerifierError::PointNotInSubgroup)
+        );
+
+        let mut proof = [0u8; PROOF_LEN];
+        proof[..64].copy_from_slice(&generator);
+        proof[64..192].copy_from_slice(&b);
+        proof[192..].copy_from_slice(&generator);
+        assert_eq!(
+            verify_single(&key, &proof, &[]).unwrap_err(),
+            error!(VerifierError::PointNotInSubgroup)
+        );
+        let batch = vec![proof.to_vec(), proof.to_vec()];
+        assert_eq!(
+            verify_batched(&key, &batch, &[Vec::new(), Vec::new()]).unwrap_err(),
+            error!(VerifierError::PointNotInSubgroup)
+        );
+
+        proof[..64].copy_from_slice(&[1u8; 64]);
+        assert_eq!(
+            verify_single(&key, &proof, &[]).unwrap_err(),
+            error!(VerifierError::InvalidCurvePoint)
+        );
+    }
+
+    #[test]
+    fn keys_for_other_proof_systems_are_refused() {
+        let mut key = groth16_key([0u8; 64]);
+        assert!(check_groth16_key(&key).is_ok());
+        for proof_system in [PROOF_SYSTEM_PLONK, u8::MAX] {
+            key.proof_system = proof_system;
+            assert_eq!(
+                check_groth16_key(&key).unwrap_err(),
+                error!(VerifierError::UnsupportedProofSystem)
+            );
+        }
+    }
+
+    #[test]
+    fn keys_in_the_original_layout_decode_unchanged() {
+        let mut data = VerifierKey::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&[1u8; 64]);
+        data.extend_from_slice(&[2u8; 128 * 3]);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[3u8; 64 * 2]);
+        data.extend_from_slice(&[0, 254]);
+        data.resize(8 + VerifierKey::INIT_SPACE, 0);
+
+        let key = VerifierKey::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(key.gamma_abc, vec![[3u8; 64]; 2]);
+        assert!(!key.mock);
+        assert_eq!(key.bump, 254);
+        assert_eq!(key.curve, CURVE_BN254);
+        assert_eq!((key.vk_version, key.frozen, key.finalized), (0, false, false));
+        assert_eq!(key.input_endianness, INPUT_ENDIAN_BE);
+        assert_eq!(key.proof_system, PROOF_SYSTEM_GROTH16);
+        assert!(gamma_abc_complete(&key));
+        assert!(check_finalized(&key).is_ok());
+    }
+
+    #[test]
+    fn gamma_abc_replacements_follow_the_initializer_rules() {
+        let mut key = groth16_key([0u8; 64]);
+        key.public_inputs_len = 2;
+        assert!(check_gamma_abc_replacement(&key, &[[0u8; 64]; 3]).is_ok());
+        assert_eq!(
+            check_gamma_abc_replacement(&key, &[[0u8; 64]; 1]).unwrap_err(),
+            error!(VerifierError::InvalidInputCount)
+        );
+
+        // A mock key initialized with fewer points than inputs can still be replaced.
+        key.mock = true;
+        assert!(check_gamma_abc_replacement(&key, &[[0u8; 64]; 1]).is_ok());
+        assert_eq!(
+            check_gamma_abc_replacement(&key, &[]).unwrap_err(),
+            error!(VerifierError::InvalidInputCount)
+        );
+        assert_eq!(
+            check_gamma_abc_replacement(&key, &vec![[0u8; 64]; MAX_PUBLIC_INPUTS + 2])
+                .unwrap_err(),
+            error!(VerifierError::TooManyInputs)
+        );
+    }
 }
@@ -29,12 +29,15 @@ import {
   deriveVault,
   deriveVkRegistry,
   deriveVerifierKey,
+  gammaAbcHash,
 } from "../sdk/src/pda";
+import { MERKLE_DEPTH } from "../sdk/src/merkle";
 
 type EnvMap = Record<string, string>;
 
 const DEFAULT_ENV_PATH = path.resolve(process.cwd(), ".env.devnet");
 const DEFAULT_WRAP_AMOUNT = "1";
+const IDENTITY_TREE_DEPTH = 20;
 
 const loadEnv = (filePath: string): EnvMap => {
   if (!fs.existsSync(filePath)) {
@@ -263,6 +266,7 @@ async function main() {
         .initializeConfig({
           feeBps: 25,
           relayerFeeBpsMax: 50,
+          relayerFeeBpsMin: 0,
           vkRegistry,
           mintAllowlist: [],
           circuitIds: [0],
@@ -280,6 +284,25 @@ async function main() {
     console.log("Config already initialized.");
   }
 
+  const nullifierChunksRaw =
+    env.VITE_NULLIFIER_CHUNKS ?? process.env.VITE_NULLIFIER_CHUNKS ?? "4294967295";
+  const nullifierChunks = Number(nullifierChunksRaw);
+  if (!Number.isInteger(nullifierChunks) || nullifierChunks < 1 || nullifierChunks > 0xffffffff) {
+    throw new Error("Invalid VITE_NULLIFIER_CHUNKS value.");
+  }
+  const configAccount = await veilpayProgram.account.config.fetch(config);
+  if (Number(configAccount.nullifierChunks) !== nullifierChunks) {
+    // Only pools created after this call pick up the new count.
+    console.log(`Setting nullifier chunk count to ${nullifierChunks}...`);
+    const sig = await sendWithLogs("setNullifierChunks", () =>
+      veilpayProgram.methods
+        .setNullifierChunks(nullifierChunks)
+        .accounts({ config, admin: wallet.publicKey })
+        .rpc()
+    );
+    await confirmFinalized(connection, sig);
+  }
+
   const vkRegistryInfo = await connection.getAccountInfo(vkRegistry);
   if (!vkRegistryInfo) {
     console.log("Initializing VK registry...");
@@ -304,8 +327,9 @@ async function main() {
     console.log("Initializing identity registry...");
     const sig = await sendWithLogs("initializeIdentityRegistry", () =>
       veilpayProgram.methods
-        .initializeIdentityRegistry()
+        .initializeIdentityRegistry(IDENTITY_TREE_DEPTH)
         .accounts({
+          config,
           identityRegistry,
           admin: wallet.publicKey,
           systemProgram: SystemProgram.programId,
@@ -339,6 +363,9 @@ async function main() {
           publicInputsLen: gammaAbc.length - 1,
           gammaAbcLen: gammaAbc.length,
           mock: false,
+          curve: 0,
+          inputEndianness: 0,
+          expectedGammaAbcHash: Array.from(gammaAbcHash(gammaAbc)),
         })
         .accounts({
           verifierKey: verifierKeyPda,
@@ -366,6 +393,16 @@ async function main() {
       );
       await confirmFinalized(connection, chunkSig);
     }
+    const finalizeSig = await sendWithLogs("finalizeVerifierKey", () =>
+      verifierProgram.methods
+        .finalizeVerifierKey()
+        .accounts({
+          verifierKey: verifierKeyPda,
+          admin: wallet.publicKey,
+        })
+        .rpc()
+    );
+    await confirmFinalized(connection, finalizeSig);
     console.log("Verifier key initialized.");
   } else {
     const fixturePath = path.resolve(process.cwd(), "app", "src", "fixtures", "verifier_key.json");
@@ -392,6 +429,27 @@ async function main() {
     console.log("Verifier key already initialized.");
   }
 
+  const vkRegistryAccount = await (veilpayProgram.account as any).vkRegistry.fetch(vkRegistry);
+  const vkEntry = vkRegistryAccount.entries.find((entry: any) => entry.circuitId === 0);
+  if (!vkEntry || !vkEntry.vkAccount.equals(verifierKeyPda)) {
+    console.log("Registering verifier key for circuit 0...");
+    const sig = await sendWithLogs("registerVkEntry", () =>
+      veilpayProgram.methods
+        .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
+        .accounts({
+          config,
+          vkRegistry,
+          verifierKey: verifierKeyPda,
+          admin: wallet.publicKey,
+        })
+        .rpc()
+    );
+    await confirmFinalized(connection, sig);
+    console.log("Verifier key registered.");
+  } else {
+    console.log("Verifier key already registered.");
+  }
+
   const mint = NATIVE_MINT;
   const configAccount = await (veilpayProgram.account as any).config.fetch(config);
   const alreadyRegistered = configAccount.mintAllowlist.some((entry: PublicKey) => entry.equals(mint));
@@ -442,7 +500,7 @@ async function main() {
     console.log("Initializing mint state...");
     const sig = await sendWithLogs("initializeMintState", () =>
       veilpayProgram.methods
-        .initializeMintState(0)
+        .initializeMintState(0, MERKLE_DEPTH, null)
         .accounts({
           config,
           vault,
@@ -514,6 +572,8 @@ async function main() {
     TOKEN_PROGRAM_ID,
     ASSOCIATED_TOKEN_PROGRAM_ID,
     SystemProgram.programId,
+    // Protocol fees go to the fee authority's ATA, which is the admin until rotated.
+    await getAssociatedTokenAddress(mint, wallet.publicKey),
   ];
   const relayerPubkey = env.VITE_RELAYER_PUBKEY || process.env.VITE_RELAYER_PUBKEY;
   if (relayerPubkey) {
@@ -530,6 +590,14 @@ async function main() {
   if (!Number.isFinite(nullifierPaddingChunks) || nullifierPaddingChunks < 0) {
     throw new Error("Invalid VITE_NULLIFIER_PADDING_CHUNKS value.");
   }
+  const poolChunks = Number(
+    (await veilpayProgram.account.vaultPool.fetch(vault)).nullifierChunks
+  );
+  if (nullifierPaddingChunks > poolChunks) {
+    throw new Error(
+      `VITE_NULLIFIER_PADDING_CHUNKS exceeds the pool's ${poolChunks} nullifier chunk(s).`
+    );
+  }
   if (nullifierPaddingChunks > 0) {
     console.log(`Ensuring ${nullifierPaddingChunks} nullifier chunk(s) for LUT padding...`);
     for (let i = 0; i < nullifierPaddingChunks; i += 1) {
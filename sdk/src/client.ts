@@ -27,6 +27,8 @@ export class VeilpayClient {
     shieldedState: PublicKey;
     userAta: PublicKey;
     mint: PublicKey;
+    // TOKEN_2022_PROGRAM_ID for Token-2022 mints.
+    tokenProgram?: PublicKey;
   }): Promise<TransactionInstruction> {
     return await this.program.methods
       .deposit({
@@ -34,6 +36,7 @@ export class VeilpayClient {
         ciphertext: Buffer.from(args.ciphertext),
         commitment: Buffer.from(args.commitment),
         newRoot: Buffer.from(args.newRoot),
+        proof: null,
       })
       .accounts({
         config: args.config,
@@ -43,7 +46,7 @@ export class VeilpayClient {
         user: this.provider.wallet.publicKey,
         userAta: args.userAta,
         mint: args.mint,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenProgram: args.tokenProgram ?? TOKEN_PROGRAM_ID,
       })
       .instruction();
   }
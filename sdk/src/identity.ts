@@ -1,7 +1,9 @@
+import { Program } from "@coral-xyz/anchor";
 import { PublicKey } from "@solana/web3.js";
 import { bytesToBigIntBE, modField, randomBytes, sha256, toHex } from "./crypto";
 import { computeIdentityCommitment } from "./prover";
-import { buildMerkleTree, getMerklePath } from "./merkle";
+import { appendToFrontier, buildMerkleTree, getMerklePath } from "./merkle";
+import { deriveIdentityRegistry } from "./pda";
 
 const identitySecretKey = (owner: PublicKey, programId: PublicKey) =>
   `veilpay.identity-secret.${programId.toBase58()}.${owner.toBase58()}`;
@@ -132,3 +134,24 @@ export async function buildIdentityRoot(programId: PublicKey): Promise<bigint> {
   const { root } = await buildMerkleTree(commitments);
   return root;
 }
+
+// `register_identity` only accepts the root of appending `commitment` to the on-chain
+// identity tree; this computes it (and the new leaf's path) from the registry frontier.
+export async function identityAppendPath(
+  program: Program,
+  commitment: bigint
+): Promise<{ root: bigint; pathElements: bigint[]; pathIndices: number[]; leafIndex: number }> {
+  const registry = await (program.account as any).identityRegistry.fetch(
+    deriveIdentityRegistry(program.programId)
+  );
+  const toBigInts = (nodes: number[][]) =>
+    nodes.map((node) => bytesToBigIntBE(Uint8Array.from(node)));
+  const leafIndex = Number(registry.commitmentCount.toString());
+  const path = await appendToFrontier(
+    toBigInts(registry.filledSubtrees),
+    toBigInts(registry.zeroHashes),
+    leafIndex,
+    commitment
+  );
+  return { ...path, leafIndex };
+}
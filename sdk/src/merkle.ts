@@ -55,3 +55,27 @@ export async function getMerklePath(
   }
   return { root, pathElements, pathIndices };
 }
+
+// Root and path of `leaf` appended at `index`, computed from an incremental tree's
+// frontier the same way the program does. Everything right of the new leaf is still
+// empty, so the path stays valid until the next append.
+export async function appendToFrontier(
+  filledSubtrees: bigint[],
+  zeroHashes: bigint[],
+  index: number,
+  leaf: bigint
+): Promise<MerklePath> {
+  const pathElements: bigint[] = [];
+  const pathIndices: number[] = [];
+  let node = leaf;
+  let position = index;
+  for (let level = 0; level < filledSubtrees.length; level += 1) {
+    const isRight = position % 2;
+    const sibling = isRight ? filledSubtrees[level] : zeroHashes[level];
+    node = isRight ? await hashPair(sibling, node) : await hashPair(node, sibling);
+    pathElements.push(sibling);
+    pathIndices.push(isRight);
+    position = Math.floor(position / 2);
+  }
+  return { root: node, pathElements, pathIndices };
+}
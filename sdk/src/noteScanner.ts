@@ -3,7 +3,7 @@ import { PublicKey } from "@solana/web3.js";
 import { decode as bs58Decode } from "@coral-xyz/anchor/dist/esm/utils/bytes/bs58.js";
 import { bytesToBigIntBE, modField } from "./crypto";
 import { computeCommitment, computeNullifier, bigIntToBytes32 } from "./prover";
-import { deriveNullifierSet } from "./pda";
+import { deriveNullifierSet, deriveVault, nullifierBitIndex, nullifierChunkIndex } from "./pda";
 import type { NoteRecord } from "./noteStore";
 import {
   decryptNotePayload,
@@ -18,7 +18,6 @@ import {
 const Buffer = globalThis.Buffer as unknown as typeof import("buffer").Buffer;
 
 const NOTE_CIPHERTEXT_BYTES = 128;
-const NULLIFIER_BITS = 8192;
 const DEFAULT_VIEW_KEY_SCAN_MAX_INDEX = 0;
 
 const parseProgramData = (line: string) => {
@@ -80,11 +79,22 @@ const parseCiphertext = (ciphertext: Uint8Array) => {
   return { c1x, c1y, c2Amount, c2Randomness };
 };
 
-const nullifierPosition = (nullifier: bigint) => {
+// Compact events hoist the mint out of each entry; re-attach it so entries decode
+// into the same shape as a typed NoteOutputEvent.
+export const expandCompactNoteOutputs = (data: any) => {
+  const entries: any[] = [];
+  for (const event of (data?.events ?? []) as any[]) {
+    const key = Object.keys(event ?? {}).find((name) => name.toLowerCase() === "noteoutput");
+    if (!key) continue;
+    entries.push({ ...event[key], mint: data.mint });
+  }
+  return entries;
+};
+
+const nullifierPosition = (nullifier: bigint, chunkCount: number) => {
   const bytes = bigIntToBytes32(nullifier);
-  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
-  const chunkIndex = view.getUint32(0, true);
-  const bitIndex = view.getUint16(4, true) % NULLIFIER_BITS;
+  const chunkIndex = nullifierChunkIndex(bytes, chunkCount);
+  const bitIndex = nullifierBitIndex(bytes);
   return { chunkIndex, bitIndex };
 };
 
@@ -241,9 +251,10 @@ const isNullifierSpent = async (
   program: Program,
   mint: PublicKey,
   nullifier: bigint,
+  chunkCount: number,
   cache: Map<number, Uint8Array>
 ) => {
-  const { chunkIndex, bitIndex } = nullifierPosition(nullifier);
+  const { chunkIndex, bitIndex } = nullifierPosition(nullifier, chunkCount);
   let bitset = cache.get(chunkIndex);
   if (!bitset) {
     const account = await (program.account as any).nullifierSet
@@ -458,6 +469,15 @@ export async function rescanNotesForOwner(params: {
             parsedEvents += 1;
           }
         }
+        if (decoded && (decoded.name === "CompactEvents" || decoded.name === "compactEvents")) {
+          for (const entry of expandCompactNoteOutputs(decoded.data as any)) {
+            const added = handleNoteEvent(entry);
+            if (added) {
+              matchedEvents += 1;
+              parsedEvents += 1;
+            }
+          }
+        }
       } catch {
         // ignore decode errors for unrelated program data
       }
@@ -541,6 +561,10 @@ export async function rescanNotesForOwner(params: {
   }
 
   const nullifierCache = new Map<number, Uint8Array>();
+  const vault = await (program.account as any).vaultPool
+    .fetch(deriveVault(program.programId, mint))
+    .catch(() => null);
+  const nullifierChunks = Number(vault?.nullifierChunks ?? 1);
   let nullifierProcessed = 0;
   const nullifierTotal = merged.size;
   for (const note of merged.values()) {
@@ -548,7 +572,13 @@ export async function rescanNotesForOwner(params: {
       continue;
     }
     const nullifier = await computeNullifier(BigInt(note.senderSecret), BigInt(note.leafIndex));
-    const spent = await isNullifierSpent(program, mint, nullifier, nullifierCache);
+    const spent = await isNullifierSpent(
+      program,
+      mint,
+      nullifier,
+      nullifierChunks,
+      nullifierCache
+    );
     note.spent = spent;
     nullifierProcessed += 1;
     if (onProgress) {
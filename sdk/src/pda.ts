@@ -1,4 +1,8 @@
+import { keccak_256 } from "@noble/hashes/sha3";
 import { PublicKey } from "@solana/web3.js";
+import { bigIntToBytes32, bytesToBigIntBE, modField } from "./crypto";
+
+const NULLIFIER_BITS = 8192;
 
 export const seeds = {
   config: (programId: PublicKey) => [Buffer.from("config"), programId.toBuffer()],
@@ -51,6 +55,37 @@ export function deriveNullifierSet(programId: PublicKey, mint: PublicKey, chunkI
   return PublicKey.findProgramAddressSync(seeds.nullifierSet(mint, chunkIndex), programId)[0];
 }
 
+// Chunk the program marks a nullifier in: the first four bytes (LE) of keccak over the
+// 32-byte big-endian nullifier, modulo the pool's `nullifierChunks`.
+export function nullifierChunkIndex(nullifier: Uint8Array, chunkCount: number): number {
+  const digest = keccak_256(nullifier);
+  const view = new DataView(digest.buffer, digest.byteOffset, digest.byteLength);
+  return view.getUint32(0, true) % Math.max(chunkCount, 1);
+}
+
+// Bit the program sets for a nullifier inside its chunk: bytes 4..6 (LE) of the same
+// keccak digest, modulo the 8192 bits of a chunk.
+export function nullifierBitIndex(nullifier: Uint8Array): number {
+  const digest = keccak_256(nullifier);
+  const view = new DataView(digest.buffer, digest.byteOffset, digest.byteLength);
+  return view.getUint16(4, true) % NULLIFIER_BITS;
+}
+
+// Public input every spend proof against `mint`'s pool exposes as `domain`: keccak over
+// the program id and mint, reduced mod r (`domain_separator` on-chain).
+export function domainSeparator(programId: PublicKey, mint: PublicKey): Uint8Array {
+  const digest = keccak_256(
+    Buffer.concat([Buffer.from("veilpay-domain"), programId.toBuffer(), mint.toBuffer()])
+  );
+  return bigIntToBytes32(modField(bytesToBigIntBE(digest)));
+}
+
+// `expected_gamma_abc_hash` for a chunked verifier key upload: keccak over the 64-byte
+// gamma_abc points concatenated in order (`finalize_verifier_key` recomputes it on-chain).
+export function gammaAbcHash(gammaAbc: Uint8Array[]): Buffer {
+  return Buffer.from(keccak_256(Buffer.concat(gammaAbc)));
+}
+
 export function deriveVerifierKey(verifierProgramId: PublicKey, keyId: number): PublicKey {
   return PublicKey.findProgramAddressSync(seeds.verifierKey(keyId), verifierProgramId)[0];
 }
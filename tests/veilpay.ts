@@ -1,16 +1,24 @@
 import * as anchor from "@coral-xyz/anchor";
+import fs from "fs";
+import path from "path";
 import { Program } from "@coral-xyz/anchor";
 import { assert } from "chai";
 import {
+  createAccount,
   createMint,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
   getAssociatedTokenAddress,
+  getMintLen,
   createAssociatedTokenAccount,
   createAssociatedTokenAccountInstruction,
   createSyncNativeInstruction,
   mintTo,
   getAccount,
+  ExtensionType,
   NATIVE_MINT,
   TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   ASSOCIATED_TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
 import {
@@ -41,17 +49,25 @@ import {
 } from "../sdk/src/notes";
 import { deriveViewKeypairFromSeed, recipientTagHashFromViewKey } from "../sdk/src/noteStore";
 import { rescanIdentityRegistry } from "../sdk/src/identityScanner";
-import { rescanNotesForOwner } from "../sdk/src/noteScanner";
+import { expandCompactNoteOutputs, rescanNotesForOwner } from "../sdk/src/noteScanner";
 import {
   restoreIdentitySecret,
   loadIdentityCommitments,
   saveIdentityCommitments,
   getIdentityMerklePath,
+  identityAppendPath,
 } from "../sdk/src/identity";
-import { buildMerkleTree } from "../sdk/src/merkle";
+import { buildMerkleTree, buildZeroes, MERKLE_DEPTH } from "../sdk/src/merkle";
+import { poseidonHash } from "../sdk/src/poseidon";
 import { computeIdentityCommitment } from "../sdk/src/prover";
 import { selectNotesForAmount } from "../sdk/src/noteStore";
-import { deriveProofAccount } from "../sdk/src/pda";
+import {
+  deriveProofAccount,
+  domainSeparator,
+  gammaAbcHash,
+  nullifierBitIndex,
+  nullifierChunkIndex,
+} from "../sdk/src/pda";
 
 const NULLIFIER = new Uint8Array(32);
 NULLIFIER[0] = 0;
@@ -82,6 +98,8 @@ const u32ToBytes32 = (value: number) => {
   out.writeUInt32BE(value, 28);
   return out;
 };
+const pubkeyToField = (key: PublicKey) =>
+  Buffer.from(bigIntToBytes32(modField(bytesToBigIntBE(key.toBytes()))));
 
 const buildLookupTable = async (
   connection: Connection,
@@ -207,6 +225,7 @@ const makePublicInputs = (params: {
   amountOut: bigint;
   feeAmount: bigint;
   circuitId: number;
+  domain: Buffer;
 }) => {
   const {
     root,
@@ -217,6 +236,7 @@ const makePublicInputs = (params: {
     amountOut,
     feeAmount,
     circuitId,
+    domain,
   } = params;
   const chunks = [
     root,
@@ -227,10 +247,18 @@ const makePublicInputs = (params: {
     u64ToBytes32(amountOut),
     u64ToBytes32(feeAmount),
     u32ToBytes32(circuitId),
+    domain,
   ];
   return Buffer.concat(chunks);
 };
 
+const hex = (value: string) => Buffer.from(value, "hex");
+// Arkworks-generated groth16 key and proof; points and inputs are hex strings.
+const loadGroth16Fixture = () =>
+  JSON.parse(
+    fs.readFileSync(path.join(process.cwd(), "tests/fixtures/groth16.json"), "utf8")
+  );
+
 const dummyG1 = Buffer.alloc(64);
 const dummyG2 = Buffer.alloc(128);
 const dummyGammaAbc = [Buffer.alloc(64)];
@@ -260,6 +288,8 @@ describe("veilpay", () => {
   const verifierProgram = (anchor.workspace.Verifier ||
     anchor.workspace.verifier) as Program;
   assert.isOk(verifierProgram, "verifier program not found in workspace");
+  const probeProgram = (anchor.workspace.VerifierProbe ||
+    anchor.workspace.verifierProbe) as Program;
 
   let mint: PublicKey;
   let vaultPda: PublicKey;
@@ -282,6 +312,15 @@ describe("veilpay", () => {
     }
   });
 
+  const deriveVerifierKeyPda = (keyId: number) => {
+    const keyIdBuf = Buffer.alloc(4);
+    keyIdBuf.writeUInt32LE(keyId, 0);
+    return PublicKey.findProgramAddressSync(
+      [Buffer.from("verifier_key"), keyIdBuf],
+      verifierProgram.programId
+    )[0];
+  };
+
   const getRoots = async () => {
     const shielded = await program.account.shieldedState.fetch(shieldedPda);
     const identity = await program.account.identityRegistry.fetch(identityRegistryPda);
@@ -300,9 +339,309 @@ describe("veilpay", () => {
     return { rootBytes, identityRootBytes };
   };
 
+  // Root the program computes after appending `leaves` to a pool's tree, replayed from
+  // the on-chain frontier so tests need not track every earlier leaf.
+  const nextRoot = async (shieldedState: PublicKey, leaves: Buffer[]) => {
+    const state = await program.account.shieldedState.fetch(shieldedState);
+    const toBigInt = (node: number[]) => bytesToBigIntBE(Uint8Array.from(node));
+    const filledSubtrees = (state.filledSubtrees as number[][]).map(toBigInt);
+    const zeroHashes = (state.zeroHashes as number[][]).map(toBigInt);
+    let index = Number(state.commitmentCount);
+    let root = toBigInt(state.merkleRoot as number[]);
+    for (const leaf of leaves) {
+      let node = bytesToBigIntBE(leaf);
+      let position = index;
+      for (let level = 0; level < filledSubtrees.length; level += 1) {
+        if (position % 2 === 0) {
+          filledSubtrees[level] = node;
+          node = await poseidonHash([node, zeroHashes[level]]);
+        } else {
+          node = await poseidonHash([filledSubtrees[level], node]);
+        }
+        position = Math.floor(position / 2);
+      }
+      root = node;
+      index += 1;
+    }
+    return Buffer.from(bigIntToBytes32(root));
+  };
+
   let proofNonce = 1n;
   const nextProofNonce = () => proofNonce++;
 
+  const deriveConfigPda = () =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), program.programId.toBuffer()],
+      program.programId
+    )[0];
+
+  const deriveVkRegistryPda = () =>
+    PublicKey.findProgramAddressSync([Buffer.from("vk_registry")], program.programId)[0];
+
+  const fetchEvents = async (signature: string) => {
+    for (let attempt = 0; attempt < 20; attempt += 1) {
+      const tx = await provider.connection.getTransaction(signature, {
+        maxSupportedTransactionVersion: 0,
+        commitment: "confirmed",
+      });
+      if (tx?.meta?.logMessages) {
+        const parser = new anchor.EventParser(program.programId, program.coder);
+        return Array.from(parser.parseLogs(tx.meta.logMessages));
+      }
+      await new Promise((resolve) => setTimeout(resolve, 200));
+    }
+    throw new Error(`missing logs for ${signature}`);
+  };
+  const eventsNamed = (events: anchor.Event[], name: string) =>
+    events.filter((event) => event.name.toLowerCase() === name.toLowerCase());
+
+  const expectError = async (action: Promise<unknown>, code: string) => {
+    try {
+      await action;
+    } catch (err: any) {
+      assert.include(err.toString(), code);
+      return;
+    }
+    assert.fail(`expected ${code}`);
+  };
+
+  let testNullifierBit = 4_000;
+  const nextTestNullifier = () => {
+    const value = Buffer.alloc(32);
+    value.writeUInt16LE(testNullifierBit++, 4);
+    return value;
+  };
+
+  type MintPool = {
+    mint: PublicKey;
+    vault: PublicKey;
+    vaultAta: PublicKey;
+    shieldedState: PublicKey;
+    nullifierSet: PublicKey;
+    tokenProgram?: PublicKey;
+  };
+  const poolDomain = (poolMint: PublicKey) =>
+    Buffer.from(domainSeparator(program.programId, poolMint));
+  const mainPool = (): MintPool => ({
+    mint,
+    vault: vaultPda,
+    vaultAta,
+    shieldedState: shieldedPda,
+    nullifierSet: nullifierPda,
+  });
+
+  const withdrawWithProof = async (params: {
+    amount: bigint;
+    pool?: MintPool;
+    nullifier?: Buffer;
+    nullifiers?: Buffer[];
+    feeAmount?: bigint;
+    relayerFeeBps?: number;
+    relayerFeeAta?: PublicKey | null;
+    relayerFeeLamports?: bigint;
+    feeEscrow?: PublicKey | null;
+    recipientSigns?: boolean;
+    protocolFeeAta?: PublicKey | null;
+    recipient?: Keypair;
+    circuitId?: number;
+    root?: Buffer;
+    identityRoot?: Buffer;
+    slotWindow?: [bigint, bigint];
+    valueIn?: bigint;
+    relayerFeeRecipient?: PublicKey;
+    domain?: Buffer;
+    verifierKey?: PublicKey;
+    verifierKeyAlt?: PublicKey;
+    proofSystem?: number;
+    change?: { commitment: Buffer; newRoot: Buffer };
+    remainingAccounts?: { pubkey: PublicKey; isSigner: boolean; isWritable: boolean }[];
+    tokenProgram?: PublicKey;
+  }) => {
+    const pool = params.pool ?? mainPool();
+    const recipient = params.recipient ?? Keypair.generate();
+    const tokenProgram = pool.tokenProgram ?? TOKEN_PROGRAM_ID;
+    await ensureSystemAccount(provider.connection, recipient.publicKey);
+    const recipientAta = await getAssociatedTokenAddress(
+      pool.mint,
+      recipient.publicKey,
+      false,
+      tokenProgram
+    );
+    if (!(await provider.connection.getAccountInfo(recipientAta))) {
+      await createAssociatedTokenAccount(
+        provider.connection,
+        provider.wallet.payer,
+        pool.mint,
+        recipient.publicKey,
+        undefined,
+        tokenProgram
+      );
+    }
+    const tempAuthority = await deriveTempAuthority(program, pool.vault, recipient.publicKey);
+    const tempWsolAta = await getAssociatedTokenAddress(pool.mint, tempAuthority, true);
+    const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    const { identityRootBytes } = await getRoots();
+    const baseInputs = makePublicInputs({
+      root: params.root ?? Buffer.from(shielded.merkleRoot as number[]),
+      identityRoot: params.identityRoot ?? identityRootBytes,
+      nullifiers: params.nullifiers ?? [
+        params.nullifier ?? nextTestNullifier(),
+        zero32(),
+        zero32(),
+        zero32(),
+      ],
+      outputCommitments: [zero32(), params.change?.commitment ?? zero32()],
+      outputEnabled: [0, params.change ? 1 : 0],
+      amountOut: params.amount,
+      feeAmount: params.feeAmount ?? 0n,
+      circuitId: params.circuitId ?? 0,
+      domain: params.domain ?? poolDomain(pool.mint),
+    });
+    const publicInputs = Buffer.concat([
+      baseInputs,
+      ...(params.slotWindow
+        ? [u64ToBytes32(params.slotWindow[0]), u64ToBytes32(params.slotWindow[1])]
+        : []),
+      ...(params.valueIn !== undefined ? [u64ToBytes32(params.valueIn)] : []),
+      ...(params.relayerFeeRecipient ? [pubkeyToField(params.relayerFeeRecipient)] : []),
+    ]);
+    const nonce = nextProofNonce();
+    const proofAccount = deriveProofAccount(program.programId, pool.mint, nonce);
+    await program.methods
+      .storeProof({
+        nonce: new anchor.BN(nonce.toString()),
+        recipient: recipient.publicKey,
+        destinationAta: recipientAta,
+        mint: pool.mint,
+        proof: dummyProof,
+        publicInputs,
+      })
+      .accounts({
+        proofAccount,
+        payer: provider.wallet.publicKey,
+        mint: pool.mint,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const signature = await program.methods
+      .externalTransferWithProof({
+        amount: new anchor.BN(params.amount.toString()),
+        relayerFeeBps: params.relayerFeeBps ?? 0,
+        relayerFeeLamports: new anchor.BN((params.relayerFeeLamports ?? 0n).toString()),
+        newRoot: params.change?.newRoot ?? buf(NEW_ROOT),
+        outputCiphertexts: params.change ? Buffer.alloc(128) : Buffer.alloc(0),
+        deliverSol: false,
+        proofSystem: params.proofSystem ?? 0,
+      })
+      .accounts({
+        config: deriveConfigPda(),
+        payer: provider.wallet.publicKey,
+        vault: pool.vault,
+        vaultAta: pool.vaultAta,
+        shieldedState: pool.shieldedState,
+        identityRegistry: identityRegistryPda,
+        nullifierSet: pool.nullifierSet,
+        proofAccount,
+        destinationAta: recipientAta,
+        recipient: recipient.publicKey,
+        tempAuthority,
+        tempWsolAta,
+        relayerFeeAta: params.relayerFeeAta ?? null,
+        protocolFeeAta: params.protocolFeeAta ?? null,
+        verifierProgram: verifierProgram.programId,
+        verifierKey: params.verifierKey ?? verifierKeyPda,
+        verifierKeyAlt: params.verifierKeyAlt ?? null,
+        mint: pool.mint,
+        tokenProgram: params.tokenProgram ?? tokenProgram,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        feeEscrow: params.feeEscrow ?? null,
+      })
+      .remainingAccounts(params.remainingAccounts ?? [])
+      .signers(params.recipientSigns ? [recipient] : [])
+      .rpc();
+    return { signature, recipient: recipient.publicKey, recipientAta };
+  };
+
+  const setNullifierChunks = (nullifierChunks: number, admin?: Keypair) =>
+    program.methods
+      .setNullifierChunks(nullifierChunks)
+      .accounts({
+        config: deriveConfigPda(),
+        admin: admin?.publicKey ?? provider.wallet.publicKey,
+      })
+      .signers(admin ? [admin] : [])
+      .rpc();
+
+  const setupMintPool = async (
+    nullifierChunks = 1,
+    treeDepth = MERKLE_DEPTH,
+    createVaultAta = true,
+    circuitId: number | null = null
+  ): Promise<MintPool> => {
+    const poolMint = await createMint(
+      provider.connection,
+      provider.wallet.payer,
+      provider.wallet.publicKey,
+      null,
+      6
+    );
+    await program.methods
+      .registerMint(poolMint)
+      .accounts({ config: deriveConfigPda(), admin: provider.wallet.publicKey })
+      .rpc();
+    const [poolVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), poolMint.toBuffer()],
+      program.programId
+    );
+    const [poolShielded] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shielded"), poolMint.toBuffer()],
+      program.programId
+    );
+    const [poolNullifier] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), poolMint.toBuffer(), Buffer.from([0, 0, 0, 0])],
+      program.programId
+    );
+    const poolVaultAta = await getAssociatedTokenAddress(poolMint, poolVault, true);
+    if (createVaultAta) {
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          createAssociatedTokenAccountInstruction(
+            provider.wallet.publicKey,
+            poolVaultAta,
+            poolVault,
+            poolMint
+          )
+        )
+      );
+    }
+    await setNullifierChunks(nullifierChunks);
+    try {
+      await program.methods
+        .initializeMintState(0, treeDepth, circuitId)
+        .accounts({
+          config: deriveConfigPda(),
+          vault: poolVault,
+          vaultAta: poolVaultAta,
+          shieldedState: poolShielded,
+          nullifierSet: poolNullifier,
+          admin: provider.wallet.publicKey,
+          mint: poolMint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    } finally {
+      await setNullifierChunks(1);
+    }
+    return {
+      mint: poolMint,
+      vault: poolVault,
+      vaultAta: poolVaultAta,
+      shieldedState: poolShielded,
+      nullifierSet: poolNullifier,
+    };
+  };
+
   it("initializes config and registry", async () => {
     const [configPda] = PublicKey.findProgramAddressSync(
       [Buffer.from("config"), program.programId.toBuffer()],
@@ -326,8 +665,9 @@ describe("veilpay", () => {
     if (!configInfo) {
       await program.methods
         .initializeConfig({
-          feeBps: 25,
+          feeBps: 0,
           relayerFeeBpsMax: 50,
+          relayerFeeBpsMin: 0,
           vkRegistry: vkRegistryPda,
           mintAllowlist: [],
           circuitIds: [0],
@@ -339,6 +679,8 @@ describe("veilpay", () => {
         })
         .rpc();
     }
+    // The suite's pools keep every spend in chunk 0 unless a test asks for more chunks.
+    await setNullifierChunks(1);
 
     const vkInfo = await provider.connection.getAccountInfo(vkRegistryPda);
     if (!vkInfo) {
@@ -353,12 +695,7 @@ describe("veilpay", () => {
     }
 
     const keyId = 0;
-    const keyIdBuf = Buffer.alloc(4);
-    keyIdBuf.writeUInt32LE(keyId, 0);
-    [verifierKeyPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from("verifier_key"), keyIdBuf],
-      verifierProgram.programId
-    );
+    verifierKeyPda = deriveVerifierKeyPda(keyId);
 
     const verifierInfo = await provider.connection.getAccountInfo(verifierKeyPda);
     if (!verifierInfo) {
@@ -369,9 +706,11 @@ describe("veilpay", () => {
           betaG2: dummyG2,
           gammaG2: dummyG2,
           deltaG2: dummyG2,
-          publicInputsLen: 13,
+          publicInputsLen: 14,
           gammaAbc: dummyGammaAbc,
           mock: true,
+          curve: 0,
+          inputEndianness: 0,
         })
         .accounts({
           verifierKey: verifierKeyPda,
@@ -382,17 +721,28 @@ describe("veilpay", () => {
     }
 
     await verifierProgram.methods
-      .verifyGroth16(dummyProof, Buffer.concat(Array.from({ length: 13 }, () => zero32())))
+      .verifyGroth16(dummyProof, Buffer.concat(Array.from({ length: 14 }, () => zero32())))
+      .accounts({
+        verifierKey: verifierKeyPda,
+      })
+      .rpc();
+
+    await program.methods
+      .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
       .accounts({
+        config: configPda,
+        vkRegistry: vkRegistryPda,
         verifierKey: verifierKeyPda,
+        admin: provider.wallet.publicKey,
       })
       .rpc();
 
     const identityInfo = await provider.connection.getAccountInfo(identityRegistryPda);
     if (!identityInfo) {
       await program.methods
-        .initializeIdentityRegistry()
+        .initializeIdentityRegistry(20)
         .accounts({
+          config: configPda,
           identityRegistry: identityRegistryPda,
           admin: provider.wallet.publicKey,
           systemProgram: SystemProgram.programId,
@@ -412,7 +762,7 @@ describe("veilpay", () => {
         `[identity-rescan] debug registered commitment=${Buffer.from(bigIntToBytes32(commitment)).toString("hex")}`
       );
       identityCommitment = commitment;
-      const { root } = await buildMerkleTree([commitment]);
+      const { root } = await identityAppendPath(program, commitment);
       await program.methods
         .registerIdentity({
           commitment: buf(bigIntToBytes32(commitment)),
@@ -423,13 +773,14 @@ describe("veilpay", () => {
           identityMember: identityMemberPda,
           payer: owner,
           user: owner,
+          identityAdmin: owner,
           systemProgram: SystemProgram.programId,
         })
         .rpc();
     }
 
     const config = await program.account.config.fetch(configPda);
-    assert.equal(config.feeBps, 25);
+    assert.equal(config.feeBps, 0);
   });
 
   it("registers mint and initializes mint state", async () => {
@@ -485,7 +836,7 @@ describe("veilpay", () => {
     await provider.sendAndConfirm(new anchor.web3.Transaction().add(vaultAtaIx));
 
     await program.methods
-      .initializeMintState(0)
+      .initializeMintState(0, MERKLE_DEPTH, null)
       .accounts({
         config: configPda,
         vault: vaultPda,
@@ -522,7 +873,7 @@ describe("veilpay", () => {
         amount: new anchor.BN(500_000),
         ciphertext: buf(CIPHERTEXT),
         commitment: buf(COMMITMENT),
-        newRoot: buf(NEW_ROOT),
+        newRoot: await nextRoot(shieldedPda, [buf(COMMITMENT)]),
       })
       .accounts({
         config: configPda,
@@ -558,6 +909,7 @@ describe("veilpay", () => {
       amountOut: 100_000n,
       feeAmount: 0n,
       circuitId: 0,
+      domain: poolDomain(mint),
     });
 
     const proofOwner = provider.wallet.publicKey;
@@ -584,9 +936,11 @@ describe("veilpay", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(100_000),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(NEW_ROOT),
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: false,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -602,6 +956,7 @@ describe("veilpay", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: verifierKeyPda,
         mint,
@@ -653,6 +1008,7 @@ describe("veilpay", () => {
       amountOut: 100_000n,
       feeAmount: 250n,
       circuitId: 0,
+      domain: poolDomain(mint),
     });
 
     const feeProofOwner = provider.wallet.publicKey;
@@ -679,9 +1035,11 @@ describe("veilpay", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(100_000),
         relayerFeeBps: 25,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(NEW_ROOT),
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: false,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -697,6 +1055,7 @@ describe("veilpay", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: relayerAta,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: verifierKeyPda,
         mint,
@@ -754,6 +1113,7 @@ describe("veilpay", () => {
         amountOut: 10_000n,
         feeAmount: 0n,
         circuitId: 0,
+        domain: poolDomain(mint),
       });
       const dsProofOwner = provider.wallet.publicKey;
       const dsProofNonce = nextProofNonce();
@@ -778,9 +1138,11 @@ describe("veilpay", () => {
         .externalTransferWithProof({
           amount: new anchor.BN(10_000),
           relayerFeeBps: 0,
+          relayerFeeLamports: new anchor.BN(0),
           newRoot: buf(NEW_ROOT),
           outputCiphertexts: Buffer.alloc(0),
           deliverSol: false,
+          proofSystem: 0,
         })
         .accounts({
           config: configPda,
@@ -796,6 +1158,7 @@ describe("veilpay", () => {
           tempAuthority,
           tempWsolAta,
           relayerFeeAta: null,
+          protocolFeeAta: null,
           verifierProgram: verifierProgram.programId,
           verifierKey: verifierKeyPda,
           mint,
@@ -842,6 +1205,7 @@ describe("veilpay", () => {
         amountOut: 10_000n,
         feeAmount: 0n,
         circuitId: 0,
+        domain: poolDomain(mint),
       });
       const urProofOwner = provider.wallet.publicKey;
       const urProofNonce = nextProofNonce();
@@ -866,9 +1230,11 @@ describe("veilpay", () => {
         .externalTransferWithProof({
           amount: new anchor.BN(10_000),
           relayerFeeBps: 0,
+          relayerFeeLamports: new anchor.BN(0),
           newRoot: buf(NEW_ROOT),
           outputCiphertexts: Buffer.alloc(0),
           deliverSol: false,
+          proofSystem: 0,
         })
         .accounts({
           config: configPda,
@@ -884,6 +1250,7 @@ describe("veilpay", () => {
           tempAuthority,
           tempWsolAta,
           relayerFeeAta: null,
+          protocolFeeAta: null,
           verifierProgram: verifierProgram.programId,
           verifierKey: verifierKeyPda,
           mint,
@@ -907,8 +1274,7 @@ describe("veilpay", () => {
     const internalNullifier = new Uint8Array(32);
     internalNullifier[0] = 0;
     internalNullifier[4] = 11;
-    const internalRoot = new Uint8Array(32);
-    internalRoot[0] = 55;
+    const internalRoot = await nextRoot(shieldedPda, [zero32()]);
 
     const { rootBytes, identityRootBytes } = await getRoots();
     const internalInputs = makePublicInputs({
@@ -920,6 +1286,7 @@ describe("veilpay", () => {
       amountOut: 0n,
       feeAmount: 0n,
       circuitId: 0,
+      domain: poolDomain(mint),
     });
 
     const internalProofOwner = provider.wallet.publicKey;
@@ -943,7 +1310,7 @@ describe("veilpay", () => {
       .rpc();
     await program.methods
       .internalTransferWithProof({
-        newRoot: buf(internalRoot),
+        newRoot: internalRoot,
         outputCiphertexts: Buffer.alloc(128),
       })
       .accounts({
@@ -960,7 +1327,7 @@ describe("veilpay", () => {
       .rpc();
 
     const shielded = await program.account.shieldedState.fetch(shieldedPda);
-    assert.equal(shielded.merkleRoot[0], internalRoot[0]);
+    assert.deepEqual(Buffer.from(shielded.merkleRoot as number[]), internalRoot);
 
     const externalNullifier = new Uint8Array(32);
     externalNullifier[0] = 0;
@@ -978,7 +1345,7 @@ describe("veilpay", () => {
     const tempWsolAta = await getAssociatedTokenAddress(mint, tempAuthority, true);
 
     const externalInputs = makePublicInputs({
-      root: buf(internalRoot),
+      root: internalRoot,
       identityRoot: identityRootBytes,
       nullifiers: [buf(externalNullifier), zero32(), zero32(), zero32()],
       outputCommitments: [zero32(), zero32()],
@@ -986,6 +1353,7 @@ describe("veilpay", () => {
       amountOut: 25_000n,
       feeAmount: 0n,
       circuitId: 0,
+      domain: poolDomain(mint),
     });
 
     const extProofOwner = provider.wallet.publicKey;
@@ -1011,9 +1379,11 @@ describe("veilpay", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(25_000),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(NEW_ROOT),
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: false,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -1029,6 +1399,7 @@ describe("veilpay", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: verifierKeyPda,
         mint,
@@ -1092,7 +1463,7 @@ describe("veilpay", () => {
     const vaultInfo = await provider.connection.getAccountInfo(wsolVaultPda);
     if (!vaultInfo) {
       await program.methods
-        .initializeMintState(0)
+        .initializeMintState(0, MERKLE_DEPTH, null)
         .accounts({
           config: configPda,
           vault: wsolVaultPda,
@@ -1131,8 +1502,7 @@ describe("veilpay", () => {
     const internalNullifier = new Uint8Array(32);
     internalNullifier[0] = 0;
     internalNullifier[4] = 21;
-    const internalRoot = new Uint8Array(32);
-    internalRoot[0] = 77;
+    const internalRoot = await nextRoot(wsolShieldedPda, [zero32()]);
     const internalInputs = makePublicInputs({
       root: wsolRootBytes,
       identityRoot: identityRootBytes,
@@ -1142,6 +1512,7 @@ describe("veilpay", () => {
       amountOut: 0n,
       feeAmount: 0n,
       circuitId: 0,
+      domain: poolDomain(wsolMint),
     });
 
     const wsolInternalProofOwner = provider.wallet.publicKey;
@@ -1169,7 +1540,7 @@ describe("veilpay", () => {
       .rpc();
     await program.methods
       .internalTransferWithProof({
-        newRoot: buf(internalRoot),
+        newRoot: internalRoot,
         outputCiphertexts: Buffer.alloc(128),
       })
       .accounts({
@@ -1201,7 +1572,7 @@ describe("veilpay", () => {
     externalNullifier[4] = 22;
     const amountOut = 100_000n;
     const externalInputs = makePublicInputs({
-      root: buf(internalRoot),
+      root: internalRoot,
       identityRoot: identityRootBytes,
       nullifiers: [buf(externalNullifier), zero32(), zero32(), zero32()],
       outputCommitments: [zero32(), zero32()],
@@ -1209,6 +1580,7 @@ describe("veilpay", () => {
       amountOut,
       feeAmount: 0n,
       circuitId: 0,
+      domain: poolDomain(wsolMint),
     });
 
     const beforeBalance = await provider.connection.getBalance(recipient.publicKey);
@@ -1235,9 +1607,11 @@ describe("veilpay", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(amountOut.toString()),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(NEW_ROOT),
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: true,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -1253,6 +1627,7 @@ describe("veilpay", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: verifierKeyPda,
         mint: wsolMint,
@@ -1297,7 +1672,7 @@ describe("veilpay", () => {
         amount: new anchor.BN(amount.toString()),
         ciphertext: Buffer.from(enc.ciphertext),
         commitment: Buffer.from(bigIntToBytes32(commitment)),
-        newRoot: buf(NEW_ROOT),
+        newRoot: await nextRoot(shieldedPda, [Buffer.from(bigIntToBytes32(commitment))]),
       })
       .accounts({
         config: configPda,
@@ -1466,7 +1841,7 @@ describe("veilpay", () => {
           amount: new anchor.BN(amount.toString()),
           ciphertext: Buffer.from(enc.ciphertext),
           commitment: Buffer.from(bigIntToBytes32(commitment)),
-          newRoot: buf(NEW_ROOT),
+          newRoot: await nextRoot(shieldedPda, [Buffer.from(bigIntToBytes32(commitment))]),
         })
         .accounts({
           config: configPda,
@@ -1509,4 +1884,3899 @@ describe("veilpay", () => {
     assert.equal(selection.notes.length, 2);
     assert.equal(selection.total.toString(), (amountA + amountB).toString());
   });
+
+  it("initializes the shielded tree to the empty root for its depth", async () => {
+    const pool = await setupMintPool();
+    const initializeTree = (depth: number) =>
+      program.methods
+        .initializeTree(depth)
+        .accounts({
+          config: deriveConfigPda(),
+          shieldedState: pool.shieldedState,
+          admin: provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .rpc();
+
+    await initializeTree(20);
+    let shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    const zeroes20 = await buildZeroes(20);
+    assert.equal(
+      Buffer.from(shielded.merkleRoot as number[]).toString("hex"),
+      Buffer.from(bigIntToBytes32(zeroes20[20])).toString("hex")
+    );
+    assert.equal(
+      Buffer.from(shielded.merkleRoot as number[]).toString("hex"),
+      "2134e76ac5d21aab186c2be1dd8f84ee880a1e46eaf712f9d371b6df22191f3e"
+    );
+
+    await initializeTree(4);
+    shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    const zeroes4 = await buildZeroes(4);
+    assert.equal(shielded.treeDepth, 4);
+    assert.equal((shielded.filledSubtrees as number[][]).length, 4);
+    (shielded.filledSubtrees as number[][]).forEach((node, level) => {
+      assert.equal(
+        Buffer.from(node).toString("hex"),
+        Buffer.from(bigIntToBytes32(zeroes4[level])).toString("hex")
+      );
+    });
+    assert.equal(
+      Buffer.from(shielded.merkleRoot as number[]).toString("hex"),
+      Buffer.from(bigIntToBytes32(zeroes4[4])).toString("hex")
+    );
+
+    try {
+      await initializeTree(0);
+      assert.fail("expected invalid depth to be rejected");
+    } catch (err: any) {
+      assert.include(err.toString(), "InvalidTreeDepth");
+    }
+  });
+
+  it("emits compact events that decode to the typed note outputs", async () => {
+    const configPda = deriveConfigPda();
+    const setCompactEvents = (enabled: boolean) =>
+      program.methods
+        .setCompactEvents(enabled)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const depositWith = async (commitment: Buffer, ciphertext: Buffer) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext,
+          commitment,
+          newRoot: await nextRoot(shieldedPda, [commitment]),
+        })
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          vaultAta,
+          shieldedState: shieldedPda,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    const commitment = Buffer.alloc(32, 7);
+    const ciphertext = Buffer.alloc(128, 3);
+
+    await setCompactEvents(true);
+    let compactEvents: anchor.Event[];
+    try {
+      compactEvents = await fetchEvents(await depositWith(commitment, ciphertext));
+    } finally {
+      await setCompactEvents(false);
+    }
+    assert.lengthOf(eventsNamed(compactEvents, "NoteOutputEvent"), 0);
+    const [compact] = eventsNamed(compactEvents, "CompactEvents");
+    assert.isOk(compact, "missing compact event");
+    assert.equal(compact.data.version, 1);
+    const [compactNote] = expandCompactNoteOutputs(compact.data);
+
+    const typedEvents = await fetchEvents(await depositWith(commitment, ciphertext));
+    assert.lengthOf(eventsNamed(typedEvents, "CompactEvents"), 0);
+    const [typed] = eventsNamed(typedEvents, "NoteOutputEvent");
+    assert.isOk(typed, "missing typed note output event");
+
+    assert.equal(new PublicKey(compactNote.mint).toBase58(), typed.data.mint.toBase58());
+    assert.equal(Number(compactNote.leafIndex) + 1, Number(typed.data.leafIndex));
+    assert.equal(compactNote.kind, typed.data.kind);
+    assert.deepEqual(Buffer.from(compactNote.commitment), Buffer.from(typed.data.commitment as number[]));
+    assert.deepEqual(Buffer.from(compactNote.ciphertext), Buffer.from(typed.data.ciphertext as number[]));
+  });
+
+  it("queues withdrawals above the threshold and pays them out after the delay", async () => {
+    const configPda = deriveConfigPda();
+    const configureQueue = (threshold: bigint, delaySlots: number) =>
+      program.methods
+        .configureWithdrawQueue(new anchor.BN(threshold.toString()), new anchor.BN(delaySlots))
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const queueWithdrawal = async (amount: bigint, nullifier: Buffer) => {
+      const recipient = Keypair.generate();
+      const recipientAta = await createAssociatedTokenAccount(
+        provider.connection,
+        provider.wallet.payer,
+        mint,
+        recipient.publicKey
+      );
+      const { rootBytes, identityRootBytes } = await getRoots();
+      const publicInputs = makePublicInputs({
+        root: rootBytes,
+        identityRoot: identityRootBytes,
+        nullifiers: [nullifier, zero32(), zero32(), zero32()],
+        outputCommitments: [zero32(), zero32()],
+        outputEnabled: [0, 0],
+        amountOut: amount,
+        feeAmount: 0n,
+        circuitId: 0,
+        domain: poolDomain(mint),
+      });
+      const nonce = nextProofNonce();
+      const proofAccount = deriveProofAccount(program.programId, mint, nonce);
+      await program.methods
+        .storeProof({
+          nonce: new anchor.BN(nonce.toString()),
+          recipient: recipient.publicKey,
+          destinationAta: recipientAta,
+          mint,
+          proof: dummyProof,
+          publicInputs,
+        })
+        .accounts({
+          proofAccount,
+          payer: provider.wallet.publicKey,
+          mint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      const vault = await program.account.vaultPool.fetch(vaultPda);
+      const [pendingWithdrawal] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("withdrawal"),
+          mint.toBuffer(),
+          (vault.nonce as anchor.BN).toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .queueWithdrawal({
+          amount: new anchor.BN(amount.toString()),
+          relayerFeeBps: 0,
+          newRoot: buf(NEW_ROOT),
+          outputCiphertexts: Buffer.alloc(0),
+        })
+        .accounts({
+          config: configPda,
+          payer: provider.wallet.publicKey,
+          vault: vaultPda,
+          vaultAta,
+          shieldedState: shieldedPda,
+          identityRegistry: identityRegistryPda,
+          nullifierSet: nullifierPda,
+          proofAccount,
+          pendingWithdrawal,
+          relayerFeeAta: null,
+          protocolFeeAta: null,
+          verifierProgram: verifierProgram.programId,
+          verifierKey: verifierKeyPda,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      return { pendingWithdrawal, recipientAta };
+    };
+    const claimWithdrawal = (queued: { pendingWithdrawal: PublicKey; recipientAta: PublicKey }) =>
+      program.methods
+        .claimWithdrawal()
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          vaultAta,
+          pendingWithdrawal: queued.pendingWithdrawal,
+          destinationAta: queued.recipientAta,
+          rentPayer: provider.wallet.publicKey,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await configureQueue(5_000n, 1_000_000);
+    try {
+      await expectError(withdrawWithProof({ amount: 5_000n }), "WithdrawalMustBeQueued");
+      await withdrawWithProof({ amount: 1_000n });
+
+      const queuedNullifier = nextTestNullifier();
+      const early = await queueWithdrawal(5_000n, queuedNullifier);
+      await expectError(queueWithdrawal(5_000n, queuedNullifier), "NullifierAlreadyUsed");
+      await expectError(claimWithdrawal(early), "WithdrawalNotReady");
+
+      await configureQueue(5_000n, 2);
+      const ready = await queueWithdrawal(5_000n, nextTestNullifier());
+      const pending = await program.account.pendingWithdrawal.fetch(ready.pendingWithdrawal);
+      const readySlot = Number(pending.readySlot);
+      while ((await provider.connection.getSlot("confirmed")) < readySlot) {
+        await new Promise((resolve) => setTimeout(resolve, 200));
+      }
+      await claimWithdrawal(ready);
+
+      const recipientAccount = await getAccount(provider.connection, ready.recipientAta);
+      assert.equal(recipientAccount.amount.toString(), "5000");
+      assert.isNull(await provider.connection.getAccountInfo(ready.pendingWithdrawal));
+    } finally {
+      await configureQueue(0n, 0);
+    }
+  });
+
+  it("rejects registering a verifier key with the wrong public input count", async () => {
+    const mismatchedKeyPda = deriveVerifierKeyPda(1206);
+    if (!(await provider.connection.getAccountInfo(mismatchedKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1206,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 12,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: mismatchedKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    const registerVkEntry = (verifierKey: PublicKey) =>
+      program.methods
+        .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
+        .accounts({
+          config: deriveConfigPda(),
+          vkRegistry: deriveVkRegistryPda(),
+          verifierKey,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+
+    await expectError(registerVkEntry(mismatchedKeyPda), "VkInputCountMismatch");
+
+    await registerVkEntry(verifierKeyPda);
+    const registry = await program.account.vkRegistry.fetch(deriveVkRegistryPda());
+    const entry = (registry.entries as any[]).find((item) => item.circuitId === 0);
+    assert.isOk(entry, "missing vk entry for circuit 0");
+    assert.equal(entry.vkAccount.toBase58(), verifierKeyPda.toBase58());
+    assert.equal(entry.status, 1);
+  });
+
+  it("stamps transfer events with the verifying circuit id", async () => {
+    const { signature } = await withdrawWithProof({ amount: 2_000n, circuitId: 0 });
+    const [transfer] = eventsNamed(await fetchEvents(signature), "TransferEvent");
+    assert.isOk(transfer, "missing transfer event");
+    assert.equal(transfer.data.circuitId, 0);
+    assert.equal(transfer.data.kind, 1);
+    assert.equal(transfer.data.mint.toBase58(), mint.toBase58());
+    assert.equal(transfer.data.amount.toString(), "2000");
+    assert.equal(transfer.data.feeAmount.toString(), "0");
+  });
+
+  it("rejects nullifier chunks beyond the configured cap", async () => {
+    const pool = await setupMintPool(4);
+    const setMaxChunks = (maxChunks: number) =>
+      program.methods
+        .setMaxChunks(maxChunks)
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          admin: provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .rpc();
+    const initializeChunk = (chunkIndex: number) => {
+      const chunkBuf = Buffer.alloc(4);
+      chunkBuf.writeUInt32LE(chunkIndex, 0);
+      const [nullifierSet] = PublicKey.findProgramAddressSync(
+        [Buffer.from("nullifier_set"), pool.mint.toBuffer(), chunkBuf],
+        program.programId
+      );
+      return program.methods
+        .initializeNullifierChunk(chunkIndex)
+        .accounts({
+          config: deriveConfigPda(),
+          nullifierSet,
+          vault: pool.vault,
+          payer: provider.wallet.publicKey,
+          mint: pool.mint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    };
+
+    const vault = await program.account.vaultPool.fetch(pool.vault);
+    assert.equal(vault.maxChunks, 0xffffffff);
+    assert.equal(vault.nullifierChunks, 4);
+    await expectError(initializeChunk(4), "ChunkIndexOutOfRange");
+
+    await setMaxChunks(2);
+    await initializeChunk(1);
+    await expectError(initializeChunk(2), "ChunkIndexOutOfRange");
+  });
+
+  it("populates a verifier key's gamma_abc across resumed batch writes", async () => {
+    const keyId = 1209;
+    const batchKeyPda = deriveVerifierKeyPda(keyId);
+    if (!(await provider.connection.getAccountInfo(batchKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKeyHeader({
+          keyId,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 13,
+          gammaAbcLen: 14,
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+          expectedGammaAbcHash: Array.from(
+            gammaAbcHash(Array.from({ length: 14 }, (_, index) => Buffer.alloc(64, index + 1)))
+          ),
+        })
+        .accounts({
+          verifierKey: batchKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    const gammaAbc = Array.from({ length: 14 }, (_, index) =>
+      Array.from(Buffer.alloc(64, index + 1))
+    );
+    const writeBatch = (startIndex: number) =>
+      verifierProgram.methods
+        .writeGammaAbcBatch({
+          keyId,
+          startIndex,
+          gammaAbc: gammaAbc.slice(startIndex, startIndex + 12),
+        })
+        .accounts({ verifierKey: batchKeyPda, admin: provider.wallet.publicKey })
+        .rpc();
+
+    await writeBatch(0);
+    let key = await verifierProgram.account.verifierKey.fetch(batchKeyPda);
+    const resumeAt = key.gammaAbcWritten as number;
+    assert.isAbove(resumeAt, 0);
+    assert.isBelow(resumeAt, 14);
+
+    await writeBatch(resumeAt);
+    key = await verifierProgram.account.verifierKey.fetch(batchKeyPda);
+    assert.equal(key.gammaAbcWritten, 14);
+    (key.gammaAbc as number[][]).forEach((entry, index) => {
+      assert.deepEqual(Buffer.from(entry), Buffer.alloc(64, index + 1));
+    });
+
+    await expectError(
+      verifierProgram.methods
+        .writeGammaAbcBatch({ keyId, startIndex: 10, gammaAbc: gammaAbc.slice(0, 5) })
+        .accounts({ verifierKey: batchKeyPda, admin: provider.wallet.publicKey })
+        .rpc(),
+      "InvalidInputCount"
+    );
+  });
+
+  it("expires historical roots by age only when age-based pruning is enabled", async () => {
+    const configPda = deriveConfigPda();
+    const setRootMaxAge = (maxAgeSlots: number) =>
+      program.methods
+        .setRootMaxAge(new anchor.BN(maxAgeSlots))
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    // Returns the root the deposit recorded.
+    const deposit = async () => {
+      const commitment = Buffer.alloc(32, 2);
+      const newRoot = await nextRoot(shieldedPda, [commitment]);
+      await program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment,
+          newRoot,
+        })
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          vaultAta,
+          shieldedState: shieldedPda,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      return newRoot;
+    };
+    const waitSlots = async (count: number) => {
+      const target = (await provider.connection.getSlot("confirmed")) + count;
+      while ((await provider.connection.getSlot("confirmed")) < target) {
+        await new Promise((resolve) => setTimeout(resolve, 200));
+      }
+    };
+
+    await setRootMaxAge(2);
+    let staleRoot!: Buffer;
+    try {
+      staleRoot = await deposit();
+      const currentRoot = await deposit();
+      await waitSlots(4);
+      await expectError(withdrawWithProof({ amount: 1_000n, root: staleRoot }), "UnknownRoot");
+      await withdrawWithProof({ amount: 1_000n, root: currentRoot });
+    } finally {
+      await setRootMaxAge(0);
+    }
+
+    await withdrawWithProof({ amount: 1_000n, root: staleRoot });
+  });
+
+  it("rejects identity registrations once the identity tree is full", async () => {
+    const setIdentityTreeDepth = (depth: number) =>
+      program.methods
+        .setIdentityTreeDepth(depth)
+        .accounts({
+          config: deriveConfigPda(),
+          identityRegistry: identityRegistryPda,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+    const registerFreshIdentity = async () => {
+      const user = Keypair.generate();
+      const [member] = PublicKey.findProgramAddressSync(
+        [Buffer.from("identity_member"), user.publicKey.toBuffer()],
+        program.programId
+      );
+      const commitment = Buffer.alloc(32, 9);
+      const { root } = await identityAppendPath(program, bytesToBigIntBE(commitment));
+      return program.methods
+        .registerIdentity({
+          commitment,
+          newRoot: buf(bigIntToBytes32(root)),
+        })
+        .accounts({
+          identityRegistry: identityRegistryPda,
+          identityMember: member,
+          payer: provider.wallet.publicKey,
+          user: user.publicKey,
+          identityAdmin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+    };
+
+    const registry = await program.account.identityRegistry.fetch(identityRegistryPda);
+    assert.equal(registry.depth, 20);
+    let count = Number(registry.commitmentCount);
+    let depth = 1;
+    while (1 << depth <= count) {
+      depth += 1;
+    }
+
+    await setIdentityTreeDepth(depth);
+    try {
+      while (count < 1 << depth) {
+        await registerFreshIdentity();
+        count += 1;
+      }
+      await expectError(registerFreshIdentity(), "IdentityRegistryFull");
+    } finally {
+      await setIdentityTreeDepth(20);
+    }
+  });
+
+  it("hands off the fee authority in two steps", async () => {
+    const configPda = deriveConfigPda();
+    const treasury = Keypair.generate();
+    const intruder = Keypair.generate();
+    const propose = (current: Keypair, next: PublicKey) =>
+      program.methods
+        .proposeFeeAuthority(next)
+        .accounts({ config: configPda, feeAuthority: current.publicKey })
+        .signers([current])
+        .rpc();
+    const accept = (next: Keypair) =>
+      program.methods
+        .acceptFeeAuthority()
+        .accounts({ config: configPda, newFeeAuthority: next.publicKey })
+        .signers([next])
+        .rpc();
+    const walletKeypair = provider.wallet.payer as Keypair;
+
+    await expectError(propose(intruder, intruder.publicKey), "Unauthorized");
+    await propose(walletKeypair, treasury.publicKey);
+    await expectError(accept(intruder), "Unauthorized");
+    let config = await program.account.config.fetch(configPda);
+    assert.equal(config.feeAuthority.toBase58(), provider.wallet.publicKey.toBase58());
+    assert.equal(config.pendingFeeAuthority.toBase58(), treasury.publicKey.toBase58());
+
+    await accept(treasury);
+    config = await program.account.config.fetch(configPda);
+    assert.equal(config.feeAuthority.toBase58(), treasury.publicKey.toBase58());
+    assert.equal(config.pendingFeeAuthority.toBase58(), PublicKey.default.toBase58());
+    assert.equal(config.admin.toBase58(), provider.wallet.publicKey.toBase58());
+
+    await propose(treasury, provider.wallet.publicKey);
+    await accept(walletKeypair);
+    config = await program.account.config.fetch(configPda);
+    assert.equal(config.feeAuthority.toBase58(), provider.wallet.publicKey.toBase58());
+  });
+
+  it("hands off the admin in two steps", async () => {
+    const configPda = deriveConfigPda();
+    const successor = Keypair.generate();
+    const intruder = Keypair.generate();
+    const propose = (current: Keypair, next: PublicKey) =>
+      program.methods
+        .proposeAdmin(next)
+        .accounts({ config: configPda, admin: current.publicKey })
+        .signers([current])
+        .rpc();
+    const accept = (next: Keypair) =>
+      program.methods
+        .acceptAdmin()
+        .accounts({ config: configPda, newAdmin: next.publicKey })
+        .signers([next])
+        .rpc();
+    const walletKeypair = provider.wallet.payer as Keypair;
+
+    await expectError(accept(walletKeypair), "Unauthorized");
+    await expectError(propose(intruder, intruder.publicKey), "Unauthorized");
+    await propose(walletKeypair, successor.publicKey);
+    await expectError(accept(intruder), "Unauthorized");
+    let config = await program.account.config.fetch(configPda);
+    assert.equal(config.admin.toBase58(), provider.wallet.publicKey.toBase58());
+    assert.equal(config.pendingAdmin.toBase58(), successor.publicKey.toBase58());
+
+    const signature = await accept(successor);
+    config = await program.account.config.fetch(configPda);
+    assert.equal(config.admin.toBase58(), successor.publicKey.toBase58());
+    assert.equal(config.pendingAdmin.toBase58(), PublicKey.default.toBase58());
+    const [changed] = eventsNamed(await fetchEvents(signature), "AdminChangedEvent");
+    assert.isOk(changed, "missing admin changed event");
+    assert.equal(changed.data.oldAdmin.toBase58(), provider.wallet.publicKey.toBase58());
+    assert.equal(changed.data.newAdmin.toBase58(), successor.publicKey.toBase58());
+    await expectError(accept(successor), "Unauthorized");
+
+    await propose(successor, provider.wallet.publicKey);
+    await accept(walletKeypair);
+    config = await program.account.config.fetch(configPda);
+    assert.equal(config.admin.toBase58(), provider.wallet.publicKey.toBase58());
+  });
+
+  it("routes proofs through the mint's verifier program override", async () => {
+    const setOverride = (verifierProgramOverride: PublicKey | null) =>
+      program.methods
+        .setVerifierProgramOverride(verifierProgramOverride)
+        .accounts({
+          config: deriveConfigPda(),
+          vault: vaultPda,
+          admin: provider.wallet.publicKey,
+          mint,
+        })
+        .rpc();
+
+    await setOverride(Keypair.generate().publicKey);
+    try {
+      await expectError(withdrawWithProof({ amount: 1_000n }), "InvalidVerifierProgram");
+      await setOverride(verifierProgram.programId);
+      await withdrawWithProof({ amount: 1_000n });
+      const vault = await program.account.vaultPool.fetch(vaultPda);
+      assert.equal(
+        (vault.verifierProgramOverride as PublicKey).toBase58(),
+        verifierProgram.programId.toBase58()
+      );
+    } finally {
+      await setOverride(null);
+    }
+  });
+
+  it("reports which circuit gate rejected a proof", async () => {
+    const configPda = deriveConfigPda();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const otherKeyPda = deriveVerifierKeyPda(1215);
+    if (!(await provider.connection.getAccountInfo(otherKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1215,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 14,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: otherKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+
+    await expectError(withdrawWithProof({ amount: 1_000n, circuitId: 7 }), "CircuitNotAllowed");
+
+    await setCircuitIds([0, 7, 8]);
+    try {
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, circuitId: 8 }),
+        "CircuitNotRegistered"
+      );
+      await program.methods
+        .registerVkEntry({ circuitId: 7, vkHash: Array.from(Buffer.alloc(32)) })
+        .accounts({
+          config: configPda,
+          vkRegistry: deriveVkRegistryPda(),
+          verifierKey: otherKeyPda,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+      await expectError(withdrawWithProof({ amount: 1_000n, circuitId: 7 }), "VkCircuitMismatch");
+    } finally {
+      await setCircuitIds([0]);
+    }
+  });
+
+  it("enforces the optional proof slot window", async () => {
+    const configPda = deriveConfigPda();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const windowKeyPda = deriveVerifierKeyPda(1216);
+    if (!(await provider.connection.getAccountInfo(windowKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1216,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 16,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: windowKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 9, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: deriveVkRegistryPda(),
+        verifierKey: windowKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+    const withdrawInWindow = (slotWindow: [bigint, bigint]) =>
+      withdrawWithProof({ amount: 1_000n, circuitId: 9, verifierKey: windowKeyPda, slotWindow });
+
+    await setCircuitIds([0, 9]);
+    try {
+      const slot = BigInt(await provider.connection.getSlot("confirmed"));
+      await expectError(withdrawInWindow([slot + 1_000n, slot + 2_000n]), "ProofNotYetValid");
+      await expectError(withdrawInWindow([0n, slot - 1n]), "ProofExpired");
+      await withdrawInWindow([slot - 1n, slot + 1_000n]);
+    } finally {
+      await setCircuitIds([0]);
+    }
+  });
+
+  it("routes the identity root check through the pool's identity policy", async () => {
+    const configPda = deriveConfigPda();
+    const setIdentityPolicy = (identityPolicy: number) =>
+      program.methods
+        .setIdentityPolicy(identityPolicy)
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          admin: provider.wallet.publicKey,
+          mint,
+        })
+        .rpc();
+    const setDenylistRoot = (root: Buffer) =>
+      program.methods
+        .setIdentityDenylistRoot(Array.from(root))
+        .accounts({
+          config: configPda,
+          identityRegistry: identityRegistryPda,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+    const registry = await program.account.identityRegistry.fetch(identityRegistryPda);
+    const previousDenylistRoot = Buffer.from(registry.denylistRoot as number[]);
+    const { identityRootBytes } = await getRoots();
+    const denylistRoot = Buffer.alloc(32, 0x18);
+
+    let vault = await program.account.vaultPool.fetch(vaultPda);
+    assert.equal(vault.identityPolicy, 0);
+    await expectError(setIdentityPolicy(2), "InvalidIdentityPolicy");
+
+    await setDenylistRoot(denylistRoot);
+    try {
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, identityRoot: denylistRoot }),
+        "IdentityRootMismatch"
+      );
+      await withdrawWithProof({ amount: 1_000n, identityRoot: identityRootBytes });
+
+      await setIdentityPolicy(1);
+      vault = await program.account.vaultPool.fetch(vaultPda);
+      assert.equal(vault.identityPolicy, 1);
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, identityRoot: identityRootBytes }),
+        "IdentityRootMismatch"
+      );
+      await withdrawWithProof({ amount: 1_000n, identityRoot: denylistRoot });
+    } finally {
+      await setIdentityPolicy(0);
+      await setDenylistRoot(previousDenylistRoot);
+    }
+  });
+
+  it("rejects proofs paying out more than their declared value_in", async () => {
+    const configPda = deriveConfigPda();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const valueKeyPda = deriveVerifierKeyPda(1219);
+    if (!(await provider.connection.getAccountInfo(valueKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1219,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 15,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: valueKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 10, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: deriveVkRegistryPda(),
+        verifierKey: valueKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+    const withdrawWithValueIn = (amount: bigint, valueIn: bigint) =>
+      withdrawWithProof({ amount, circuitId: 10, verifierKey: valueKeyPda, valueIn });
+
+    await setCircuitIds([0, 10]);
+    try {
+      await expectError(withdrawWithValueIn(1_001n, 1_000n), "ValueConservationViolation");
+      await withdrawWithValueIn(1_000n, 1_000n);
+    } finally {
+      await setCircuitIds([0]);
+    }
+  });
+
+  it("returns the config through get_config return data", async () => {
+    const configPda = deriveConfigPda();
+    const config = await program.account.config.fetch(configPda);
+    const view = await program.methods.getConfig(0).accounts({ config: configPda }).view();
+
+    assert.equal(view.admin.toBase58(), config.admin.toBase58());
+    assert.equal(view.feeAuthority.toBase58(), config.feeAuthority.toBase58());
+    assert.equal(view.feeBps, config.feeBps);
+    assert.equal(view.relayerFeeBpsMax, config.relayerFeeBpsMax);
+    assert.equal(view.verifierProgram.toBase58(), config.verifierProgram.toBase58());
+    assert.deepEqual(view.circuitIds, config.circuitIds);
+    assert.equal(view.paused, config.paused);
+    assert.equal(view.rootMaxAgeSlots.toString(), config.rootMaxAgeSlots.toString());
+    assert.equal(view.mintAllowlistLen, (config.mintAllowlist as PublicKey[]).length);
+
+    const allowlist: string[] = [];
+    let page = view;
+    while (true) {
+      allowlist.push(...(page.mintAllowlist as PublicKey[]).map((key) => key.toBase58()));
+      if (page.mintAllowlist.length === 0 || allowlist.length >= page.mintAllowlistLen) {
+        break;
+      }
+      page = await program.methods
+        .getConfig(allowlist.length)
+        .accounts({ config: configPda })
+        .view();
+      assert.equal(page.mintAllowlistOffset, allowlist.length);
+    }
+    assert.deepEqual(
+      allowlist,
+      (config.mintAllowlist as PublicKey[]).map((key) => key.toBase58())
+    );
+  });
+
+  it("manages the protocol fee exemption list", async () => {
+    const configPda = deriveConfigPda();
+    const treasury = Keypair.generate().publicKey;
+    const updateExempt = (method: "addFeeExempt" | "removeFeeExempt", owner: PublicKey) =>
+      program.methods[method](owner)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const exemptList = async () =>
+      ((await program.account.config.fetch(configPda)).feeExempt as PublicKey[]).map((key) =>
+        key.toBase58()
+      );
+
+    assert.notInclude(await exemptList(), treasury.toBase58());
+    await updateExempt("addFeeExempt", treasury);
+    await updateExempt("addFeeExempt", treasury);
+    try {
+      const exempt = await exemptList();
+      assert.equal(exempt.filter((key) => key === treasury.toBase58()).length, 1);
+
+      const outsider = Keypair.generate();
+      await expectError(
+        program.methods
+          .addFeeExempt(outsider.publicKey)
+          .accounts({ config: configPda, admin: outsider.publicKey })
+          .signers([outsider])
+          .rpc(),
+        "Unauthorized"
+      );
+    } finally {
+      await updateExempt("removeFeeExempt", treasury);
+    }
+    assert.notInclude(await exemptList(), treasury.toBase58());
+  });
+
+  it("suppresses events whose flag is cleared", async () => {
+    const configPda = deriveConfigPda();
+    const setEventFlags = (eventFlags: number) =>
+      program.methods
+        .setEventFlags(eventFlags)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const config = await program.account.config.fetch(configPda);
+    assert.equal(config.eventFlags, 15);
+    await expectError(setEventFlags(16), "InvalidEventFlags");
+
+    await setEventFlags(1);
+    try {
+      const { signature } = await withdrawWithProof({ amount: 1_000n });
+      const events = await fetchEvents(signature);
+      assert.lengthOf(eventsNamed(events, "TransferEvent"), 0);
+      assert.lengthOf(eventsNamed(events, "ShieldedTransferEvent"), 0);
+      assert.lengthOf(eventsNamed(events, "WithdrawEvent"), 0);
+    } finally {
+      await setEventFlags(15);
+    }
+    const { signature } = await withdrawWithProof({ amount: 1_000n });
+    assert.lengthOf(eventsNamed(await fetchEvents(signature), "TransferEvent"), 1);
+  });
+
+  it("guards the emergency nullifier bit clear", async () => {
+    const nullifier = nextTestNullifier();
+    await withdrawWithProof({ amount: 1_000n, nullifier });
+    const clearBit = (confirm: boolean, admin?: Keypair) =>
+      program.methods
+        .adminClearNullifierBit(Array.from(nullifier), confirm)
+        .accounts({
+          config: deriveConfigPda(),
+          nullifierSet: nullifierPda,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+          mint,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+
+    await expectError(clearBit(true, Keypair.generate()), "Unauthorized");
+    await expectError(clearBit(true), "ProtocolNotPaused");
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, nullifier }),
+      "NullifierAlreadyUsed"
+    );
+  });
+
+  it("verifies deposit proofs when the config requires them", async () => {
+    const configPda = deriveConfigPda();
+    const setRequireDepositProof = (required: boolean) =>
+      program.methods
+        .setRequireDepositProof(required)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const depositKeyPda = deriveVerifierKeyPda(1225);
+    if (!(await provider.connection.getAccountInfo(depositKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1225,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 4,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: depositKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 11, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: deriveVkRegistryPda(),
+        verifierKey: depositKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const commitment = Buffer.alloc(32, 0x25);
+    const deposit = async (proofCommitment?: Buffer) => {
+      const { identityRootBytes } = await getRoots();
+      return program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment,
+          newRoot: await nextRoot(shieldedPda, [commitment]),
+          proof: proofCommitment
+            ? {
+                proof: dummyProof,
+                publicInputs: Buffer.concat([
+                  proofCommitment,
+                  u64ToBytes32(1_000n),
+                  identityRootBytes,
+                  u64ToBytes32(11n),
+                ]),
+              }
+            : null,
+        })
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          vaultAta,
+          shieldedState: shieldedPda,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta,
+          identityRegistry: identityRegistryPda,
+          vkRegistry: deriveVkRegistryPda(),
+          verifierProgram: proofCommitment ? verifierProgram.programId : null,
+          verifierKey: proofCommitment ? depositKeyPda : null,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    };
+
+    await deposit();
+    await setCircuitIds([0, 11]);
+    await setRequireDepositProof(true);
+    try {
+      await expectError(deposit(), "DepositProofRequired");
+      await expectError(deposit(Buffer.alloc(32, 0x26)), "DepositCommitmentMismatch");
+      const before = await program.account.shieldedState.fetch(shieldedPda);
+      await deposit(commitment);
+      const after = await program.account.shieldedState.fetch(shieldedPda);
+      assert.equal(
+        after.commitmentCount.toString(),
+        before.commitmentCount.addn(1).toString()
+      );
+    } finally {
+      await setRequireDepositProof(false);
+      await setCircuitIds([0]);
+    }
+  });
+
+  it("reports protocol health in a single event", async () => {
+    const signature = await program.methods
+      .emitHealth()
+      .accounts({
+        config: deriveConfigPda(),
+        vault: vaultPda,
+        vaultAta,
+        vkRegistry: deriveVkRegistryPda(),
+        identityRegistry: identityRegistryPda,
+        mint,
+      })
+      .rpc();
+    const [health] = eventsNamed(await fetchEvents(signature), "HealthEvent");
+    assert.isOk(health, "missing health event");
+
+    const vault = await program.account.vaultPool.fetch(vaultPda);
+    const vaultBalance = (await getAccount(provider.connection, vaultAta)).amount;
+    const registry = await program.account.identityRegistry.fetch(identityRegistryPda);
+    assert.equal(health.data.mint.toBase58(), mint.toBase58());
+    assert.isFalse(health.data.paused);
+    assert.equal(health.data.vaultBalance.toString(), vaultBalance.toString());
+    assert.equal(
+      health.data.liabilities.toString(),
+      vault.totalDeposited.sub(vault.totalWithdrawn).toString()
+    );
+    assert.isTrue(health.data.solvent);
+    assert.isAtLeast(health.data.activeCircuits, 1);
+    assert.equal(health.data.identityCount.toString(), registry.commitmentCount.toString());
+    assert.equal(health.data.identityCapacity.toString(), (2 ** registry.depth).toString());
+  });
+
+  it("names the missing nullifier chunk before failing", async () => {
+    const pool = await setupMintPool(4);
+    const nullifier = Buffer.alloc(32);
+    nullifier.writeUInt16LE(5, 4);
+    for (let tweak = 1; nullifierChunkIndex(nullifier, 4) === 0; tweak += 1) {
+      nullifier.writeUInt16LE(tweak, 30);
+    }
+    let logs: string[] | undefined;
+    try {
+      await withdrawWithProof({ amount: 1_000n, pool, nullifier });
+    } catch (err: any) {
+      assert.include(err.toString(), "MissingNullifierAccount");
+      logs = err.logs ?? err.transactionLogs;
+    }
+    assert.isOk(logs, "expected MissingNullifierAccount with logs");
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const [missing] = eventsNamed(Array.from(parser.parseLogs(logs!)), "MissingChunkEvent");
+    assert.isOk(missing, "missing chunk event");
+    assert.equal(missing.data.chunkIndex, nullifierChunkIndex(nullifier, 4));
+    assert.equal(missing.data.mint.toBase58(), pool.mint.toBase58());
+  });
+
+  it("enforces the absolute relayer fee floor on relayed withdrawals", async () => {
+    const configPda = deriveConfigPda();
+    const setRelayerFeeMin = (minimum: number) =>
+      program.methods
+        .setRelayerFeeMin(new anchor.BN(minimum))
+        .accounts({ config: configPda, feeAuthority: provider.wallet.publicKey })
+        .rpc();
+    const relayerFeeAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    const relayedWithdraw = (amount: bigint) =>
+      withdrawWithProof({
+        amount,
+        relayerFeeBps: 50,
+        feeAmount: (amount * 50n) / 10_000n,
+        relayerFeeAta,
+      });
+
+    await setRelayerFeeMin(100);
+    try {
+      await expectError(relayedWithdraw(10_000n), "RelayerFeeTooLow");
+      await relayedWithdraw(20_000n);
+      await withdrawWithProof({ amount: 1_000n });
+      assert.equal(
+        (await getAccount(provider.connection, relayerFeeAta)).amount.toString(),
+        "100"
+      );
+    } finally {
+      await setRelayerFeeMin(0);
+    }
+  });
+
+  it("returns vk_x matching the arkworks fixture", async () => {
+    const fixture = loadGroth16Fixture();
+    const fixtureKeyPda = deriveVerifierKeyPda(1229);
+    if (!(await provider.connection.getAccountInfo(fixtureKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1229,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbc: fixture.gamma_abc.map(hex),
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: fixtureKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    const computeVkX = (publicInputs: Buffer) =>
+      verifierProgram.methods
+        .computeVkXOnly(publicInputs)
+        .accounts({ verifierKey: fixtureKeyPda })
+        .view();
+
+    const publicInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const vkX = await computeVkX(publicInputs);
+    assert.equal(Buffer.from(vkX).toString("hex"), fixture.vk_x);
+
+    // Zero and one inputs skip the multiplication; r and r + 1 (the scalar field
+    // modulus) are the same scalars but go through the full multiply-and-add path.
+    const BN254_SCALAR_MODULUS =
+      21888242871839275222246405745257275088548364400416034343698204186575808495617n;
+    const scalar = (value: bigint) => Buffer.from(value.toString(16).padStart(64, "0"), "hex");
+    const vkXHex = async (value: bigint) => Buffer.from(await computeVkX(scalar(value))).toString("hex");
+    assert.equal(fixture.public_inputs.length, 1);
+    assert.equal(await vkXHex(0n), fixture.gamma_abc[0]);
+    assert.equal(await vkXHex(BN254_SCALAR_MODULUS), await vkXHex(0n));
+    assert.equal(await vkXHex(1n), fixture.vk_x);
+    assert.equal(await vkXHex(BN254_SCALAR_MODULUS + 1n), await vkXHex(1n));
+    try {
+      await computeVkX(Buffer.concat([publicInputs, zero32()]));
+      assert.fail("expected InvalidInputCount");
+    } catch (err: any) {
+      const logs: string[] = err.simulationResponse?.logs ?? err.logs ?? [];
+      assert.include([err.toString(), ...logs].join("\n"), "InvalidInputCount");
+    }
+  });
+
+  it("narrows the global circuit allowlist per pool", async () => {
+    const configPda = deriveConfigPda();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const setPoolCircuits = (circuitIds: number[]) =>
+      program.methods
+        .setPoolCircuits(circuitIds)
+        .accounts({
+          config: configPda,
+          shieldedState: shieldedPda,
+          admin: provider.wallet.publicKey,
+          mint,
+        })
+        .rpc();
+
+    const other = await setupMintPool();
+    const otherShielded = await program.account.shieldedState.fetch(other.shieldedState);
+    assert.deepEqual(otherShielded.circuitIds, []);
+
+    await setCircuitIds([0, 7]);
+    await setPoolCircuits([7]);
+    try {
+      const shielded = await program.account.shieldedState.fetch(shieldedPda);
+      assert.deepEqual(shielded.circuitIds, [7]);
+      await expectError(withdrawWithProof({ amount: 1_000n }), "PoolCircuitNotAllowed");
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, circuitId: 8 }),
+        "CircuitNotAllowed"
+      );
+    } finally {
+      await setPoolCircuits([]);
+      await setCircuitIds([0]);
+    }
+    await withdrawWithProof({ amount: 1_000n });
+  });
+
+  it("batches groth16 verification with the same results as single checks", async () => {
+    const fixture = loadGroth16Fixture();
+    const fixtureKeyPda = deriveVerifierKeyPda(1231);
+    if (!(await provider.connection.getAccountInfo(fixtureKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1231,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbc: fixture.gamma_abc.map(hex),
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: fixtureKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    const proof = hex(fixture.proof);
+    const validInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const wrongInputs = Buffer.from(validInputs);
+    wrongInputs[wrongInputs.length - 1] ^= 1;
+    // Same public inputs, but `a` negated: still a valid curve point, no longer a valid proof.
+    const BN254_FIELD_MODULUS =
+      21888242871839275222246405745257275088696311157297823662689037894645226208583n;
+    const badProof = Buffer.from(proof);
+    const aY = BigInt("0x" + badProof.subarray(32, 64).toString("hex"));
+    Buffer.from((BN254_FIELD_MODULUS - aY).toString(16).padStart(64, "0"), "hex").copy(badProof, 32);
+    const verifyBatch = (inputs: Buffer[], proofs: Buffer[] = inputs.map(() => proof)) =>
+      verifierProgram.methods
+        .verifyGroth16Batch(proofs, inputs)
+        .accounts({ verifierKey: fixtureKeyPda })
+        .rpc();
+
+    const cases: { inputs: Buffer[]; ok: boolean }[] = [
+      { inputs: [validInputs], ok: true },
+      { inputs: [validInputs, validInputs], ok: true },
+      { inputs: [validInputs, validInputs, validInputs], ok: true },
+      { inputs: [wrongInputs], ok: false },
+      { inputs: [validInputs, wrongInputs], ok: false },
+      { inputs: [wrongInputs, validInputs, validInputs], ok: false },
+    ];
+    for (const { inputs, ok } of cases) {
+      if (ok) {
+        await verifyBatch(inputs);
+      } else {
+        await expectError(verifyBatch(inputs), "PairingNotOne");
+      }
+    }
+    // One tampered proof among valid ones sinks the whole folded pairing.
+    await expectError(
+      verifyBatch([validInputs, validInputs, validInputs], [proof, badProof, proof]),
+      "PairingNotOne"
+    );
+    await expectError(verifyBatch([validInputs], [badProof]), "PairingNotOne");
+    await expectError(
+      verifierProgram.methods
+        .verifyGroth16Batch([proof], [])
+        .accounts({ verifierKey: fixtureKeyPda })
+        .rpc(),
+      "BatchLengthMismatch"
+    );
+  });
+
+  it("reports a failed pairing separately from a malformed proof", async () => {
+    const fixture = loadGroth16Fixture();
+    const fixtureKeyPda = deriveVerifierKeyPda(1231);
+    const publicInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const verify = (proof: Buffer) =>
+      verifierProgram.methods
+        .verifyGroth16(proof, publicInputs)
+        .accounts({ verifierKey: fixtureKeyPda })
+        .rpc();
+    // `a` negated stays on the curve, so the pairing runs and comes out not one.
+    const BN254_FIELD_MODULUS =
+      21888242871839275222246405745257275088696311157297823662689037894645226208583n;
+    const tampered = hex(fixture.proof);
+    const aY = BigInt("0x" + tampered.subarray(32, 64).toString("hex"));
+    Buffer.from((BN254_FIELD_MODULUS - aY).toString(16).padStart(64, "0"), "hex").copy(tampered, 32);
+
+    await verify(hex(fixture.proof));
+    await expectError(verify(hex(fixture.proof).subarray(0, 255)), "InvalidProof");
+    try {
+      await verify(tampered);
+      assert.fail("expected the tampered proof to fail the pairing");
+    } catch (err: any) {
+      const logs: string[] = err.simulationResponse?.logs ?? err.logs ?? [];
+      const output = [err.toString(), ...logs].join("\n");
+      assert.include(output, "PairingNotOne");
+      assert.match(output, /pairing result is not one, last byte \d+/);
+    }
+  });
+
+  it("replaces gamma_abc atomically and refuses once frozen", async () => {
+    const fixture = loadGroth16Fixture();
+    const fixtureKeyPda = deriveVerifierKeyPda(1232);
+    const gammaAbc: Buffer[] = fixture.gamma_abc.map(hex);
+    await verifierProgram.methods
+      .initializeVerifierKey({
+        keyId: 1232,
+        alphaG1: hex(fixture.alpha_g1),
+        betaG2: hex(fixture.beta_g2),
+        gammaG2: hex(fixture.gamma_g2),
+        deltaG2: hex(fixture.delta_g2),
+        publicInputsLen: fixture.public_inputs.length,
+        gammaAbc: [...gammaAbc].reverse(),
+        mock: false,
+        curve: 0,
+        inputEndianness: 0,
+      })
+      .accounts({
+        verifierKey: fixtureKeyPda,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const verify = () =>
+      verifierProgram.methods
+        .verifyGroth16(hex(fixture.proof), Buffer.concat(fixture.public_inputs.map(hex)))
+        .accounts({ verifierKey: fixtureKeyPda })
+        .rpc();
+    const replace = (points: Buffer[], admin?: Keypair) =>
+      verifierProgram.methods
+        .replaceGammaAbc(points.map((point) => Array.from(point)))
+        .accounts({
+          verifierKey: fixtureKeyPda,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const freeze = (admin?: Keypair) =>
+      verifierProgram.methods
+        .freezeVerifierKey()
+        .accounts({
+          verifierKey: fixtureKeyPda,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+
+    await expectError(verify(), "PairingNotOne");
+    const outsider = Keypair.generate();
+    await expectError(replace(gammaAbc, outsider), "Unauthorized");
+    await expectError(freeze(outsider), "Unauthorized");
+    const offCurve = Buffer.alloc(64);
+    offCurve[31] = 1;
+    offCurve[63] = 1;
+    await expectError(replace([gammaAbc[0], offCurve]), "InvalidCurvePoint");
+    await expectError(replace([gammaAbc[0]]), "InvalidInputCount");
+
+    await replace(gammaAbc);
+    await verify();
+    const key = await verifierProgram.account.verifierKey.fetch(fixtureKeyPda);
+    assert.equal(key.vkVersion, 1);
+
+    await freeze();
+    await expectError(replace(gammaAbc), "VerifierKeyFrozen");
+    await verify();
+  });
+
+  it("rejects nullifiers aliased past the scalar field modulus", async () => {
+    const modulus = BigInt(
+      "21888242871839275222246405745257275088548364400416034343698204186575808495617"
+    );
+    const nullifier = nextTestNullifier();
+    const aliased = Buffer.from(
+      (BigInt("0x" + nullifier.toString("hex")) + modulus).toString(16).padStart(64, "0"),
+      "hex"
+    );
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, nullifier: aliased }),
+      "NonCanonicalNullifier"
+    );
+    await withdrawWithProof({ amount: 1_000n, nullifier });
+  });
+
+  it("caps a single withdrawal at a share of the vault balance", async () => {
+    const setMaxSingleWithdraw = (bps: number) =>
+      program.methods
+        .setMaxSingleWithdraw(bps)
+        .accounts({ config: deriveConfigPda(), admin: provider.wallet.publicKey })
+        .rpc();
+
+    await expectError(setMaxSingleWithdraw(10_001), "InvalidWithdrawCap");
+    await setMaxSingleWithdraw(1);
+    try {
+      const balance = (await getAccount(provider.connection, vaultAta)).amount;
+      const cap = (balance * 1n) / 10_000n;
+      assert.isTrue(cap > 0n, "vault too small for a 1 bps cap");
+      await expectError(withdrawWithProof({ amount: cap + 1n }), "SingleWithdrawTooLarge");
+      await withdrawWithProof({ amount: cap });
+    } finally {
+      await setMaxSingleWithdraw(0);
+    }
+    const view = await program.methods
+      .getConfig(0)
+      .accounts({ config: deriveConfigPda() })
+      .view();
+    assert.equal(view.maxSingleWithdrawBps, 0);
+  });
+
+  it("rejects verifier keys for curves without pairing syscalls", async () => {
+    const initializeKey = (keyId: number, curve: number) => {
+      const keyPda = deriveVerifierKeyPda(keyId);
+      return verifierProgram.methods
+        .initializeVerifierKey({
+          keyId,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 13,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: keyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    };
+
+    await expectError(initializeKey(1236, 1), "UnsupportedCurve");
+    await expectError(initializeKey(1236, 7), "InvalidCurve");
+  });
+
+  it("prechecks withdraw semantics and names each failing invariant", async () => {
+    const configPda = deriveConfigPda();
+    const shielded = await program.account.shieldedState.fetch(shieldedPda);
+    const { identityRootBytes } = await getRoots();
+    const inputs = (
+      overrides: Partial<Parameters<typeof makePublicInputs>[0]> = {},
+      extra: Buffer[] = []
+    ) =>
+      Buffer.concat([
+        makePublicInputs({
+          root: Buffer.from(shielded.merkleRoot as number[]),
+          identityRoot: identityRootBytes,
+          nullifiers: [zero32(), zero32(), zero32(), zero32()],
+          outputCommitments: [zero32(), zero32()],
+          outputEnabled: [0, 0],
+          amountOut: 1_000n,
+          feeAmount: 0n,
+          circuitId: 0,
+          domain: poolDomain(mint),
+          ...overrides,
+        }),
+        ...extra,
+      ]);
+    const precheck = async (
+      publicInputs: Buffer,
+      opts: {
+        amount?: bigint;
+        relayerFeeBps?: number;
+        verifierKey?: PublicKey;
+        proofAccount?: PublicKey;
+      } = {}
+    ) => {
+      const signature = await program.methods
+        .precheckWithdraw({
+          amount: new anchor.BN((opts.amount ?? 1_000n).toString()),
+          relayerFeeBps: opts.relayerFeeBps ?? 0,
+          publicInputs,
+        })
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          shieldedState: shieldedPda,
+          identityRegistry: identityRegistryPda,
+          vkRegistry: deriveVkRegistryPda(),
+          verifierKey: opts.verifierKey ?? verifierKeyPda,
+          recipient: provider.wallet.publicKey,
+          destinationAta: userAta,
+          proofAccount: opts.proofAccount ?? null,
+          mint,
+        })
+        .rpc();
+      const [event] = eventsNamed(await fetchEvents(signature), "WithdrawPrecheckEvent");
+      assert.isOk(event, "missing precheck event");
+      return {
+        passed: event.data.passed as number,
+        failed: (event.data.failures as { check: object }[]).map(
+          (failure) => Object.keys(failure.check)[0]
+        ),
+      };
+    };
+
+    const clean = await precheck(inputs());
+    assert.deepEqual(clean.failed, []);
+    assert.equal(clean.passed, (1 << 12) - 1);
+
+    assert.deepEqual((await precheck(inputs(), { amount: 999n })).failed, ["amount"]);
+    assert.deepEqual(
+      (await precheck(inputs({ outputEnabled: [1, 0] }))).failed,
+      ["outputFlags"]
+    );
+    assert.deepEqual(
+      (await precheck(inputs(), { verifierKey: Keypair.generate().publicKey })).failed,
+      ["vkBinding"]
+    );
+    assert.deepEqual(
+      (await precheck(inputs({ identityRoot: Buffer.alloc(32, 0x27) }))).failed,
+      ["identityRoot"]
+    );
+    assert.deepEqual(
+      (await precheck(inputs({ root: Buffer.alloc(32, 0x27) }))).failed,
+      ["root"]
+    );
+    assert.deepEqual(
+      (await precheck(inputs({}, [u64ToBytes32(0n), u64ToBytes32(1n)]))).failed,
+      ["slotWindow"]
+    );
+    assert.deepEqual(
+      (await precheck(inputs({}, [u64ToBytes32(500n)]))).failed,
+      ["valueConservation"]
+    );
+    assert.deepEqual((await precheck(inputs(), { relayerFeeBps: 100 })).failed, ["fee"]);
+    assert.deepEqual(
+      (await precheck(inputs({ domain: poolDomain(Keypair.generate().publicKey) }))).failed,
+      ["domain"]
+    );
+
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    await setCircuitIds([7]);
+    try {
+      assert.deepEqual((await precheck(inputs())).failed, ["circuit"]);
+    } finally {
+      await setCircuitIds([0]);
+    }
+
+    const setRelayerFeeMin = (minimum: number) =>
+      program.methods
+        .setRelayerFeeMin(new anchor.BN(minimum))
+        .accounts({ config: configPda, feeAuthority: provider.wallet.publicKey })
+        .rpc();
+    await setRelayerFeeMin(1_000);
+    try {
+      const relayed = await precheck(inputs({ amountOut: 10_000n, feeAmount: 100n }), {
+        amount: 10_000n,
+        relayerFeeBps: 100,
+      });
+      assert.deepEqual(relayed.failed, ["relayerFeeFloor"]);
+    } finally {
+      await setRelayerFeeMin(0);
+    }
+
+    const nonce = nextProofNonce();
+    const proofAccount = deriveProofAccount(program.programId, mint, nonce);
+    await program.methods
+      .storeProof({
+        nonce: new anchor.BN(nonce.toString()),
+        recipient: Keypair.generate().publicKey,
+        destinationAta: userAta,
+        mint,
+        proof: dummyProof,
+        publicInputs: inputs(),
+      })
+      .accounts({
+        proofAccount,
+        payer: provider.wallet.publicKey,
+        mint,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    assert.deepEqual(
+      (await precheck(Buffer.alloc(0), { proofAccount })).failed,
+      ["recipient"]
+    );
+  });
+
+  it("pauses and unpauses the protocol", async () => {
+    const configPda = deriveConfigPda();
+    const setPaused = (paused: boolean, admin?: Keypair) =>
+      program.methods
+        .setPaused(paused)
+        .accounts({
+          config: configPda,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const nullifier = nextTestNullifier();
+    await withdrawWithProof({ amount: 1_000n, nullifier });
+    const bit = nullifierBitIndex(nullifier);
+    const bitSet = async () => {
+      const set = await program.account.nullifierSet.fetch(nullifierPda);
+      return {
+        set: ((set.bitset as number[])[bit >> 3] & (1 << (bit & 7))) !== 0,
+        count: set.count as number,
+      };
+    };
+    const clearBit = (target: Buffer, confirm: boolean) =>
+      program.methods
+        .adminClearNullifierBit(Array.from(target), confirm)
+        .accounts({
+          config: configPda,
+          nullifierSet: nullifierPda,
+          admin: provider.wallet.publicKey,
+          mint,
+        })
+        .rpc();
+
+    await expectError(setPaused(true, Keypair.generate()), "Unauthorized");
+    const pauseSignature = await setPaused(true);
+    try {
+      const [paused] = eventsNamed(await fetchEvents(pauseSignature), "PausedEvent");
+      assert.isOk(paused, "missing paused event");
+      assert.isTrue(paused.data.paused);
+      const repeat = await setPaused(true);
+      assert.lengthOf(eventsNamed(await fetchEvents(repeat), "PausedEvent"), 0);
+      assert.isTrue((await program.account.config.fetch(configPda)).paused);
+
+      await expectError(withdrawWithProof({ amount: 1_000n }), "ProtocolPaused");
+      const healthSignature = await program.methods
+        .emitHealth()
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          vaultAta,
+          vkRegistry: deriveVkRegistryPda(),
+          identityRegistry: identityRegistryPda,
+          mint,
+        })
+        .rpc();
+      const [health] = eventsNamed(await fetchEvents(healthSignature), "HealthEvent");
+      assert.isTrue(health.data.paused);
+
+      await expectError(clearBit(nullifier, false), "ConfirmationRequired");
+      await expectError(clearBit(nextTestNullifier(), true), "NullifierNotSet");
+      const before = await bitSet();
+      assert.isTrue(before.set);
+      await clearBit(nullifier, true);
+      const after = await bitSet();
+      assert.isFalse(after.set);
+      assert.equal(after.count, before.count - 1);
+    } finally {
+      await setPaused(false);
+    }
+    assert.isFalse((await program.account.config.fetch(configPda)).paused);
+    await withdrawWithProof({ amount: 1_000n, nullifier });
+  });
+
+  it("collects the protocol fee ahead of the relayer fee", async () => {
+    const configPda = deriveConfigPda();
+    const configureFees = (feeBps: number) =>
+      program.methods
+        .configureFees(feeBps, 50, 0)
+        .accounts({ config: configPda, feeAuthority: provider.wallet.publicKey })
+        .rpc();
+    const updateExempt = (method: "addFeeExempt" | "removeFeeExempt", owner: PublicKey) =>
+      program.methods[method](owner)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const balance = async (ata: PublicKey) =>
+      BigInt((await getAccount(provider.connection, ata)).amount.toString());
+    const relayerFeeAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    const strangerAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    const exemptRecipient = Keypair.generate();
+    // 10_000 at 25 bps protocol + 50 bps relayer: the proof commits to 75.
+    const relayed = {
+      amount: 10_000n,
+      relayerFeeBps: 50,
+      feeAmount: 75n,
+      relayerFeeAta,
+      protocolFeeAta: userAta,
+    };
+
+    await configureFees(25);
+    try {
+      await expectError(withdrawWithProof({ ...relayed, protocolFeeAta: null }), "MissingProtocolFeeAccount");
+      await expectError(
+        withdrawWithProof({ ...relayed, protocolFeeAta: strangerAta }),
+        "InvalidProtocolFeeAccount"
+      );
+      await expectError(withdrawWithProof({ ...relayed, feeAmount: 50n }), "FeeMismatch");
+
+      const protocolBefore = await balance(userAta);
+      const relayerBefore = await balance(relayerFeeAta);
+      const { signature, recipientAta } = await withdrawWithProof(relayed);
+      assert.equal(await balance(recipientAta), 9_925n);
+      assert.equal((await balance(userAta)) - protocolBefore, 25n);
+      assert.equal((await balance(relayerFeeAta)) - relayerBefore, 50n);
+      const [transfer] = eventsNamed(await fetchEvents(signature), "TransferEvent");
+      assert.equal(transfer.data.feeAmount.toString(), "75");
+
+      await updateExempt("addFeeExempt", exemptRecipient.publicKey);
+      await expectError(
+        withdrawWithProof({ ...relayed, recipient: exemptRecipient }),
+        "FeeMismatch"
+      );
+      const exempt = await withdrawWithProof({
+        ...relayed,
+        feeAmount: 50n,
+        protocolFeeAta: null,
+        recipient: exemptRecipient,
+      });
+      assert.equal(await balance(exempt.recipientAta), 9_950n);
+    } finally {
+      await updateExempt("removeFeeExempt", exemptRecipient.publicKey);
+      await configureFees(0);
+    }
+  });
+
+  it("removes a mint from the allowlist while letting shielded funds exit", async () => {
+    const configPda = deriveConfigPda();
+    const pool = await setupMintPool();
+    const removeMint = (target: PublicKey, admin?: Keypair) =>
+      program.methods
+        .removeMint(target)
+        .accounts({
+          config: configPda,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const deposit = async () =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(5_000),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment: Buffer.alloc(32, 2),
+          newRoot: await nextRoot(pool.shieldedState, [Buffer.alloc(32, 2)]),
+        })
+        .accounts({
+          config: configPda,
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    await deposit();
+
+    await expectError(removeMint(pool.mint, Keypair.generate()), "Unauthorized");
+    await removeMint(pool.mint);
+    const allowlist = (await program.account.config.fetch(configPda)).mintAllowlist as PublicKey[];
+    assert.isFalse(allowlist.some((key) => key.equals(pool.mint)));
+    assert.isTrue(allowlist.some((key) => key.equals(mint)));
+    await expectError(removeMint(pool.mint), "MintNotAllowed");
+    await expectError(deposit(), "MintNotAllowed");
+
+    const { recipientAta } = await withdrawWithProof({ amount: 5_000n, pool });
+    assert.equal((await getAccount(provider.connection, recipientAta)).amount.toString(), "5000");
+  });
+
+  it("emits structured deposit, withdraw and shielded transfer events", async () => {
+    const commitment = Buffer.alloc(32, 0x1b);
+    const newRoot = await nextRoot(shieldedPda, [commitment]);
+    const depositSignature = await program.methods
+      .deposit({
+        amount: new anchor.BN(2_000),
+        ciphertext: Buffer.alloc(128, 1),
+        commitment,
+        newRoot,
+      })
+      .accounts({
+        config: deriveConfigPda(),
+        vault: vaultPda,
+        vaultAta,
+        shieldedState: shieldedPda,
+        user: provider.wallet.publicKey,
+        identityMember: identityMemberPda,
+        userAta,
+        mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const shielded = await program.account.shieldedState.fetch(shieldedPda);
+    const [deposit] = eventsNamed(await fetchEvents(depositSignature), "DepositEvent");
+    assert.isOk(deposit, "missing deposit event");
+    assert.equal(deposit.data.mint.toBase58(), mint.toBase58());
+    assert.deepEqual(Buffer.from(deposit.data.commitment), commitment);
+    assert.deepEqual(Buffer.from(deposit.data.newRoot), newRoot);
+    assert.equal(deposit.data.amount.toString(), "2000");
+    assert.equal(deposit.data.commitmentCount.toString(), shielded.commitmentCount.toString());
+
+    const nullifier = nextTestNullifier();
+    const { signature, recipient } = await withdrawWithProof({ amount: 1_000n, nullifier });
+    const events = await fetchEvents(signature);
+    const [withdraw] = eventsNamed(events, "WithdrawEvent");
+    assert.isOk(withdraw, "missing withdraw event");
+    assert.equal(withdraw.data.amount.toString(), "1000");
+    assert.equal(withdraw.data.feeAmount.toString(), "0");
+    assert.equal(withdraw.data.recipient.toBase58(), recipient.toBase58());
+    const nullifiers = (withdraw.data.nullifiers as number[][]).map((value) => Buffer.from(value));
+    assert.lengthOf(nullifiers, 4);
+    assert.deepEqual(nullifiers[0], nullifier);
+    nullifiers.slice(1).forEach((padding) => assert.deepEqual(padding, zero32()));
+    const [transfer] = eventsNamed(events, "ShieldedTransferEvent");
+    assert.isOk(transfer, "missing shielded transfer event");
+    assert.isFalse(transfer.data.internal);
+    assert.equal(transfer.data.outputCount, 0);
+    assert.deepEqual(Buffer.from(transfer.data.newRoot), newRoot);
+  });
+
+  it("checks deposit roots against the on-chain tree append", async () => {
+    const pool = await setupMintPool();
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const deposit = (commitment: bigint, newRoot: Buffer) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment: Buffer.from(bigIntToBytes32(commitment)),
+          newRoot,
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const rootOf = async (leaves: bigint[]) =>
+      Buffer.from(bigIntToBytes32((await buildMerkleTree(leaves)).root));
+    const merkleRoot = async () =>
+      Buffer.from(
+        (await program.account.shieldedState.fetch(pool.shieldedState)).merkleRoot as number[]
+      );
+
+    const emptyRoot = await merkleRoot();
+    assert.deepEqual(emptyRoot, await rootOf([]));
+    await expectError(deposit(11n, emptyRoot), "NewRootMismatch");
+    await expectError(deposit(11n, await rootOf([12n])), "NewRootMismatch");
+
+    await deposit(11n, await rootOf([11n]));
+    assert.deepEqual(await merkleRoot(), await rootOf([11n]));
+    await deposit(12n, await rootOf([11n, 12n]));
+    const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.deepEqual(Buffer.from(shielded.merkleRoot as number[]), await rootOf([11n, 12n]));
+    assert.equal(shielded.commitmentCount.toString(), "2");
+  });
+
+  it("hashes nullifiers into the pool's chunk count instead of their leading bytes", async () => {
+    await expectError(setNullifierChunks(0), "InvalidNullifierChunks");
+    await expectError(setNullifierChunks(8, Keypair.generate()), "Unauthorized");
+
+    const vault = await program.account.vaultPool.fetch(vaultPda);
+    assert.equal(vault.nullifierChunks, 1);
+    const steered = nextTestNullifier();
+    steered.writeUInt32LE(1227 << 8, 0);
+    assert.equal(nullifierChunkIndex(steered, 1), 0);
+    await withdrawWithProof({ amount: 1_000n, nullifier: steered });
+    const set = await program.account.nullifierSet.fetch(nullifierPda);
+    assert.equal(set.chunkCount, 1);
+    const bit = nullifierBitIndex(steered);
+    assert.notEqual((set.bitset as number[])[bit >> 3] & (1 << (bit & 7)), 0);
+  });
+
+  it("only lets the identity admin append verified identity roots", async () => {
+    const user = Keypair.generate();
+    const intruder = Keypair.generate();
+    const [member] = PublicKey.findProgramAddressSync(
+      [Buffer.from("identity_member"), user.publicKey.toBuffer()],
+      program.programId
+    );
+    const commitment = Buffer.alloc(32, 0x2a);
+    const { root } = await identityAppendPath(program, bytesToBigIntBE(commitment));
+    const register = (newRoot: Buffer, identityAdmin: Keypair | null) =>
+      program.methods
+        .registerIdentity({ commitment, newRoot })
+        .accounts({
+          identityRegistry: identityRegistryPda,
+          identityMember: member,
+          payer: provider.wallet.publicKey,
+          user: user.publicKey,
+          identityAdmin: identityAdmin?.publicKey ?? provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers(identityAdmin ? [user, identityAdmin] : [user])
+        .rpc();
+
+    const before = await program.account.identityRegistry.fetch(identityRegistryPda);
+    assert.equal(before.identityAdmin.toBase58(), provider.wallet.publicKey.toBase58());
+    await expectError(register(buf(bigIntToBytes32(root)), intruder), "Unauthorized");
+    await expectError(register(Buffer.alloc(32, 7), null), "NewRootMismatch");
+
+    await register(buf(bigIntToBytes32(root)), null);
+    const after = await program.account.identityRegistry.fetch(identityRegistryPda);
+    assert.equal(
+      after.commitmentCount.toString(),
+      before.commitmentCount.addn(1).toString()
+    );
+    assert.deepEqual(Buffer.from(after.merkleRoot as number[]), buf(bigIntToBytes32(root)));
+  });
+
+  it("deposits and withdraws a Token-2022 mint with a transfer fee", async () => {
+    const configPda = deriveConfigPda();
+    const owner = provider.wallet.publicKey;
+    const mintKeypair = Keypair.generate();
+    const poolMint = mintKeypair.publicKey;
+    const mintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: owner,
+          newAccountPubkey: poolMint,
+          space: mintLen,
+          lamports: await provider.connection.getMinimumBalanceForRentExemption(mintLen),
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        // 1% transfer fee, withheld in the receiving account.
+        createInitializeTransferFeeConfigInstruction(
+          poolMint,
+          owner,
+          owner,
+          100,
+          BigInt(1_000_000),
+          TOKEN_2022_PROGRAM_ID
+        ),
+        createInitializeMintInstruction(poolMint, 6, owner, null, TOKEN_2022_PROGRAM_ID)
+      ),
+      [mintKeypair]
+    );
+    await program.methods.registerMint(poolMint).accounts({ config: configPda, admin: owner }).rpc();
+    const [poolVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), poolMint.toBuffer()],
+      program.programId
+    );
+    const [poolShielded] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shielded"), poolMint.toBuffer()],
+      program.programId
+    );
+    const [poolNullifier] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), poolMint.toBuffer(), Buffer.from([0, 0, 0, 0])],
+      program.programId
+    );
+    const poolVaultAta = await getAssociatedTokenAddress(
+      poolMint,
+      poolVault,
+      true,
+      TOKEN_2022_PROGRAM_ID
+    );
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        createAssociatedTokenAccountInstruction(
+          owner,
+          poolVaultAta,
+          poolVault,
+          poolMint,
+          TOKEN_2022_PROGRAM_ID
+        )
+      )
+    );
+    await program.methods
+      .initializeMintState(0, MERKLE_DEPTH, null)
+      .accounts({
+        config: configPda,
+        vault: poolVault,
+        vaultAta: poolVaultAta,
+        shieldedState: poolShielded,
+        nullifierSet: poolNullifier,
+        admin: owner,
+        mint: poolMint,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const pool: MintPool = {
+      mint: poolMint,
+      vault: poolVault,
+      vaultAta: poolVaultAta,
+      shieldedState: poolShielded,
+      nullifierSet: poolNullifier,
+      tokenProgram: TOKEN_2022_PROGRAM_ID,
+    };
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      poolMint,
+      owner,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      poolMint,
+      poolUserAta,
+      owner,
+      100_000,
+      [],
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    const balance = async (account: PublicKey) =>
+      (await getAccount(provider.connection, account, undefined, TOKEN_2022_PROGRAM_ID)).amount;
+
+    const depositSignature = await program.methods
+      .deposit({
+        amount: new anchor.BN(10_000),
+        ciphertext: Buffer.alloc(128, 1),
+        commitment: Buffer.alloc(32, 2),
+        newRoot: await nextRoot(poolShielded, [Buffer.alloc(32, 2)]),
+      })
+      .accounts({
+        config: configPda,
+        vault: poolVault,
+        vaultAta: poolVaultAta,
+        shieldedState: poolShielded,
+        user: owner,
+        identityMember: identityMemberPda,
+        userAta: poolUserAta,
+        mint: poolMint,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+      })
+      .rpc();
+    // The pool is credited with what arrived, not the 10_000 that left the user.
+    assert.equal(await balance(poolUserAta), 90_000n);
+    assert.equal(await balance(poolVaultAta), 9_900n);
+    let vault = await program.account.vaultPool.fetch(poolVault);
+    assert.equal(vault.totalDeposited.toString(), "9900");
+    const [deposit] = eventsNamed(await fetchEvents(depositSignature), "DepositEvent");
+    assert.equal(deposit.data.amount.toString(), "9900");
+
+    const { recipientAta } = await withdrawWithProof({ amount: 5_000n, pool });
+    assert.equal(await balance(recipientAta), 4_950n);
+    assert.equal(await balance(poolVaultAta), 4_900n);
+    vault = await program.account.vaultPool.fetch(poolVault);
+    assert.equal(vault.totalWithdrawn.toString(), "5000");
+  });
+
+  it("lets the admin sweep protocol fees from a vault-owned fee account", async () => {
+    const configPda = deriveConfigPda();
+    const configureFees = (feeBps: number) =>
+      program.methods
+        .configureFees(feeBps, 50, 0)
+        .accounts({ config: configPda, feeAuthority: provider.wallet.publicKey })
+        .rpc();
+    const balance = async (ata: PublicKey) =>
+      BigInt((await getAccount(provider.connection, ata)).amount.toString());
+    // Not the vault's ATA: that one holds the deposits backing notes.
+    const feeAccount = await createAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      vaultPda,
+      Keypair.generate()
+    );
+    const sweep = (amount: bigint, feeAta = feeAccount, admin?: Keypair) =>
+      program.methods
+        .withdrawProtocolFees(new anchor.BN(amount.toString()))
+        .accounts({
+          config: configPda,
+          vault: vaultPda,
+          feeAta,
+          destinationAta: userAta,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+
+    await configureFees(25);
+    try {
+      await expectError(
+        withdrawWithProof({ amount: 10_000n, feeAmount: 25n, protocolFeeAta: vaultAta }),
+        "InvalidProtocolFeeAccount"
+      );
+      await withdrawWithProof({ amount: 10_000n, feeAmount: 25n, protocolFeeAta: feeAccount });
+    } finally {
+      await configureFees(0);
+    }
+    assert.equal(await balance(feeAccount), 25n);
+
+    await expectError(sweep(25n, feeAccount, Keypair.generate()), "Unauthorized");
+    await expectError(sweep(26n), "InsufficientProtocolFees");
+    await expectError(sweep(1n, vaultAta), "InvalidProtocolFeeAccount");
+    const vaultBefore = await balance(vaultAta);
+    const adminBefore = await balance(userAta);
+    const signature = await sweep(25n);
+    assert.equal(await balance(feeAccount), 0n);
+    assert.equal((await balance(userAta)) - adminBefore, 25n);
+    assert.equal(await balance(vaultAta), vaultBefore);
+    const [event] = eventsNamed(await fetchEvents(signature), "ProtocolFeesWithdrawnEvent");
+    assert.equal(event.data.amount.toString(), "25");
+    assert.equal(event.data.destination.toBase58(), userAta.toBase58());
+  });
+
+  it("enforces the relayer fee bps floor and cap at their boundaries", async () => {
+    const configPda = deriveConfigPda();
+    const configureFees = (max: number, min: number) =>
+      program.methods
+        .configureFees(0, max, min)
+        .accounts({ config: configPda, feeAuthority: provider.wallet.publicKey })
+        .rpc();
+    const relayerFeeAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    // 10_000 at N bps of relayer fee commits to a fee of N.
+    const relayed = (relayerFeeBps: number) =>
+      withdrawWithProof({
+        amount: 10_000n,
+        relayerFeeBps,
+        feeAmount: BigInt(relayerFeeBps),
+        relayerFeeAta,
+      });
+
+    await expectError(configureFees(20, 21), "InvalidRelayerFeeBounds");
+    await configureFees(50, 20);
+    try {
+      const config = await program.account.config.fetch(configPda);
+      assert.equal(config.relayerFeeBpsMin, 20);
+      assert.equal(config.relayerFeeBpsMax, 50);
+      await expectError(relayed(19), "RelayerFeeTooLow");
+      await expectError(relayed(0), "RelayerFeeTooLow");
+      await relayed(20);
+      await relayed(50);
+      await expectError(relayed(51), "RelayerFeeTooHigh");
+      await configureFees(20, 20);
+      await relayed(20);
+    } finally {
+      await configureFees(50, 0);
+    }
+  });
+
+  it("caches the negated alpha_g1 on verifier keys", async () => {
+    const fixture = loadGroth16Fixture();
+    const fixtureKeyPda = deriveVerifierKeyPda(1263);
+    if (!(await provider.connection.getAccountInfo(fixtureKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1263,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbc: fixture.gamma_abc.map(hex),
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: fixtureKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    const BN254_FIELD_MODULUS =
+      21888242871839275222246405745257275088696311157297823662689037894645226208583n;
+    const alpha = hex(fixture.alpha_g1);
+    const alphaY = BigInt("0x" + alpha.subarray(32, 64).toString("hex"));
+    const negY = alphaY === 0n ? 0n : BN254_FIELD_MODULUS - alphaY;
+    const expected = Buffer.concat([
+      alpha.subarray(0, 32),
+      Buffer.from(negY.toString(16).padStart(64, "0"), "hex"),
+    ]);
+    const key = await verifierProgram.account.verifierKey.fetch(fixtureKeyPda);
+    assert.deepEqual(Buffer.from(key.negAlphaG1 as number[]), expected);
+
+    await verifierProgram.methods
+      .verifyGroth16(hex(fixture.proof), Buffer.concat(fixture.public_inputs.map(hex)))
+      .accounts({ verifierKey: fixtureKeyPda })
+      .rpc();
+  });
+
+  it("checks transfer roots against the enabled output commitments", async () => {
+    const pool = await setupMintPool();
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const leaf = (value: bigint) => Buffer.from(bigIntToBytes32(value));
+    const rootOf = async (leaves: bigint[]) => leaf((await buildMerkleTree(leaves)).root);
+    await program.methods
+      .deposit({
+        amount: new anchor.BN(1_000),
+        ciphertext: Buffer.alloc(128, 1),
+        commitment: leaf(11n),
+        newRoot: await rootOf([11n]),
+      })
+      .accounts({
+        config: deriveConfigPda(),
+        vault: pool.vault,
+        vaultAta: pool.vaultAta,
+        shieldedState: pool.shieldedState,
+        user: provider.wallet.publicKey,
+        identityMember: identityMemberPda,
+        userAta: poolUserAta,
+        mint: pool.mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const { identityRootBytes } = await getRoots();
+    const internalTransfer = async (outputs: [bigint, bigint], enabled: [number, number], newRoot: Buffer) => {
+      const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+      return program.methods
+        .internalTransfer({
+          proof: dummyProof,
+          publicInputs: makePublicInputs({
+            root: Buffer.from(shielded.merkleRoot as number[]),
+            identityRoot: identityRootBytes,
+            nullifiers: [nextTestNullifier(), zero32(), zero32(), zero32()],
+            outputCommitments: [leaf(outputs[0]), leaf(outputs[1])],
+            outputEnabled: enabled,
+            amountOut: 0n,
+            feeAmount: 0n,
+            circuitId: 0,
+            domain: poolDomain(pool.mint),
+          }),
+          newRoot,
+          outputCiphertexts: Buffer.alloc(128 * (enabled[0] + enabled[1])),
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          shieldedState: pool.shieldedState,
+          identityRegistry: identityRegistryPda,
+          nullifierSet: pool.nullifierSet,
+          verifierProgram: verifierProgram.programId,
+          verifierKey: verifierKeyPda,
+          mint: pool.mint,
+        })
+        .rpc();
+    };
+    const merkleRoot = async () =>
+      Buffer.from(
+        (await program.account.shieldedState.fetch(pool.shieldedState)).merkleRoot as number[]
+      );
+
+    // One output: only the enabled commitment is appended.
+    await expectError(
+      internalTransfer([21n, 0n], [1, 0], await rootOf([11n, 21n, 0n])),
+      "NewRootMismatch"
+    );
+    await expectError(internalTransfer([21n, 0n], [1, 0], await rootOf([11n, 22n])), "NewRootMismatch");
+    await internalTransfer([21n, 0n], [1, 0], await rootOf([11n, 21n]));
+    assert.deepEqual(await merkleRoot(), await rootOf([11n, 21n]));
+
+    // Two outputs: both commitments, in output order.
+    await expectError(
+      internalTransfer([31n, 32n], [1, 1], await rootOf([11n, 21n, 32n, 31n])),
+      "NewRootMismatch"
+    );
+    await expectError(internalTransfer([31n, 32n], [1, 1], await rootOf([11n, 21n, 31n])), "NewRootMismatch");
+    await internalTransfer([31n, 32n], [1, 1], await rootOf([11n, 21n, 31n, 32n]));
+    assert.deepEqual(await merkleRoot(), await rootOf([11n, 21n, 31n, 32n]));
+
+    // External withdrawals append their change output the same way.
+    const withChange = async (newRoot: Buffer) =>
+      withdrawWithProof({ amount: 500n, pool, change: { commitment: leaf(41n), newRoot } });
+    await expectError(withChange(await rootOf([11n, 21n, 31n, 32n])), "NewRootMismatch");
+    await withChange(await rootOf([11n, 21n, 31n, 32n, 41n]));
+    const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.deepEqual(Buffer.from(shielded.merkleRoot as number[]), await rootOf([11n, 21n, 31n, 32n, 41n]));
+    assert.equal(shielded.commitmentCount.toString(), "5");
+  });
+
+  it("reports whether a nullifier is spent without a transaction", async () => {
+    const isSpent = (nullifier: Buffer): Promise<boolean> =>
+      program.methods
+        .isNullifierSpent(Array.from(nullifier))
+        .accounts({ nullifierSet: nullifierPda, mint })
+        .view();
+    const spent = nextTestNullifier();
+    const unspent = nextTestNullifier();
+    assert.isFalse(await isSpent(spent));
+    await withdrawWithProof({ amount: 1_000n, nullifier: spent });
+    assert.isTrue(await isSpent(spent));
+    assert.isFalse(await isSpent(unspent));
+  });
+
+  it("sets the pool tree depth at initialization and rejects deposits past 2^depth", async () => {
+    // Without a depth the program would have to trust client-supplied roots.
+    await expectError(setupMintPool(1, 0), "InvalidTreeDepth");
+    const depth = 2;
+    const pool = await setupMintPool(1, depth);
+    let shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.treeDepth, depth);
+    assert.deepEqual(
+      Buffer.from(shielded.merkleRoot as number[]),
+      Buffer.from(bigIntToBytes32((await buildMerkleTree([], depth)).root))
+    );
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const deposit = (commitment: bigint, newRoot: Buffer) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment: Buffer.from(bigIntToBytes32(commitment)),
+          newRoot,
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    const leaves: bigint[] = [];
+    for (let i = 1; i <= 2 ** depth; i += 1) {
+      leaves.push(BigInt(100 + i));
+      const root = (await buildMerkleTree(leaves, depth)).root;
+      await deposit(leaves[leaves.length - 1], Buffer.from(bigIntToBytes32(root)));
+    }
+    shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.commitmentCount.toString(), String(2 ** depth));
+    await expectError(deposit(200n, Buffer.alloc(32, 1)), "TreeFull");
+  });
+
+  it("rejects spends bound to a deactivated circuit until it is reactivated", async () => {
+    const intruder = Keypair.generate();
+    const setVkStatus = (circuitId: number, status: number, admin?: Keypair) =>
+      program.methods
+        .setVkStatus(circuitId, status)
+        .accounts({
+          config: deriveConfigPda(),
+          vkRegistry: deriveVkRegistryPda(),
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const entryStatus = async (circuitId: number) => {
+      const registry = await program.account.vkRegistry.fetch(deriveVkRegistryPda());
+      return (registry.entries as any[]).find((entry) => entry.circuitId === circuitId)?.status;
+    };
+
+    await expectError(setVkStatus(0, 0, intruder), "Unauthorized");
+    await expectError(setVkStatus(0, 2), "InvalidVkStatus");
+    await expectError(setVkStatus(4_000_000, 0), "CircuitNotRegistered");
+
+    await setVkStatus(0, 0);
+    try {
+      assert.equal(await entryStatus(0), 0);
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, nullifier: nextTestNullifier() }),
+        "VkInactive"
+      );
+    } finally {
+      await setVkStatus(0, 1);
+    }
+    assert.equal(await entryStatus(0), 1);
+    await withdrawWithProof({ amount: 1_000n, nullifier: nextTestNullifier() });
+  });
+
+  it("enforces per-pool deposit and withdrawal caps inclusively", async () => {
+    const pool = await setupMintPool();
+    const configureVaultLimits = (depositCap: number, perWithdrawCap: number, admin?: Keypair) =>
+      program.methods
+        .configureVaultLimits(new anchor.BN(depositCap), new anchor.BN(perWithdrawCap))
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const deposit = async (amount: number) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(amount),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment: Buffer.alloc(32, 5),
+          newRoot: await nextRoot(pool.shieldedState, [Buffer.alloc(32, 5)]),
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(configureVaultLimits(1, 1, Keypair.generate()), "Unauthorized");
+    await configureVaultLimits(5_000, 2_000);
+    const vault = await program.account.vaultPool.fetch(pool.vault);
+    assert.equal(vault.depositCap.toString(), "5000");
+    assert.equal(vault.perWithdrawCap.toString(), "2000");
+
+    await deposit(3_000);
+    await expectError(deposit(2_001), "DepositCapExceeded");
+    await deposit(2_000);
+    await expectError(deposit(1), "DepositCapExceeded");
+
+    await expectError(withdrawWithProof({ amount: 2_001n, pool }), "WithdrawCapExceeded");
+    await withdrawWithProof({ amount: 2_000n, pool });
+
+    await configureVaultLimits(0, 0);
+    await deposit(1);
+    await withdrawWithProof({ amount: 2_500n, pool });
+  });
+
+  it("grows the verifier key registry past its initial capacity", async () => {
+    const vkRegistryPda = deriveVkRegistryPda();
+    const resize = (newMax: number, admin?: Keypair) =>
+      program.methods
+        .resizeVkRegistry(newMax)
+        .accounts({
+          config: deriveConfigPda(),
+          vkRegistry: vkRegistryPda,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const register = (circuitId: number) =>
+      program.methods
+        .registerVkEntry({ circuitId, vkHash: Array.from(Buffer.alloc(32)) })
+        .accounts({
+          config: deriveConfigPda(),
+          vkRegistry: vkRegistryPda,
+          verifierKey: verifierKeyPda,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+
+    let registry = await program.account.vkRegistry.fetch(vkRegistryPda);
+    const capacity = registry.capacity || 16;
+    let circuitId = 127_500;
+    for (let count = registry.entries.length; count < capacity; count += 1) {
+      await register(circuitId++);
+    }
+    await expectError(register(circuitId), "VkRegistryFull");
+
+    await expectError(resize(capacity + 2, Keypair.generate()), "Unauthorized");
+    await expectError(resize(capacity - 1), "VkRegistryTooSmall");
+    const sizeBefore = (await provider.connection.getAccountInfo(vkRegistryPda))!.data.length;
+    await resize(capacity + 2);
+    const sizeAfter = (await provider.connection.getAccountInfo(vkRegistryPda))!.data.length;
+    assert.equal(sizeAfter - sizeBefore, 2 * 69);
+
+    await register(circuitId++);
+    registry = await program.account.vkRegistry.fetch(vkRegistryPda);
+    assert.equal(registry.capacity, capacity + 2);
+    assert.lengthOf(registry.entries, capacity + 1);
+    await expectError(resize(capacity), "VkRegistryTooSmall");
+  });
+
+  it("rejects public inputs at or above the scalar field modulus", async () => {
+    const modulus = BigInt(
+      "21888242871839275222246405745257275088548364400416034343698204186575808495617"
+    );
+    const atModulus = Buffer.from(bigIntToBytes32(modulus));
+    const aboveModulus = Buffer.from(bigIntToBytes32(modulus + 1n));
+    for (const root of [atModulus, aboveModulus]) {
+      await expectError(withdrawWithProof({ amount: 1_000n, root }), "NonCanonicalPublicInput");
+      await expectError(
+        withdrawWithProof({ amount: 1_000n, identityRoot: root }),
+        "NonCanonicalPublicInput"
+      );
+    }
+  });
+
+  it("creates the vault token account on the first deposit into a fresh pool", async () => {
+    const pool = await setupMintPool(1, MERKLE_DEPTH, false);
+    assert.isNull(await provider.connection.getAccountInfo(pool.vaultAta));
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      250_000
+    );
+
+    await program.methods
+      .deposit({
+        amount: new anchor.BN(250_000),
+        ciphertext: buf(CIPHERTEXT),
+        commitment: buf(COMMITMENT),
+        newRoot: await nextRoot(pool.shieldedState, [buf(COMMITMENT)]),
+      })
+      .accounts({
+        config: deriveConfigPda(),
+        vault: pool.vault,
+        vaultAta: pool.vaultAta,
+        shieldedState: pool.shieldedState,
+        user: provider.wallet.publicKey,
+        identityMember: identityMemberPda,
+        userAta: poolUserAta,
+        mint: pool.mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const vaultAccount = await getAccount(provider.connection, pool.vaultAta);
+    assert.isTrue(vaultAccount.owner.equals(pool.vault));
+    assert.equal(vaultAccount.amount.toString(), "250000");
+    const vault = await program.account.vaultPool.fetch(pool.vault);
+    assert.isTrue(vault.vaultAta.equals(pool.vaultAta));
+    assert.equal(vault.totalDeposited.toString(), "250000");
+  });
+
+  it("verifies withdrawals against the key picked by the proof system selector", async () => {
+    const retired = Keypair.generate().publicKey;
+    await withdrawWithProof({ amount: 1_000n, proofSystem: 0, verifierKeyAlt: retired });
+    await withdrawWithProof({
+      amount: 1_000n,
+      proofSystem: 1,
+      verifierKey: retired,
+      verifierKeyAlt: verifierKeyPda,
+    });
+
+    await expectError(
+      withdrawWithProof({
+        amount: 1_000n,
+        proofSystem: 0,
+        verifierKey: retired,
+        verifierKeyAlt: verifierKeyPda,
+      }),
+      "InvalidVerifierProgram"
+    );
+    await expectError(withdrawWithProof({ amount: 1_000n, proofSystem: 1 }), "MissingVerifierAccounts");
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, proofSystem: 2, verifierKeyAlt: verifierKeyPda }),
+      "InvalidProofSystem"
+    );
+  });
+
+  it("pays the relayer fee only to the recipient the proof commits to", async () => {
+    const configPda = deriveConfigPda();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const recipientKeyPda = deriveVerifierKeyPda(1280);
+    if (!(await provider.connection.getAccountInfo(recipientKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1280,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 18,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: recipientKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 12, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: deriveVkRegistryPda(),
+        verifierKey: recipientKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+    const relayer = Keypair.generate().publicKey;
+    const relayerAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      relayer
+    );
+    const frontRunnerAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    const slot = BigInt(await provider.connection.getSlot("confirmed"));
+    const relayedWithdraw = (relayerFeeAta: PublicKey) =>
+      withdrawWithProof({
+        amount: 10_000n,
+        relayerFeeBps: 50,
+        feeAmount: 50n,
+        relayerFeeAta,
+        circuitId: 12,
+        verifierKey: recipientKeyPda,
+        slotWindow: [0n, slot + 1_000n],
+        valueIn: 10_050n,
+        relayerFeeRecipient: relayer,
+      });
+
+    await setCircuitIds([0, 12]);
+    try {
+      await expectError(relayedWithdraw(frontRunnerAta), "RelayerRecipientMismatch");
+      await relayedWithdraw(relayerAta);
+      assert.equal((await getAccount(provider.connection, relayerAta)).amount.toString(), "50");
+    } finally {
+      await setCircuitIds([0]);
+    }
+  });
+
+  it("skips the relayer fee when it rounds down to zero", async () => {
+    const relayerFeeAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      Keypair.generate().publicKey
+    );
+    // At 50 bps the fee is floor(amount / 200): nothing at 199, one unit at 200.
+    await expectError(
+      withdrawWithProof({ amount: 199n, relayerFeeBps: 50, feeAmount: 1n, relayerFeeAta }),
+      "FeeMismatch"
+    );
+    await withdrawWithProof({ amount: 199n, relayerFeeBps: 50, feeAmount: 0n, relayerFeeAta: null });
+    await expectError(
+      withdrawWithProof({ amount: 200n, relayerFeeBps: 50, feeAmount: 0n, relayerFeeAta }),
+      "FeeMismatch"
+    );
+    await expectError(
+      withdrawWithProof({ amount: 200n, relayerFeeBps: 50, feeAmount: 1n, relayerFeeAta: null }),
+      "MissingRelayerFeeAccount"
+    );
+    await withdrawWithProof({ amount: 200n, relayerFeeBps: 50, feeAmount: 1n, relayerFeeAta });
+    assert.equal((await getAccount(provider.connection, relayerFeeAta)).amount.toString(), "1");
+  });
+
+  const closeMintState = (pool: MintPool) =>
+    program.methods
+      .closeMintState()
+      .accounts({
+        config: deriveConfigPda(),
+        vault: pool.vault,
+        vaultAta: pool.vaultAta,
+        shieldedState: pool.shieldedState,
+        nullifierSet: pool.nullifierSet,
+        admin: provider.wallet.publicKey,
+        mint: pool.mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+  it("refuses to close a pool that still holds funds", async () => {
+    const pool = await setupMintPool();
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      pool.vaultAta,
+      provider.wallet.publicKey,
+      5
+    );
+    await expectError(closeMintState(pool), "VaultNotEmpty");
+
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      1_000
+    );
+    await program.methods
+      .deposit({
+        amount: new anchor.BN(1_000),
+        ciphertext: buf(CIPHERTEXT),
+        commitment: buf(COMMITMENT),
+        newRoot: await nextRoot(pool.shieldedState, [buf(COMMITMENT)]),
+      })
+      .accounts({
+        config: deriveConfigPda(),
+        vault: pool.vault,
+        vaultAta: pool.vaultAta,
+        shieldedState: pool.shieldedState,
+        user: provider.wallet.publicKey,
+        identityMember: identityMemberPda,
+        userAta: poolUserAta,
+        mint: pool.mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    await expectError(closeMintState(pool), "PoolNotDrained");
+    assert.isOk(await provider.connection.getAccountInfo(pool.vault));
+  });
+
+  it("closes a drained pool and returns the rent to the admin", async () => {
+    const pool = await setupMintPool();
+    const accounts = [pool.vault, pool.vaultAta, pool.shieldedState, pool.nullifierSet];
+    let rent = 0;
+    for (const account of accounts) {
+      rent += (await provider.connection.getAccountInfo(account))!.lamports;
+    }
+    const before = await provider.connection.getBalance(provider.wallet.publicKey);
+
+    await closeMintState(pool);
+
+    for (const account of accounts) {
+      assert.isNull(await provider.connection.getAccountInfo(account));
+    }
+    const after = await provider.connection.getBalance(provider.wallet.publicKey);
+    // Only the transaction fee is kept back.
+    assert.isAbove(after - before, rent - 10_000);
+  });
+
+  it("rejects proofs bound to another pool's domain", async () => {
+    const otherPool = await setupMintPool();
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, domain: poolDomain(otherPool.mint) }),
+      "DomainMismatch"
+    );
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, domain: Buffer.alloc(32) }),
+      "DomainMismatch"
+    );
+    await withdrawWithProof({ amount: 1_000n, domain: poolDomain(mint) });
+  });
+
+  it("rejects spends from other circuits against a pinned pool", async () => {
+    const pool = await setupMintPool(1, MERKLE_DEPTH, true, 7);
+    const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.circuitId, 7);
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool, circuitId: 0 }),
+      "PoolCircuitMismatch"
+    );
+
+    const unpinned = await program.account.shieldedState.fetch(shieldedPda);
+    assert.isNull(unpinned.circuitId);
+  });
+
+  // The last `Program return:` line for `programId`; inner CPI calls log their own.
+  const returnData = (logs: readonly string[], programId: PublicKey) => {
+    const prefix = `Program return: ${programId.toBase58()} `;
+    const returned = [...logs].reverse().find((line) => line.startsWith(prefix));
+    assert.isOk(returned, "missing return data");
+    return Buffer.from(returned!.slice(prefix.length), "base64");
+  };
+
+  const getValidRoots = async (pool: MintPool) => {
+    const { raw } = await program.methods
+      .getValidRoots()
+      .accounts({ config: deriveConfigPda(), shieldedState: pool.shieldedState, mint: pool.mint })
+      .simulate();
+    const data = returnData(raw, program.programId);
+    return Array.from({ length: data.length / 32 }, (_, index) =>
+      data.subarray(index * 32, (index + 1) * 32)
+    );
+  };
+
+  const setupRootPool = async (deposits: number, nullifierChunks = 1) => {
+    const pool = await setupMintPool(nullifierChunks);
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      deposits * 1_000
+    );
+    // roots[i] is the pool root after deposit i + 1.
+    const roots: Buffer[] = [];
+    for (let index = 1; index <= deposits; index += 1) {
+      const newRoot = await nextRoot(pool.shieldedState, [buf(COMMITMENT)]);
+      roots.push(newRoot);
+      await program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: buf(CIPHERTEXT),
+          commitment: buf(COMMITMENT),
+          newRoot,
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    }
+    return { ...pool, roots };
+  };
+
+  it("returns the newest roots from get_valid_roots after the history wraps", async () => {
+    const pool = await setupRootPool(40);
+    const roots = await getValidRoots(pool);
+
+    // The current root, then the 31 roots before it, oldest first. The root the ring
+    // overwrote last may still be in its grace window but does not fit in return data.
+    assert.equal(roots.length, 32);
+    assert.deepEqual(roots[0], pool.roots[39]);
+    roots.slice(1).forEach((root, index) => assert.deepEqual(root, pool.roots[index + 8]));
+  });
+
+  it("keeps only the newest roots when the pool's root history is shortened", async () => {
+    const pool = await setupRootPool(5);
+    const setRootHistoryLen = (len: number) =>
+      program.methods
+        .setRootHistoryLen(len)
+        .accounts({
+          config: deriveConfigPda(),
+          shieldedState: pool.shieldedState,
+          admin: provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .rpc();
+    await expectError(setRootHistoryLen(0), "InvalidRootHistoryLen");
+    await expectError(setRootHistoryLen(33), "InvalidRootHistoryLen");
+
+    await setRootHistoryLen(3);
+    const shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.rootHistoryLen, 3);
+    const roots = await getValidRoots(pool);
+    assert.deepEqual(roots, [4, 2, 3].map((index) => pool.roots[index]));
+  });
+
+  it("only lets listed relayers submit withdrawals while the allowlist is enabled", async () => {
+    const configPda = deriveConfigPda();
+    const vetted = Keypair.generate().publicKey;
+    const wallet = provider.wallet.publicKey;
+    const updateRelayers = (method: "addRelayer" | "removeRelayer", relayer: PublicKey) =>
+      program.methods[method](relayer).accounts({ config: configPda, admin: wallet }).rpc();
+    const setEnabled = (enabled: boolean) =>
+      program.methods
+        .setRelayerAllowlistEnabled(enabled)
+        .accounts({ config: configPda, admin: wallet })
+        .rpc();
+
+    const outsider = Keypair.generate();
+    await expectError(
+      program.methods
+        .addRelayer(outsider.publicKey)
+        .accounts({ config: configPda, admin: outsider.publicKey })
+        .signers([outsider])
+        .rpc(),
+      "Unauthorized"
+    );
+
+    await updateRelayers("addRelayer", vetted);
+    // Listed but disabled: anyone may still submit.
+    await withdrawWithProof({ amount: 1_000n });
+    await setEnabled(true);
+    try {
+      await expectError(withdrawWithProof({ amount: 1_000n }), "RelayerNotAllowed");
+      await updateRelayers("addRelayer", wallet);
+      await withdrawWithProof({ amount: 1_000n });
+      await updateRelayers("removeRelayer", wallet);
+      await expectError(withdrawWithProof({ amount: 1_000n }), "RelayerNotAllowed");
+    } finally {
+      await setEnabled(false);
+      await updateRelayers("removeRelayer", wallet);
+      await updateRelayers("removeRelayer", vetted);
+    }
+    const config = await program.account.config.fetch(configPda);
+    assert.isFalse(config.relayerAllowlistEnabled);
+    assert.deepEqual(config.relayerAllowlist, []);
+    await withdrawWithProof({ amount: 1_000n });
+  });
+
+  it("marks extra nullifier chunks only in the spending pool's own set", async () => {
+    const pool = await setupRootPool(2, 4);
+    const other = await setupMintPool(4);
+    const nullifier = Buffer.alloc(32);
+    nullifier.writeUInt16LE(7, 4);
+    for (let tweak = 1; nullifierChunkIndex(nullifier, 4) === 0; tweak += 1) {
+      nullifier.writeUInt16LE(tweak, 30);
+    }
+    const chunkIndex = nullifierChunkIndex(nullifier, 4);
+    const initializeChunk = async (target: MintPool) => {
+      const chunkBuf = Buffer.alloc(4);
+      chunkBuf.writeUInt32LE(chunkIndex, 0);
+      const [nullifierSet] = PublicKey.findProgramAddressSync(
+        [Buffer.from("nullifier_set"), target.mint.toBuffer(), chunkBuf],
+        program.programId
+      );
+      await program.methods
+        .initializeNullifierChunk(chunkIndex)
+        .accounts({
+          config: deriveConfigPda(),
+          nullifierSet,
+          vault: target.vault,
+          payer: provider.wallet.publicKey,
+          mint: target.mint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      return nullifierSet;
+    };
+    const ownChunk = await initializeChunk(pool);
+    const decoyChunk = await initializeChunk(other);
+    const writable = (pubkey: PublicKey) => ({ pubkey, isSigner: false, isWritable: true });
+
+    // Another mint's set with the same chunk index does not stand in for this pool's.
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool, nullifier, remainingAccounts: [writable(decoyChunk)] }),
+      "MissingNullifierAccount"
+    );
+
+    await withdrawWithProof({
+      amount: 1_000n,
+      pool,
+      nullifier,
+      remainingAccounts: [writable(decoyChunk), writable(ownChunk)],
+    });
+    assert.equal((await program.account.nullifierSet.fetch(ownChunk)).count, 1);
+    assert.equal((await program.account.nullifierSet.fetch(decoyChunk)).count, 0);
+
+    // The mark was written back, so the same nullifier cannot be spent again.
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool, nullifier, remainingAccounts: [writable(ownChunk)] }),
+      "NullifierAlreadyUsed"
+    );
+  });
+
+  it("reports proof validity as return data from try_verify_groth16, also over CPI", async () => {
+    const fixture = loadGroth16Fixture();
+    const initializeKey = async (keyId: number, gammaAbc: Buffer[]) => {
+      const keyPda = deriveVerifierKeyPda(keyId);
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbc,
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: keyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      return keyPda;
+    };
+    const gammaAbc: Buffer[] = fixture.gamma_abc.map(hex);
+    const rightKey = await initializeKey(1292, gammaAbc);
+    const wrongKey = await initializeKey(1293, [...gammaAbc].reverse());
+    const publicInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const proof = hex(fixture.proof);
+
+    const tryVerify = (verifierKey: PublicKey, proofBytes: Buffer) =>
+      verifierProgram.methods
+        .tryVerifyGroth16(proofBytes, publicInputs)
+        .accounts({ verifierKey });
+    const verdict = async (verifierKey: PublicKey, proofBytes: Buffer) =>
+      returnData((await tryVerify(verifierKey, proofBytes).simulate()).raw, verifierProgram.programId);
+    assert.deepEqual([...(await verdict(rightKey, proof))], [1]);
+    assert.deepEqual([...(await verdict(wrongKey, proof))], [0]);
+    // A pairing failure is a verdict, but a malformed proof still reverts.
+    await tryVerify(wrongKey, proof).rpc();
+    await expectError(tryVerify(rightKey, proof.subarray(0, 255)).rpc(), "InvalidProof");
+
+    const { raw } = await probeProgram.methods
+      .probe(proof, publicInputs)
+      .accounts({ verifierProgram: verifierProgram.programId })
+      .remainingAccounts(
+        [wrongKey, rightKey].map((pubkey) => ({ pubkey, isSigner: false, isWritable: false }))
+      )
+      .simulate();
+    assert.deepEqual([...returnData(raw, probeProgram.programId)], [0, 1]);
+  });
+
+  it("rejects 255- and 257-byte proofs before any verification work", async () => {
+    const publicInputs = makePublicInputs({
+      root: (await getRoots()).rootBytes,
+      identityRoot: (await getRoots()).identityRootBytes,
+      nullifiers: [nextTestNullifier(), zero32(), zero32(), zero32()],
+      outputCommitments: [Buffer.alloc(32, 1), zero32()],
+      outputEnabled: [1, 0],
+      amountOut: 0n,
+      feeAmount: 0n,
+      circuitId: 0,
+      domain: poolDomain(mint),
+    });
+    for (const proof of [Buffer.alloc(255), Buffer.alloc(257)]) {
+      // Even a mock key checks the proof length first.
+      await expectError(
+        verifierProgram.methods
+          .verifyGroth16(proof, publicInputs)
+          .accounts({ verifierKey: verifierKeyPda })
+          .rpc(),
+        "InvalidProof"
+      );
+      await expectError(
+        program.methods
+          .internalTransfer({
+            proof,
+            publicInputs,
+            newRoot: buf(NEW_ROOT),
+            outputCiphertexts: Buffer.alloc(128),
+          })
+          .accounts({
+            config: deriveConfigPda(),
+            vault: vaultPda,
+            shieldedState: shieldedPda,
+            identityRegistry: identityRegistryPda,
+            nullifierSet: nullifierPda,
+            verifierProgram: verifierProgram.programId,
+            verifierKey: verifierKeyPda,
+            mint,
+          })
+          .rpc(),
+        "InvalidProofDataLength"
+      );
+    }
+  });
+
+  it("lets anyone create a missing nullifier chunk right before spending into it", async () => {
+    const pool = await setupRootPool(2, 4);
+    const nullifier = Buffer.alloc(32);
+    nullifier.writeUInt16LE(11, 4);
+    for (let tweak = 1; nullifierChunkIndex(nullifier, 4) === 0; tweak += 1) {
+      nullifier.writeUInt16LE(tweak, 30);
+    }
+    const chunkIndex = nullifierChunkIndex(nullifier, 4);
+    const chunkBuf = Buffer.alloc(4);
+    chunkBuf.writeUInt32LE(chunkIndex, 0);
+    const [chunk] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), pool.mint.toBuffer(), chunkBuf],
+      program.programId
+    );
+    const writable = [{ pubkey: chunk, isSigner: false, isWritable: true }];
+    const payer = Keypair.generate();
+    await ensureSystemAccount(provider.connection, payer.publicKey);
+    const ensureChunk = () =>
+      program.methods
+        .ensureNullifierChunk(chunkIndex)
+        .accounts({
+          config: deriveConfigPda(),
+          nullifierSet: chunk,
+          vault: pool.vault,
+          payer: payer.publicKey,
+          mint: pool.mint,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool, nullifier, remainingAccounts: writable }),
+      "MissingNullifierAccount"
+    );
+    await ensureChunk();
+    await withdrawWithProof({ amount: 1_000n, pool, nullifier, remainingAccounts: writable });
+    const set = await program.account.nullifierSet.fetch(chunk);
+    assert.ok(set.mint.equals(pool.mint));
+    assert.equal(set.chunkIndex, chunkIndex);
+    assert.equal(set.count, 1);
+
+    // Ensuring an existing chunk again keeps its marks.
+    await ensureChunk();
+    assert.equal((await program.account.nullifierSet.fetch(chunk)).count, 1);
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool, nullifier, remainingAccounts: writable }),
+      "NullifierAlreadyUsed"
+    );
+
+    // A delisted pool can still create the chunk its next withdrawal needs.
+    await program.methods
+      .removeMint(pool.mint)
+      .accounts({ config: deriveConfigPda(), admin: provider.wallet.publicKey })
+      .rpc();
+    const late = Buffer.alloc(32);
+    late.writeUInt16LE(12, 4);
+    for (let tweak = 1; [0, chunkIndex].includes(nullifierChunkIndex(late, 4)); tweak += 1) {
+      late.writeUInt16LE(tweak, 30);
+    }
+    const lateIndex = nullifierChunkIndex(late, 4);
+    const lateBuf = Buffer.alloc(4);
+    lateBuf.writeUInt32LE(lateIndex, 0);
+    const [lateChunk] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), pool.mint.toBuffer(), lateBuf],
+      program.programId
+    );
+    assert.isNull(await provider.connection.getAccountInfo(lateChunk));
+    await program.methods
+      .ensureNullifierChunk(lateIndex)
+      .accounts({
+        config: deriveConfigPda(),
+        nullifierSet: lateChunk,
+        vault: pool.vault,
+        payer: payer.publicKey,
+        mint: pool.mint,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([payer])
+      .rpc();
+    await withdrawWithProof({
+      amount: 1_000n,
+      pool,
+      nullifier: late,
+      remainingAccounts: [{ pubkey: lateChunk, isSigner: false, isWritable: true }],
+    });
+    assert.equal((await program.account.nullifierSet.fetch(lateChunk)).count, 1);
+  });
+
+  it("reads public inputs in the byte order the verifier key was created with", async () => {
+    const fixture = loadGroth16Fixture();
+    const initializeKey = async (keyId: number, inputEndianness: number) => {
+      const keyPda = deriveVerifierKeyPda(keyId);
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbc: fixture.gamma_abc.map(hex),
+          mock: false,
+          curve: 0,
+          inputEndianness,
+        })
+        .accounts({
+          verifierKey: keyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      return keyPda;
+    };
+    const beKey = await initializeKey(1295, 0);
+    const leKey = await initializeKey(1296, 1);
+    await expectError(initializeKey(1297, 2), "InvalidInputEndianness");
+
+    const proof = hex(fixture.proof);
+    const beInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const leInputs = Buffer.concat(fixture.public_inputs.map((word: string) => hex(word).reverse()));
+    const verify = (verifierKey: PublicKey, publicInputs: Buffer) =>
+      verifierProgram.methods.verifyGroth16(proof, publicInputs).accounts({ verifierKey }).rpc();
+    await verify(beKey, beInputs);
+    await verify(leKey, leInputs);
+    await expectError(verify(leKey, beInputs), "PairingNotOne");
+    await expectError(verify(beKey, leInputs), "PairingNotOne");
+  });
+
+  it("pauses a single vault without touching other mints", async () => {
+    const configPda = deriveConfigPda();
+    const pool = await setupRootPool(2);
+    const setVaultPaused = (paused: boolean, admin?: Keypair) =>
+      program.methods
+        .setVaultPaused(paused)
+        .accounts({
+          config: configPda,
+          vault: pool.vault,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const setPaused = (paused: boolean) =>
+      program.methods
+        .setPaused(paused)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const poolUserAta = await getAssociatedTokenAddress(pool.mint, provider.wallet.publicKey);
+    const deposit = () =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext: buf(CIPHERTEXT),
+          commitment: buf(COMMITMENT),
+          newRoot: Buffer.alloc(32, 3),
+        })
+        .accounts({
+          config: configPda,
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(setVaultPaused(true, Keypair.generate()), "Unauthorized");
+    const [event] = eventsNamed(await fetchEvents(await setVaultPaused(true)), "VaultPausedEvent");
+    assert.isOk(event, "missing vault paused event");
+    assert.isTrue(event.data.mint.equals(pool.mint));
+    try {
+      assert.isTrue((await program.account.vaultPool.fetch(pool.vault)).paused);
+      await expectError(deposit(), "VaultPaused");
+      await expectError(withdrawWithProof({ amount: 1_000n, pool }), "VaultPaused");
+      // Other mints keep operating.
+      await withdrawWithProof({ amount: 1_000n });
+
+      await setPaused(true);
+      try {
+        await expectError(withdrawWithProof({ amount: 1_000n, pool }), "ProtocolPaused");
+        await expectError(withdrawWithProof({ amount: 1_000n }), "ProtocolPaused");
+      } finally {
+        await setPaused(false);
+      }
+    } finally {
+      await setVaultPaused(false);
+    }
+    await withdrawWithProof({ amount: 1_000n, pool });
+  });
+
+  it("rejects deposits below the pool minimum and accepts exactly the minimum", async () => {
+    const pool = await setupMintPool();
+    const setMinDeposit = (minDeposit: number, admin?: Keypair) =>
+      program.methods
+        .setMinDeposit(new anchor.BN(minDeposit))
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const poolUserAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      provider.wallet.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      poolUserAta,
+      provider.wallet.publicKey,
+      10_000
+    );
+    const deposit = async (amount: number) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(amount),
+          ciphertext: Buffer.alloc(128, 1),
+          commitment: Buffer.alloc(32, 5),
+          newRoot: await nextRoot(pool.shieldedState, [Buffer.alloc(32, 5)]),
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: pool.vault,
+          vaultAta: pool.vaultAta,
+          shieldedState: pool.shieldedState,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta: poolUserAta,
+          mint: pool.mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(setMinDeposit(1_000, Keypair.generate()), "Unauthorized");
+    await setMinDeposit(1_000);
+    assert.equal((await program.account.vaultPool.fetch(pool.vault)).minDeposit.toString(), "1000");
+    await expectError(deposit(999), "DepositTooSmall");
+    await deposit(1_000);
+
+    await setMinDeposit(0);
+    await deposit(1);
+  });
+
+  it("only accepts the vault's associated token account as its token account", async () => {
+    const pool = await setupRootPool(2);
+    // Same mint, same owner, funded, but not the associated token account.
+    const decoy = await createAccount(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      pool.vault,
+      Keypair.generate()
+    );
+    await mintTo(
+      provider.connection,
+      provider.wallet.payer,
+      pool.mint,
+      decoy,
+      provider.wallet.publicKey,
+      5_000
+    );
+    await expectError(
+      withdrawWithProof({ amount: 1_000n, pool: { ...pool, vaultAta: decoy } }),
+      "AccountNotAssociatedTokenAccount"
+    );
+    await withdrawWithProof({ amount: 1_000n, pool });
+
+    const fresh = await createMint(
+      provider.connection,
+      provider.wallet.payer,
+      provider.wallet.publicKey,
+      null,
+      6
+    );
+    await program.methods
+      .registerMint(fresh)
+      .accounts({ config: deriveConfigPda(), admin: provider.wallet.publicKey })
+      .rpc();
+    const seeded = (seed: string, ...extra: Buffer[]) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from(seed), fresh.toBuffer(), ...extra],
+        program.programId
+      )[0];
+    const freshVault = seeded("vault");
+    const freshDecoy = await createAccount(
+      provider.connection,
+      provider.wallet.payer,
+      fresh,
+      freshVault,
+      Keypair.generate()
+    );
+    await expectError(
+      program.methods
+        .initializeMintState(0, MERKLE_DEPTH, null)
+        .accounts({
+          config: deriveConfigPda(),
+          vault: freshVault,
+          vaultAta: freshDecoy,
+          shieldedState: seeded("shielded"),
+          nullifierSet: seeded("nullifier_set", Buffer.from([0, 0, 0, 0])),
+          admin: provider.wallet.publicKey,
+          mint: fresh,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc(),
+      "InvalidVaultAuthority"
+    );
+    await program.methods
+      .removeMint(fresh)
+      .accounts({ config: deriveConfigPda(), admin: provider.wallet.publicKey })
+      .rpc();
+  });
+
+  it("rotates a verifier key in place for its admin only", async () => {
+    const fixture = loadGroth16Fixture();
+    const keyPda = deriveVerifierKeyPda(1299);
+    const points = {
+      alphaG1: hex(fixture.alpha_g1),
+      betaG2: hex(fixture.beta_g2),
+      gammaG2: hex(fixture.gamma_g2),
+      deltaG2: hex(fixture.delta_g2),
+    };
+    const gammaAbc: Buffer[] = fixture.gamma_abc.map(hex);
+    await verifierProgram.methods
+      .initializeVerifierKey({
+        keyId: 1299,
+        ...points,
+        publicInputsLen: fixture.public_inputs.length,
+        gammaAbc,
+        mock: false,
+        curve: 0,
+        inputEndianness: 0,
+      })
+      .accounts({
+        verifierKey: keyPda,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const update = (publicInputsLen: number, newGammaAbc: Buffer[], admin?: Keypair) =>
+      verifierProgram.methods
+        .updateVerifierKey({ ...points, publicInputsLen, gammaAbc: newGammaAbc })
+        .accounts({
+          verifierKey: keyPda,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const proof = hex(fixture.proof);
+    const publicInputs = Buffer.concat(fixture.public_inputs.map(hex));
+    const verify = () =>
+      verifierProgram.methods
+        .verifyGroth16(proof, publicInputs)
+        .accounts({ verifierKey: keyPda })
+        .rpc();
+    await verify();
+
+    const rotated = [...gammaAbc].reverse();
+    await expectError(update(fixture.public_inputs.length, rotated, Keypair.generate()), "Unauthorized");
+    // A new input count needs a gamma_abc of the matching length in the same call.
+    await expectError(update(fixture.public_inputs.length + 1, rotated), "InvalidInputCount");
+    await update(fixture.public_inputs.length, rotated);
+    const key = await verifierProgram.account.verifierKey.fetch(keyPda);
+    assert.equal(key.vkVersion, 1);
+    assert.ok(key.admin.equals(provider.wallet.publicKey));
+    await expectError(verify(), "PairingNotOne");
+
+    await update(fixture.public_inputs.length, gammaAbc);
+    await verify();
+    await verifierProgram.methods
+      .freezeVerifierKey()
+      .accounts({ verifierKey: keyPda, admin: provider.wallet.publicKey })
+      .rpc();
+    await expectError(update(fixture.public_inputs.length, rotated), "VerifierKeyFrozen");
+  });
+
+  it("finalizes a chunked verifier key only when gamma_abc matches the expected hash", async () => {
+    const fixture = loadGroth16Fixture();
+    const gammaAbc: Buffer[] = fixture.gamma_abc.map(hex);
+    const keyPda = deriveVerifierKeyPda(1300);
+    const header = (expectedGammaAbcHash: Buffer) =>
+      verifierProgram.methods
+        .initializeVerifierKeyHeader({
+          keyId: 1300,
+          alphaG1: hex(fixture.alpha_g1),
+          betaG2: hex(fixture.beta_g2),
+          gammaG2: hex(fixture.gamma_g2),
+          deltaG2: hex(fixture.delta_g2),
+          publicInputsLen: fixture.public_inputs.length,
+          gammaAbcLen: gammaAbc.length,
+          mock: false,
+          curve: 0,
+          inputEndianness: 0,
+          expectedGammaAbcHash: Array.from(expectedGammaAbcHash),
+        })
+        .accounts({
+          verifierKey: keyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    const write = (startIndex: number, points: Buffer[]) =>
+      verifierProgram.methods
+        .setVerifierKeyGammaAbc({ keyId: 1300, startIndex, gammaAbc: points })
+        .accounts({ verifierKey: keyPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const finalize = (admin?: Keypair) =>
+      verifierProgram.methods
+        .finalizeVerifierKey()
+        .accounts({ verifierKey: keyPda, admin: admin?.publicKey ?? provider.wallet.publicKey })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const verify = () =>
+      verifierProgram.methods
+        .verifyGroth16(hex(fixture.proof), Buffer.concat(fixture.public_inputs.map(hex)))
+        .accounts({ verifierKey: keyPda })
+        .rpc();
+
+    await expectError(header(Buffer.alloc(32)), "InvalidVerifierKey");
+    await header(gammaAbcHash(gammaAbc));
+    await expectError(finalize(), "InvalidVerifierKey");
+    // Chunks landing in the wrong slots leave a complete but wrong array.
+    await write(0, [gammaAbc[1]]);
+    await write(1, [gammaAbc[0]]);
+    await expectError(finalize(), "GammaAbcHashMismatch");
+    await expectError(verify(), "VerifierKeyNotFinalized");
+
+    await write(0, gammaAbc);
+    await expectError(finalize(Keypair.generate()), "Unauthorized");
+    await finalize();
+    assert.isTrue((await verifierProgram.account.verifierKey.fetch(keyPda)).finalized);
+    await verify();
+    await expectError(write(0, gammaAbc), "VerifierKeyFinalized");
+  });
+
+  it("parses spends with the pool's own input count once its layout is switched", async () => {
+    const configPda = deriveConfigPda();
+    const pool = await setupRootPool(2);
+    const setSpendLayout = (maxInputs: number, maxOutputs: number, admin?: Keypair) =>
+      program.methods
+        .setSpendLayout(maxInputs, maxOutputs)
+        .accounts({
+          config: configPda,
+          shieldedState: pool.shieldedState,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+          mint: pool.mint,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const setCircuitIds = (circuitIds: number[]) =>
+      program.methods
+        .setCircuitIds(circuitIds)
+        .accounts({ config: configPda, admin: provider.wallet.publicKey })
+        .rpc();
+    const wideKeyPda = deriveVerifierKeyPda(1302);
+    if (!(await provider.connection.getAccountInfo(wideKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: 1302,
+          alphaG1: dummyG1,
+          betaG2: dummyG2,
+          gammaG2: dummyG2,
+          deltaG2: dummyG2,
+          publicInputsLen: 18,
+          gammaAbc: dummyGammaAbc,
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({
+          verifierKey: wideKeyPda,
+          admin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 13, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: deriveVkRegistryPda(),
+        verifierKey: wideKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    let shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.maxInputs, 4);
+    assert.equal(shielded.maxOutputs, 2);
+    await expectError(setSpendLayout(8, 2, Keypair.generate()), "Unauthorized");
+    await expectError(setSpendLayout(9, 2), "InvalidSpendLayout");
+    await expectError(setSpendLayout(8, 3), "InvalidSpendLayout");
+    await setSpendLayout(8, 2);
+    shielded = await program.account.shieldedState.fetch(pool.shieldedState);
+    assert.equal(shielded.maxInputs, 8);
+
+    await setCircuitIds([0, 13]);
+    try {
+      await expectError(withdrawWithProof({ amount: 1_000n, pool }), "InvalidPublicInputs");
+      await withdrawWithProof({
+        amount: 1_000n,
+        pool,
+        circuitId: 13,
+        verifierKey: wideKeyPda,
+        nullifiers: [nextTestNullifier(), ...Array.from({ length: 6 }, zero32), nextTestNullifier()],
+      });
+    } finally {
+      await setCircuitIds([0]);
+    }
+    const nullifierSet = await program.account.nullifierSet.fetch(pool.nullifierSet);
+    assert.equal(nullifierSet.count.toString(), "2");
+  });
+
+  it("rejects deposit ciphertexts of the wrong length and emits the right one", async () => {
+    const depositWith = async (ciphertext: Buffer) =>
+      program.methods
+        .deposit({
+          amount: new anchor.BN(1_000),
+          ciphertext,
+          commitment: buf(COMMITMENT),
+          newRoot: await nextRoot(shieldedPda, [buf(COMMITMENT)]),
+        })
+        .accounts({
+          config: deriveConfigPda(),
+          vault: vaultPda,
+          vaultAta,
+          shieldedState: shieldedPda,
+          user: provider.wallet.publicKey,
+          identityMember: identityMemberPda,
+          userAta,
+          mint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    const balanceBefore = (await getAccount(provider.connection, vaultAta)).amount;
+    for (const length of [0, 127, 129]) {
+      await expectError(depositWith(Buffer.alloc(length, 5)), "InvalidByteLength");
+    }
+    assert.equal((await getAccount(provider.connection, vaultAta)).amount, balanceBefore);
+
+    const ciphertext = Buffer.from(Array.from({ length: 128 }, (_, index) => index));
+    const { commitmentCount } = await program.account.shieldedState.fetch(shieldedPda);
+    const events = await fetchEvents(await depositWith(ciphertext));
+    const [note] = eventsNamed(events, "NoteOutputEvent");
+    assert.isOk(note, "missing note output event");
+    assert.equal(note.data.kind, 0);
+    assert.equal(note.data.leafIndex.toString(), commitmentCount.toString());
+    assert.deepEqual(Buffer.from(note.data.ciphertext as number[]), ciphertext);
+    assert.deepEqual(Buffer.from(note.data.commitment as number[]), buf(COMMITMENT));
+    const [deposited] = eventsNamed(events, "DepositEvent");
+    assert.deepEqual(Buffer.from(deposited.data.commitment as number[]), buf(COMMITMENT));
+  });
+
+  it("pays the relayer in lamports from the recipient's fee escrow", async () => {
+    const recipient = Keypair.generate();
+    const [feeEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("fee_escrow"), recipient.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .fundFeeEscrow(recipient.publicKey, new anchor.BN(50_000))
+      .accounts({
+        feeEscrow,
+        funder: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const escrowBalance = () => provider.connection.getBalance(feeEscrow);
+    const funded = await escrowBalance();
+
+    const solFee = { amount: 1_000n, recipient, relayerFeeLamports: 10_000n };
+    await expectError(withdrawWithProof(solFee), "MissingFeeEscrow");
+    await expectError(withdrawWithProof({ ...solFee, feeEscrow }), "FeeEscrowNotApproved");
+    await expectError(
+      withdrawWithProof({ ...solFee, feeEscrow, recipientSigns: true, relayerFeeBps: 50, feeAmount: 5n }),
+      "InvalidRelayerFeeMode"
+    );
+    await expectError(
+      withdrawWithProof({ ...solFee, feeEscrow, recipientSigns: true, relayerFeeLamports: 50_001n }),
+      "InsufficientFeeEscrow"
+    );
+    assert.equal(await escrowBalance(), funded);
+
+    // The token side is untouched: the recipient gets the full amount.
+    const { recipientAta } = await withdrawWithProof({ ...solFee, feeEscrow, recipientSigns: true });
+    assert.equal((await getAccount(provider.connection, recipientAta)).amount, 1_000n);
+    assert.equal(await escrowBalance(), funded - 10_000);
+
+    const withdrawEscrow = (lamports: number) =>
+      program.methods
+        .withdrawFeeEscrow(new anchor.BN(lamports))
+        .accounts({ feeEscrow, owner: recipient.publicKey })
+        .signers([recipient])
+        .rpc();
+    await expectError(withdrawEscrow(40_001), "InsufficientFeeEscrow");
+    await withdrawEscrow(40_000);
+    assert.equal(await escrowBalance(), funded - 50_000);
+  });
+
+  it("emits one NullifierSpentEvent per real nullifier of a spend", async () => {
+    const pool = await setupRootPool(2);
+    const spent = [nextTestNullifier(), nextTestNullifier()];
+    const { signature } = await withdrawWithProof({
+      amount: 1_000n,
+      pool,
+      nullifiers: [...spent, zero32(), zero32()],
+    });
+    const events = eventsNamed(await fetchEvents(signature), "NullifierSpentEvent");
+    assert.equal(events.length, spent.length);
+    assert.deepEqual(
+      events.map((event) => Buffer.from(event.data.nullifier as number[])),
+      spent
+    );
+    for (const event of events) {
+      assert.ok((event.data.mint as PublicKey).equals(pool.mint));
+      assert.equal(event.data.chunkIndex, 0);
+    }
+  });
+
+  it("skips the identity root only while the registry does not require one", async () => {
+    const setIdentityRequired = (required: boolean, admin?: Keypair) =>
+      program.methods
+        .setIdentityRequired(required)
+        .accounts({
+          config: deriveConfigPda(),
+          identityRegistry: identityRegistryPda,
+          admin: admin?.publicKey ?? provider.wallet.publicKey,
+        })
+        .signers(admin ? [admin] : [])
+        .rpc();
+    const outsider = Keypair.generate();
+    const unknownRoot = Buffer.alloc(32, 9);
+
+    assert.isTrue((await program.account.identityRegistry.fetch(identityRegistryPda)).identityRequired);
+    await expectError(withdrawWithProof({ amount: 1_000n, identityRoot: unknownRoot }), "IdentityRootMismatch");
+    await expectError(setIdentityRequired(false, outsider), "Unauthorized");
+
+    await setIdentityRequired(false);
+    try {
+      await withdrawWithProof({ amount: 1_000n, identityRoot: unknownRoot });
+    } finally {
+      await setIdentityRequired(true);
+    }
+    await expectError(withdrawWithProof({ amount: 1_000n, identityRoot: unknownRoot }), "IdentityRootMismatch");
+  });
+
+  it("leaves a config already at the current version untouched when migrating", async () => {
+    const configPda = deriveConfigPda();
+    const before = await program.account.config.fetch(configPda);
+    const outsider = Keypair.generate();
+
+    await expectError(
+      program.methods
+        .migrateConfig()
+        .accounts({ config: configPda, admin: outsider.publicKey })
+        .signers([outsider])
+        .rpc(),
+      "Unauthorized"
+    );
+
+    const signature = await program.methods
+      .migrateConfig()
+      .accounts({ config: configPda, admin: provider.wallet.publicKey })
+      .rpc();
+    const after = await program.account.config.fetch(configPda);
+
+    assert.equal(after.version, 2);
+    assert.equal(after.admin.toBase58(), before.admin.toBase58());
+    assert.deepEqual(
+      (after.mintAllowlist as PublicKey[]).map((key) => key.toBase58()),
+      (before.mintAllowlist as PublicKey[]).map((key) => key.toBase58())
+    );
+    assert.equal(after.nullifierChunks, before.nullifierChunks);
+    assert.lengthOf(eventsNamed(await fetchEvents(signature), "ConfigMigratedEvent"), 0);
+  });
+
+  it("answers which nullifier chunk and bit a nullifier maps to", async () => {
+    const pool = await setupMintPool(16);
+    const nullifier = Buffer.alloc(32);
+    nullifier[4] = 0x34;
+    nullifier[5] = 0xa2;
+    nullifier[31] = 0x2a;
+
+    const view = await program.methods
+      .nullifierChunkFor(Array.from(nullifier))
+      .accounts({ vault: pool.vault, mint: pool.mint })
+      .view();
+
+    const chunkIndex = nullifierChunkIndex(nullifier, 16);
+    assert.equal(view.chunkIndex, 9);
+    assert.equal(view.chunkIndex, chunkIndex);
+    assert.equal(view.bitIndex, 4681);
+    assert.equal(view.bitIndex, nullifierBitIndex(nullifier));
+    const [expectedSet] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("nullifier_set"),
+        pool.mint.toBuffer(),
+        new anchor.BN(chunkIndex).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    );
+    assert.equal(view.nullifierSet.toBase58(), expectedSet.toBase58());
+  });
+
+  it("keeps pool accounting intact when the token program is not a real one", async () => {
+    const pool = await setupRootPool(2);
+    const nullifier = nextTestNullifier();
+    const before = await program.account.vaultPool.fetch(pool.vault);
+
+    // A stand-in program that could call back into the pool never gets a CPI: the
+    // token program must be SPL Token or Token-2022.
+    await expectError(
+      withdrawWithProof({
+        amount: 1_000n,
+        pool,
+        nullifier,
+        tokenProgram: verifierProgram.programId,
+      }),
+      "InvalidProgramId"
+    );
+    const untouched = await program.account.vaultPool.fetch(pool.vault);
+    assert.equal(untouched.totalWithdrawn.toString(), before.totalWithdrawn.toString());
+    assert.equal(untouched.nonce.toString(), before.nonce.toString());
+
+    await withdrawWithProof({ amount: 1_000n, pool, nullifier });
+    const after = await program.account.vaultPool.fetch(pool.vault);
+    assert.equal(after.totalWithdrawn.toString(), before.totalWithdrawn.addn(1_000).toString());
+    assert.equal(after.nonce.toString(), before.nonce.addn(1).toString());
+    await expectError(withdrawWithProof({ amount: 1_000n, pool, nullifier }), "NullifierAlreadyUsed");
+  });
+
+  it("rejects a withdrawal the vault balance cannot cover before moving anything", async () => {
+    const pool = await setupRootPool(1);
+    await withdrawWithProof({ amount: 600n, pool });
+    const drained = (await getAccount(provider.connection, pool.vaultAta)).amount;
+    assert.equal(drained.toString(), "400");
+
+    const nullifier = nextTestNullifier();
+    await expectError(
+      withdrawWithProof({ amount: 600n, pool, nullifier }),
+      "InsufficientVaultBalance"
+    );
+    assert.equal((await getAccount(provider.connection, pool.vaultAta)).amount.toString(), "400");
+    await withdrawWithProof({ amount: 400n, pool, nullifier });
+  });
 });
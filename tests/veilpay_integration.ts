@@ -0,0 +1,444 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { assert } from "chai";
+import {
+  createMint,
+  getAssociatedTokenAddress,
+  createAssociatedTokenAccount,
+  createAssociatedTokenAccountInstruction,
+  mintTo,
+  getAccount,
+  TOKEN_PROGRAM_ID,
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+} from "@solana/spl-token";
+import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import { deriveProofAccount, domainSeparator, nullifierBitIndex } from "../sdk/src/pda";
+import { bigIntToBytes32, bytesToBigIntBE } from "../sdk/src/crypto";
+import { identityAppendPath } from "../sdk/src/identity";
+import { buildMerkleTree, MERKLE_DEPTH } from "../sdk/src/merkle";
+
+// Full deposit -> withdraw wiring against a fresh mint, a mock verifier key and
+// real token accounts. Runs after the other suites, so shared PDAs (config,
+// registries) are created only when missing.
+
+const MOCK_KEY_ID = 1217;
+
+const zero32 = () => Buffer.alloc(32);
+const u64ToBytes32 = (value: bigint) => {
+  const out = Buffer.alloc(32);
+  out.writeBigUInt64BE(value, 24);
+  return out;
+};
+const u32ToBytes32 = (value: number) => {
+  const out = Buffer.alloc(32);
+  out.writeUInt32BE(value, 28);
+  return out;
+};
+const makePublicInputs = (params: {
+  root: Buffer;
+  identityRoot: Buffer;
+  nullifier: Buffer;
+  amountOut: bigint;
+  domain: Buffer;
+}) =>
+  Buffer.concat([
+    params.root,
+    params.identityRoot,
+    params.nullifier,
+    zero32(),
+    zero32(),
+    zero32(),
+    zero32(),
+    zero32(),
+    u64ToBytes32(0n),
+    u64ToBytes32(0n),
+    u64ToBytes32(params.amountOut),
+    u64ToBytes32(0n),
+    u32ToBytes32(0),
+    params.domain,
+  ]);
+const testNullifier = (seed: number) => {
+  const value = Buffer.alloc(32);
+  value.writeUInt16LE(seed, 4);
+  return value;
+};
+const isBitSet = (bitset: number[], bit: number) => (bitset[bit >> 3] & (1 << (bit % 8))) !== 0;
+
+const expectError = async (action: Promise<unknown>, code: string) => {
+  try {
+    await action;
+  } catch (err: any) {
+    assert.include(err.toString(), code);
+    return;
+  }
+  assert.fail(`expected ${code}`);
+};
+
+describe("veilpay integration", () => {
+  const provider = anchor.AnchorProvider.local();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.Veilpay as Program;
+  const verifierProgram = (anchor.workspace.Verifier ||
+    anchor.workspace.verifier) as Program;
+  const admin = provider.wallet.publicKey;
+  const user = Keypair.generate();
+
+  const [configPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config"), program.programId.toBuffer()],
+    program.programId
+  );
+  const [vkRegistryPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("vk_registry")],
+    program.programId
+  );
+  const [identityRegistryPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("identity_registry")],
+    program.programId
+  );
+  const [identityMemberPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("identity_member"), user.publicKey.toBuffer()],
+    program.programId
+  );
+  const keyIdBuf = Buffer.alloc(4);
+  keyIdBuf.writeUInt32LE(MOCK_KEY_ID, 0);
+  const [verifierKeyPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("verifier_key"), keyIdBuf],
+    verifierProgram.programId
+  );
+
+  let mint: PublicKey;
+  let vaultPda: PublicKey;
+  let vaultAta: PublicKey;
+  let shieldedPda: PublicKey;
+  let nullifierPda: PublicKey;
+  let userAta: PublicKey;
+  let proofNonce = 1n;
+
+  const deposit = (amount: bigint, newRoot: Buffer) =>
+    program.methods
+      .deposit({
+        amount: new anchor.BN(amount.toString()),
+        ciphertext: Buffer.alloc(128, 1),
+        commitment: Buffer.alloc(32, 2),
+        newRoot,
+      })
+      .accounts({
+        config: configPda,
+        vault: vaultPda,
+        vaultAta,
+        shieldedState: shieldedPda,
+        user: user.publicKey,
+        identityMember: identityMemberPda,
+        userAta,
+        mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([user])
+      .rpc();
+
+  const withdraw = async (params: {
+    amount: bigint;
+    nullifier: Buffer;
+    destinationMint?: PublicKey;
+  }) => {
+    const recipient = Keypair.generate();
+    const destinationAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      params.destinationMint ?? mint,
+      recipient.publicKey
+    );
+    const shielded = await program.account.shieldedState.fetch(shieldedPda);
+    const identity = await program.account.identityRegistry.fetch(identityRegistryPda);
+    const publicInputs = makePublicInputs({
+      root: Buffer.from(shielded.merkleRoot as number[]),
+      identityRoot: Buffer.from(identity.merkleRoot as number[]),
+      nullifier: params.nullifier,
+      amountOut: params.amount,
+      domain: Buffer.from(domainSeparator(program.programId, mint)),
+    });
+    const nonce = proofNonce++;
+    const proofAccount = deriveProofAccount(program.programId, mint, nonce);
+    await program.methods
+      .storeProof({
+        nonce: new anchor.BN(nonce.toString()),
+        recipient: recipient.publicKey,
+        destinationAta,
+        mint,
+        proof: Buffer.alloc(256),
+        publicInputs,
+      })
+      .accounts({
+        proofAccount,
+        payer: admin,
+        mint,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const vault = await program.account.vaultPool.fetch(vaultPda);
+    const [tempAuthority] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("temp_wsol"),
+        recipient.publicKey.toBuffer(),
+        (vault.nonce as anchor.BN).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    await program.methods
+      .externalTransferWithProof({
+        amount: new anchor.BN(params.amount.toString()),
+        relayerFeeBps: 0,
+        relayerFeeLamports: new anchor.BN(0),
+        newRoot: Buffer.from(shielded.merkleRoot as number[]),
+        outputCiphertexts: Buffer.alloc(0),
+        deliverSol: false,
+        proofSystem: 0,
+      })
+      .accounts({
+        config: configPda,
+        payer: admin,
+        vault: vaultPda,
+        vaultAta,
+        shieldedState: shieldedPda,
+        identityRegistry: identityRegistryPda,
+        nullifierSet: nullifierPda,
+        proofAccount,
+        destinationAta,
+        recipient: recipient.publicKey,
+        tempAuthority,
+        tempWsolAta: await getAssociatedTokenAddress(mint, tempAuthority, true),
+        relayerFeeAta: null,
+        protocolFeeAta: null,
+        verifierProgram: verifierProgram.programId,
+        verifierKey: verifierKeyPda,
+        mint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    return { destinationAta };
+  };
+
+  before(async () => {
+    if (!(await provider.connection.getAccountInfo(configPda))) {
+      await program.methods
+        .initializeConfig({
+          feeBps: 0,
+          relayerFeeBpsMax: 50,
+          relayerFeeBpsMin: 0,
+          vkRegistry: vkRegistryPda,
+          mintAllowlist: [],
+          circuitIds: [0],
+        })
+        .accounts({ config: configPda, admin, systemProgram: SystemProgram.programId })
+        .rpc();
+    }
+    if (!(await provider.connection.getAccountInfo(vkRegistryPda))) {
+      await program.methods
+        .initializeVkRegistry()
+        .accounts({ vkRegistry: vkRegistryPda, admin, systemProgram: SystemProgram.programId })
+        .rpc();
+    }
+    if (!(await provider.connection.getAccountInfo(identityRegistryPda))) {
+      await program.methods
+        .initializeIdentityRegistry(20)
+        .accounts({
+          config: configPda,
+          identityRegistry: identityRegistryPda,
+          admin,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+    if (!(await provider.connection.getAccountInfo(verifierKeyPda))) {
+      await verifierProgram.methods
+        .initializeVerifierKey({
+          keyId: MOCK_KEY_ID,
+          alphaG1: Buffer.alloc(64),
+          betaG2: Buffer.alloc(128),
+          gammaG2: Buffer.alloc(128),
+          deltaG2: Buffer.alloc(128),
+          publicInputsLen: 14,
+          gammaAbc: [Buffer.alloc(64)],
+          mock: true,
+          curve: 0,
+          inputEndianness: 0,
+        })
+        .accounts({ verifierKey: verifierKeyPda, admin, systemProgram: SystemProgram.programId })
+        .rpc();
+    }
+    await program.methods
+      .registerVkEntry({ circuitId: 0, vkHash: Array.from(Buffer.alloc(32)) })
+      .accounts({
+        config: configPda,
+        vkRegistry: vkRegistryPda,
+        verifierKey: verifierKeyPda,
+        admin,
+      })
+      .rpc();
+
+    const identityCommitment = Buffer.alloc(32, 3);
+    const { root: identityRoot } = await identityAppendPath(
+      program,
+      bytesToBigIntBE(identityCommitment)
+    );
+    await program.methods
+      .registerIdentity({
+        commitment: identityCommitment,
+        newRoot: Buffer.from(bigIntToBytes32(identityRoot)),
+      })
+      .accounts({
+        identityRegistry: identityRegistryPda,
+        identityMember: identityMemberPda,
+        payer: admin,
+        user: user.publicKey,
+        identityAdmin: admin,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
+
+    mint = await createMint(provider.connection, provider.wallet.payer, admin, null, 6);
+    await program.methods.registerMint(mint).accounts({ config: configPda, admin }).rpc();
+    // Keep this pool's spends in the chunk created below.
+    await program.methods.setNullifierChunks(1).accounts({ config: configPda, admin }).rpc();
+    [vaultPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), mint.toBuffer()],
+      program.programId
+    );
+    [shieldedPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shielded"), mint.toBuffer()],
+      program.programId
+    );
+    [nullifierPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), mint.toBuffer(), Buffer.alloc(4)],
+      program.programId
+    );
+    vaultAta = await getAssociatedTokenAddress(mint, vaultPda, true);
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        createAssociatedTokenAccountInstruction(admin, vaultAta, vaultPda, mint)
+      )
+    );
+    await program.methods
+      .initializeMintState(0, MERKLE_DEPTH, null)
+      .accounts({
+        config: configPda,
+        vault: vaultPda,
+        vaultAta,
+        shieldedState: shieldedPda,
+        nullifierSet: nullifierPda,
+        admin,
+        mint,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    userAta = await createAssociatedTokenAccount(
+      provider.connection,
+      provider.wallet.payer,
+      mint,
+      user.publicKey
+    );
+    await mintTo(provider.connection, provider.wallet.payer, mint, userAta, admin, 1_000_000);
+  });
+
+  it("moves tokens and updates pool state across deposit and withdraw", async () => {
+    const { root } = await buildMerkleTree([bytesToBigIntBE(Buffer.alloc(32, 2))]);
+    const depositRoot = Buffer.from(bigIntToBytes32(root));
+    await deposit(50_000n, depositRoot);
+
+    let vault = await program.account.vaultPool.fetch(vaultPda);
+    assert.equal(vault.totalDeposited.toString(), "50000");
+    assert.equal((await getAccount(provider.connection, vaultAta)).amount.toString(), "50000");
+    assert.equal((await getAccount(provider.connection, userAta)).amount.toString(), "950000");
+    const shielded = await program.account.shieldedState.fetch(shieldedPda);
+    assert.equal(shielded.commitmentCount.toString(), "1");
+    assert.deepEqual(Buffer.from(shielded.merkleRoot as number[]), depositRoot);
+    assert.isTrue(
+      (shielded.rootHistory as number[][]).some((root) => Buffer.from(root).equals(depositRoot))
+    );
+
+    const nullifier = testNullifier(17);
+    const { destinationAta } = await withdraw({ amount: 20_000n, nullifier });
+
+    vault = await program.account.vaultPool.fetch(vaultPda);
+    assert.equal(vault.totalWithdrawn.toString(), "20000");
+    assert.equal((await getAccount(provider.connection, vaultAta)).amount.toString(), "30000");
+    assert.equal((await getAccount(provider.connection, destinationAta)).amount.toString(), "20000");
+    const nullifiers = await program.account.nullifierSet.fetch(nullifierPda);
+    assert.equal(nullifiers.count.toString(), "1");
+    assert.isTrue(isBitSet(nullifiers.bitset as number[], nullifierBitIndex(nullifier)));
+    assert.isFalse(
+      isBitSet(nullifiers.bitset as number[], nullifierBitIndex(testNullifier(18)))
+    );
+  });
+
+  it("rejects a reused nullifier", async () => {
+    await withdraw({ amount: 1_000n, nullifier: testNullifier(30) });
+    await expectError(
+      withdraw({ amount: 1_000n, nullifier: testNullifier(30) }),
+      "NullifierAlreadyUsed"
+    );
+  });
+
+  it("rejects mints outside the allowlist and mismatched destination mints", async () => {
+    const otherMint = await createMint(provider.connection, provider.wallet.payer, admin, null, 6);
+    const [otherVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), otherMint.toBuffer()],
+      program.programId
+    );
+    const [otherShielded] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shielded"), otherMint.toBuffer()],
+      program.programId
+    );
+    const [otherNullifier] = PublicKey.findProgramAddressSync(
+      [Buffer.from("nullifier_set"), otherMint.toBuffer(), Buffer.alloc(4)],
+      program.programId
+    );
+    const otherVaultAta = await getAssociatedTokenAddress(otherMint, otherVault, true);
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        createAssociatedTokenAccountInstruction(admin, otherVaultAta, otherVault, otherMint)
+      )
+    );
+    await expectError(
+      program.methods
+        .initializeMintState(0, MERKLE_DEPTH, null)
+        .accounts({
+          config: configPda,
+          vault: otherVault,
+          vaultAta: otherVaultAta,
+          shieldedState: otherShielded,
+          nullifierSet: otherNullifier,
+          admin,
+          mint: otherMint,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc(),
+      "MintNotAllowed"
+    );
+
+    await expectError(
+      withdraw({ amount: 1_000n, nullifier: testNullifier(40), destinationMint: otherMint }),
+      "InvalidRecipientTokenAccount"
+    );
+  });
+
+  it("rejects withdrawals while the protocol is paused", async () => {
+    const setPaused = (paused: boolean) =>
+      program.methods.setPaused(paused).accounts({ config: configPda, admin }).rpc();
+    await setPaused(true);
+    try {
+      await expectError(
+        withdraw({ amount: 1_000n, nullifier: testNullifier(50) }),
+        "ProtocolPaused"
+      );
+    } finally {
+      await setPaused(false);
+    }
+    await withdraw({ amount: 1_000n, nullifier: testNullifier(50) });
+  });
+});
@@ -10,6 +10,26 @@ use serde_json::Value;
 use std::{env, fs::File, path::PathBuf};
 use tokio::runtime::Runtime;
 
+/// Public signals of the VeilPay withdraw circuit, in declaration order; the default
+/// when `--public` is not given.
+const WITHDRAW_PUBLIC_INPUTS: [&str; 13] = [
+    "root",
+    "identity_root",
+    "nullifier[0]",
+    "nullifier[1]",
+    "nullifier[2]",
+    "nullifier[3]",
+    "output_commitment[0]",
+    "output_commitment[1]",
+    "output_enabled[0]",
+    "output_enabled[1]",
+    "amount_out",
+    "fee_amount",
+    "circuit_id",
+];
+
+const USAGE: &str = "Usage: ark-prover [--public <name,name[i],...>] <wasm> <r1cs> <zkey> <input.json> <out.json> <vk.json>";
+
 // Shim for wasmer on some toolchains that don't export __rust_probestack.
 #[unsafe(no_mangle)]
 pub extern "C" fn __rust_probestack() {}
@@ -41,6 +61,38 @@ fn push_inputs(builder: &mut CircomBuilder<Fr>, key: &str, value: &Value) -> Res
     Ok(())
 }
 
+/// Splits `--public` into input names, e.g. `root,nullifier[0],amount_out`.
+fn parse_public_names(list: &str) -> Result<Vec<String>> {
+    let names: Vec<String> = list.split(',').map(|name| name.trim().to_string()).collect();
+    if names.iter().any(String::is_empty) {
+        return Err(anyhow!("--public has an empty input name: {list:?}"));
+    }
+    Ok(names)
+}
+
+/// Looks up `name` (`base` or `base[i]`) in the witness input object.
+fn lookup_input<'a>(
+    input_obj: &'a serde_json::Map<String, Value>,
+    name: &str,
+) -> Result<&'a Value> {
+    let Some((base, idx)) = name.split_once('[') else {
+        return input_obj
+            .get(name)
+            .ok_or_else(|| anyhow!("public input {name} is not in the witness input"));
+    };
+    let idx = idx
+        .trim_end_matches(']')
+        .parse::<usize>()
+        .with_context(|| format!("invalid index in public input name {name}"))?;
+    input_obj
+        .get(base)
+        .ok_or_else(|| anyhow!("public input {name} is not in the witness input"))?
+        .as_array()
+        .ok_or_else(|| anyhow!("input {base} must be array"))?
+        .get(idx)
+        .ok_or_else(|| anyhow!("public input {name} is not in the witness input"))
+}
+
 fn fq_to_be(fq: &impl BigInteger) -> [u8; 32] {
     let mut out = [0u8; 32];
     let bytes = fq.to_bytes_be();
@@ -72,18 +124,30 @@ fn hex_encode(bytes: &[u8]) -> String {
 }
 
 fn run() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 7 {
-        return Err(anyhow!(
-            "Usage: ark-prover <wasm> <r1cs> <zkey> <input.json> <out.json> <vk.json>"
-        ));
+    let mut args = env::args().skip(1);
+    let mut public_names: Option<Vec<String>> = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--public" {
+            let list = args.next().ok_or_else(|| anyhow!("--public needs a value\n{USAGE}"))?;
+            public_names = Some(parse_public_names(&list)?);
+        } else if let Some(list) = arg.strip_prefix("--public=") {
+            public_names = Some(parse_public_names(list)?);
+        } else {
+            positional.push(arg);
+        }
     }
-    let wasm_path = PathBuf::from(&args[1]);
-    let r1cs_path = PathBuf::from(&args[2]);
-    let zkey_path = PathBuf::from(&args[3]);
-    let input_path = PathBuf::from(&args[4]);
-    let out_path = PathBuf::from(&args[5]);
-    let vk_path = PathBuf::from(&args[6]);
+    if positional.len() < 6 {
+        return Err(anyhow!(USAGE));
+    }
+    let public_names = public_names
+        .unwrap_or_else(|| WITHDRAW_PUBLIC_INPUTS.iter().map(|name| name.to_string()).collect());
+    let wasm_path = PathBuf::from(&positional[0]);
+    let r1cs_path = PathBuf::from(&positional[1]);
+    let zkey_path = PathBuf::from(&positional[2]);
+    let input_path = PathBuf::from(&positional[3]);
+    let out_path = PathBuf::from(&positional[4]);
+    let vk_path = PathBuf::from(&positional[5]);
 
     let input_value: Value =
         serde_json::from_reader(File::open(&input_path).context("open input")?)?;
@@ -105,37 +169,17 @@ fn run() -> Result<()> {
         .get_public_inputs()
         .ok_or_else(|| anyhow!("missing public inputs"))?;
 
-    let expected_inputs = [
-        "root",
-        "identity_root",
-        "nullifier[0]",
-        "nullifier[1]",
-        "nullifier[2]",
-        "nullifier[3]",
-        "output_commitment[0]",
-        "output_commitment[1]",
-        "output_enabled[0]",
-        "output_enabled[1]",
-        "amount_out",
-        "fee_amount",
-        "circuit_id",
-    ];
-    for (index, name) in expected_inputs.iter().enumerate() {
-        let value = if let Some((base, idx)) = name.split_once('[') {
-            let idx = idx.trim_end_matches(']').parse::<usize>()?;
-            let array = input_obj
-                .get(base)
-                .ok_or_else(|| anyhow!("missing input {base}"))?
-                .as_array()
-                .ok_or_else(|| anyhow!("input {base} must be array"))?;
-            array
-                .get(idx)
-                .ok_or_else(|| anyhow!("missing input {base}[{idx}]"))?
-        } else {
-            input_obj
-                .get(*name)
-                .ok_or_else(|| anyhow!("missing input {name}"))?
-        };
+    // The names cover the circuit's public signals in order; a circuit may declare
+    // more (e.g. the optional slot window), which are then not cross-checked.
+    if public_names.len() > public_inputs.len() {
+        return Err(anyhow!(
+            "{} public input names given but the circuit has {} public inputs",
+            public_names.len(),
+            public_inputs.len()
+        ));
+    }
+    for (index, name) in public_names.iter().enumerate() {
+        let value = lookup_input(input_obj, name)?;
         let big = parse_big(value)?;
         let fr = Fr::from_be_bytes_mod_order(&big.to_bytes_be());
         if public_inputs
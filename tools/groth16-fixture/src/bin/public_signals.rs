@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Context, Result};
+use groth16_fixture::big_to_bytes32;
+use num_bigint::BigUint;
+use num_traits::Num;
+use serde_json::Value;
+use std::{env, fs};
+
+// BN254 scalar field (Fr): every public signal must be a canonical element.
+const SCALAR_MODULUS_DEC: &str =
+    "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+
+/// Packs snarkjs decimal public signals into the big-endian 32-byte layout the
+/// verifier program expects.
+fn pack_signals(signals: &[String], expected: usize) -> Result<Vec<u8>> {
+    if signals.len() != expected {
+        return Err(anyhow!(
+            "expected {expected} public signals, got {}",
+            signals.len()
+        ));
+    }
+    let modulus = BigUint::from_str_radix(SCALAR_MODULUS_DEC, 10)?;
+    let mut out = Vec::with_capacity(expected * 32);
+    for (i, signal) in signals.iter().enumerate() {
+        let value = BigUint::from_str_radix(signal.trim(), 10)
+            .map_err(|err| anyhow!("signal {i} ({signal:?}) is not a decimal integer: {err}"))?;
+        if value >= modulus {
+            return Err(anyhow!("signal {i} is not below the scalar field modulus"));
+        }
+        out.extend_from_slice(&big_to_bytes32(&value)?);
+    }
+    Ok(out)
+}
+
+fn unpack_signals(bytes: &[u8]) -> Result<Vec<String>> {
+    if !bytes.len().is_multiple_of(32) {
+        return Err(anyhow!("input length {} is not a multiple of 32", bytes.len()));
+    }
+    Ok(bytes
+        .chunks(32)
+        .map(|chunk| BigUint::from_bytes_be(chunk).to_str_radix(10))
+        .collect())
+}
+
+fn read_signals(source: &str) -> Result<Vec<String>> {
+    let json: Value = serde_json::from_str(&fs::read_to_string(source)?)?;
+    let signals = json
+        .get("publicSignals")
+        .unwrap_or(&json)
+        .as_array()
+        .context("expected an array of public signals")?;
+    signals
+        .iter()
+        .map(|value| {
+            value
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("expected decimal string"))
+        })
+        .collect()
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let usage = "Usage: public_signals <count> <public.json | signal...>\n       public_signals --decode <hex>";
+    if args.len() < 3 {
+        return Err(anyhow!(usage));
+    }
+    if args[1] == "--decode" {
+        let clean = args[2].strip_prefix("0x").unwrap_or(&args[2]);
+        for signal in unpack_signals(&hex::decode(clean)?)? {
+            println!("{signal}");
+        }
+        return Ok(());
+    }
+    let expected: usize = args[1].parse().context(usage)?;
+    let signals = if args.len() == 3 && args[2].ends_with(".json") {
+        read_signals(&args[2])?
+    } else {
+        args[2..].to_vec()
+    };
+    println!("{}", hex::encode(pack_signals(&signals, expected)?));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn pack_round_trips_through_unpack() {
+        let max = (BigUint::from_str_radix(SCALAR_MODULUS_DEC, 10).unwrap() - 1u32).to_str_radix(10);
+        let signals = strings(&["0", "1", "1000", "18446744073709551615", &max]);
+        let packed = pack_signals(&signals, signals.len()).unwrap();
+        assert_eq!(packed.len(), signals.len() * 32);
+        assert_eq!(unpack_signals(&packed).unwrap(), signals);
+    }
+
+    #[test]
+    fn pack_is_big_endian() {
+        let packed = pack_signals(&strings(&["258"]), 1).unwrap();
+        assert!(packed[..30].iter().all(|b| *b == 0));
+        assert_eq!(&packed[30..], &[1, 2]);
+    }
+
+    #[test]
+    fn pack_rejects_wrong_count_and_out_of_field_values() {
+        assert!(pack_signals(&strings(&["1", "2"]), 3).is_err());
+        assert!(pack_signals(&strings(&[SCALAR_MODULUS_DEC]), 1).is_err());
+        assert!(pack_signals(&strings(&["0x10"]), 1).is_err());
+        assert!(unpack_signals(&[0u8; 31]).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ark_bn254::{Fq, Fq2, G1Affine, G2Affine};
+use ark_ff::{BigInteger, PrimeField};
+use num_bigint::BigUint;
+use num_traits::Num;
+use serde::Serialize;
+use serde_json::Value;
+use std::{env, fs};
+
+/// `InitializeVerifierKeyArgs` fields, hex encoded in the verifier's big-endian layout.
+#[derive(Serialize)]
+struct VerifierKeyJson {
+    alpha_g1: String,
+    beta_g2: String,
+    gamma_g2: String,
+    delta_g2: String,
+    gamma_abc: Vec<String>,
+    public_inputs_len: u32,
+}
+
+fn parse_fq(value: &Value) -> Result<Fq> {
+    let s = value.as_str().ok_or_else(|| anyhow!("expected decimal string"))?;
+    let big = BigUint::from_str_radix(s, 10).map_err(|err| anyhow!("{s:?}: {err}"))?;
+    if big >= BigUint::from(Fq::MODULUS) {
+        bail!("{s} is not below the base field modulus");
+    }
+    Ok(Fq::from(big))
+}
+
+fn coords<'a>(value: &'a Value, what: &str) -> Result<&'a Vec<Value>> {
+    let arr = value.as_array().with_context(|| format!("{what} not an array"))?;
+    if arr.len() < 2 {
+        bail!("{what} needs at least x and y");
+    }
+    Ok(arr)
+}
+
+/// snarkjs writes affine points with a trailing projective `z` of one; anything else
+/// (including the point at infinity) is not a usable key element.
+fn parse_g1(value: &Value, what: &str) -> Result<G1Affine> {
+    let arr = coords(value, what)?;
+    if let Some(z) = arr.get(2)
+        && z.as_str() != Some("1")
+    {
+        bail!("{what} is not an affine point (z != 1)");
+    }
+    let point = G1Affine::new_unchecked(parse_fq(&arr[0])?, parse_fq(&arr[1])?);
+    if !point.is_on_curve() || !point.is_in_correct_subgroup_assuming_on_curve() {
+        bail!("{what} is not a valid G1 point");
+    }
+    Ok(point)
+}
+
+fn parse_fq2(value: &Value, what: &str) -> Result<Fq2> {
+    let arr = coords(value, what)?;
+    Ok(Fq2::new(parse_fq(&arr[0])?, parse_fq(&arr[1])?))
+}
+
+/// snarkjs orders each G2 coordinate as `[c0, c1]`.
+fn parse_g2(value: &Value, what: &str) -> Result<G2Affine> {
+    let arr = coords(value, what)?;
+    if let Some(z) = arr.get(2)
+        && (z.get(0).and_then(Value::as_str) != Some("1")
+            || z.get(1).and_then(Value::as_str) != Some("0"))
+    {
+        bail!("{what} is not an affine point (z != 1)");
+    }
+    let point = G2Affine::new_unchecked(parse_fq2(&arr[0], what)?, parse_fq2(&arr[1], what)?);
+    if !point.is_on_curve() {
+        bail!("{what} is not on the G2 curve");
+    }
+    if !point.is_in_correct_subgroup_assuming_on_curve() {
+        bail!("{what} is not in the G2 prime-order subgroup");
+    }
+    Ok(point)
+}
+
+fn fq_to_be(fq: &Fq) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let bytes = fq.into_bigint().to_bytes_be();
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+fn g1_to_be(point: &G1Affine) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    out[..32].copy_from_slice(&fq_to_be(&point.x));
+    out[32..].copy_from_slice(&fq_to_be(&point.y));
+    out
+}
+
+/// Same layout as `g2_to_be` in the fixture generator: `c1` before `c0`.
+fn g2_to_be(point: &G2Affine) -> [u8; 128] {
+    let mut out = [0u8; 128];
+    out[0..32].copy_from_slice(&fq_to_be(&point.x.c1));
+    out[32..64].copy_from_slice(&fq_to_be(&point.x.c0));
+    out[64..96].copy_from_slice(&fq_to_be(&point.y.c1));
+    out[96..128].copy_from_slice(&fq_to_be(&point.y.c0));
+    out
+}
+
+fn convert(vkey: &Value) -> Result<VerifierKeyJson> {
+    if let Some(protocol) = vkey.get("protocol").and_then(Value::as_str)
+        && protocol != "groth16"
+    {
+        bail!("unsupported protocol {protocol:?}");
+    }
+    if let Some(curve) = vkey.get("curve").and_then(Value::as_str)
+        && curve != "bn128"
+    {
+        bail!("unsupported curve {curve:?}");
+    }
+    let field = |name: &str| vkey.get(name).with_context(|| format!("{name} missing"));
+    let ic = field("IC")?.as_array().context("IC not an array")?;
+    let n_public = field("nPublic")?.as_u64().context("nPublic not a number")?;
+    if ic.len() as u64 != n_public + 1 {
+        bail!("IC has {} points, expected nPublic + 1 = {}", ic.len(), n_public + 1);
+    }
+    let gamma_abc = ic
+        .iter()
+        .enumerate()
+        .map(|(i, point)| Ok(hex::encode(g1_to_be(&parse_g1(point, &format!("IC[{i}]"))?))))
+        .collect::<Result<_>>()?;
+    Ok(VerifierKeyJson {
+        alpha_g1: hex::encode(g1_to_be(&parse_g1(field("vk_alpha_1")?, "vk_alpha_1")?)),
+        beta_g2: hex::encode(g2_to_be(&parse_g2(field("vk_beta_2")?, "vk_beta_2")?)),
+        gamma_g2: hex::encode(g2_to_be(&parse_g2(field("vk_gamma_2")?, "vk_gamma_2")?)),
+        delta_g2: hex::encode(g2_to_be(&parse_g2(field("vk_delta_2")?, "vk_delta_2")?)),
+        gamma_abc,
+        public_inputs_len: u32::try_from(n_public).context("nPublic too large")?,
+    })
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        return Err(anyhow!("Usage: snarkjs_to_vk <verification_key.json> [out.json]"));
+    }
+    let vkey: Value = serde_json::from_str(&fs::read_to_string(&args[1])?)?;
+    let json = serde_json::to_string_pretty(&convert(&vkey)?)?;
+    match args.get(2) {
+        Some(out) => fs::write(out, json)?,
+        None => println!("{json}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_ec::AffineRepr;
+    use serde_json::json;
+
+    fn dec(fq: &Fq) -> String {
+        fq.into_bigint().to_string()
+    }
+
+    fn g1_json(point: &G1Affine) -> Value {
+        json!([dec(&point.x), dec(&point.y), "1"])
+    }
+
+    fn g2_json(point: &G2Affine) -> Value {
+        json!([
+            [dec(&point.x.c0), dec(&point.x.c1)],
+            [dec(&point.y.c0), dec(&point.y.c1)],
+            ["1", "0"]
+        ])
+    }
+
+    fn vkey() -> Value {
+        let g1 = G1Affine::generator();
+        let g2 = G2Affine::generator();
+        json!({
+            "protocol": "groth16",
+            "curve": "bn128",
+            "nPublic": 1,
+            "vk_alpha_1": g1_json(&g1),
+            "vk_beta_2": g2_json(&g2),
+            "vk_gamma_2": g2_json(&g2),
+            "vk_delta_2": g2_json(&g2),
+            "IC": [g1_json(&g1), g1_json(&(g1 + g1).into())],
+        })
+    }
+
+    #[test]
+    fn converts_with_c1_before_c0() {
+        let g2 = G2Affine::generator();
+        let out = convert(&vkey()).unwrap();
+        assert_eq!(out.public_inputs_len, 1);
+        assert_eq!(out.gamma_abc.len(), 2);
+        assert_eq!(out.alpha_g1, hex::encode(g1_to_be(&G1Affine::generator())));
+        assert_eq!(&out.beta_g2[..64], hex::encode(fq_to_be(&g2.x.c1)));
+        assert_eq!(&out.beta_g2[64..128], hex::encode(fq_to_be(&g2.x.c0)));
+        assert_eq!(&out.beta_g2[192..], hex::encode(fq_to_be(&g2.y.c0)));
+    }
+
+    #[test]
+    fn rejects_bad_points_and_counts() {
+        let mut swapped = vkey();
+        let g2 = G2Affine::generator();
+        swapped["vk_beta_2"] = json!([
+            [dec(&g2.x.c1), dec(&g2.x.c0)],
+            [dec(&g2.y.c1), dec(&g2.y.c0)],
+            ["1", "0"]
+        ]);
+        assert!(convert(&swapped).is_err());
+
+        let mut off_curve = vkey();
+        off_curve["IC"][1] = json!(["1", "1", "1"]);
+        assert!(convert(&off_curve).is_err());
+
+        // The G2 twist has a large cofactor, so a point found from a small x is on the
+        // curve but almost surely outside the prime-order subgroup.
+        let outside = (1u64..)
+            .filter_map(|x| {
+                G2Affine::get_point_from_x_unchecked(Fq2::new(Fq::from(x), Fq::from(0u64)), false)
+            })
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap();
+        let mut wrong_subgroup = vkey();
+        wrong_subgroup["vk_delta_2"] = g2_json(&outside);
+        let err = convert(&wrong_subgroup).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("subgroup"));
+
+        let mut wrong_count = vkey();
+        wrong_count["nPublic"] = json!(2);
+        assert!(convert(&wrong_count).is_err());
+    }
+}
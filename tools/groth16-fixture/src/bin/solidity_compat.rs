@@ -1,18 +1,21 @@
-use anyhow::{anyhow, Context, Result};
-use solana_bn254::prelude::{
-    alt_bn128_g1_addition_be, alt_bn128_g1_addition_le, alt_bn128_g1_multiplication_be,
-    alt_bn128_g1_multiplication_le, alt_bn128_pairing_be, alt_bn128_pairing_le,
-    ALT_BN128_G1_MULTIPLICATION_INPUT_SIZE, ALT_BN128_G1_POINT_SIZE,
-    ALT_BN128_PAIRING_ELEMENT_SIZE, ALT_BN128_PAIRING_OUTPUT_SIZE,
-};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
 use std::{env, fs};
 use ark_bn254::{Fq, Fq2, G1Affine, G2Affine};
 use ark_ff::PrimeField;
+use verifier::compat::{detect_encoding, encode_g2, CompatKey, CompatProof, Endian, G2Order};
 
-#[derive(Clone, Copy, Debug)]
-enum Endian {
-    Be,
-    Le,
+/// `--emit` output: the proof and public inputs as `verify_groth16` takes them, plus
+/// the key in the same `InitializeVerifierKeyArgs` layout `snarkjs_to_vk` writes.
+#[derive(Serialize)]
+struct EmitJson {
+    proof_bytes: String,
+    public_inputs_bytes: String,
+    alpha_g1: String,
+    beta_g2: String,
+    gamma_g2: String,
+    delta_g2: String,
+    gamma_abc: Vec<String>,
 }
 
 fn hex_to_bytes<const N: usize>(value: &str) -> Result<[u8; N]> {
@@ -61,137 +64,48 @@ fn g2_on_curve(bytes: &[u8; 128], swapped: bool) -> (bool, bool) {
     (p.is_on_curve(), p.is_in_correct_subgroup_assuming_on_curve())
 }
 
-fn pairing_is_one(output: &[u8]) -> bool {
-    output.len() == ALT_BN128_PAIRING_OUTPUT_SIZE
-        && output.iter().take(31).all(|b| *b == 0)
-        && output[31] == 1
-}
-
-fn g1_add(a: &[u8; 64], b: &[u8; 64], endian: Endian) -> Result<[u8; 64]> {
-    let mut input = [0u8; 128];
-    input[..64].copy_from_slice(a);
-    input[64..].copy_from_slice(b);
-    let out = match endian {
-        Endian::Be => alt_bn128_g1_addition_be(&input)
-            .map_err(|_| anyhow!("g1 add failed"))?,
-        Endian::Le => alt_bn128_g1_addition_le(&input)
-            .map_err(|_| anyhow!("g1 add failed"))?,
-    };
-    if out.len() != ALT_BN128_G1_POINT_SIZE {
-        return Err(anyhow!("invalid g1 add output"));
-    }
-    let mut fixed = [0u8; 64];
-    fixed.copy_from_slice(&out[..64]);
-    Ok(fixed)
-}
-
-fn g1_mul(point: &[u8; 64], scalar: &[u8; 32], endian: Endian) -> Result<[u8; 64]> {
-    let mut input = [0u8; ALT_BN128_G1_MULTIPLICATION_INPUT_SIZE];
-    input[..64].copy_from_slice(point);
-    input[64..96].copy_from_slice(scalar);
-    let out = match endian {
-        Endian::Be => alt_bn128_g1_multiplication_be(&input)
-            .map_err(|_| anyhow!("g1 mul failed"))?,
-        Endian::Le => alt_bn128_g1_multiplication_le(&input)
-            .map_err(|_| anyhow!("g1 mul failed"))?,
-    };
-    if out.len() != ALT_BN128_G1_POINT_SIZE {
-        return Err(anyhow!("invalid g1 mul output"));
-    }
-    let mut fixed = [0u8; 64];
-    fixed.copy_from_slice(&out[..64]);
-    Ok(fixed)
-}
-
-fn negate_g1(point: &[u8; 64], endian: Endian) -> Result<[u8; 64]> {
-    let mut out = *point;
-    let mut y = [0u8; 32];
-    y.copy_from_slice(&point[32..64]);
-    if y.iter().all(|b| *b == 0) {
-        return Ok(out);
-    }
-    let p = [
-        48, 100, 78, 114, 225, 49, 160, 41, 184, 80, 69, 182, 129, 129, 88, 93, 151, 129,
-        106, 145, 104, 113, 202, 141, 60, 32, 140, 22, 216, 124, 253, 71,
-    ];
-    let mut y_be = y;
-    if let Endian::Le = endian {
-        y_be.reverse();
-    }
-    let mut neg = [0u8; 32];
-    let mut borrow = 0u16;
-    for i in (0..32).rev() {
-        let a = p[i] as i16;
-        let b = y_be[i] as i16 + borrow as i16;
-        if a < b {
-            neg[i] = (a + 256 - b) as u8;
-            borrow = 1;
-        } else {
-            neg[i] = (a - b) as u8;
-            borrow = 0;
-        }
-    }
-    if let Endian::Le = endian {
-        neg.reverse();
-    }
-    out[32..64].copy_from_slice(&neg);
-    Ok(out)
-}
-
-fn compute_vk_x(
-    gamma_abc: &[[u8; 64]],
-    public_inputs: &[[u8; 32]],
-    endian: Endian,
-) -> Result<[u8; 64]> {
-    let mut acc = gamma_abc[0];
-    for (i, input) in public_inputs.iter().enumerate() {
-        let term = g1_mul(&gamma_abc[i + 1], input, endian)?;
-        acc = g1_add(&acc, &term, endian)?;
-    }
-    Ok(acc)
-}
-
-fn verify(
-    a: &[u8; 64],
-    b: &[u8; 128],
-    c: &[u8; 64],
-    key_alpha: &[u8; 64],
-    key_beta: &[u8; 128],
-    key_gamma: &[u8; 128],
-    key_delta: &[u8; 128],
-    gamma_abc: &[[u8; 64]],
+/// The program verifies with the big-endian syscalls, so only a big-endian match can
+/// be re-encoded; the detected G2 orders are applied so every G2 has `c1` first.
+fn emit(
+    key: &CompatKey,
+    proof: &CompatProof,
     public_inputs: &[[u8; 32]],
-    endian: Endian,
-) -> Result<bool> {
-    let vk_x = compute_vk_x(gamma_abc, public_inputs, endian)?;
-    let neg_alpha = negate_g1(key_alpha, endian)?;
-    let neg_vk_x = negate_g1(&vk_x, endian)?;
-    let neg_c = negate_g1(c, endian)?;
-
-    let mut pairing_input =
-        Vec::with_capacity(ALT_BN128_PAIRING_ELEMENT_SIZE * 4);
-    pairing_input.extend_from_slice(a);
-    pairing_input.extend_from_slice(b);
-    pairing_input.extend_from_slice(&neg_alpha);
-    pairing_input.extend_from_slice(key_beta);
-    pairing_input.extend_from_slice(&neg_vk_x);
-    pairing_input.extend_from_slice(key_gamma);
-    pairing_input.extend_from_slice(&neg_c);
-    pairing_input.extend_from_slice(key_delta);
-
-    let result = match endian {
-        Endian::Be => alt_bn128_pairing_be(&pairing_input),
-        Endian::Le => alt_bn128_pairing_le(&pairing_input),
+    (endian, vk_order, proof_order): (Endian, G2Order, G2Order),
+) -> Result<EmitJson> {
+    if endian != Endian::Be {
+        bail!("only the little-endian syscalls verify this proof; the program uses big-endian");
     }
-    .map_err(|err| anyhow!("pairing failed: {err:?}"))?;
-    Ok(pairing_is_one(&result))
+    let proof_bytes = [
+        proof.a.as_slice(),
+        &encode_g2(&proof.b, proof_order, endian),
+        &proof.c,
+    ]
+    .concat();
+    Ok(EmitJson {
+        proof_bytes: hex::encode(proof_bytes),
+        public_inputs_bytes: hex::encode(public_inputs.concat()),
+        alpha_g1: hex::encode(key.alpha_g1),
+        beta_g2: hex::encode(encode_g2(&key.beta_g2, vk_order, endian)),
+        gamma_g2: hex::encode(encode_g2(&key.gamma_g2, vk_order, endian)),
+        delta_g2: hex::encode(encode_g2(&key.delta_g2, vk_order, endian)),
+        gamma_abc: key.gamma_abc.iter().map(hex::encode).collect(),
+    })
 }
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let emit_path = match args.iter().position(|arg| arg == "--emit") {
+        Some(index) if index + 1 < args.len() => {
+            let path = args.remove(index + 1);
+            args.remove(index);
+            Some(path)
+        }
+        Some(_) => bail!("--emit needs an output path"),
+        None => None,
+    };
     if args.len() < 3 {
         return Err(anyhow!(
-            "Usage: solidity_compat <verifier_key.json> <snarkjs_proof.json>"
+            "Usage: solidity_compat <verifier_key.json> <snarkjs_proof.json> [--emit <out.json>]"
         ));
     }
     let vk_json: serde_json::Value =
@@ -258,13 +172,6 @@ fn main() -> Result<()> {
         hex_to_bytes::<32>(b1[1].as_str().context("b11")?)?,
     ]
     .concat();
-    let b_bytes_swapped = [
-        hex_to_bytes::<32>(b0[1].as_str().context("b01")?)?,
-        hex_to_bytes::<32>(b0[0].as_str().context("b00")?)?,
-        hex_to_bytes::<32>(b1[1].as_str().context("b11")?)?,
-        hex_to_bytes::<32>(b1[0].as_str().context("b10")?)?,
-    ]
-    .concat();
     let c_bytes = [
         hex_to_bytes::<32>(c[0].as_str().context("c0")?)?,
         hex_to_bytes::<32>(c[1].as_str().context("c1")?)?,
@@ -278,13 +185,9 @@ fn main() -> Result<()> {
 
     let mut b_direct = [0u8; 128];
     b_direct.copy_from_slice(&b_bytes_direct);
-    let mut b_swapped = [0u8; 128];
-    b_swapped.copy_from_slice(&b_bytes_swapped);
 
     let (b_on, b_sub) = g2_on_curve(&b_direct, false);
     let (b_sw_on, b_sw_sub) = g2_on_curve(&b_direct, true);
-    let (b2_on, b2_sub) = g2_on_curve(&b_swapped, false);
-    let (b2_sw_on, b2_sw_sub) = g2_on_curve(&b_swapped, true);
     let (beta_on, beta_sub) = g2_on_curve(&beta_g2, false);
     let (beta_sw_on, beta_sw_sub) = g2_on_curve(&beta_g2, true);
     let (gamma_on, gamma_sub) = g2_on_curve(&gamma_g2, false);
@@ -294,10 +197,8 @@ fn main() -> Result<()> {
 
     println!("A on curve: {}", g1_on_curve(&a_fixed));
     println!("C on curve: {}", g1_on_curve(&c_fixed));
-    println!("B direct on curve (as-is): {b_on} subgroup: {b_sub}");
-    println!("B direct on curve (swapped): {b_sw_on} subgroup: {b_sw_sub}");
-    println!("B swapped on curve (as-is): {b2_on} subgroup: {b2_sub}");
-    println!("B swapped on curve (swapped): {b2_sw_on} subgroup: {b2_sw_sub}");
+    println!("B on curve (as-is): {b_on} subgroup: {b_sub}");
+    println!("B on curve (swapped): {b_sw_on} subgroup: {b_sw_sub}");
     println!("vk beta on curve (as-is): {beta_on} subgroup: {beta_sub}");
     println!("vk beta on curve (swapped): {beta_sw_on} subgroup: {beta_sw_sub}");
     println!("vk gamma on curve (as-is): {gamma_on} subgroup: {gamma_sub}");
@@ -310,25 +211,77 @@ fn main() -> Result<()> {
         .map(|v| hex_to_bytes::<32>(v.as_str().context("input")?))
         .collect::<Result<Vec<_>>>()?;
 
-    for (label, b_fixed) in [("direct", b_direct), ("swapped", b_swapped)] {
-        for endian in [Endian::Be, Endian::Le] {
-            match verify(
-                &a_fixed,
-                &b_fixed,
-                &c_fixed,
-                &alpha_g1,
-                &beta_g2,
-                &gamma_g2,
-                &delta_g2,
-                &gamma_abc,
-                &public_inputs,
-                endian,
-            ) {
-                Ok(ok) => println!("{label} {endian:?} -> {ok}"),
-                Err(err) => println!("{label} {endian:?} -> error: {err}"),
-            }
-        }
-    }
+    let key = CompatKey {
+        alpha_g1,
+        beta_g2,
+        gamma_g2,
+        delta_g2,
+        gamma_abc,
+    };
+    let proof = CompatProof {
+        a: a_fixed,
+        b: b_direct,
+        c: c_fixed,
+    };
+    let encoding = detect_encoding(&key, &proof, &public_inputs)
+        .ok_or_else(|| anyhow!("no endianness / G2 order combination verifies"))?;
+    let (endian, vk_order, proof_order) = encoding;
+    println!("endian={endian:?} vk_g2={vk_order:?} proof_g2={proof_order:?} -> ok");
 
+    if let Some(path) = emit_path {
+        let json = serde_json::to_string_pretty(&emit(&key, &proof, &public_inputs, encoding)?)?;
+        fs::write(&path, json)?;
+        println!("wrote {path}");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn field<const N: usize>(fixture: &Value, name: &str) -> [u8; N] {
+        hex_to_bytes(fixture[name].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn emits_the_fixture_from_its_snarkjs_layout() {
+        let fixture: Value =
+            serde_json::from_str(include_str!("../../../../tests/fixtures/groth16.json")).unwrap();
+        let proof_bytes: [u8; 256] = field(&fixture, "proof");
+        let snarkjs = |point: &[u8; 128]| encode_g2(point, G2Order::Swapped, Endian::Be);
+        let key = CompatKey {
+            alpha_g1: field(&fixture, "alpha_g1"),
+            beta_g2: snarkjs(&field(&fixture, "beta_g2")),
+            gamma_g2: snarkjs(&field(&fixture, "gamma_g2")),
+            delta_g2: snarkjs(&field(&fixture, "delta_g2")),
+            gamma_abc: fixture["gamma_abc"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|point| hex_to_bytes(point.as_str().unwrap()).unwrap())
+                .collect(),
+        };
+        let proof = CompatProof {
+            a: proof_bytes[..64].try_into().unwrap(),
+            b: snarkjs(&proof_bytes[64..192].try_into().unwrap()),
+            c: proof_bytes[192..].try_into().unwrap(),
+        };
+        let inputs: Vec<[u8; 32]> = fixture["public_inputs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|input| hex_to_bytes(input.as_str().unwrap()).unwrap())
+            .collect();
+
+        let encoding = detect_encoding(&key, &proof, &inputs).unwrap();
+        let out = emit(&key, &proof, &inputs, encoding).unwrap();
+        assert_eq!(out.proof_bytes, fixture["proof"].as_str().unwrap());
+        assert_eq!(out.public_inputs_bytes, hex::encode(inputs.concat()));
+        assert_eq!(out.delta_g2, fixture["delta_g2"].as_str().unwrap());
+
+        let little_endian = (Endian::Le, encoding.1, encoding.2);
+        assert!(emit(&key, &proof, &inputs, little_endian).is_err());
+    }
+}
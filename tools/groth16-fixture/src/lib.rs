@@ -0,0 +1,13 @@
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+
+/// Left-pads `value` into a big-endian 32-byte word, failing if it does not fit.
+pub fn big_to_bytes32(value: &BigUint) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    let bytes = value.to_bytes_be();
+    if bytes.len() > 32 {
+        return Err(anyhow!("value exceeds 32 bytes"));
+    }
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(out)
+}
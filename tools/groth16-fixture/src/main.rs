@@ -7,7 +7,7 @@ use ark_r1cs_std::{alloc::AllocVar, eq::EqGadget, fields::fp::FpVar, fields::Fie
 use ark_snark::SNARK;
 use rand::thread_rng;
 use serde::Serialize;
-use std::{fs, path::PathBuf};
+use std::{env, fs, path::PathBuf};
 use solana_bn254::prelude::{alt_bn128_pairing_be, ALT_BN128_PAIRING_ELEMENT_SIZE};
 
 #[derive(Clone)]
@@ -33,9 +33,66 @@ struct Fixture {
     gamma_abc: Vec<String>,
     proof: String,
     public_inputs: Vec<String>,
+    vk_x: String,
+}
+
+/// Public inputs of the VeilPay withdraw circuit, `domain` included, without the
+/// optional slot window, `value_in` or relayer fee recipient.
+const VEILPAY_PUBLIC_INPUTS_LEN: usize = 14;
+
+/// A `mock: true` verifier key fixture. The verifier accepts any proof against such a
+/// key, so it is for local and CI tests only and must never be registered on mainnet.
+#[derive(Serialize)]
+struct MockFixture {
+    warning: &'static str,
+    mock: bool,
+    curve: u8,
+    input_endianness: u8,
+    public_inputs_len: u32,
+    alpha_g1: String,
+    beta_g2: String,
+    gamma_g2: String,
+    delta_g2: String,
+    gamma_abc: Vec<String>,
+    proof: String,
+    public_inputs: Vec<String>,
+}
+
+fn mock_fixture() -> MockFixture {
+    MockFixture {
+        warning: "mock verifier key: accepts any proof, never use on mainnet",
+        mock: true,
+        curve: 0,
+        input_endianness: 0,
+        public_inputs_len: VEILPAY_PUBLIC_INPUTS_LEN as u32,
+        alpha_g1: hex_encode(&[0u8; 64]),
+        beta_g2: hex_encode(&[0u8; 128]),
+        gamma_g2: hex_encode(&[0u8; 128]),
+        delta_g2: hex_encode(&[0u8; 128]),
+        gamma_abc: vec![hex_encode(&[0u8; 64]); VEILPAY_PUBLIC_INPUTS_LEN + 1],
+        proof: hex_encode(&[0u8; 256]),
+        public_inputs: vec![hex_encode(&[0u8; 32]); VEILPAY_PUBLIC_INPUTS_LEN],
+    }
+}
+
+fn write_fixture(out_path: PathBuf, fixture: &impl Serialize) -> anyhow::Result<()> {
+    fs::create_dir_all(out_path.parent().unwrap())?;
+    fs::write(out_path, serde_json::to_vec_pretty(fixture)?)?;
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
+    match env::args().nth(1).as_deref() {
+        None => {}
+        Some("--mock") => {
+            return write_fixture(
+                PathBuf::from("../../tests/fixtures/groth16_mock.json"),
+                &mock_fixture(),
+            );
+        }
+        Some(other) => anyhow::bail!("unknown argument {other:?}; usage: groth16-fixture [--mock]"),
+    }
+
     let mut rng = thread_rng();
     let circuit = OneCircuit { x: Fr::from(1u64) };
     let (pk, vk) =
@@ -58,6 +115,8 @@ fn main() -> anyhow::Result<()> {
         .map(|g1| hex_encode(&g1_to_be(g1)))
         .collect();
 
+    let vk_x = compute_vk_x(&vk, &public_inputs);
+
     let fixture = Fixture {
         alpha_g1: hex_encode(&g1_to_be(&vk.alpha_g1)),
         beta_g2: hex_encode(&g2_to_be(&vk.beta_g2)),
@@ -69,12 +128,10 @@ fn main() -> anyhow::Result<()> {
             .iter()
             .map(|fr| hex_encode(&fr_to_be(fr)))
             .collect(),
+        vk_x: hex_encode(&g1_to_be(&vk_x)),
     };
 
-    let out_path = PathBuf::from("../../tests/fixtures/groth16.json");
-    fs::create_dir_all(out_path.parent().unwrap())?;
-    fs::write(out_path, serde_json::to_vec_pretty(&fixture)?)?;
-    Ok(())
+    write_fixture(PathBuf::from("../../tests/fixtures/groth16.json"), &fixture)
 }
 
 fn fr_to_be(fr: &Fr) -> [u8; 32] {
@@ -119,18 +176,22 @@ fn hex_encode(bytes: &[u8]) -> String {
     bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-fn verify_with_solana_bn254(
-    vk: &ark_groth16::VerifyingKey<Bn254>,
-    proof: &ark_groth16::Proof<Bn254>,
-    public_inputs: &[Fr],
-) -> anyhow::Result<bool> {
+fn compute_vk_x(vk: &ark_groth16::VerifyingKey<Bn254>, public_inputs: &[Fr]) -> G1Affine {
     let mut acc = vk.gamma_abc_g1[0].into_group();
     for (i, input) in public_inputs.iter().enumerate() {
         let mut term = vk.gamma_abc_g1[i + 1].into_group();
         term *= *input;
         acc += term;
     }
-    let vk_x = acc.into_affine();
+    acc.into_affine()
+}
+
+fn verify_with_solana_bn254(
+    vk: &ark_groth16::VerifyingKey<Bn254>,
+    proof: &ark_groth16::Proof<Bn254>,
+    public_inputs: &[Fr],
+) -> anyhow::Result<bool> {
+    let vk_x = compute_vk_x(vk, public_inputs);
 
     let a = g1_to_be(&proof.a);
     let b = g2_to_be(&proof.b);